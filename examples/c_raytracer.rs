@@ -7,9 +7,10 @@ extern crate raytracer;
 use raytracer::{
     render, load_world, CFramebuffer,
     color::ColorU8,
-    image::write_image
+    image::{Framebuffer, write_image}
 };
 
+use std::convert::TryFrom;
 use std::ptr::NonNull;
 
 const WORLD_SOURCE: &str = "\
@@ -50,13 +51,15 @@ fn main() {
     let width  = 200;
     let height = 200;
 
-    let mut buffer = Vec::<ColorU8>::with_capacity(width*height);
+    let mut buffer = vec![ColorU8 { r: 0, g: 0, b: 0, a: 0 }; width*height];
     let pixels = NonNull::new(buffer.as_mut_ptr()).unwrap();
 
-    let source = &*load_world(WORLD_SOURCE.as_ptr() as *const i8);
+    let source = unsafe { &*load_world(WORLD_SOURCE.as_ptr() as *const i8) };
 
     let cframebuffer = CFramebuffer{ width, height, pixels };
-    let framebuffer = render(cframebuffer, source).into();
+    let framebuffer = Framebuffer::try_from(unsafe { render(cframebuffer, source) })
+        .map_err(|(_, error)| error)
+        .unwrap();
 
     write_image(&framebuffer, Some("examples/image.ppm")).unwrap();
 }
@@ -57,7 +57,7 @@ pub struct Sphere {
     pub material: Box<dyn Material>,
 }
 impl Sphere {
-    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = ray.origin - self.center;
         let a = ray.direction.length_squared();
         let b = 2.0 * oc.dot(&ray.direction);
@@ -82,7 +82,7 @@ impl Sphere {
         let position = ray.at(t);
         let normal   = ((position - self.center) / self.radius).normalize();
 
-        return Some(HitRecord{ t, position, normal, material: &self.material });
+        Some(HitRecord{ t, position, normal, material: &self.material })
     }
 }
 
@@ -91,12 +91,12 @@ pub struct World {
 }
 
 impl World {
-    fn hit(&self, ray: &Ray) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray) -> Option<HitRecord<'_>> {
         let mut closest = f32::INFINITY;
         let mut hit_record : Option<HitRecord> = None;
 
         for sphere in &self.spheres {
-            let hit = sphere.hit(&ray, 0.001, closest);
+            let hit = sphere.hit(ray, 0.001, closest);
             if let Some(h) = hit {
                 closest = h.t;
                 hit_record = Some(h);
@@ -115,7 +115,7 @@ pub fn ray_color(ray: &Ray, world: &World, random: &mut Random, depth: i32) -> V
 
     if let Some(hit) = world.hit(ray) {
         let material = hit.material;
-        return if let Some((color, new_ray)) = material.scatter(ray, &hit, random) {
+        if let Some((color, new_ray)) = material.scatter(ray, &hit, random) {
             color * ray_color(&new_ray, world, random, depth - 1)
         } else {
             Vec3::new(0.0, 0.0, 0.0)
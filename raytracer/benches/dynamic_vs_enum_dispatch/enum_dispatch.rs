@@ -57,7 +57,7 @@ pub struct Sphere {
     pub material: MaterialType,
 }
 impl Sphere {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = ray.origin - self.center;
         let a = ray.direction.length_squared();
         let b = 2.0 * oc.dot(&ray.direction);
@@ -82,7 +82,7 @@ impl Sphere {
         let position = ray.at(t);
         let normal   = ((position - self.center) / self.radius).normalize();
 
-        return Some(HitRecord{ t, position, normal, material: &self.material });
+        Some(HitRecord{ t, position, normal, material: &self.material })
     }
 }
 
@@ -92,12 +92,12 @@ pub struct World {
 }
 
 impl World {
-    fn hit(&self, ray: &Ray) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray) -> Option<HitRecord<'_>> {
         let mut closest = f32::INFINITY;
         let mut hit_record : Option<HitRecord> = None;
 
         for sphere in &self.spheres {
-            let hit = sphere.hit(&ray, 0.001, closest);
+            let hit = sphere.hit(ray, 0.001, closest);
             if let Some(h) = hit {
                 closest = h.t;
                 hit_record = Some(h);
@@ -116,7 +116,7 @@ pub fn ray_color(ray: &Ray, world: &World, random: &mut Random, depth: i32) -> V
 
     if let Some(hit) = world.hit(ray) {
         let material = hit.material;
-        return if let Some((color, new_ray)) = material.scatter(ray, &hit, random) {
+        if let Some((color, new_ray)) = material.scatter(ray, &hit, random) {
             color * ray_color(&new_ray, world, random, depth - 1)
         } else {
             Vec3::new(0.0, 0.0, 0.0)
@@ -129,12 +129,12 @@ pub fn ray_color(ray: &Ray, world: &World, random: &mut Random, depth: i32) -> V
 }
 
 
-pub const RED_DIFFUSE   : MaterialType = MaterialType::Diffuse{ 0: Vec3 { x: 1.0, y: 0.0, z: 0.0 } };
-pub const GREEN_DIFFUSE : MaterialType = MaterialType::Diffuse{ 0: Vec3 { x: 0.0, y: 1.0, z: 0.0 } };
-pub const BLUE_DIFFUSE  : MaterialType = MaterialType::Diffuse{ 0: Vec3 { x: 0.0, y: 0.0, z: 1.0 } };
+pub const RED_DIFFUSE   : MaterialType = MaterialType::Diffuse(Vec3 { x: 1.0, y: 0.0, z: 0.0 });
+pub const GREEN_DIFFUSE : MaterialType = MaterialType::Diffuse(Vec3 { x: 0.0, y: 1.0, z: 0.0 });
+pub const BLUE_DIFFUSE  : MaterialType = MaterialType::Diffuse(Vec3 { x: 0.0, y: 0.0, z: 1.0 });
 
-pub const GROUND_MATERIAL : MaterialType = MaterialType::Diffuse{ 0: Vec3 { x: 0.8, y: 0.8, z: 0.0 } };
-pub const BALL_MATERIAL   : MaterialType = MaterialType::Diffuse{ 0: Vec3 { x: 0.7, y: 0.3, z: 0.3 } };
+pub const GROUND_MATERIAL : MaterialType = MaterialType::Diffuse(Vec3 { x: 0.8, y: 0.8, z: 0.0 });
+pub const BALL_MATERIAL   : MaterialType = MaterialType::Diffuse(Vec3 { x: 0.7, y: 0.3, z: 0.3 });
 
-pub const METAL_MATERIAL_1 : MaterialType = MaterialType::Metal{ 0: Vec3 { x: 0.8, y: 0.8, z: 0.8 }, 1: 0.3 };
-pub const METAL_MATERIAL_2 : MaterialType = MaterialType::Metal{ 0: Vec3 { x: 0.8, y: 0.6, z: 0.2 }, 1: 1.0 };
+pub const METAL_MATERIAL_1 : MaterialType = MaterialType::Metal(Vec3 { x: 0.8, y: 0.8, z: 0.8 }, 0.3);
+pub const METAL_MATERIAL_2 : MaterialType = MaterialType::Metal(Vec3 { x: 0.8, y: 0.6, z: 0.2 }, 1.0);
@@ -1,3 +1,5 @@
+#![allow(dead_code, clippy::borrowed_box)]
+
 use std::num::NonZeroU32;
 
 pub mod dynamic_dispatch;
@@ -69,14 +71,14 @@ fn bench_tracer(c: &mut Criterion) {
         let ray = camera.cast_ray(*u, *v);
 
         group.bench_with_input(
-            BenchmarkId::new("Dynamic", &ray.direction),
+            BenchmarkId::new("Dynamic", ray.direction),
             &(&ray, &world_dynamic, *i),
             |b, (ray, world, i)|
                 b.iter(|| dd::ray_color(ray, world, &mut Random::new(NonZeroU32::new(*i).unwrap()), 8))
         );
 
         group.bench_with_input(
-            BenchmarkId::new("Enum", &ray.direction),
+            BenchmarkId::new("Enum", ray.direction),
             &(&ray, &world_enum, *i),
             |b, (ray, world, i)|
                 b.iter(|| ed::ray_color(ray, world, &mut Random::new(NonZeroU32::new(*i).unwrap()), 8))
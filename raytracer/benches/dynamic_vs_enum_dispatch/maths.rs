@@ -1,5 +1,4 @@
 use std::ops::{Add, Sub, Mul, Div};
-use std::convert::Into;
 
 
 pub fn reflect(v: NVec3, n: NVec3) -> Vec3 {
@@ -125,9 +124,9 @@ impl Vector for NVec3 {
     fn length(&self)         -> f32 { 1.0 }
 }
 
-impl Into<Vec3> for NVec3 {
-    fn into(self) -> Vec3 {
-        Vec3::new(self.x, self.y, self.z)
+impl From<NVec3> for Vec3 {
+    fn from(val: NVec3) -> Self {
+        Vec3::new(val.x, val.y, val.z)
     }
 }
 
@@ -0,0 +1,115 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use crate::camera::Camera;
+use crate::common::{World, Options, ray_trace};
+use crate::image::{Framebuffer, write_png};
+use crate::maths::{Vec3, IVector};
+
+
+/// How the camera moves over the frames of an animation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CameraPath {
+    /// Circles the center of the world's bounding box around the Y axis,
+    /// `turns` times over the animation, at the distance where
+    /// `Camera::frame_bounds` keeps all of the world in view. It starts in
+    /// front of the world, looking down -Z.
+    Turntable { turns: f32 },
+}
+
+impl CameraPath {
+    /// The camera of frame `index` out of `frames`, or None if there's
+    /// nothing in the world to move around.
+    pub fn camera(&self, world: &World, index: usize, frames: usize, aspect_ratio: f32) -> Option<Camera> {
+        let (min, max) = world.bounding_box()?;
+        match *self {
+            CameraPath::Turntable { turns } => {
+                let mut camera = Camera::frame_bounds(min, max, aspect_ratio);
+                let center   = 0.5 * (min + max);
+                let distance = (camera.position() - center).length();
+
+                let angle = turns * std::f32::consts::TAU * index as f32 / frames.max(1) as f32;
+                camera.set_origin(center + Vec3::new(distance * angle.sin(), 0.0, distance * angle.cos()));
+                camera.look_at(center);
+                Some(camera)
+            },
+        }
+    }
+}
+
+/// Renders `frames` images of `width` x `height` with the camera moving
+/// along `path`, written as `frame_000.png`, `frame_001.png` and so on to
+/// `out_dir`, which has to exist. Frame `i` is rendered with the seed
+/// `options.seed + i`, so the noise changes between frames but each frame
+/// is the same every time. Returns the paths of the frames.
+pub fn render_animation(world: &World, frames: usize, path: CameraPath, width: usize, height: usize, options: &mut Options, out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let aspect_ratio = width as f32 / height.max(1) as f32;
+    let base_seed = options.seed;
+    let mut written = Vec::with_capacity(frames);
+
+    for index in 0..frames {
+        let camera = path.camera(world, index, frames, aspect_ratio)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "There's nothing in the world to animate around"))?;
+        let framebuffer = Framebuffer::try_new(width, height).map_err(|error| Error::new(ErrorKind::InvalidInput, error.to_string()))?;
+
+        options.seed = base_seed.wrapping_add(index as u64);
+        let framebuffer = ray_trace(world, &camera, framebuffer, options);
+        options.seed = base_seed;
+
+        let file = out_dir.join(format!("frame_{:03}.png", index));
+        write_png(&framebuffer, file.to_str().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "The output directory isn't valid UTF-8"))?)?;
+        written.push(file);
+    }
+
+    Ok(written)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::common::Sphere;
+    use crate::materials::MaterialType;
+
+    fn world() -> World {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, material.clone()), Sphere::new(Vec3::new(2.0, 0.0, -1.0), 0.5, material)], vec![])
+    }
+
+    #[test]
+    fn turntable_circles_the_center() {
+        let world = world();
+        let path = CameraPath::Turntable { turns: 1.0 };
+        let center = Vec3::new(1.0, 0.0, -1.0);
+
+        let first   = path.camera(&world, 0, 4, 1.0).unwrap();
+        let quarter = path.camera(&world, 1, 4, 1.0).unwrap();
+        let distance = (first.position() - center).length();
+        assert!((first.position() - (center + Vec3::new(0.0, 0.0, distance))).length() < 1e-4);
+        assert!((quarter.position() - (center + Vec3::new(distance, 0.0, 0.0))).length() < 1e-4);
+
+        // Looking at the center.
+        let ray = quarter.cast_ray(0.5, 0.5);
+        assert!((Vec3::from(ray.direction) - Vec3::from((center - quarter.position()).normalize())).length() < 1e-5);
+
+        assert!(path.camera(&World::new(vec![], vec![]), 0, 4, 1.0).is_none());
+    }
+
+    #[test]
+    fn writes_a_file_per_frame() {
+        let directory = std::env::temp_dir().join(format!("raytracer_animation_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut options = Options { seed: 7, ..Options::new(1, 2, None, true) };
+        let result = render_animation(&world(), 2, CameraPath::Turntable { turns: 1.0 }, 4, 3, &mut options, &directory);
+        let frames: Vec<Vec<u8>> = result.as_ref().unwrap().iter().map(|file| std::fs::read(file).unwrap()).collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let files = result.unwrap();
+        assert_eq!(files, vec![directory.join("frame_000.png"), directory.join("frame_001.png")]);
+        assert!(frames.iter().all(|data| data.starts_with(b"\x89PNG\r\n\x1a\n")));
+        assert_ne!(frames[0], frames[1]);
+        assert_eq!(options.seed, 7);
+    }
+}
@@ -1,4 +1,4 @@
-#![allow(dead_code)]
+#![allow(dead_code, clippy::items_after_test_module, clippy::unnecessary_cast, clippy::neg_multiply)]
 
 use std::fmt;
 
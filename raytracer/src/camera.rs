@@ -5,6 +5,20 @@ use crate::maths::{Point, Vec3, IVector, NVec3};
 pub struct Radians(pub f32);
 
 
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    /// Parallel rays, all in the viewing direction, from a viewport that's
+    /// `scale` units high (and as wide as the aspect ratio gives).
+    Orthographic { scale: f32 },
+}
+
+
+/// How much larger than the scene the view is in `Camera::frame_bounds`.
+const FRAME_MARGIN: f32 = 1.1;
+
+
 pub struct Camera {
     origin: Point,
 
@@ -12,6 +26,8 @@ pub struct Camera {
     lower_left_corner: Point,
     horizontal: Vec3,
     vertical:   Vec3,
+
+    projection: Projection,
 }
 
 impl Camera {
@@ -29,7 +45,7 @@ impl Camera {
         let vertical   = Vec3::new(0.0, viewport_height, 0.0);
         let lower_left_corner = origin - Vec3::new(viewport_width / 2.0, viewport_height / 2.0, focal_length);
 
-        Camera { origin, lower_left_corner, horizontal, vertical }
+        Camera { origin, lower_left_corner, horizontal, vertical, projection: Projection::Perspective }
     }
     pub fn new_with_vertical_fov(origin: Point, vertical_fov: Radians, aspect_ratio: f32) -> Self {
         let h = f32::tan(vertical_fov.0 / 2.0);
@@ -44,7 +60,7 @@ impl Camera {
         let vertical   = Vec3::new(0.0, viewport_height, 0.0);
         let lower_left_corner = origin - Vec3::new(viewport_width / 2.0, viewport_height / 2.0, focal_length);
 
-        Camera { origin, lower_left_corner, horizontal, vertical }
+        Camera { origin, lower_left_corner, horizontal, vertical, projection: Projection::Perspective }
     }
     pub fn new_look_at(origin: Point, look_at: Point, up: NVec3, vertical_fov: Radians, aspect_ratio: f32) -> Self {
         assert!(!(origin-look_at).near_zero(), "Origin and look_at must differ!");
@@ -65,7 +81,26 @@ impl Camera {
         let vertical   = v * viewport_height;
         let lower_left_corner = origin - horizontal/2.0 - vertical/2.0 - w;
 
-        Camera { origin, lower_left_corner, horizontal, vertical }
+        Camera { origin, lower_left_corner, horizontal, vertical, projection: Projection::Perspective }
+    }
+    /// A camera looking down -Z at the box from `min` to `max`, far enough away
+    /// for all of it to be in view with some margin. Same field of view as `new`.
+    pub fn frame_bounds(min: Point, max: Point, aspect_ratio: f32) -> Self {
+        let center = 0.5 * (min + max);
+        // Fit the sphere around the box in the narrowest direction of the view.
+        let radius = (0.5 * (max - min).length()).max(1e-3) * FRAME_MARGIN;
+        let half_vertical_fov   = f32::atan(1.0);
+        let half_horizontal_fov = f32::atan(aspect_ratio);
+        let distance = radius / f32::sin(half_vertical_fov.min(half_horizontal_fov));
+
+        Self::new_at(center + Vec3::new(0.0, 0.0, distance), aspect_ratio)
+    }
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+    pub fn projection(&self) -> Projection {
+        self.projection
     }
     pub fn aspect_ratio(&self) -> f32 {
         self.horizontal.x / self.vertical.y
@@ -82,13 +117,146 @@ impl Camera {
     // }
     /// Cast a ray from the normalized viewport coordinates s and t.
     pub fn cast_ray(&self, s: f32, t: f32) -> Ray {
-        Ray::new(
-            self.origin,
-            (self.lower_left_corner + s*self.horizontal + t*self.vertical - self.origin).normalize()
-        )
+        match self.projection {
+            Projection::Perspective => Ray::new(
+                self.origin,
+                (self.lower_left_corner + s*self.horizontal + t*self.vertical - self.origin).normalize()
+            ),
+            Projection::Orthographic { scale } => {
+                // Move the origin across a viewport centered on the camera instead.
+                let center    = self.lower_left_corner + 0.5*self.horizontal + 0.5*self.vertical;
+                let up        = self.vertical.normalize();
+                let right     = self.horizontal.normalize();
+                let width     = scale * self.horizontal.length() / self.vertical.length();
+                let origin    = self.origin + ((s - 0.5) * width) * right + ((t - 0.5) * scale) * up;
+                Ray::new(origin, (center - self.origin).normalize())
+            },
+        }
+    }
+    /// Cast a ray from the pixel coordinates (`px`, `py`) of a `width` x
+    /// `height` image, from the top left with y going down. The integer
+    /// coordinates (column, row) give the same ray as `ray_trace` does for
+    /// that pixel before jittering, which adds to `px` and subtracts from `py`.
+    /// An image that's a single pixel wide (or high) looks through the center
+    /// of the viewport along that axis.
+    pub fn cast_ray_pixel(&self, px: f32, py: f32, width: usize, height: usize) -> Ray {
+        let s = if width  > 1 { px / (width-1) as f32 } else { 0.5 };
+        let t = if height > 1 { ((height-1) as f32 - py) / (height-1) as f32 } else { 0.5 };
+        self.cast_ray(s, t)
     }
 
     pub fn position(&self) -> Vec3 {
         self.origin
     }
+    /// Moves the camera to `origin` without turning it.
+    pub fn set_origin(&mut self, origin: Point) {
+        self.lower_left_corner += origin - self.origin;
+        self.origin = origin;
+    }
+    /// Turns the camera towards `target` with +Y up, keeping the origin, field
+    /// of view and projection. Returns false and leaves the camera as it is if
+    /// `target` is the origin or straight above or below it.
+    pub fn look_at(&mut self, target: Point) -> bool {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let w  = match (self.origin - target).try_normalize() {
+            Some(w) => Vec3::from(w),
+            None    => return false,
+        };
+        let u = match up.cross(&w).try_normalize() {
+            Some(u) => Vec3::from(u),
+            None    => return false,
+        };
+        let v = w.cross(&u);
+
+        let focal_length = (self.lower_left_corner + 0.5*self.horizontal + 0.5*self.vertical - self.origin).length();
+        self.horizontal = u * self.horizontal.length();
+        self.vertical   = v * self.vertical.length();
+        self.lower_left_corner = self.origin - self.horizontal/2.0 - self.vertical/2.0 - w * focal_length;
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perspective_is_the_default() {
+        assert_eq!(Camera::new(1.5).projection(), Projection::Perspective);
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let camera = Camera::new(2.0).with_projection(Projection::Orthographic { scale: 4.0 });
+        let a = camera.cast_ray(0.1, 0.2);
+        let b = camera.cast_ray(0.9, 0.7);
+
+        assert!((Vec3::from(a.direction) - Vec3::from(b.direction)).length() < 1e-6);
+        assert!((a.direction.z() + 1.0).abs() < 1e-6);
+        // The viewport is 4 high and 8 wide.
+        assert!((b.origin.x - a.origin.x - 0.8 * 8.0).abs() < 1e-5);
+        assert!((b.origin.y - a.origin.y - 0.5 * 4.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn framed_bounds_are_in_view() {
+        let (min, max) = (Vec3::new(-1.0, -0.5, -5.0), Vec3::new(3.0, 2.0, -1.5));
+        for aspect_ratio in [0.5, 1.0, 2.0] {
+            let camera = Camera::frame_bounds(min, max, aspect_ratio);
+            let center = camera.cast_ray(0.5, 0.5);
+            assert!((center.direction.z() + 1.0).abs() < 1e-6);
+            assert!((center.origin.x - 1.0).abs() < 1e-6 && (center.origin.y - 0.75).abs() < 1e-6);
+
+            // Same as `new`, the viewport is 2 high and 1 away.
+            for corner in [min, max, Vec3::new(min.x, max.y, max.z), Vec3::new(max.x, min.y, max.z)] {
+                let to_corner = corner - camera.position();
+                assert!(to_corner.z < 0.0);
+                assert!((to_corner.x / -to_corner.z).abs() < aspect_ratio);
+                assert!((to_corner.y / -to_corner.z).abs() < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn looking_at_a_target_keeps_the_field_of_view() {
+        let mut camera = Camera::new_at(Vec3::new(1.0, 2.0, 3.0), 2.0);
+        let corner = |camera: &Camera| camera.cast_ray(0.0, 0.0).direction.dot(&camera.cast_ray(0.5, 0.5).direction);
+        let before = corner(&camera);
+
+        let target = Vec3::new(4.0, 0.0, 3.0);
+        assert!(camera.look_at(target));
+        let center = camera.cast_ray(0.5, 0.5);
+        assert!((Vec3::from(center.direction) - Vec3::from((target - camera.position()).normalize())).length() < 1e-5);
+        assert!((corner(&camera) - before).abs() < 1e-5);
+
+        assert!(!camera.look_at(Vec3::new(1.0, 5.0, 3.0)));
+        assert!((Vec3::from(camera.cast_ray(0.5, 0.5).direction) - Vec3::from(center.direction)).length() < 1e-6);
+    }
+
+    #[test]
+    fn center_pixel_casts_the_center_ray() {
+        let camera = Camera::new_at(Vec3::new(1.0, 2.0, 3.0), 2.0);
+        let center = camera.cast_ray(0.5, 0.5);
+        let ray = camera.cast_ray_pixel(4.0, 2.0, 9, 5);
+        assert!((Vec3::from(ray.direction) - Vec3::from(center.direction)).length() < 1e-6);
+
+        // The top left pixel is at the top left of the viewport.
+        let corner = camera.cast_ray_pixel(0.0, 0.0, 9, 5);
+        assert!((Vec3::from(corner.direction) - Vec3::from(camera.cast_ray(0.0, 1.0).direction)).length() < 1e-6);
+    }
+
+    #[test]
+    fn single_pixel_axis_looks_through_the_center() {
+        let camera = Camera::new(1.0);
+        let center = camera.cast_ray(0.5, 0.5);
+        for &(px, py, width, height) in [(0.3, 0.0, 1, 1), (0.0, 0.7, 1, 1)].iter() {
+            let ray = camera.cast_ray_pixel(px, py, width, height);
+            assert!((Vec3::from(ray.direction) - Vec3::from(center.direction)).length() < 1e-6);
+        }
+
+        let strip = camera.cast_ray_pixel(0.0, 0.0, 7, 1);
+        assert!((strip.direction.y() - center.direction.y()).abs() < 1e-6);
+        assert!(strip.direction.x() < 0.0);
+    }
 }
@@ -1,4 +1,5 @@
 use crate::maths::Vec3;
+use std::ops::{Add, Sub, Mul, Div};
 
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
@@ -9,6 +10,14 @@ pub struct ColorU8 {
     pub a: u8,
 }
 
+/// Linear RGB, e.g. radiance, with an alpha for the coverage: 1 where
+/// something was hit and 0 where the ray went through to a transparent sky.
+///
+/// The pixel average of the samples is their sum with `add_with_alpha`
+/// scaled by one over the total weight with `scale_with_alpha`, which treats
+/// alpha as a fourth channel. The average color is then premultiplied by the
+/// coverage, until it's divided out for display. The operators and `add`
+/// and `mul` only work on the RGB channels.
 #[derive(Debug, Copy, Clone)]
 pub struct Color {
     pub r: f32,
@@ -24,18 +33,63 @@ impl Color {
     pub fn new_with_alpha(r: f32, g: f32, b: f32, a: f32) -> Self {
         Self { r, g, b, a }
     }
+    /// The sum of the RGB channels, with an alpha of 1.
     pub fn add(&self, rhs: &Self) -> Self {
         Self::new(self.r + rhs.r, self.g + rhs.g, self.b + rhs.b)
     }
+    /// The sum of all four channels.
     pub fn add_with_alpha(&self, rhs: &Self) -> Self {
         Self::new_with_alpha(self.r + rhs.r, self.g + rhs.g, self.b + rhs.b, self.a + rhs.a)
     }
+    /// The product of the RGB channels, with an alpha of 1.
     pub fn mul(&self, rhs: &Self) -> Self {
         Self::new(self.r * rhs.r, self.g * rhs.g, self.b * rhs.b)
     }
+    /// The product of all four channels.
     pub fn mul_with_alpha(&self, rhs: &Self) -> Self {
         Self::new_with_alpha(self.r * rhs.r, self.g * rhs.g, self.b * rhs.b, self.a * rhs.a)
     }
+    /// All four channels multiplied by `scale`, e.g. to weigh a sample.
+    pub fn scale_with_alpha(&self, scale: f32) -> Self {
+        Self::new_with_alpha(self.r * scale, self.g * scale, self.b * scale, self.a * scale)
+    }
+    /// Relative luminance of the RGB channels, using the Rec. 709 weights.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+    /// Approximate linear color of a black body at the temperature `kelvin`,
+    /// clamped to [1000, 40000] K. Uses Tanner Helland's fit of the Planckian
+    /// locus in sRGB, then gamma decodes it like the textures. The brightest
+    /// channel is 1, so scale it for the strength of a light.
+    pub fn from_blackbody(kelvin: f32) -> Self {
+        let t = kelvin.clamp(1000.0, 40000.0) / 100.0;
+
+        let r = if t <= 66.0 { 255.0 } else { 329.698_73 * (t - 60.0).powf(-0.133_204_76) };
+        let g = if t <= 66.0 {
+            99.470_8 * t.ln() - 161.119_57
+        } else {
+            288.122_17 * (t - 60.0).powf(-0.075_514_85)
+        };
+        let b = if t >= 66.0 {
+            255.0
+        } else if t <= 19.0 {
+            0.0
+        } else {
+            138.517_73 * (t - 10.0).ln() - 305.044_8
+        };
+
+        let linear = |channel: f32| (channel.clamp(0.0, 255.0) / 255.0).powi(2);
+        Self::new(linear(r), linear(g), linear(b))
+    }
+    /// Linear interpolation of all channels, from `self` at 0 to `rhs` at 1.
+    pub fn lerp(&self, rhs: &Self, t: f32) -> Self {
+        Self::new_with_alpha(
+            self.r + (rhs.r - self.r) * t,
+            self.g + (rhs.g - self.g) * t,
+            self.b + (rhs.b - self.b) * t,
+            self.a + (rhs.a - self.a) * t,
+        )
+    }
     pub fn lerp_with_alpha(&self, rhs: &Self, t: f32) -> Self {
         let l = 1.0-t;
         let r = t;
@@ -48,8 +102,83 @@ impl Color {
 }
 
 
+// The operators only work on the RGB channels, the result keeps the alpha
+// of the left hand side. Use `add_with_alpha` and `mul_with_alpha` to
+// include it.
+impl Add<Color> for Color { type Output = Color; fn add(self, rhs: Color) -> Self::Output { Color::new_with_alpha(self.r + rhs.r, self.g + rhs.g, self.b + rhs.b, self.a) } }
+impl Sub<Color> for Color { type Output = Color; fn sub(self, rhs: Color) -> Self::Output { Color::new_with_alpha(self.r - rhs.r, self.g - rhs.g, self.b - rhs.b, self.a) } }
+impl Mul<Color> for Color { type Output = Color; fn mul(self, rhs: Color) -> Self::Output { Color::new_with_alpha(self.r * rhs.r, self.g * rhs.g, self.b * rhs.b, self.a) } }
+impl Mul<f32>   for Color { type Output = Color; fn mul(self, rhs: f32)   -> Self::Output { Color::new_with_alpha(self.r * rhs, self.g * rhs, self.b * rhs, self.a) } }
+impl Mul<Color> for f32   { type Output = Color; fn mul(self, rhs: Color) -> Self::Output { rhs * self } }
+impl Div<f32>   for Color { type Output = Color; fn div(self, rhs: f32)   -> Self::Output { Color::new_with_alpha(self.r / rhs, self.g / rhs, self.b / rhs, self.a) } }
+
+
 impl From<Vec3> for Color {
     fn from(vec3: Vec3) -> Self {
         Self::new(vec3.x, vec3.y, vec3.z)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blackbody_6500_is_roughly_white() {
+        let color = Color::from_blackbody(6500.0);
+        assert!(color.r > 0.9 && color.g > 0.9 && color.b > 0.9, "{:?}", color);
+    }
+
+    #[test]
+    fn blackbody_warm_and_cool() {
+        let warm = Color::from_blackbody(2700.0);
+        let cool = Color::from_blackbody(12000.0);
+        assert!(warm.r > warm.b);
+        assert!(cool.b > cool.r);
+    }
+
+    #[test]
+    fn blackbody_is_clamped() {
+        let (low, high) = (Color::from_blackbody(100.0), Color::from_blackbody(1e6));
+        let (min, max) = (Color::from_blackbody(1000.0), Color::from_blackbody(40000.0));
+        assert_eq!((low.r, low.g, low.b), (min.r, min.g, min.b));
+        assert_eq!((high.r, high.g, high.b), (max.r, max.g, max.b));
+    }
+
+    fn rgba(color: Color) -> (f32, f32, f32, f32) {
+        (color.r, color.g, color.b, color.a)
+    }
+
+    #[test]
+    fn operators_ignore_alpha() {
+        let a = Color::new_with_alpha(1.0, 2.0, 4.0, 0.5);
+        let b = Color::new_with_alpha(0.5, 1.0, 2.0, 0.25);
+
+        assert_eq!(rgba(a + b), (1.5, 3.0, 6.0, 0.5));
+        assert_eq!(rgba(a - b), (0.5, 1.0, 2.0, 0.5));
+        assert_eq!(rgba(a * b), (0.5, 2.0, 8.0, 0.5));
+        assert_eq!(rgba(a * 2.0), (2.0, 4.0, 8.0, 0.5));
+        assert_eq!(rgba(2.0 * a), (2.0, 4.0, 8.0, 0.5));
+        assert_eq!(rgba(a / 2.0), (0.5, 1.0, 2.0, 0.5));
+    }
+
+    #[test]
+    fn averaging_identical_samples_gives_the_sample() {
+        for &sample in [Color::new(0.3, 1.7, 0.01), Color::new_with_alpha(0.2, 0.4, 0.6, 0.5), Color::new_with_alpha(0.0, 0.0, 0.0, 0.0)].iter() {
+            for &count in [1, 3, 100, 1000].iter() {
+                let sum = (0..count).fold(Color::new_with_alpha(0.0, 0.0, 0.0, 0.0), |sum, _| sum.add_with_alpha(&sample));
+                let (r, g, b, a) = rgba(sum.scale_with_alpha(1.0 / count as f32));
+                let close = |x: f32, y: f32| (x - y).abs() <= 1e-5 * y.abs().max(1.0);
+                assert!(close(r, sample.r) && close(g, sample.g) && close(b, sample.b) && close(a, sample.a), "{:?} from {} samples", (r, g, b, a), count);
+            }
+        }
+    }
+
+    #[test]
+    fn luminance_uses_rec_709_weights() {
+        assert!((Color::new(1.0, 1.0, 1.0).luminance() - 1.0).abs() < 1e-6);
+        assert!((Color::new(1.0, 0.0, 0.0).luminance() - 0.2126).abs() < 1e-6);
+        assert!((Color::new(0.0, 0.5, 2.0).luminance() - (0.3576 + 0.1444)).abs() < 1e-6);
+    }
+}
@@ -1,11 +1,19 @@
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::io::{Write, stderr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::materials::{MaterialType, Material, ScatterData};
-use crate::random::Random;
-use crate::image::Framebuffer;
+use crate::random::{Random, SampleStream};
+use crate::image::{Framebuffer, Bloom, ImageOrigin, apply_bloom, auto_exposure, flip_rows};
 use crate::camera::Camera;
-use crate::maths::{Vec3, Point, NVec3, IVector};
+use crate::maths::{Vec3, Point, NVec3, IVector, orthonormal_basis};
 use crate::color::{ColorU8, Color};
+use crate::texture::sphere_uv;
+use crate::bvh::{Aabb, Bvh, BvhStrategy};
 
 
 // ----------------- RAY ----------------------
@@ -23,41 +31,252 @@ impl Ray {
 
 
 // ----------------- OTHER ----------------------
-fn lerp<T>(a: T, b: T, t: f32) -> T
-    where T: std::ops::Mul<f32, Output=T> + std::ops::Add<T, Output=T> {
-    a*(1.0-t) + b*t
+pub fn random_unit_sphere(random: &mut Random) -> NVec3 {
+    loop {
+        let point = Vec3::new(
+            random.random_bilateral_f32(),
+            random.random_bilateral_f32(),
+            random.random_bilateral_f32(),
+        );
+        // Only points inside the ball give uniformly distributed directions,
+        // the corners of the cube would be overrepresented.
+        if point.length_squared() > 1.0 { continue; }
+        if let Some(direction) = point.try_normalize() {
+            return direction;
+        }
+    }
+}
+
+/// A random direction on the side of `normal`.
+pub fn random_in_hemisphere(normal: &NVec3, random: &mut Random) -> NVec3 {
+    let direction = random_unit_sphere(random);
+    if direction.dot(normal) < 0.0 { -direction } else { direction }
 }
 
 
-pub fn random_unit_sphere(random: &mut Random) -> NVec3 {
-    NVec3::new(
-        random.random_bilateral_f32(),
-        random.random_bilateral_f32(),
-        random.random_bilateral_f32(),
-    )
+// ----------------- LIGHTS ----------------------
+/// The geometry of an emissive primitive, copied out of the world so the
+/// lights can be sampled directly.
+#[derive(Debug, Clone)]
+enum LightShape {
+    Sphere { center: Point, radius: f32 },
+    Triangle { v0: Vec3, edge1: Vec3, edge2: Vec3, normal: NVec3, area: f32 },
+}
+
+#[derive(Debug, Clone)]
+struct Light {
+    shape:   LightShape,
+    emitted: Color,
+    two_sided: bool,
+    /// Id of the primitive, to check that a shadow ray reached this light.
+    id:      u32,
+}
+
+/// A direction towards a point on a light.
+struct LightSample {
+    direction: NVec3,
+    /// Solid angle density of the direction.
+    pdf:       f32,
+    /// Distance to the point on the light, where a shadow ray stops.
+    distance:  f32,
+}
+
+impl Light {
+    fn sample(&self, origin: &Point, random: &mut Random) -> Option<LightSample> {
+        match self.shape {
+            LightShape::Sphere { center, radius } => {
+                // Sample the cone of directions that the sphere covers.
+                let to_center = center - *origin;
+                let distance_squared = to_center.length_squared();
+                if distance_squared <= radius * radius { return None; }  // Inside the light.
+
+                let cos_theta_max = f32::sqrt(1.0 - radius * radius / distance_squared);
+                let cos_theta = 1.0 - random.random_f32() * (1.0 - cos_theta_max);
+                let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+                let phi = 2.0 * std::f32::consts::PI * random.random_f32();
+
+                let axis = to_center.normalize();
+                let (tangent, bitangent) = orthonormal_basis(axis);
+                let direction = (sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * axis).normalize();
+
+                // The nearest intersection of the direction with the sphere.
+                let distance_to_center = distance_squared.sqrt();
+                let half_chord = f32::sqrt(f32::max(0.0, radius * radius - distance_squared * sin_theta * sin_theta));
+                let distance = distance_to_center * cos_theta - half_chord;
+
+                Some(LightSample { direction, pdf: cone_pdf(cos_theta_max), distance })
+            },
+            LightShape::Triangle { v0, edge1, edge2, normal, area } => {
+                // Uniform point on the triangle.
+                let r = f32::sqrt(random.random_f32());
+                let s = random.random_f32();
+                let point = v0 + r * (1.0 - s) * edge1 + r * s * edge2;
+
+                let direction = (point - *origin).try_normalize()?;
+                let pdf = area_to_solid_angle(&point, origin, &direction, &normal, area)?;
+                Some(LightSample { direction, pdf, distance: (point - *origin).length() })
+            },
+        }
+    }
+
+    /// Whether light going in `direction` comes from the front of the light.
+    fn faces(&self, direction: &NVec3) -> bool {
+        match self.shape {
+            LightShape::Sphere { .. } => true,
+            LightShape::Triangle { normal, .. } => direction.dot(&normal) < 0.0,
+        }
+    }
+
+    /// The density `sample` would've had for the direction from `origin` to
+    /// the point `hit` on the light.
+    fn pdf(&self, origin: &Point, hit: &HitRecord) -> f32 {
+        match self.shape {
+            LightShape::Sphere { center, radius } => {
+                let distance_squared = (center - *origin).length_squared();
+                if distance_squared <= radius * radius { return 0.0; }
+                cone_pdf(f32::sqrt(1.0 - radius * radius / distance_squared))
+            },
+            LightShape::Triangle { normal, area, .. } => {
+                match (hit.position - *origin).try_normalize() {
+                    Some(direction) => area_to_solid_angle(&hit.position, origin, &direction, &normal, area).unwrap_or(0.0),
+                    None => 0.0,
+                }
+            },
+        }
+    }
+}
+
+fn cone_pdf(cos_theta_max: f32) -> f32 {
+    1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_theta_max)).max(1e-8)
+}
+
+/// Converts the density of a uniformly sampled point on an area to a density
+/// over the directions from `origin`.
+fn area_to_solid_angle(point: &Point, origin: &Point, direction: &NVec3, normal: &NVec3, area: f32) -> Option<f32> {
+    let cos_light = direction.dot(normal).abs();
+    if cos_light < 1e-6 { return None; }
+    Some((*point - *origin).length_squared() / (cos_light * area))
+}
+
+/// Weight of a sample from the strategy with density `pdf` when combined with
+/// a strategy with density `other`, what Veach calls the power heuristic.
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
 }
 
 
 // ----------------- HITTABLES ----------------------
 pub struct HitRecord<'a> {
     pub position: Point,
+    /// Always points against the ray, i.e. to the side that was hit.
     pub normal: NVec3,
+    /// Whether the ray hit the outside of a sphere or the front of a
+    /// triangle, see `Triangle`.
+    pub front_face: bool,
     pub t: f32,
     pub material: &'a MaterialType,
+    /// Id of the primitive that was hit, assigned by `World::new`.
+    pub id: u32,
+    /// Texture coordinates of the hit. For triangles they're interpolated
+    /// from the texture coordinates of the vertices, see `Triangle::with_uvs`.
+    pub uv: (f32, f32),
+    /// Barycentric coordinates (u, v) of triangle hits, i.e. the weights of
+    /// v1 and v2, with 1 - u - v for v0. None for other primitives.
+    pub barycentric: Option<(f32, f32)>,
+    /// The vertex colors of a triangle interpolated to the hit, if it has
+    /// any, which multiply the color of the material.
+    pub vertex_color: Option<Color>,
+}
+
+impl HitRecord<'_> {
+    /// `color` of the material multiplied by the vertex color, if there is one.
+    pub fn tint(&self, color: Color) -> Color {
+        match self.vertex_color {
+            Some(tint) => color * tint,
+            None => color,
+        }
+    }
+}
+
+/// Flips the `outward` normal to point against the ray if needed, and tells
+/// whether the ray hit the outward facing side.
+fn facing_ray(ray: &Ray, outward: NVec3) -> (NVec3, bool) {
+    if ray.direction.dot(&outward) < 0.0 {
+        (outward, true)
+    } else {
+        (-outward, false)
+    }
 }
 
 trait Renderable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
+}
+
+/// What a ray is traced for, which decides the primitives it can hit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RayKind {
+    /// From the camera, the first hit of a path.
+    Camera,
+    /// Scattered from a surface, e.g. for reflections and refractions.
+    Secondary,
+    /// Only testing whether something is in between, towards a light or
+    /// for ambient occlusion.
+    Shadow,
+}
+
+/// Which kinds of rays a primitive shows up for. Every primitive is seen
+/// by secondary rays, so a hidden one still shows up in reflections and
+/// bounces light around.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Visibility {
+    pub visible_to_camera: bool,
+    pub casts_shadow: bool,
+}
+impl Visibility {
+    pub fn hit_by(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera    => self.visible_to_camera,
+            RayKind::Secondary => true,
+            RayKind::Shadow    => self.casts_shadow,
+        }
+    }
+}
+impl Default for Visibility {
+    fn default() -> Self {
+        Self { visible_to_camera: true, casts_shadow: true }
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Sphere {
     pub center: Point,
     pub radius: f32,
     pub material: MaterialType,
+    pub id: u32,
+    /// Optional name from the scene file, for debugging and picking.
+    pub name: Option<String>,
+    pub visibility: Visibility,
+}
+impl Sphere {
+    pub fn new(center: Point, radius: f32, material: MaterialType) -> Self {
+        Self { center, radius, material, id: 0, name: None, visibility: Visibility::default() }
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    pub fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+    pub fn bounding_box(&self) -> Aabb {
+        let extent = Vec3::new(self.radius, self.radius, self.radius);
+        Aabb { min: self.center - extent, max: self.center + extent }
+    }
 }
 impl Renderable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         // let oc = ray.origin - self.center;
         // let a  = ray.direction.length_squared();
         // let b  = 2.0 * oc.dot(&ray.direction);
@@ -85,25 +304,95 @@ impl Renderable for Sphere {
         let root1 = (-half_b - discriminant_sqrt) / a;
         let root2 = (-half_b + discriminant_sqrt) / a;
 
-        let t = [root1, root2]
-            .iter()
-            .cloned()
-            .filter(|&x| t_min < x && x < t_max)
-            .min_by(|a, b| a.partial_cmp(b).expect("Tried to compare a NaN"))?;
+        // The roots are in order, and the far one is hit if the near one is cut out.
+        [root1, root2].iter().cloned().filter(|&x| t_min < x && x < t_max).find_map(|t| {
+            let position = ray.at(t);
+            let outward  = ((position - self.center) / self.radius).normalize();
+            let (normal, front_face) = facing_ray(ray, outward);
+            let uv = sphere_uv(&outward);
+            if self.material.cuts_out(uv) {
+                return None;
+            }
+
+            Some(HitRecord{ t, position, normal, front_face, material: &self.material, id: self.id, uv, barycentric: None, vertex_color: None })
+        })
+    }
+}
+
+/// An infinite plane through `point`, with the front on the side `normal`
+/// points to. The texture coordinates of a hit are its position along the
+/// plane, in world units from `point`.
+#[derive(Debug, Clone)]
+pub struct Plane {
+    pub point:    Point,
+    pub normal:   NVec3,
+    pub material: MaterialType,
+    pub id: u32,
+    /// Optional name from the scene file, for debugging and picking.
+    pub name: Option<String>,
+    pub visibility: Visibility,
+}
+impl Plane {
+    pub fn new(point: Point, normal: NVec3, material: MaterialType) -> Self {
+        Self { point, normal, material, id: 0, name: None, visibility: Visibility::default() }
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    pub fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+}
+impl Renderable for Plane {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let denominator = ray.direction.dot(&self.normal);
+        if denominator.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.point - ray.origin).dot(&self.normal) / denominator;
+        if !(t_min < t && t < t_max) {
+            return None;
+        }
 
         let position = ray.at(t);
-        let normal   = ((position - self.center) / self.radius).normalize();
+        let (normal, front_face) = facing_ray(ray, self.normal);
+        let (tangent, bitangent) = orthonormal_basis(self.normal);
+        let offset = position - self.point;
+        let uv = (offset.dot(&tangent), offset.dot(&bitangent));
+        if self.material.cuts_out(uv) {
+            return None;
+        }
 
-        return Some(HitRecord{ t, position, normal, material: &self.material });
+        Some(HitRecord{ t, position, normal, front_face, material: &self.material, id: self.id, uv, barycentric: None, vertex_color: None })
     }
 }
 
+/// The texture coordinates of v0, v1 and v2 of a triangle that isn't given
+/// any, so the texture coordinates of a hit are its barycentric coordinates.
+pub const DEFAULT_TRIANGLE_UVS: [(f32, f32); 3] = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+
+/// The front of a triangle is the side from which v0, v1 and v2 go around
+/// counter-clockwise, which is the side its normal points to.
+#[derive(Debug, Clone)]
 pub struct Triangle {
     v0 : Vec3,
     v1 : Vec3,
     v2 : Vec3,
+    // Cached from the vertices, as they're needed on every intersection test.
+    edge1       : Vec3,  // v1 - v0
+    edge2       : Vec3,  // v2 - v0
+    face_normal : Vec3,  // edge1 x edge2, not normalized.
     normal   : NVec3,
     material : MaterialType,
+    /// Optional colors of v0, v1 and v2, see `HitRecord::vertex_color`.
+    colors   : Option<[Color; 3]>,
+    /// Texture coordinates of v0, v1 and v2.
+    uvs      : [(f32, f32); 3],
+    id       : u32,
+    name     : Option<String>,
+    visibility: Visibility,
 }
 pub enum Intersection {
     Intersect,
@@ -114,132 +403,338 @@ pub enum Intersection {
 
 impl Triangle {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: MaterialType) -> Self {
-        let a = v1 - v0;
-        let b = v2 - v0;
-        let n = a.cross(&b).normalize();
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+        let face_normal = edge1.cross(&edge2);
         Self {
-            v0, v1, v2, normal: n, material
+            v0, v1, v2, edge1, edge2, face_normal, normal: face_normal.normalize(), material, colors: None, uvs: DEFAULT_TRIANGLE_UVS, id: 0, name: None, visibility: Visibility::default()
         }
     }
-    pub fn intersect(&self, ray: &Ray,  t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let Triangle { v0, v1, v2, .. } = *self;
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    pub fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+    /// Gives the vertices colors, interpolated over the triangle.
+    pub fn with_colors(mut self, colors: [Color; 3]) -> Self {
+        self.colors = Some(colors);
+        self
+    }
+    /// Gives the vertices texture coordinates, interpolated over the
+    /// triangle. They're `DEFAULT_TRIANGLE_UVS` otherwise.
+    pub fn with_uvs(mut self, uvs: [(f32, f32); 3]) -> Self {
+        self.uvs = uvs;
+        self
+    }
+    /// Optional name from the scene file, for debugging and picking.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    pub fn vertices(&self) -> [Vec3; 3] {
+        [self.v0, self.v1, self.v2]
+    }
+    pub fn colors(&self) -> Option<[Color; 3]> {
+        self.colors
+    }
+    pub fn uvs(&self) -> [(f32, f32); 3] {
+        self.uvs
+    }
+    pub fn material(&self) -> &MaterialType {
+        &self.material
+    }
+    /// The triangle with the vertices moved by `f`, keeping the material,
+    /// colors, texture coordinates, name and visibility.
+    pub fn map_vertices<F: Fn(Vec3) -> Vec3>(self, f: F) -> Self {
+        let mut triangle = Triangle::new(f(self.v0), f(self.v1), f(self.v2), self.material);
+        triangle.colors = self.colors;
+        triangle.uvs = self.uvs;
+        triangle.name = self.name;
+        triangle.visibility = self.visibility;
+        triangle
+    }
+    pub fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(&[self.v0, self.v1, self.v2])
+    }
+    /// The point with the barycentric coordinates (u, v), as in `HitRecord::barycentric`.
+    pub fn at_barycentric(&self, (u, v): (f32, f32)) -> Point {
+        (1.0 - u - v) * self.v0 + u * self.v1 + v * self.v2
+    }
+    pub fn intersect(&self, ray: &Ray,  t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.intersect_culled(ray, t_min, t_max, false)
+    }
+    /// Same as `intersect`, but if `cull_backfaces` is set, rays that come
+    /// from behind the triangle go straight through it.
+    pub fn intersect_culled(&self, ray: &Ray,  t_min: f32, t_max: f32, cull_backfaces: bool) -> Option<HitRecord<'_>> {
+        let Triangle { v0, v1, v2, edge1, edge2, .. } = *self;
 
         // -- Intersection with the triangle's coplanar plane.
 
         // NOTE: Not normalized as the length is significant, which
         //  is why we can't use the triangles normal field.
-        let a = v1 - v0;
-        let b = v2 - v0;
-        let n = a.cross(&b);
+        let n = self.face_normal;
 
         fn is_zero(a: f32) -> bool { -1e-8 < a && a < 1e-8 }
 
         let cos_angle_and_length = n.dot(&ray.direction);
         if is_zero(cos_angle_and_length) { return None; }  // Parallel
+        if cull_backfaces && cos_angle_and_length > 0.0 { return None; }  // Behind
 
         let d = n.dot(&v0);
-        let t = (n.dot(&ray.origin) + d) / cos_angle_and_length;
+        let t = (d - n.dot(&ray.origin)) / cos_angle_and_length;
         if t < t_min || t > t_max { return None; }  // TODO: Might need to check intersection in this case.
 
         // -- Intersection with triangle.
         let p = ray.at(t);
+        // The distance to the plane, scaled by the length of the normal, is
+        // only off by rounding errors that grow with the coordinates.
+        debug_assert!(
+            (n.dot(&p) - d).abs() <= 1e-4 * n.length() * (1.0 + p.length().max(v0.length())),
+            "The hit at {:?} isn't on the plane of the triangle.", p
+        );
 
         // Edge 0
-        let e0  = v1 - v0;
+        let e0  = edge1;
         let vp0 = p  - v0;
         let n0  = e0.cross(&vp0);
         if n.dot(&n0) < 0.0 { return None; }
 
         // Edge 1
-        let e1  = v2 - v1;
+        let e1  = edge2 - edge1;
         let vp1 = p  - v1;
         let n1 = e1.cross(&vp1);
         if n.dot(&n1) < 0.0 { return None }
 
         // Edge 2
-        let e2  = v0 - v2;
+        let e2  = -edge2;
         let vp2 = p  - v2;
         let n2 = e2.cross(&vp2);
         if n.dot(&n2) < 0.0 { return None }
 
-        return Some(HitRecord{ position: p, normal: self.normal, t: t, material: &self.material });
+        // The sub-triangle opposite of each vertex, relative to the whole.
+        let area = n.dot(&n);
+        let barycentric = (n.dot(&n2) / area, n.dot(&n0) / area);
+
+        let (normal, front_face) = facing_ray(ray, self.normal);
+
+        let (u, v) = barycentric;
+        let vertex_color = self.colors.map(|[c0, c1, c2]| c0 * (1.0 - u - v) + c1 * u + c2 * v);
+        let [uv0, uv1, uv2] = self.uvs;
+        let uv = (
+            uv0.0 * (1.0 - u - v) + uv1.0 * u + uv2.0 * v,
+            uv0.1 * (1.0 - u - v) + uv1.1 * u + uv2.1 * v,
+        );
+
+        Some(HitRecord{ position: p, normal, front_face, t, material: &self.material, id: self.id, uv, barycentric: Some(barycentric), vertex_color })
     }
 }
 
 pub struct Mesh {
     triangles: Vec<Triangle>,
+    bvh:       Bvh,
+    /// Around all of the triangles, so rays that miss the mesh are rejected
+    /// without traversing the BVH.
+    bounds:    Aabb,
+    /// Only hit the front of the triangles. Only for closed, opaque meshes,
+    /// as e.g. the inside of glass or thin sheets would go missing.
+    cull_backfaces: bool,
 }
 impl Mesh {
     pub fn new(triangles: Vec<Triangle>) -> Self {
-        Self { triangles }
+        Self::new_with_strategy(triangles, BvhStrategy::default())
+    }
+    /// Same as `new`, but with control over how the BVH is built.
+    pub fn new_with_strategy(triangles: Vec<Triangle>, strategy: BvhStrategy) -> Self {
+        let bounds: Vec<Aabb> = triangles.iter().map(Triangle::bounding_box).collect();
+        let bvh = Bvh::new(&bounds, strategy);
+        let bounds = bounds.iter().fold(Aabb::empty(), |all, bounds| all.union(bounds));
+        Self { triangles, bvh, bounds, cull_backfaces: false }
+    }
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles
+    }
+    pub fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+    /// Whether rays should go through the back of the triangles, see `Triangle`.
+    pub fn with_backface_culling(mut self, cull_backfaces: bool) -> Self {
+        self.cull_backfaces = cull_backfaces;
+        self
     }
 }
 impl Renderable for Mesh {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.hit_tests(ray, t_min, t_max, RayKind::Secondary).0
+    }
+}
+impl Mesh {
+    /// Same as `hit`, but only for the triangles that `kind` of ray can hit,
+    /// and also returns how many of those were tested. The `triangle_tests`
+    /// counter still includes the triangles skipped for their visibility.
+    fn hit_tests(&self, ray: &Ray, t_min: f32, t_max: f32, kind: RayKind) -> (Option<HitRecord<'_>>, u64) {
+        if self.bounds.is_empty() || !self.bounds.hit(ray, t_min, t_max) {
+            return (None, 0);
+        }
+
         let mut hit_record : Option<HitRecord> = None;
-        let mut closest_intersection = f32::INFINITY;
-
-        for triangle in self.triangles.iter() {
-            if let Some(hit) = triangle.intersect(&ray, t_min, t_max) {
-                if hit.t < closest_intersection {
-                    closest_intersection = hit.t;
-                    hit_record = Some(
-                        HitRecord {
-                            position:  hit.position, normal: triangle.normal, t: hit.t, material: &triangle.material
-                        }
-                    );
-                }
+        let (mut triangle_tests, mut tests) = (0, 0);
+
+        self.bvh.traverse(ray, t_min, t_max, |index, closest_intersection| {
+            let triangle = &self.triangles[index];
+            triangle_tests += 1;
+            if !triangle.visibility.hit_by(kind) {
+                return None;
             }
+            tests += 1;
+            let hit = triangle.intersect_culled(ray, t_min, closest_intersection, self.cull_backfaces)?;
+            if hit.material.cuts_out(hit.uv) {
+                return None;
+            }
+            let t = hit.t;
+            hit_record = Some(hit);
+            Some(t)
+        });
+        count(|counters| counters.triangle_tests += triangle_tests);
+
+        //
+        // let v0 = triangle.v0;
+        // let v1 = triangle.v1;
+        // let v2 = triangle.v2;
+        //
+        // let e1 = v1 - v0;
+        // let e2 = v2 - v0;
+        //
+        // let b = ray.origin - v0;
+        // let a = Mat3::new((-ray.direction).into(), e1, e2);
+        //
+        // if let Some(inverse) = a.inverse() {
+        //     let result = inverse.mul_vec3(&b);
+        //     let t = result.x;
+        //     let u = result.y;
+        //     let v = result.z;
+        //
+        //     // CLARIFY! u and v should be able to be 0, right?
+        //     if 0.0 <= u && 0.0 <= v && (u + v) <= 1.0 && 0.0 <= t && t < closest_intersection {
+        //         closest_intersection = t;
+        //         let position = ray.at(t);
+        //         hit_record = Some(
+        //             HitRecord{ position, normal: triangle.normal, t, material: &triangle.material }
+        //         );
+        //     }
+        // }
 
-            //
-            // let v0 = triangle.v0;
-            // let v1 = triangle.v1;
-            // let v2 = triangle.v2;
-            //
-            // let e1 = v1 - v0;
-            // let e2 = v2 - v0;
-            //
-            // let b = ray.origin - v0;
-            // let a = Mat3::new((-ray.direction).into(), e1, e2);
-            //
-            // if let Some(inverse) = a.inverse() {
-            //     let result = inverse.mul_vec3(&b);
-            //     let t = result.x;
-            //     let u = result.y;
-            //     let v = result.z;
-            //
-            //     // CLARIFY! u and v should be able to be 0, right?
-            //     if 0.0 <= u && 0.0 <= v && (u + v) <= 1.0 && 0.0 <= t && t < closest_intersection {
-            //         closest_intersection = t;
-            //         let position = ray.at(t);
-            //         hit_record = Some(
-            //             HitRecord{ position, normal: triangle.normal, t, material: &triangle.material }
-            //         );
-            //     }
-            // }
-        }
-
-        return hit_record;
+        (hit_record, tests)
     }
 }
 
 
+/// The scene to render. It's shared by reference between the render threads,
+/// so it, and everything in it, must be `Sync`: immutable after `World::new`,
+/// with shared data like textures behind an `Arc`, and no `Rc` or `Cell`.
+/// Per render state, like the statistics counters, lives on the threads.
 pub struct World {
     spheres: Vec<Sphere>,
     meshes:  Vec<Mesh>,
+    planes:  Vec<Plane>,
+    /// The emissive spheres and triangles, for next event estimation. The
+    /// ones that don't cast shadows can't be found by shadow rays, so they're
+    /// only found by scattering.
+    lights:  Vec<Light>,
+    /// The index in `lights` of each emissive primitive, by id, so a hit can
+    /// be matched to its light without going through all of them.
+    light_indices: HashMap<u32, usize>,
 }
 
 impl World {
-    pub fn new(spheres: Vec<Sphere>, meshes: Vec<Mesh>) -> Self {
-        Self { spheres, meshes }
+    /// Creates the world and gives each sphere and triangle a unique id, in
+    /// order, starting at 1. The id 0 is reserved for the background.
+    pub fn new(mut spheres: Vec<Sphere>, mut meshes: Vec<Mesh>) -> Self {
+        let mut next_id = 1;
+        for sphere in spheres.iter_mut() {
+            sphere.id = next_id;
+            next_id  += 1;
+        }
+        for triangle in meshes.iter_mut().flat_map(|mesh| mesh.triangles.iter_mut()) {
+            triangle.id = next_id;
+            next_id    += 1;
+        }
+
+        let mut lights = Vec::new();
+        for sphere in spheres.iter() {
+            if let (MaterialType::Emission { color: emitted, two_sided }, true) = (&sphere.material, sphere.visibility.casts_shadow) {
+                let (emitted, two_sided) = (*emitted, *two_sided);
+                let shape = LightShape::Sphere { center: sphere.center, radius: sphere.radius };
+                lights.push(Light { shape, emitted, two_sided, id: sphere.id });
+            }
+        }
+        for triangle in meshes.iter().flat_map(|mesh| mesh.triangles.iter()) {
+            if let (MaterialType::Emission { color: emitted, two_sided }, true) = (&triangle.material, triangle.visibility.casts_shadow) {
+                let (emitted, two_sided) = (*emitted, *two_sided);
+                let shape = LightShape::Triangle {
+                    v0: triangle.v0, edge1: triangle.edge1, edge2: triangle.edge2,
+                    normal: triangle.normal, area: 0.5 * triangle.face_normal.length(),
+                };
+                lights.push(Light { shape, emitted, two_sided, id: triangle.id });
+            }
+        }
+
+        let light_indices = lights.iter().enumerate().map(|(index, light)| (light.id, index)).collect();
+        Self { spheres, meshes, planes: Vec::new(), lights, light_indices }
+    }
+
+    /// The light that `hit` is on, if it's one and emits towards the ray.
+    fn light_at(&self, hit: &HitRecord) -> Option<&Light> {
+        let light = &self.lights[*self.light_indices.get(&hit.id)?];
+        if light.two_sided || hit.front_face { Some(light) } else { None }
     }
 
-    pub fn hit(&self, ray: &Ray) -> Option<HitRecord> {
-        let mut closest = f32::INFINITY;
+    /// Adds the `planes`, with ids following the other primitives. Planes
+    /// aren't sampled as lights, even if they're emissive, and aren't part
+    /// of the `bounding_box`.
+    pub fn with_planes(mut self, mut planes: Vec<Plane>) -> Self {
+        let first_id = 1 + (self.sphere_count() + self.triangle_count() + self.planes.len()) as u32;
+        for (id, plane) in (first_id..).zip(planes.iter_mut()) {
+            plane.id = id;
+        }
+        self.planes.extend(planes);
+        self
+    }
+
+    /// The closest hit along the ray, if any, ignoring the ones closer than
+    /// `RAY_EPSILON` so that rays don't hit the surface they start on.
+    pub fn hit(&self, ray: &Ray) -> Option<HitRecord<'_>> {
+        self.hit_range(ray, RAY_EPSILON, f32::INFINITY)
+    }
+
+    /// The closest hit along the ray with a `t` in [`t_min`, `t_max`], if any,
+    /// e.g. to only look for occluders up to a light.
+    pub fn hit_range(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.hit_kind(ray, t_min, t_max, RayKind::Secondary)
+    }
+
+    /// Same as `hit_range`, but skips the primitives that `kind` of ray
+    /// can't hit, see `Visibility`.
+    pub fn hit_kind(&self, ray: &Ray, t_min: f32, t_max: f32, kind: RayKind) -> Option<HitRecord<'_>> {
+        self.hit_tests(ray, t_min, t_max, kind).0
+    }
+
+    /// Same as `hit_kind`, but also returns how many primitives that `kind`
+    /// of ray can hit were tested against it, see `RenderMode::HeatMap`.
+    /// The `sphere_tests` counter still counts every sphere.
+    fn hit_tests(&self, ray: &Ray, t_min: f32, t_max: f32, kind: RayKind) -> (Option<HitRecord<'_>>, u64) {
+        let mut closest = t_max;
         let mut hit_record : Option<HitRecord> = None;
+        let sphere_tests = self.spheres.len() as u64;
+        let mut tests = 0;
 
-        for sphere in &self.spheres {
-            let hit = sphere.hit(&ray, 0.001, closest);
+        for sphere in self.spheres.iter().filter(|sphere| sphere.visibility.hit_by(kind)) {
+            tests += 1;
+            let hit = sphere.hit(ray, t_min, closest);
             if let Some(h) = hit {
                 closest = h.t;
                 hit_record = Some(h);
@@ -247,129 +742,1999 @@ impl World {
         }
 
         for mesh in &self.meshes {
-            let hit = mesh.hit(&ray, 0.001, closest);
+            let (hit, triangle_tests) = mesh.hit_tests(ray, t_min, closest, kind);
+            tests += triangle_tests;
+            if let Some(h) = hit {
+                closest = h.t;
+                hit_record = Some(h);
+            }
+        }
+
+        for plane in self.planes.iter().filter(|plane| plane.visibility.hit_by(kind)) {
+            tests += 1;
+            let hit = plane.hit(ray, t_min, closest);
             if let Some(h) = hit {
                 closest = h.t;
                 hit_record = Some(h);
             }
         }
 
-        hit_record
+        let hits = hit_record.is_some() as u64;
+        count(|counters| {
+            counters.total_rays   += 1;
+            counters.hits         += hits;
+            counters.sphere_tests += sphere_tests;
+        });
+
+        (hit_record, tests)
+    }
+
+    /// The corners (min, max) of the axis aligned box around all of the
+    /// geometry, or None if the world is empty.
+    pub fn bounding_box(&self) -> Option<(Point, Point)> {
+        let spheres = self.spheres.iter().map(Sphere::bounding_box);
+        let meshes  = self.meshes.iter().map(Mesh::bounding_box);
+        let bounds  = spheres.chain(meshes).fold(Aabb::empty(), |bounds, other| bounds.union(&other));
+
+        if bounds.is_empty() { None } else { Some((bounds.min, bounds.max)) }
+    }
+
+    pub fn sphere_count(&self) -> usize {
+        self.spheres.len()
+    }
+    pub fn triangle_count(&self) -> usize {
+        self.meshes.iter().map(|mesh| mesh.triangles.len()).sum()
+    }
+
+    /// The name of the primitive with the given id (e.g. from the id buffer),
+    /// if it was given one.
+    pub fn name_of(&self, id: u32) -> Option<&str> {
+        let index = (id as usize).checked_sub(1)?;
+        if let Some(sphere) = self.spheres.get(index) {
+            return sphere.name.as_deref();
+        }
+        let index = index - self.spheres.len();
+        if let Some(triangle) = self.meshes.iter().flat_map(|mesh| mesh.triangles.iter()).nth(index) {
+            return triangle.name();
+        }
+        self.planes.get(index - self.triangle_count())?.name.as_deref()
+    }
+
+    /// Casts a single ray through (`u`, `v`) of the camera, both in [0, 1]
+    /// with (0, 0) at the bottom left, and returns the position, normal and
+    /// distance of the closest hit. Handy for picking without rendering.
+    pub fn trace_primary(&self, camera: &Camera, u: f32, v: f32) -> Option<(Point, NVec3, f32)> {
+        let ray = camera.cast_ray(u, v);
+        self.hit_kind(&ray, RAY_EPSILON, f32::INFINITY, RayKind::Camera).map(|hit| (hit.position, hit.normal, hit.t))
     }
 }
 
 
 
-fn ray_color(ray: &Ray, world: &World, random: &mut Random, depth: i32) -> Color {
-    let mut ray = ray.clone();
+/// Samples one of the lights from the diffuse surface at `hit` and returns
+/// the light it contributes, weighted against finding the light by scattering.
+/// `albedo` is the color from the scatter, i.e. without the 1/π of the BRDF.
+fn sample_direct_light(world: &World, hit: &HitRecord, albedo: &Color, random: &mut Random) -> Color {
+    let black = Color::new(0.0, 0.0, 0.0);
+    if world.lights.is_empty() { return black; }
+
+    let index = ((random.random_f32() * world.lights.len() as f32) as usize).min(world.lights.len() - 1);
+    let light = &world.lights[index];
+    let selection_pdf = 1.0 / world.lights.len() as f32;
+
+    let sample = match light.sample(&hit.position, random) {
+        Some(sample) => sample,
+        None => return black,
+    };
+    let cos_surface = sample.direction.dot(&hit.normal);
+    if cos_surface <= 0.0 { return black; }
+
+    if !light.two_sided && !light.faces(&sample.direction) { return black; }
+
+    // Shadow ray, nothing can be in between the surface and the light.
+    let shadow_ray = Ray::new(hit.position, sample.direction);
+    if world.hit_kind(&shadow_ray, RAY_EPSILON, sample.distance - RAY_EPSILON, RayKind::Shadow).is_some() {
+        return black;
+    }
+
+    let light_pdf = sample.pdf * selection_pdf;
+    let bsdf_pdf  = cos_surface / std::f32::consts::PI;
+    let scale = power_heuristic(light_pdf, bsdf_pdf) * bsdf_pdf / light_pdf;
+    Color::new(albedo.r * light.emitted.r * scale, albedo.g * light.emitted.g * scale, albedo.b * light.emitted.b * scale)
+}
+
+/// Adds `direct` to the RGB channels of `color`, keeping its alpha.
+fn add_direct(color: Color, direct: &Color) -> Color {
+    Color::new_with_alpha(color.r + direct.r, color.g + direct.g, color.b + direct.b, color.a)
+}
+
+fn ray_color(ray: &Ray, world: &World, random: &mut Random, options: &Options) -> Color {
+    let mut ray = *ray;
     let mut final_color = Color::new(1.0, 1.0, 1.0);
+    // Light gathered by next event estimation along the path.
+    let mut direct = Color::new(0.0, 0.0, 0.0);
+    // The position and scatter density of the previous bounce, if the lights
+    // were sampled there.
+    let mut light_sampled_from: Option<(Point, f32)> = None;
+    // Lights that are overridden aren't lights anymore.
+    let lights_overridden = options.material_override.is_some() && options.override_emission;
+
+    for bounce in 0..options.max_ray_bounces {
+        // Russian roulette: terminate paths that carry little energy, and
+        // boost the ones that survive to keep the estimate unbiased.
+        if let Some(guaranteed_bounces) = options.russian_roulette {
+            if bounce >= guaranteed_bounces {
+                let survival = final_color.r.max(final_color.g).max(final_color.b).clamp(0.05, 1.0);
+                if random.random_f32() > survival {
+                    return add_direct(Color::new(0.0, 0.0, 0.0), &direct);
+                }
+                final_color = Color::new_with_alpha(
+                    final_color.r / survival, final_color.g / survival, final_color.b / survival, final_color.a
+                );
+            }
+        }
+
+        let kind = if bounce == 0 { RayKind::Camera } else { RayKind::Secondary };
+        if let Some(hit) = world.hit_kind(&ray, RAY_EPSILON, f32::INFINITY, kind) {
+            // A light that was also sampled directly at the previous bounce
+            // only gets its share of the contribution.
+            let material = shading_material(&hit, options);
+            if let (Some((origin, bsdf_pdf)), false) = (light_sampled_from, lights_overridden) {
+                if let Some(light) = world.light_at(&hit) {
+                    let light_pdf = light.pdf(&origin, &hit) / world.lights.len() as f32;
+                    let weight = power_heuristic(bsdf_pdf, light_pdf);
+                    let Color { r, g, b, a } = light.emitted;
+                    return add_direct(final_color.mul_with_alpha(&Color::new_with_alpha(r * weight, g * weight, b * weight, a)), &direct);
+                }
+            }
 
-    for _ in 0..depth {
-        if let Some(hit) = world.hit(&ray) {
-            let ScatterData { color, next_ray } = hit.material.scatter(&ray, &hit, random);
+            let ScatterData { color, next_ray } = material.scatter(&ray, &hit, random);
+            let color = hit.tint(color);
             if let Some(next_ray) = next_ray {
+                light_sampled_from = None;
+                if material.is_lambertian() {
+                    direct = add_direct(direct, &final_color.mul(&color.mul(&options.ambient)));
+                }
+                if options.next_event_estimation && !lights_overridden && material.is_lambertian() {
+                    let light = sample_direct_light(world, &hit, &color, random);
+                    direct = add_direct(direct, &final_color.mul(&light));
+
+                    let cos_scatter = next_ray.direction.dot(&hit.normal).max(0.0);
+                    light_sampled_from = Some((hit.position, cos_scatter / std::f32::consts::PI));
+                }
+
                 final_color = final_color.mul_with_alpha(&color);
-                ray = next_ray.clone();
+                ray = next_ray;
+                if final_color.luminance() < options.min_throughput {
+                    return add_direct(Color::new(0.0, 0.0, 0.0), &direct);
+                }
             } else {
-                return final_color.mul_with_alpha(&color);
+                return add_direct(final_color.mul_with_alpha(&color), &direct);
             };
         } else {
-            // Background
-            let t = 0.5 * (ray.direction.normalize().y() + 1.0);
-            let color = lerp(Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.7, 1.0), t).into();
-            return final_color.mul_with_alpha(&color);
+            // Only camera rays see through a transparent sky, an object lit
+            // by it is still opaque.
+            let color = sky_color(&ray.direction.normalize(), options);
+            let color = if bounce == 0 { color } else { Color::new(color.r, color.g, color.b) };
+            return add_direct(final_color.mul_with_alpha(&color), &direct);
+        }
+    }
+
+    // The path ran out of bounces while still carrying light. Returning black
+    // here would darken e.g. glass and mirrors, so use a small ambient term as
+    // a rough stand-in for the light it would've gathered.
+    add_direct(final_color.mul_with_alpha(&options.exhausted_ambient), &direct)
+}
+
+
+/// The material to shade the hit with, which is the override from the
+/// options if there is one. Emissive materials keep emitting, unless
+/// `override_emission` is set.
+fn shading_material<'a>(hit: &HitRecord<'a>, options: &'a Options) -> &'a MaterialType {
+    match &options.material_override {
+        Some(_) if matches!(hit.material, MaterialType::Emission { .. }) && !options.override_emission => hit.material,
+        Some(material) => material,
+        None => hit.material,
+    }
+}
+
+
+/// What rays that miss everything see.
+#[derive(Debug, Copy, Clone, Default)]
+pub enum Sky {
+    /// Blends from `Options::sky_horizon` to `Options::sky_zenith`. The default.
+    #[default]
+    Gradient,
+    /// Transparent black, so the background can be composited later.
+    None,
+    /// The same color in every direction, as is.
+    Color(Color),
+}
+
+
+/// The background, see `Sky`. The gradient blends from the horizon color to
+/// the zenith color as the direction goes up. Up is +Y, or -Y if
+/// `positive_is_up` is false.
+fn sky_color(direction: &NVec3, options: &Options) -> Color {
+    match options.sky {
+        Sky::Gradient   => (),
+        Sky::None       => return Color::new_with_alpha(0.0, 0.0, 0.0, 0.0),
+        Sky::Color(color) => return color,
+    }
+
+    let up = if options.positive_is_up { direction.y() } else { -direction.y() };
+    let t = 0.5 * (up + 1.0);
+    let Color { r, g, b, a } = options.sky_horizon.lerp(&options.sky_zenith, t);
+    let intensity = options.sky_intensity;
+    Color::new_with_alpha(r * intensity, g * intensity, b * intensity, a)
+}
+
+
+/// How the samples within and around a pixel are weighted for antialiasing.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum PixelFilter {
+    /// Samples are spread evenly over the pixel, with the same weight. The default.
+    #[default]
+    Box,
+    /// Samples reach one pixel out from the center, with a weight that falls
+    /// off linearly.
+    Tent,
+    /// Samples reach `3 * sigma` pixels out from the center, with a Gaussian weight.
+    Gaussian { sigma: f32 },
+}
+impl PixelFilter {
+    /// How far from the pixel center the filter reaches, in pixels.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent => 1.0,
+            PixelFilter::Gaussian { sigma } => 3.0 * sigma,
+        }
+    }
+
+    /// The weight of a sample offset by (`dx`, `dy`) pixels from the pixel
+    /// center. The weights integrate to 1 (almost, for the Gaussian, which
+    /// is cut off at its radius).
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        let radius = self.radius();
+        if dx.abs() > radius || dy.abs() > radius {
+            return 0.0;
         }
+        match *self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent => (1.0 - dx.abs()) * (1.0 - dy.abs()),
+            PixelFilter::Gaussian { sigma } => {
+                let normalization = 1.0 / (2.0 * std::f32::consts::PI * sigma * sigma);
+                normalization * f32::exp(-(dx * dx + dy * dy) / (2.0 * sigma * sigma))
+            },
+        }
+    }
+}
+
+
+/// How far along a ray `World::hit` starts looking for hits, so that rays
+/// leaving a surface don't hit it again because of rounding errors.
+pub const RAY_EPSILON: f32 = 0.001;
+
+/// Color of the triangle edges in `RenderMode::Wireframe`.
+const WIREFRAME_EDGE_COLOR: Color = Color { r: 1.0, g: 0.8, b: 0.0, a: 1.0 };
+
+/// The color of `fraction` in [0, 1] on a heat map, from blue through cyan,
+/// green and yellow to red.
+fn heat_map_color(fraction: f32) -> Color {
+    let x = 4.0 * fraction.clamp(0.0, 1.0);
+    let r = (x - 2.0).clamp(0.0, 1.0);
+    let g = if x < 3.0 { x.min(1.0) } else { 4.0 - x };
+    let b = (2.0 - x).clamp(0.0, 1.0);
+    Color::new(r, g, b)
+}
+
+/// Color of a ray for the debug render modes, which only look at the first hit.
+fn debug_color(ray: &Ray, world: &World, mode: RenderMode) -> Color {
+    if let RenderMode::HeatMap { ceiling } = mode {
+        let (_, tests) = world.hit_tests(ray, RAY_EPSILON, f32::INFINITY, RayKind::Camera);
+        return heat_map_color(tests as f32 / ceiling.max(1) as f32);
+    }
+
+    let hit = match world.hit_kind(ray, RAY_EPSILON, f32::INFINITY, RayKind::Camera) {
+        Some(hit) => hit,
+        None => return Color::new(0.0, 0.0, 0.0),
+    };
+
+    match mode {
+        RenderMode::PathTrace | RenderMode::AmbientOcclusion { .. } | RenderMode::HeatMap { .. } => unreachable!("Not a debug mode."),
+        RenderMode::Normals    => (0.5 * (hit.normal + 1.0)).into(),
+        RenderMode::Depth { far_plane } => {
+            let brightness = (1.0 - hit.t / far_plane).max(0.0);
+            Color::new(brightness, brightness, brightness)
+        },
+        RenderMode::AlbedoOnly => hit.tint(hit.material.albedo(hit.uv)),
+        RenderMode::Wireframe { thickness } => match hit.barycentric {
+            Some((u, v)) if u.min(v).min(1.0 - u - v) < thickness => WIREFRAME_EDGE_COLOR,
+            _ => hit.tint(hit.material.albedo(hit.uv)),
+        },
     }
+}
+
+
+/// Brightness of a ray in `RenderMode::AmbientOcclusion`. Misses are white.
+fn ambient_occlusion(ray: &Ray, world: &World, samples: u32, radius: f32, random: &mut Random) -> Color {
+    let hit = match world.hit_kind(ray, RAY_EPSILON, f32::INFINITY, RayKind::Camera) {
+        Some(hit) => hit,
+        None => return Color::new(1.0, 1.0, 1.0),
+    };
+
+    let samples = samples.max(1);
+    let open = (0..samples).filter(|_| {
+        let ray = Ray::new(hit.position, random_in_hemisphere(&hit.normal, random));
+        world.hit_kind(&ray, RAY_EPSILON, radius, RayKind::Shadow).is_none()
+    }).count();
+
+    let brightness = open as f32 / samples as f32;
+    Color::new(brightness, brightness, brightness)
+}
+
 
-    return Vec3::new_zero().into();
+/// What `ray_trace` writes to the framebuffer.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum RenderMode {
+    /// The full path traced image.
+    #[default]
+    PathTrace,
+    /// The surface normal of the first hit, mapped from [-1, 1] to [0, 1].
+    Normals,
+    /// The distance to the first hit, from white at the camera to black
+    /// at `far_plane` and beyond.
+    Depth { far_plane: f32 },
+    /// The material color of the first hit, without any lighting.
+    AlbedoOnly,
+    /// Like `AlbedoOnly`, but with the edges of triangles drawn on top.
+    /// `thickness` is in barycentric coordinates, i.e. the fraction of the
+    /// distance from an edge to the opposite vertex.
+    Wireframe { thickness: f32 },
+    /// The fraction of `samples` random directions from the first hit that
+    /// aren't blocked within `radius`, from black when fully occluded to
+    /// white when open. Materials are ignored.
+    AmbientOcclusion { samples: u32, radius: f32 },
+    /// How many primitives the camera ray was tested against, to see where
+    /// the BVH or the scene costs the most. From blue for none, through
+    /// green and yellow, to red at `ceiling` tests and more. Only
+    /// intersection tests are counted, not visits to BVH nodes, and not
+    /// primitives skipped because camera rays can't hit them.
+    HeatMap { ceiling: u32 },
+}
+impl RenderMode {
+    /// Whether the output is a color that should be gamma corrected,
+    /// rather than data that should be written as is.
+    fn is_color(&self) -> bool {
+        matches!(self, RenderMode::PathTrace | RenderMode::AlbedoOnly | RenderMode::Wireframe { .. })
+    }
 }
 
 
-#[derive(Default)]
 pub struct Options {
     pub samples_per_pixel: i32,
     pub max_ray_bounces:   i32,
-    pub logger: Option<Box<dyn Write>>,
+    /// Where the progress and statistics are written. It's behind a mutex
+    /// so the options can be shared with the render threads, which is why
+    /// the writer has to be `Send`.
+    pub logger: Option<Mutex<Box<dyn Write + Send>>>,
+    /// Whether +Y is up in the world, e.g. for the sky. Otherwise -Y is.
+    /// This doesn't affect the image, see `image_origin`.
     pub positive_is_up:    bool,
+    /// Where row 0 of the framebuffer (and the other output buffers) is.
+    pub image_origin:      ImageOrigin,
+    /// Path traces every object with this material instead of its own, e.g.
+    /// for a clay render. This replaces textured materials as well, and a
+    /// textured override is mapped with each object's own texture coordinates.
+    pub material_override: Option<MaterialType>,
+    /// Whether the override replaces emissive materials too, which leaves
+    /// the scene lit by the sky alone.
+    pub override_emission: bool,
+    /// How the samples are weighted for antialiasing, `PixelFilter::Box` by default.
+    pub pixel_filter:      PixelFilter,
+    pub render_mode:       RenderMode,
+    /// Whether `ray_trace_with_output` should also produce a depth buffer.
+    pub output_depth:      bool,
+    /// Whether `ray_trace_with_output` should also produce an id buffer.
+    pub output_ids:        bool,
+    /// Whether `ray_trace_with_output` should also produce a buffer of the
+    /// averaged linear colors, before gamma correction and quantization.
+    pub output_linear:     bool,
+    /// Clamps the luminance of each sample to suppress fireflies, i.e. single
+    /// very bright pixels from unlikely paths. This biases the image (it gets
+    /// darker), but removes a lot of noise. `None` disables the clamping.
+    pub max_radiance:      Option<f32>,
+    /// Number of bounces every path gets before Russian roulette may terminate
+    /// it, based on how much light it can still carry. Unbiased, but adds some
+    /// noise in exchange for cheaper deep paths. `None` disables it.
+    pub russian_roulette:  Option<i32>,
+    /// Terminates a path once the luminance of the light it can still carry
+    /// drops below this, e.g. after hitting a black surface. Biased (the path
+    /// gets black), but a cheaper alternative to Russian roulette. 0 disables it.
+    pub min_throughput:    f32,
+    /// Light gathered by paths that reach `max_ray_bounces` without terminating.
+    /// This is biased, but less so than the black these paths would otherwise get.
+    pub exhausted_ambient: Color,
+    /// Enables adaptive sampling. A pixel stops taking samples (out of at most
+    /// `samples_per_pixel`) once the estimated standard error of its mean
+    /// luminance falls below this threshold.
+    pub noise_threshold:   Option<f32>,
+    /// The image is rendered in square tiles of this size, which the
+    /// threads take from a shared queue until all are done.
+    pub tile_size:         usize,
+    /// How many threads render the tiles, all of them that the machine
+    /// has by default. 0 is taken as 1.
+    pub threads:           usize,
+    /// Samples a light at each diffuse bounce (next event estimation), with
+    /// the contribution weighted against hitting the light by scattering
+    /// (multiple importance sampling). Unbiased, and much less noisy for
+    /// scenes lit by small emissive objects.
+    pub next_event_estimation: bool,
+    /// Adds a glow around bright parts of path traced images, see `apply_bloom`.
+    pub bloom:             Option<Bloom>,
+    /// Multiplies the linear color of path traced images before tone mapping.
+    pub exposure:          f32,
+    /// Adjusts the exposure of path traced images to their brightness, on
+    /// top of `exposure`, so the average is middle gray. See `auto_exposure`.
+    pub auto_exposure:     bool,
+    /// How much the corners of path traced images are darkened, from 0 (not
+    /// at all) to 1 (black corners).
+    pub vignette:          f32,
+    /// Seeds the random numbers of every pixel, see `Random::for_pixel`.
+    pub seed:              u64,
+    /// The color of the sky towards the horizon and straight up, scaled by
+    /// `sky_intensity`.
+    pub sky_horizon:       Color,
+    pub sky_zenith:        Color,
+    pub sky_intensity:     f32,
+    /// Whether the background is the sky gradient, or something else.
+    pub sky:               Sky,
+    /// Light that every diffuse surface gets, even in full shadow, as if it
+    /// came from everywhere without being blocked. It's not physical and
+    /// brightens the image beyond what the scene lights give, but keeps
+    /// the shadows from going black without a sky. Black turns it off.
+    pub ambient:           Color,
 }
 impl Options {
     pub fn new(
         samples_per_pixel: i32,
         max_ray_bounces: i32,
-        logger: Option<Box<dyn Write>>,
+        logger: Option<Box<dyn Write + Send>>,
         positive_is_up: bool
     ) -> Self {
         Self {
             samples_per_pixel,
             max_ray_bounces,
-            logger,
+            logger: logger.map(Mutex::new),
             positive_is_up,
+            image_origin: ImageOrigin::default(),
+            pixel_filter: PixelFilter::default(),
+            material_override: None,
+            override_emission: false,
+            render_mode: RenderMode::default(),
+            output_depth: false,
+            output_ids:   false,
+            output_linear: false,
+            max_radiance: None,
+            russian_roulette: None,
+            min_throughput: 0.0,
+            exhausted_ambient: Color::new(0.1, 0.1, 0.1),
+            noise_threshold: None,
+            tile_size: 32,
+            threads: thread::available_parallelism().map_or(1, |count| count.get()),
+            next_event_estimation: true,
+            bloom: None,
+            exposure: 1.0,
+            auto_exposure: false,
+            vignette: 0.0,
+            seed: 0,
+            sky_horizon: Color::new(1.0, 1.0, 1.0),
+            sky_zenith:  Color::new(0.5, 0.7, 1.0),
+            sky_intensity: 1.0,
+            sky: Sky::default(),
+            ambient: Color::new(0.0, 0.0, 0.0),
         }
     }
-    pub fn default() -> Self {
+
+    /// Writes to the `logger`, if there is one.
+    fn log(&self, arguments: std::fmt::Arguments) {
+        if let Some(logger) = &self.logger {
+            logger.lock().unwrap().write_fmt(arguments).unwrap();
+        }
+    }
+}
+impl Default for Options {
+    fn default() -> Self {
         Self {
             samples_per_pixel: 32,
             max_ray_bounces:    8,
-            logger: Some(Box::new(stderr())),
+            logger: Some(Mutex::new(Box::new(stderr()))),
             positive_is_up:  true,
+            image_origin: ImageOrigin::default(),
+            pixel_filter: PixelFilter::default(),
+            material_override: None,
+            override_emission: false,
+            render_mode: RenderMode::default(),
+            output_depth: false,
+            output_ids:   false,
+            output_linear: false,
+            max_radiance: None,
+            russian_roulette: None,
+            min_throughput: 0.0,
+            exhausted_ambient: Color::new(0.1, 0.1, 0.1),
+            noise_threshold: None,
+            tile_size: 32,
+            threads: thread::available_parallelism().map_or(1, |count| count.get()),
+            next_event_estimation: true,
+            bloom: None,
+            exposure: 1.0,
+            auto_exposure: false,
+            vignette: 0.0,
+            seed: 0,
+            sky_horizon: Color::new(1.0, 1.0, 1.0),
+            sky_zenith:  Color::new(0.5, 0.7, 1.0),
+            sky_intensity: 1.0,
+            sky: Sky::default(),
+            ambient: Color::new(0.0, 0.0, 0.0),
         }
     }
 }
 
 
-pub fn ray_trace(world: &World, camera: &Camera, mut framebuffer: Framebuffer, options: &mut Options) -> Framebuffer {
-    let mut random = Random::new();
+/// Scales the color down so its luminance is at most `max_radiance`, keeping the hue.
+fn clamp_radiance(color: Color, max_radiance: f32) -> Color {
+    let luminance = color.luminance();
+    if luminance > max_radiance {
+        let scale = max_radiance / luminance;
+        Color::new_with_alpha(color.r * scale, color.g * scale, color.b * scale, color.a)
+    } else {
+        color
+    }
+}
+
+
+/// Samples every pixel gets before adaptive sampling may stop early.
+const ADAPTIVE_MIN_SAMPLES: i32 = 8;
+
+
+/// What happened during a render, see `RenderOutput`. Counting the tests
+/// and the rays other than the primary ones slows down rendering, so they're
+/// only counted with the `render-stats` feature, and 0 otherwise.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct RenderStats {
+    /// Rays from the camera, i.e. the total number of samples.
+    pub primary_rays:   u64,
+    /// Every ray traced through the world, including bounces and shadow rays.
+    pub total_rays:     u64,
+    /// How many of `total_rays` hit something.
+    pub hits:           u64,
+    pub triangle_tests: u64,
+    pub sphere_tests:   u64,
+    pub elapsed:        Duration,
+}
+
+impl std::fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let per_second = |count: u64| count as f64 / self.elapsed.as_secs_f64().max(1e-9);
+        if !cfg!(feature = "render-stats") {
+            return write!(f, "Rendered in {:.2?}: {} primary rays ({:.2}M rays/s)", self.elapsed, self.primary_rays, per_second(self.primary_rays) / 1e6);
+        }
+        write!(f,
+            "Rendered in {:.2?}: {} rays ({} primary, {} hit, {:.2}M rays/s), {} sphere tests, {} triangle tests",
+            self.elapsed, self.total_rays, self.primary_rays, self.hits, per_second(self.total_rays) / 1e6,
+            self.sphere_tests, self.triangle_tests
+        )
+    }
+}
+
+/// The counters of the thread's current tile.
+#[derive(Debug, Copy, Clone, Default)]
+struct Counters {
+    total_rays:     u64,
+    hits:           u64,
+    triangle_tests: u64,
+    sphere_tests:   u64,
+}
+
+thread_local! {
+    // Per thread, so the workers don't contend on them.
+    static COUNTERS: Cell<Counters> = const { Cell::new(Counters { total_rays: 0, hits: 0, triangle_tests: 0, sphere_tests: 0 }) };
+}
+
+fn count(update: impl FnOnce(&mut Counters)) {
+    if cfg!(feature = "render-stats") {
+        COUNTERS.with(|cell| {
+            let mut counters = cell.get();
+            update(&mut counters);
+            cell.set(counters);
+        });
+    }
+}
+
+
+/// Everything `ray_trace_with_output` produces. The extra buffers use the
+/// same layout as the framebuffer, i.e. `[row * width + column]`, and are
+/// only filled in if requested through the `Options`.
+pub struct RenderOutput {
+    pub framebuffer: Framebuffer,
+    /// Distance to the nearest hit along the ray through each pixel's
+    /// center, or infinity on a miss.
+    pub depth: Option<Vec<f32>>,
+    /// Id of the primitive hit by the ray through each pixel's center,
+    /// or 0 on a miss. See `World::new`.
+    pub ids: Option<Vec<u32>>,
+    /// Number of samples taken for each pixel, when using adaptive sampling.
+    pub sample_counts: Option<Vec<u32>>,
+    /// The average of the samples for each pixel, in linear color and not
+    /// clamped to [0, 1]. See `image::write_hdr`.
+    pub linear: Option<Vec<Color>>,
+    pub stats: RenderStats,
+}
+
+
+pub fn ray_trace(world: &World, camera: &Camera, framebuffer: Framebuffer, options: &mut Options) -> Framebuffer {
+    ray_trace_with_output(world, camera, framebuffer, options).framebuffer
+}
+
 
+pub fn ray_trace_with_output(world: &World, camera: &Camera, mut framebuffer: Framebuffer, options: &mut Options) -> RenderOutput {
     let width  = framebuffer.width;
     let height = framebuffer.height;
 
-    // Image
-    for row in 0..height {
-        if let Some(logger) = &mut options.logger {
-            write!(logger, "\rScanline: {:<4}", height-row-1).unwrap();
+    let mut depth = if options.output_depth { Some(vec![f32::INFINITY; width * height]) } else { None };
+    let mut ids   = if options.output_ids   { Some(vec![0u32; width * height]) } else { None };
+    let mut sample_counts = if options.noise_threshold.is_some() { Some(vec![0u32; width * height]) } else { None };
+    // Bloom and auto exposure are applied to the whole image, before it's quantized.
+    let bloom = options.bloom.filter(|_| options.render_mode.is_color());
+    let whole_image = bloom.is_some() || (options.auto_exposure && options.render_mode.is_color());
+    let keep_linear = options.output_linear || whole_image;
+    let mut linear = if keep_linear { Some(vec![Color::new(0.0, 0.0, 0.0); width * height]) } else { None };
+
+    let start = Instant::now();
+    let mut stats = RenderStats::default();
+    let tiles = split_into_tiles(width, height, options.tile_size);
+    let next_tile = AtomicUsize::new(0);
+
+    let settings: &Options = options;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..settings.threads.max(1) {
+            let sender = sender.clone();
+            let (tiles, next_tile) = (&tiles, &next_tile);
+            scope.spawn(move || {
+                // Grab tiles until there are none left.
+                loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    if index >= tiles.len() { break; }
+
+                    COUNTERS.with(|cell| cell.set(Counters::default()));
+                    let pixels = render_tile(world, camera, settings, width, height, &tiles[index]);
+                    let counters = COUNTERS.with(Cell::get);
+                    if sender.send((index, pixels, counters)).is_err() { break; }
+                }
+            });
         }
-
-        for column in 0..width {
-            let mut color = Color::new(0.0, 0.0, 0.0);
-            for _ in 0..options.samples_per_pixel {
-                let u = (column as f32 + random.random_f32()) / (width-1)  as f32;
-                let v = (row    as f32 + random.random_f32()) / (height-1) as f32;
-                let ray = camera.cast_ray(u, v);
-                color = color.add_with_alpha(
-                    &ray_color(&ray, world, &mut random, options.max_ray_bounces)
-                );
+        drop(sender);
+
+        for (completed, (index, pixels, counters)) in receiver.iter().enumerate() {
+            stats.total_rays     += counters.total_rays;
+            stats.hits           += counters.hits;
+            stats.triangle_tests += counters.triangle_tests;
+            stats.sphere_tests   += counters.sphere_tests;
+
+            let tile = &tiles[index];
+            let rows = tile.row_start..tile.row_end;
+            let positions = rows.flat_map(|row| (tile.column_start..tile.column_end).map(move |column| (row, column)));
+
+            for ((row, column), pixel) in positions.zip(pixels) {
+                let index = row * width + column;
+                stats.primary_rays += pixel.samples as u64;
+                framebuffer[[row, column]] = pixel.color;
+                if let Some(depth) = &mut depth { depth[index] = pixel.depth; }
+                if let Some(ids)   = &mut ids   { ids[index]   = pixel.id; }
+                if let Some(sample_counts) = &mut sample_counts { sample_counts[index] = pixel.samples; }
+                if let Some(linear) = &mut linear { linear[index] = pixel.linear; }
             }
 
-            // Gamma correction (approximate to sqrt).
-            let rgba = Color::new_with_alpha(
-                f32::sqrt(color.r * (1.0 / options.samples_per_pixel as f32)) * 255.999,
-                f32::sqrt(color.g * (1.0 / options.samples_per_pixel as f32)) * 255.999,
-                f32::sqrt(color.b * (1.0 / options.samples_per_pixel as f32)) * 255.999,
-                color.a * (1.0 / options.samples_per_pixel as f32) * 255.999
-            );
-
-            framebuffer[[height - row - 1, column]] = ColorU8 {
-                r: rgba.r as u8,
-                g: rgba.g as u8,
-                b: rgba.b as u8,
-                a: rgba.a as u8
-            };
+            settings.log(format_args!("\rTiles: {}/{}", completed + 1, tiles.len()));
         }
+    });
+
+    if let Some(sample_counts) = &sample_counts {
+        let total: u64 = sample_counts.iter().map(|&count| count as u64).sum();
+        options.log(format_args!("\nAverage samples per pixel: {:.2}", total as f32 / (width * height) as f32));
     }
+    stats.elapsed = start.elapsed();
+    options.log(format_args!("\n{}", stats));
 
-    framebuffer
-}
+    if let (true, Some(colors)) = (whole_image, &linear) {
+        write_display_colors(&mut framebuffer, colors, options);
+    }
+    if !options.output_linear {
+        linear = None;
+    }
 
+    // Everything is rendered top to bottom, and flipped for other origins.
+    if options.image_origin == ImageOrigin::BottomLeft {
+        framebuffer.flip_vertically();
+        if let Some(depth) = &mut depth { flip_rows(depth, width, height); }
+        if let Some(ids)   = &mut ids   { flip_rows(ids, width, height); }
+        if let Some(sample_counts) = &mut sample_counts { flip_rows(sample_counts, width, height); }
+        if let Some(linear) = &mut linear { flip_rows(linear, width, height); }
+    }
 
+    RenderOutput { framebuffer, depth, ids, sample_counts, linear, stats }
+}
 
 
-#[cfg(test)]
-mod tests {
+/// Renders a `width` x `height` image of linear RGBA: the average of the
+/// samples of each pixel, without exposure, gamma or clamping to [0, 1]
+/// (unless `max_radiance` is set). The pixels are in the same order as the
+/// framebuffer's, row by row from the top unless `image_origin` is
+/// `ImageOrigin::BottomLeft`. For handing the image to e.g. a compositor
+/// that does its own tone mapping.
+pub fn ray_trace_f32(world: &World, camera: &Camera, width: usize, height: usize, options: &mut Options) -> Vec<[f32; 4]> {
+    let output_linear = options.output_linear;
+    options.output_linear = true;
+    let output = ray_trace_with_output(world, camera, Framebuffer::new(width, height), options);
+    options.output_linear = output_linear;
+
+    output.linear.unwrap_or_default().iter().map(|color| [color.r, color.g, color.b, color.a]).collect()
+}
 
-    #[test]
-    fn bla() {
 
+/// Renders `options.samples_per_pixel` more samples per pixel on top of the
+/// ones already in `accumulation`, and returns the average of all of them.
+/// This lets e.g. a viewer keep refining the image between frames.
+///
+/// `accumulation` holds the sum of the linear colors of the samples so far,
+/// and `sample_counts` the number of samples in each of the sums, both with
+/// the same layout as the framebuffer. The counts differ between pixels
+/// with adaptive sampling, see `Options::noise_threshold`. Both are updated.
+/// Reset them by filling them with zeroes, e.g. when the camera or the scene
+/// changes.
+pub fn render_accumulate(world: &World, camera: &Camera, framebuffer: Framebuffer, accumulation: &mut [Color], sample_counts: &mut [u32], options: &mut Options) -> Framebuffer {
+    assert_eq!(accumulation.len(), framebuffer.width * framebuffer.height, "Accumulation buffer doesn't match the framebuffer!");
+    assert_eq!(sample_counts.len(), accumulation.len(), "Sample counts don't match the accumulation buffer!");
+
+    // Every pass needs new random numbers, or it would just repeat the first one.
+    let (seed, output_linear) = (options.seed, options.output_linear);
+    let samples_so_far: u64 = sample_counts.iter().map(|&count| count as u64).sum();
+    options.seed = seed.wrapping_add(samples_so_far);
+    options.output_linear = true;
+    let output = ray_trace_with_output(world, camera, framebuffer, options);
+    options.seed = seed;
+    options.output_linear = output_linear;
+
+    // Each pixel's color is the average of its own samples.
+    let samples = options.samples_per_pixel.max(1) as u32;
+    let linear = output.linear.as_deref().unwrap_or_default();
+    for (index, (sum, color)) in accumulation.iter_mut().zip(linear).enumerate() {
+        let count = output.sample_counts.as_ref().map_or(samples, |counts| counts[index]);
+        *sum = sum.add_with_alpha(&color.scale_with_alpha(count as f32));
+        sample_counts[index] += count;
     }
 
+    let average: Vec<Color> = accumulation.iter().zip(sample_counts.iter())
+        .map(|(sum, &count)| sum.scale_with_alpha(1.0 / count.max(1) as f32))
+        .collect();
+
+    let mut framebuffer = output.framebuffer;
+    write_display_colors(&mut framebuffer, &average, options);
+    framebuffer
+}
+
 
-}
\ No newline at end of file
+/// Quantizes the linear `colors` into the framebuffer, after applying bloom
+/// and auto exposure if the options ask for them.
+fn write_display_colors(framebuffer: &mut Framebuffer, colors: &[Color], options: &Options) {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    for (pixel, color) in framebuffer.pixels.iter_mut().zip(display_colors(colors, width, height, options)) {
+        *pixel = quantize(&color);
+    }
+}
+
+/// Converts a `width` x `height` buffer of linear colors, e.g. the
+/// `RenderOutput::linear` buffer, to display colors in [0, 1] the same way
+/// as the framebuffer is: with bloom, exposure, vignette and gamma. For
+/// writing them with more precision than the 8-bit framebuffer has.
+pub fn display_colors(colors: &[Color], width: usize, height: usize, options: &Options) -> Vec<Color> {
+    let mut colors = colors.to_vec();
+    let exposure = if options.auto_exposure && options.render_mode.is_color() {
+        options.exposure * auto_exposure(&colors)
+    } else {
+        options.exposure
+    };
+    if let Some(bloom) = options.bloom.filter(|_| options.render_mode.is_color()) {
+        apply_bloom(&mut colors, width, height, bloom.threshold, bloom.radius, bloom.intensity);
+    }
+
+    for (index, color) in colors.iter_mut().enumerate() {
+        *color = display_color(color, options, exposure, width, height, index / width, index % width);
+    }
+    colors
+}
+
+
+/// A rectangle of framebuffer pixels, with exclusive ends.
+struct Tile {
+    row_start:    usize,
+    row_end:      usize,
+    column_start: usize,
+    column_end:   usize,
+}
+
+/// Splits the framebuffer into `tile_size` x `tile_size` tiles, row by row.
+/// The tiles at the right and bottom edges are cut to fit.
+fn split_into_tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let mut tiles = Vec::new();
+
+    for row_start in (0..height).step_by(tile_size) {
+        for column_start in (0..width).step_by(tile_size) {
+            tiles.push(Tile {
+                row_start,
+                row_end: (row_start + tile_size).min(height),
+                column_start,
+                column_end: (column_start + tile_size).min(width),
+            });
+        }
+    }
+
+    tiles
+}
+
+/// The result for a single pixel.
+struct Pixel {
+    color:   ColorU8,
+    depth:   f32,
+    id:      u32,
+    samples: u32,
+    linear:  Color,
+}
+
+/// Renders the pixels of the tile, row by row.
+fn render_tile(world: &World, camera: &Camera, options: &Options, width: usize, height: usize, tile: &Tile) -> Vec<Pixel> {
+    let mut pixels = Vec::with_capacity((tile.row_end - tile.row_start) * (tile.column_end - tile.column_start));
+
+    for row in tile.row_start..tile.row_end {
+        for column in tile.column_start..tile.column_end {
+            // Each pixel gets its own generator, so the image doesn't depend on
+            // the tiling or on which thread renders which tile.
+            let mut random = Random::for_pixel(options.seed, row, column);
+            pixels.push(render_pixel(world, camera, options, &mut random, width, height, row, column));
+        }
+    }
+
+    pixels
+}
+
+/// Renders the pixel at (`row`, `column`) in the framebuffer, where row 0 is the top.
+#[allow(clippy::too_many_arguments)]
+fn render_pixel(world: &World, camera: &Camera, options: &Options, random: &mut Random, width: usize, height: usize, row: usize, column: usize) -> Pixel {
+    // The row counted from the bottom, like the camera's v.
+    let image_row = ImageOrigin::BottomLeft.row_from_top(row, height);
+
+    // The alpha is averaged too, so it has to start at 0.
+    let mut color = Color::new_with_alpha(0.0, 0.0, 0.0, 0.0);
+    let mut samples = 0;
+    let mut total_weight = 0.0;
+    let (mut mean, mut squared_deviations) = (0.0, 0.0);
+
+    // The offsets in the pixel have their own stream, so they don't line up
+    // with what's drawn along the paths, see `SampleStream`.
+    let mut jitter = Random::for_pixel_stream(options.seed, row, column, SampleStream::Jitter);
+
+    while samples < options.samples_per_pixel {
+        // Offset of the sample from the bottom left corner of the pixel.
+        let (x, y, weight) = match options.pixel_filter {
+            PixelFilter::Box => (jitter.random_f32(), jitter.random_f32(), 1.0),
+            filter => {
+                let radius = filter.radius();
+                let dx = jitter.random_bilateral_f32() * radius;
+                let dy = jitter.random_bilateral_f32() * radius;
+                (0.5 + dx, 0.5 + dy, filter.weight(dx, dy))
+            },
+        };
+        // The rows go down, so up in the pixel is towards the previous row.
+        let ray = camera.cast_ray_pixel(column as f32 + x, row as f32 - y, width, height);
+        let sample = match options.render_mode {
+            RenderMode::PathTrace => ray_color(&ray, world, random, options),
+            RenderMode::AmbientOcclusion { samples, radius } => ambient_occlusion(&ray, world, samples, radius, random),
+            mode => debug_color(&ray, world, mode),
+        };
+        let sample = match options.max_radiance {
+            Some(max_radiance) => clamp_radiance(sample, max_radiance),
+            None => sample,
+        };
+        color = color.add_with_alpha(&sample.scale_with_alpha(weight));
+        total_weight += weight;
+        samples += 1;
+
+        // Adaptive sampling: stop once the standard error of the mean
+        // luminance is small enough (Welford's online variance).
+        if let Some(noise_threshold) = options.noise_threshold {
+            let luminance = sample.luminance();
+            let delta = luminance - mean;
+            mean += delta / samples as f32;
+            squared_deviations += delta * (luminance - mean);
+
+            if samples >= ADAPTIVE_MIN_SAMPLES {
+                let variance = squared_deviations / (samples - 1) as f32;
+                if f32::sqrt(variance / samples as f32) < noise_threshold {
+                    break;
+                }
+            }
+        }
+    }
+
+    let scale = if total_weight > 0.0 { 1.0 / total_weight } else { 0.0 };
+    let average = color.scale_with_alpha(scale);
+
+    let mut pixel = Pixel {
+        color: to_display_color(&average, options, width, height, image_row, column),
+        depth: f32::INFINITY,
+        id: 0,
+        samples: samples as u32,
+        linear: average,
+    };
+
+    if options.output_depth || options.output_ids {
+        if let Some(hit) = primary_hit(world, camera, width, height, row, column) {
+            pixel.depth = hit.t;
+            pixel.id    = hit.id;
+        }
+    }
+
+    pixel
+}
+
+
+/// Converts the average of a pixel's samples to the 8-bit color it's displayed with,
+/// applying exposure, vignette and gamma for the color render modes.
+fn to_display_color(color: &Color, options: &Options, width: usize, height: usize, row: usize, column: usize) -> ColorU8 {
+    quantize(&display_color(color, options, options.exposure, width, height, row, column))
+}
+
+/// The gamma corrected color, which is in [0, 1] unless it's too bright to display.
+fn display_color(color: &Color, options: &Options, exposure: f32, width: usize, height: usize, row: usize, column: usize) -> Color {
+    // Averaging the samples premultiplies the color by the alpha, e.g. at the
+    // edge of an object against a transparent sky. Divide it out, so display
+    // colors have straight alpha like PNGs do.
+    let color = if color.a > 0.0 && color.a < 1.0 { *color / color.a } else { *color };
+    let (gamma, scale): (fn(f32) -> f32, f32) = if options.render_mode.is_color() {
+        // Gamma correction (approximate to sqrt).
+        (f32::sqrt, exposure * vignette_factor(options.vignette, width, height, row, column))
+    } else {
+        (std::convert::identity, 1.0)
+    };
+    Color::new_with_alpha(gamma(color.r * scale), gamma(color.g * scale), gamma(color.b * scale), color.a)
+}
+
+/// Clamps the channels of a display color to 8 bits.
+fn quantize(color: &Color) -> ColorU8 {
+    ColorU8 {
+        r: (color.r * 255.999) as u8,
+        g: (color.g * 255.999) as u8,
+        b: (color.b * 255.999) as u8,
+        a: (color.a * 255.999) as u8
+    }
+}
+
+
+/// How much the pixel at (`row`, `column`) is darkened by a vignette of the given
+/// `strength`, falling off with the squared distance from the center of the image.
+fn vignette_factor(strength: f32, width: usize, height: usize, row: usize, column: usize) -> f32 {
+    if strength <= 0.0 {
+        return 1.0;
+    }
+
+    // Distance from the center, where the corners are at 1.
+    let x = (column as f32 + 0.5) / width  as f32 * 2.0 - 1.0;
+    let y = (row    as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+    let distance_squared = (x * x + y * y) / 2.0;
+
+    (1.0 - strength.min(1.0) * distance_squared).max(0.0)
+}
+
+
+/// The first hit along the ray through the center of the pixel at (`row`, `column`)
+/// in a `width` x `height` framebuffer with `ImageOrigin::TopLeft`, the default for `ray_trace`.
+/// This doesn't depend on any jitter, so it's stable for e.g. picking.
+pub fn primary_hit<'a>(world: &'a World, camera: &Camera, width: usize, height: usize, row: usize, column: usize) -> Option<HitRecord<'a>> {
+    world.hit_kind(&camera.cast_ray_pixel(column as f32, row as f32, width, height), RAY_EPSILON, f32::INFINITY, RayKind::Camera)
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::texture::Texture;
+
+    #[test]
+    fn scene_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<World>();
+        assert_send_sync::<Camera>();
+        assert_send_sync::<MaterialType>();
+        assert_send_sync::<Options>();
+    }
+
+    fn single_sphere_world(material: MaterialType) -> World {
+        World::new(
+            vec![Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, material)],
+            vec![]
+        )
+    }
+
+    fn forward_ray() -> Ray {
+        Ray::new(Vec3::new_zero(), NVec3::new(0.0, 0.0, -1.0))
+    }
+
+    #[test]
+    fn bla() {
+
+    }
+
+    #[test]
+    fn normals_mode_maps_normal_to_color() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.2, 0.2, 0.2)));
+        let color = debug_color(&forward_ray(), &world, RenderMode::Normals);
+        assert!((color.r - 0.5).abs() < 1e-6 && (color.g - 0.5).abs() < 1e-6 && (color.b - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn depth_mode_uses_far_plane() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.2, 0.2, 0.2)));
+        let color = debug_color(&forward_ray(), &world, RenderMode::Depth { far_plane: 10.0 });
+        assert!((color.r - 0.95).abs() < 1e-6);
+
+        let color = debug_color(&forward_ray(), &world, RenderMode::Depth { far_plane: 0.25 });
+        assert_eq!(color.r, 0.0);
+    }
+
+    #[test]
+    fn ambient_occlusion_darkens_contacts() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let world = World::new(vec![
+            Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, material.clone()),
+            Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, material),
+        ], vec![]);
+        let mut random = Random::new();
+        let mut brightness_at = |target: Vec3| {
+            let ray = Ray::new(Vec3::new_zero(), target.normalize());
+            ambient_occlusion(&ray, &world, 512, 1.0, &mut random).r
+        };
+
+        let contact = brightness_at(Vec3::new(0.55, -0.5, -1.0));
+        let open = brightness_at(Vec3::new(3.0, -0.6, -1.0));
+        assert!(open > 0.95);
+        assert!(contact < open - 0.2);
+        assert_eq!(brightness_at(Vec3::new(0.0, 1.0, 0.0)), 1.0);
+    }
+
+    #[test]
+    fn heat_map_is_hotter_over_dense_meshes() {
+        // A stack of triangles covering the left half of the view.
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangles = (0..64).map(|i| {
+            let z = -2.0 - 0.01 * i as f32;
+            Triangle::new(Vec3::new(-3.0, -3.0, z), Vec3::new(0.0, -3.0, z), Vec3::new(0.0, 3.0, z), material.clone())
+        }).collect();
+        let world = World::new(vec![], vec![Mesh::new(triangles)]);
+        let mut options = Options { render_mode: RenderMode::HeatMap { ceiling: 32 }, ..Options::new(1, 1, None, true) };
+
+        let image = ray_trace(&world, &Camera::new(1.0), Framebuffer::new(8, 8), &mut options);
+        let (dense, empty) = (image[[5, 1]], image[[5, 6]]);
+        assert!(dense.r > empty.r && dense.b < empty.b, "{:?} isn't hotter than {:?}", (dense.r, dense.b), (empty.r, empty.b));
+        assert_eq!((empty.r, empty.g, empty.b), (0, 0, 255));
+
+        let (r, g, b) = (heat_map_color(0.5).r, heat_map_color(0.5).g, heat_map_color(0.5).b);
+        assert_eq!((r, g, b), (0.0, 1.0, 0.0));
+        let hot = heat_map_color(2.0);
+        assert_eq!((hot.r, hot.g, hot.b), (1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn rays_pass_through_cutouts() {
+        // Transparent on the left half of the texture, opaque on the right.
+        let mut image = Framebuffer::new(2, 1);
+        image[[0, 0]] = ColorU8 { r: 255, g: 255, b: 255, a: 0 };
+        image[[0, 1]] = ColorU8 { r: 255, g: 255, b: 255, a: 255 };
+        let cutout = MaterialType::Cutout { texture: Arc::new(Texture::new(image)), threshold: 0.5 };
+
+        let normal = NVec3::new(0.0, 0.0, 1.0);
+        let world = World::new(vec![], vec![]).with_planes(vec![
+            Plane::new(Vec3::new(0.0, 0.0, -2.0), normal, cutout),
+            Plane::new(Vec3::new(0.0, 0.0, -4.0), normal, MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5))),
+        ]);
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        let hit_at = |u: f32| {
+            let origin = Vec3::from(tangent) * u + Vec3::from(bitangent) * 0.5;
+            world.hit(&Ray::new(origin, NVec3::new(0.0, 0.0, -1.0))).map(|hit| (hit.id, hit.t))
+        };
+
+        assert_eq!(hit_at(0.25), Some((2, 4.0)));
+        assert_eq!(hit_at(0.75), Some((1, 2.0)));
+    }
+
+    #[test]
+    fn albedo_mode_ignores_lighting() {
+        let world = single_sphere_world(MaterialType::Metal(Color::new(0.1, 0.6, 0.3), 0.5));
+        let color = debug_color(&forward_ray(), &world, RenderMode::AlbedoOnly);
+        assert_eq!((color.r, color.g, color.b), (0.1, 0.6, 0.3));
+
+        let miss = Ray::new(Vec3::new_zero(), NVec3::new(0.0, 1.0, 0.0));
+        let color = debug_color(&miss, &world, RenderMode::AlbedoOnly);
+        assert_eq!((color.r, color.g, color.b), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn wireframe_mode_draws_triangle_edges() {
+        let material = MaterialType::Diffuse(Color::new(0.2, 0.4, 0.6));
+        let triangle = Triangle::new(Vec3::new(-0.5, -0.5, -1.0), Vec3::new(0.5, -0.5, -1.0), Vec3::new(0.0, 0.5, -1.0), material);
+        let world = World::new(vec![], vec![Mesh::new(vec![triangle])]);
+        let mode = RenderMode::Wireframe { thickness: 0.05 };
+
+        // Through the inside, and just inside of the bottom edge.
+        let color = debug_color(&forward_ray(), &world, mode);
+        assert_eq!((color.r, color.g, color.b), (0.2, 0.4, 0.6));
+        let near_edge = Ray::new(Vec3::new(0.0, -0.48, 0.0), NVec3::new(0.0, 0.0, -1.0));
+        let color = debug_color(&near_edge, &world, mode);
+        assert_eq!((color.r, color.g, color.b), (1.0, 0.8, 0.0));
+    }
+
+    #[test]
+    fn triangle_hit_has_barycentric_coordinates() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangle = Triangle::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(0.0, 1.0, -1.0), material);
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 0.0), NVec3::new(0.0, 0.0, -1.0));
+
+        let (u, v) = triangle.intersect(&ray, 0.001, f32::INFINITY).unwrap().barycentric.unwrap();
+        assert!((u - 0.25).abs() < 1e-5 && (v - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn triangle_hit_position_is_the_barycentric_point() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangle = Triangle::new(Vec3::new(-1.0, -0.5, -3.0), Vec3::new(2.0, 0.0, -4.0), Vec3::new(0.0, 1.5, -2.0), material);
+
+        let mut random = Random::new();
+        let mut hits = 0;
+        for _ in 0..200 {
+            let origin = Vec3::new(random.random_bilateral_f32(), random.random_bilateral_f32(), 1.0);
+            let ray = Ray::new(origin, NVec3::new(random.random_bilateral_f32(), random.random_bilateral_f32(), -2.0));
+            if let Some(hit) = triangle.intersect(&ray, 0.001, f32::INFINITY) {
+                let (u, v) = hit.barycentric.unwrap();
+                assert!(u >= 0.0 && v >= 0.0 && u + v <= 1.0 + 1e-6);
+                assert!((triangle.at_barycentric((u, v)) - hit.position).length() < 1e-4);
+                hits += 1;
+            }
+        }
+        assert!(hits > 20, "{} hits", hits);
+    }
+
+    #[test]
+    fn vertex_colors_are_interpolated() {
+        let material = MaterialType::Diffuse(Color::new(1.0, 1.0, 1.0));
+        let colors = [Color::new(1.0, 0.0, 0.0), Color::new(0.0, 1.0, 0.0), Color::new(0.0, 0.0, 1.0)];
+        let triangle = Triangle::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(0.0, 1.0, -1.0), material).with_colors(colors);
+        let world = World::new(vec![], vec![Mesh::new(vec![triangle])]);
+
+        // Through the centroid, where all corners weigh a third.
+        let ray = Ray::new(Vec3::new(1.0 / 3.0, 1.0 / 3.0, 0.0), NVec3::new(0.0, 0.0, -1.0));
+        let color = debug_color(&ray, &world, RenderMode::AlbedoOnly);
+        for channel in [color.r, color.g, color.b] {
+            assert!((channel - 1.0 / 3.0).abs() < 1e-5);
+        }
+    }
+
+
+
+    #[test]
+    fn depth_buffer_doesnt_change_color() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+
+        let mut options = Options::new(4, 4, None, true);
+        let without = ray_trace(&world, &camera, Framebuffer::new(5, 5), &mut options);
+
+        options.output_depth = true;
+        let output = ray_trace_with_output(&world, &camera, Framebuffer::new(5, 5), &mut options);
+        let depth  = output.depth.unwrap();
+
+        for (a, b) in without.pixels.iter().zip(output.framebuffer.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+        }
+        // Center pixel looks straight at the sphere, the corners miss it.
+        assert!((depth[2 * 5 + 2] - 0.5).abs() < 1e-4);
+        assert!(depth[0].is_infinite());
+    }
+
+    #[test]
+    fn id_buffer_identifies_primitives() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let world = World::new(
+            vec![
+                Sphere::new(Vec3::new(-1.0, 0.0, -1.0), 0.5, material.clone()),
+                Sphere::new(Vec3::new( 1.0, 0.0, -1.0), 0.5, material.clone()),
+            ],
+            vec![Mesh::new(vec![
+                Triangle::new(Vec3::new(-0.2, -0.2, -1.0), Vec3::new(0.2, -0.2, -1.0), Vec3::new(0.0, 0.2, -1.0), material)
+            ])]
+        );
+        let camera = Camera::new(2.0);
+
+        let mut options = Options::new(1, 1, None, true);
+        options.output_ids = true;
+        let output = ray_trace_with_output(&world, &camera, Framebuffer::new(9, 5), &mut options);
+        let ids = output.ids.unwrap();
+
+        assert_eq!(ids[2 * 9 + 2], 1);
+        assert_eq!(ids[2 * 9 + 6], 2);
+        assert_eq!(ids[2 * 9 + 4], 3);
+        assert_eq!(ids[0], 0);
+        assert!(output.depth.is_none());
+    }
+
+    #[test]
+    fn large_metal_fuzz_renders_without_nan() {
+        let world = single_sphere_world(MaterialType::Metal(Color::new(0.8, 0.8, 0.8), 2.0));
+        let camera = Camera::new(1.0);
+        let options = Options::new(1, 8, None, true);
+        let mut random = Random::new();
+
+        for i in 0..200 {
+            let ray = camera.cast_ray(0.4 + (i % 20) as f32 * 0.01, 0.4 + (i / 20) as f32 * 0.02);
+            let color = ray_color(&ray, &world, &mut random, &options);
+            assert!(color.r.is_finite() && color.g.is_finite() && color.b.is_finite() && color.a.is_finite());
+        }
+    }
+
+    #[test]
+    fn clamp_radiance_only_affects_bright_samples() {
+        let firefly = clamp_radiance(Color::new(1000.0, 500.0, 100.0), 4.0);
+        assert!((firefly.luminance() - 4.0).abs() < 1e-4);
+        assert!((firefly.r / firefly.g - 2.0).abs() < 1e-4);
+        assert_eq!(firefly.a, 1.0);
+
+        let normal = clamp_radiance(Color::new(0.7, 0.5, 0.2), 4.0);
+        assert_eq!((normal.r, normal.g, normal.b, normal.a), (0.7, 0.5, 0.2, 1.0));
+    }
+
+    #[test]
+    fn russian_roulette_preserves_average_brightness() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.8, 0.8, 0.8)));
+        let camera = Camera::new(1.0);
+        let ray = camera.cast_ray(0.5, 0.5);
+
+        let average = |options: &Options| {
+            let mut random = Random::new();
+            let samples = 20000;
+            let mut sum = 0.0;
+            for _ in 0..samples {
+                sum += ray_color(&ray, &world, &mut random, options).luminance();
+            }
+            sum / samples as f32
+        };
+
+        let mut options = Options::new(1, 50, None, true);
+        let without = average(&options);
+        options.russian_roulette = Some(1);
+        let with = average(&options);
+
+        assert!((with - without).abs() / without < 0.03, "{} vs {}", with, without);
+    }
+
+    #[test]
+    fn dark_paths_terminate_early() {
+        // Inside a black sphere, where every bounce hits the sphere again.
+        let world = World::new(vec![Sphere::new(Vec3::new_zero(), 2.0, MaterialType::Diffuse(Color::new(0.0, 0.0, 0.0)))], vec![]);
+        let next_random = |max_ray_bounces: i32, min_throughput: f32| {
+            let options = Options { min_throughput, ..Options::new(1, max_ray_bounces, None, true) };
+            let mut random = Random::new();
+            let color = ray_color(&forward_ray(), &world, &mut random, &options);
+            assert_eq!((color.r, color.g, color.b), (0.0, 0.0, 0.0));
+            random.random_u32()
+        };
+
+        // The path takes as many random numbers as one that's out of bounces after the first.
+        assert_eq!(next_random(50, 1e-4), next_random(1, 0.0));
+        assert_ne!(next_random(50, 0.0), next_random(1, 0.0));
+    }
+
+    #[test]
+    fn exhausted_paths_get_ambient_light() {
+        // With a single bounce, the path ends right after scattering off the sphere.
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let mut options = Options::new(1, 1, None, true);
+        options.exhausted_ambient = Color::new(0.2, 0.3, 0.4);
+        let mut random = Random::new();
+
+        let color = ray_color(&forward_ray(), &world, &mut random, &options);
+        assert!((color.r - 0.1).abs() < 1e-6 && (color.g - 0.15).abs() < 1e-6 && (color.b - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_samples_on_noisy_pixels() {
+        // The right half is a diffuse sphere, the left half is only sky.
+        let world = World::new(
+            vec![Sphere::new(Vec3::new(2.0, 0.0, -1.0), 1.5, MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)))],
+            vec![]
+        );
+        let camera = Camera::new(2.0);
+
+        let mut options = Options::new(128, 8, None, true);
+        options.noise_threshold = Some(0.005);
+        let output = ray_trace_with_output(&world, &camera, Framebuffer::new(8, 4), &mut options);
+        let counts = output.sample_counts.unwrap();
+
+        let average = |columns: std::ops::Range<usize>| {
+            let mut total = 0;
+            for row in 0..4 { for column in columns.clone() { total += counts[row * 8 + column]; } }
+            total as f32 / (4 * columns.len()) as f32
+        };
+        let sky    = average(0..2);
+        let sphere = average(6..8);
+
+        assert!(counts.iter().all(|&count| count >= ADAPTIVE_MIN_SAMPLES as u32 && count <= 128));
+        assert!(sky < 16.0, "{}", sky);
+        assert!(sphere > 2.0 * sky, "{} vs {}", sphere, sky);
+    }
+
+    #[test]
+    fn tiles_cover_the_image() {
+        let tiles = split_into_tiles(70, 33, 32);
+        assert_eq!(tiles.len(), 3 * 2);
+
+        let mut covered = vec![0; 70 * 33];
+        for tile in tiles.iter() {
+            for row in tile.row_start..tile.row_end {
+                for column in tile.column_start..tile.column_end {
+                    covered[row * 70 + column] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn image_doesnt_depend_on_thread_count() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.5);
+
+        let mut options = Options::new(4, 4, None, true);
+        options.tile_size = 8;
+
+        options.threads = 1;
+        let single = ray_trace(&world, &camera, Framebuffer::new(30, 20), &mut options);
+        options.threads = 4;
+        let multiple = ray_trace(&world, &camera, Framebuffer::new(30, 20), &mut options);
+        options.tile_size = 5;
+        let retiled = ray_trace(&world, &camera, Framebuffer::new(30, 20), &mut options);
+
+        for ((a, b), c) in single.pixels.iter().zip(multiple.pixels.iter()).zip(retiled.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+            assert_eq!((a.r, a.g, a.b, a.a), (c.r, c.g, c.b, c.a));
+        }
+    }
+
+    #[test]
+    fn loggers_only_have_to_be_send() {
+        // The cell makes it `Send` but not `Sync`.
+        struct Log(std::sync::Arc<Mutex<Vec<u8>>>, Cell<()>);
+        impl Write for Log {
+            fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buffer);
+                Ok(buffer.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+        }
+
+        let written = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let mut options = Options::new(1, 2, Some(Box::new(Log(written.clone(), Cell::new(())))), true);
+        options.threads = 2;
+        ray_trace(&world, &Camera::new(1.0), Framebuffer::new(4, 4), &mut options);
+
+        assert!(String::from_utf8(written.lock().unwrap().clone()).unwrap().contains("Tiles: 1/1"));
+        assert!(options.logger.is_some());
+    }
+
+    #[test]
+    fn triangle_hit_and_miss() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangle = Triangle::new(Vec3::new(-0.5, -0.5, -1.0), Vec3::new(0.5, -0.5, -1.0), Vec3::new(0.0, 0.5, -1.0), material);
+
+        let hit = triangle.intersect(&forward_ray(), 0.001, f32::INFINITY).expect("Should hit the triangle!");
+        assert!((hit.t - 1.0).abs() < 1e-5);
+        assert!((hit.normal.z() - 1.0).abs() < 1e-5);
+
+        let beside = Ray::new(Vec3::new(0.0, 0.0, 0.0), NVec3::new(1.0, 0.0, -1.0));
+        assert!(triangle.intersect(&beside, 0.001, f32::INFINITY).is_none());
+    }
+
+    #[test]
+    fn trace_primary_through_the_center() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+
+        let (position, normal, distance) = world.trace_primary(&camera, 0.5, 0.5).expect("Should hit the sphere!");
+        assert!((distance - 0.5).abs() < 1e-4);
+        assert!((position.z + 0.5).abs() < 1e-4);
+        assert!((normal.z() - 1.0).abs() < 1e-4);
+
+        assert!(world.trace_primary(&camera, 0.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn light_sampling_is_unbiased_and_less_noisy() {
+        // A diffuse floor lit by a small light above it.
+        let world = World::new(
+            vec![
+                Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5))),
+                Sphere::new(Vec3::new(0.0, 1.0, -1.0), 0.1, MaterialType::Emission { color: Color::new(50.0, 50.0, 50.0), two_sided: true }),
+            ],
+            vec![]
+        );
+        let ray = Ray::new(Vec3::new_zero(), NVec3::new(0.0, -0.5, -1.0));
+
+        let statistics = |next_event_estimation: bool| {
+            let mut options = Options::new(1, 4, None, true);
+            options.next_event_estimation = next_event_estimation;
+            let mut random = Random::new();
+
+            let samples: Vec<f32> = (0..20000).map(|_| ray_color(&ray, &world, &mut random, &options).luminance()).collect();
+            let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+            let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / samples.len() as f32;
+            (mean, variance)
+        };
+
+        let (mean_without, variance_without) = statistics(false);
+        let (mean_with, variance_with) = statistics(true);
+
+        assert!((mean_with - mean_without).abs() < 0.05 * mean_without, "{} vs {}", mean_with, mean_without);
+        assert!(variance_with < 0.5 * variance_without, "{} vs {}", variance_with, variance_without);
+    }
+
+    #[test]
+    fn light_samples_end_on_the_light() {
+        let emission = MaterialType::Emission { color: Color::new(1.0, 1.0, 1.0), two_sided: true };
+        let triangle = Triangle::new(Vec3::new(-1.0, 2.0, -1.0), Vec3::new(1.0, 2.0, -1.0), Vec3::new(0.0, 2.0, 1.0), emission.clone());
+        let world = World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -3.0), 1.0, emission)], vec![Mesh::new(vec![triangle])]);
+        let mut random = Random::new();
+
+        for light in world.lights.iter() {
+            for _ in 0..64 {
+                let sample = light.sample(&Vec3::new_zero(), &mut random).unwrap();
+                let hit = world.hit(&Ray::new(Vec3::new_zero(), sample.direction)).unwrap();
+                assert_eq!(hit.id, light.id);
+                assert!((hit.t - sample.distance).abs() < 1e-3, "{} vs {}", hit.t, sample.distance);
+            }
+        }
+    }
+
+    #[test]
+    fn hits_are_matched_to_their_lights() {
+        let emission = MaterialType::Emission { color: Color::new(1.0, 1.0, 1.0), two_sided: false };
+        let world = World::new(vec![
+            Sphere::new(Vec3::new(0.0, 0.0, -3.0), 1.0, MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5))),
+            Sphere::new(Vec3::new(0.0, 0.0, 3.0), 1.0, emission),
+        ], Vec::new());
+
+        let light = world.hit(&Ray::new(Vec3::new_zero(), NVec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert_eq!(world.light_at(&light).map(|light| light.id), Some(light.id));
+        assert!(world.light_at(&HitRecord { front_face: false, ..light }).is_none());
+
+        let diffuse = world.hit(&Ray::new(Vec3::new_zero(), NVec3::new(0.0, 0.0, -1.0))).unwrap();
+        assert!(world.light_at(&diffuse).is_none());
+    }
+
+    #[test]
+    fn one_sided_lights_only_emit_from_the_front() {
+        // A large triangle light above a diffuse floor, facing down or up.
+        let lit_floor = |facing_down: bool| {
+            let (v1, v2) = (Vec3::new(4.0, 1.0, -4.0), Vec3::new(0.0, 1.0, 4.0));
+            let (v1, v2) = if facing_down { (v1, v2) } else { (v2, v1) };
+            let light = Triangle::new(Vec3::new(-4.0, 1.0, -4.0), v1, v2, MaterialType::Emission { color: Color::new(4.0, 4.0, 4.0), two_sided: false });
+            let world = World::new(
+                vec![Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)))],
+                vec![Mesh::new(vec![light])]
+            );
+
+            let mut random = Random::new();
+            let mut options = Options::new(1, 4, None, true);
+            options.sky_intensity = 0.0;
+            let up   = Ray::new(Vec3::new_zero(), NVec3::new(0.0, 1.0, -0.1));
+            let down = Ray::new(Vec3::new_zero(), NVec3::new(0.0, -0.5, -1.0));
+            let light = (0..100).map(|_| ray_color(&up, &world, &mut random, &options).luminance()).sum::<f32>();
+            let floor = (0..100).map(|_| ray_color(&down, &world, &mut random, &options).luminance()).sum::<f32>();
+            (light, floor)
+        };
+
+        let (light, floor) = lit_floor(true);
+        assert!(light > 0.0 && floor > 0.0);
+        assert_eq!(lit_floor(false), (0.0, 0.0));
+    }
+
+    #[test]
+    fn single_pixel_wide_or_high_images_are_finite() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(2, 4, None, true);
+        options.output_linear = true;
+
+        for &(width, height) in [(1, 8), (8, 1), (1, 1)].iter() {
+            let output = ray_trace_with_output(&world, &camera, Framebuffer::new(width, height), &mut options);
+            let linear = output.linear.unwrap();
+            assert_eq!(linear.len(), width * height);
+            assert!(linear.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()), "{}x{}", width, height);
+        }
+        assert!(primary_hit(&world, &camera, 1, 1, 0, 0).is_some());
+    }
+
+    #[test]
+    fn linear_buffer_is_the_average_before_gamma() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(4, 4, None, true);
+        options.render_mode = RenderMode::AlbedoOnly;
+        options.output_linear = true;
+
+        let output = ray_trace_with_output(&world, &camera, Framebuffer::new(9, 9), &mut options);
+        let linear = output.linear.unwrap();
+
+        // The center pixel sees only the sphere, which is gamma corrected in the 8-bit image.
+        let center = linear[4 * 9 + 4];
+        assert!((center.r - 0.5).abs() < 1e-6);
+        assert_eq!(output.framebuffer[[4, 4]].r, (f32::sqrt(0.5) * 255.999) as u8);
+    }
+
+    #[test]
+    fn f32_output_matches_the_8_bit_image_after_gamma() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(4, 4, None, true);
+
+        let framebuffer = ray_trace(&world, &camera, Framebuffer::new(8, 8), &mut options);
+        let linear = ray_trace_f32(&world, &camera, 8, 8, &mut options);
+        assert_eq!(linear.len(), 8 * 8);
+        assert!(!options.output_linear);
+
+        let displayed: f32 = linear.iter().map(|[r, _, _, _]| f32::sqrt(r.min(1.0))).sum::<f32>() / 64.0;
+        let quantized: f32 = framebuffer.pixels.iter().map(|pixel| pixel.r as f32 / 255.0).sum::<f32>() / 64.0;
+        assert!((displayed - quantized).abs() < 1.0 / 255.0, "{} vs {}", displayed, quantized);
+        assert!(linear.iter().all(|[_, _, _, a]| *a == 1.0));
+    }
+
+    #[test]
+    fn bloom_spreads_light_from_emitters() {
+        let world = single_sphere_world(MaterialType::Emission { color: Color::new(20.0, 20.0, 20.0), two_sided: true });
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(1, 2, None, true);
+        options.output_linear = true;
+
+        let without = ray_trace_with_output(&world, &camera, Framebuffer::new(21, 21), &mut options);
+        options.bloom = Some(Bloom { threshold: 1.0, radius: 2.0, intensity: 0.5 });
+        let with = ray_trace_with_output(&world, &camera, Framebuffer::new(21, 21), &mut options);
+
+        // Just outside of the sphere, the sky gets brighter.
+        let (row, column) = (10, 1);
+        assert!(with.framebuffer[[row, column]].r > without.framebuffer[[row, column]].r);
+        // The linear buffer stays as rendered.
+        let index = row * 21 + column;
+        assert_eq!(with.linear.unwrap()[index].r, without.linear.unwrap()[index].r);
+    }
+
+    #[test]
+    fn exposure_scales_linear_color() {
+        let world = single_sphere_world(MaterialType::Emission { color: Color::new(0.25, 0.25, 0.25), two_sided: true });
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(1, 2, None, true);
+
+        let center = |options: &mut Options| ray_trace(&world, &camera, Framebuffer::new(5, 5), options)[[2, 2]];
+        // sqrt(0.25) = 0.5 and sqrt(4 * 0.25) = 1.0.
+        assert_eq!(center(&mut options).r, 127);
+        options.exposure = 4.0;
+        assert_eq!(center(&mut options).r, 255);
+        options.exposure = 0.0;
+        assert_eq!(center(&mut options).r, 0);
+    }
+
+    #[test]
+    fn auto_exposure_brightens_dim_images() {
+        let world = single_sphere_world(MaterialType::Emission { color: Color::new(0.01, 0.01, 0.01), two_sided: true });
+        let camera = Camera::new(1.0);
+        let mut options = Options { sky: Sky::Color(Color::new(0.01, 0.01, 0.01)), ..Options::new(1, 2, None, true) };
+
+        let without = ray_trace(&world, &camera, Framebuffer::new(5, 5), &mut options)[[2, 2]];
+        options.auto_exposure = true;
+        let with = ray_trace(&world, &camera, Framebuffer::new(5, 5), &mut options)[[2, 2]];
+        // sqrt(0.01) = 0.1 and sqrt(0.18) ≈ 0.42.
+        assert_eq!((without.r, with.r), (25, 108));
+    }
+
+    #[test]
+    fn vignette_darkens_towards_the_corners() {
+        assert_eq!(vignette_factor(0.0, 10, 10, 0, 0), 1.0);
+
+        let center = vignette_factor(1.0, 11, 11, 5, 5);
+        let edge   = vignette_factor(1.0, 11, 11, 5, 0);
+        let corner = vignette_factor(1.0, 11, 11, 0, 0);
+        assert_eq!(center, 1.0);
+        assert!(center > edge && edge > corner && corner > 0.0);
+    }
+
+    #[test]
+    fn planes_are_hit_from_both_sides() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let world = World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, material.clone())], vec![])
+            .with_planes(vec![Plane::new(Vec3::new(0.0, 0.0, -3.0), NVec3::new(0.0, 0.0, 1.0), material).with_name("wall")]);
+
+        let beside = Ray::new(Vec3::new(2.0, 1.0, 0.0), NVec3::new(0.0, 0.0, -1.0));
+        let hit = world.hit(&beside).expect("Should hit the plane!");
+        assert!((hit.t - 3.0).abs() < 1e-5 && hit.front_face);
+        assert_eq!((hit.uv.0.abs(), hit.uv.1.abs()), (2.0, 1.0));
+        assert_eq!(world.name_of(hit.id), Some("wall"));
+        assert_eq!(hit.id, 2);
+
+        let behind = Ray::new(Vec3::new(0.0, 0.0, -5.0), NVec3::new(0.0, 0.0, 1.0));
+        assert!(!world.hit(&behind).unwrap().front_face);
+        assert!(world.hit(&Ray::new(Vec3::new(0.0, 0.0, -5.0), NVec3::new(1.0, 0.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn hits_beyond_the_range_are_ignored() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangle = Triangle::new(Vec3::new(-1.0, -1.0, -2.0), Vec3::new(1.0, -1.0, -2.0), Vec3::new(0.0, 1.0, -2.0), material.clone());
+        let world = World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -4.0), 0.5, material.clone())], vec![Mesh::new(vec![triangle])])
+            .with_planes(vec![Plane::new(Vec3::new(0.0, 0.0, -6.0), NVec3::new(0.0, 0.0, 1.0), material)]);
+
+        assert_eq!(world.hit(&forward_ray()).unwrap().id, 2);
+        assert!(world.hit_range(&forward_ray(), RAY_EPSILON, 1.9).is_none());
+        // Past the triangle, up to the sphere, and then only the plane.
+        assert!(world.hit_range(&forward_ray(), 2.1, 3.4).is_none());
+        assert_eq!(world.hit_range(&forward_ray(), 2.1, 5.0).unwrap().id, 1);
+        assert_eq!(world.hit_range(&forward_ray(), 4.6, f32::INFINITY).unwrap().id, 3);
+    }
+
+    #[test]
+    fn rays_skip_the_primitives_they_cant_see() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let hidden   = Visibility { visible_to_camera: false, casts_shadow: true };
+        let noshadow = Visibility { visible_to_camera: true, casts_shadow: false };
+        let triangle = Triangle::new(Vec3::new(-1.0, -1.0, -2.0), Vec3::new(1.0, -1.0, -2.0), Vec3::new(0.0, 1.0, -2.0), material.clone());
+        let world = World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -4.0), 0.5, material.clone()).with_visibility(noshadow)], vec![Mesh::new(vec![triangle.with_visibility(hidden)])])
+            .with_planes(vec![Plane::new(Vec3::new(0.0, 0.0, -6.0), NVec3::new(0.0, 0.0, 1.0), material)]);
+
+        let first_hit = |kind| world.hit_kind(&forward_ray(), RAY_EPSILON, f32::INFINITY, kind).unwrap().id;
+        assert_eq!(first_hit(RayKind::Secondary), 2);
+        assert_eq!(first_hit(RayKind::Camera), 1);
+        assert_eq!(first_hit(RayKind::Shadow), 2);
+        assert_eq!(world.hit_kind(&forward_ray(), 2.1, f32::INFINITY, RayKind::Shadow).unwrap().id, 3);
+    }
+
+    #[test]
+    fn hidden_spheres_still_show_up_in_reflections() {
+        let red = Color::new(1.0, 0.0, 0.0);
+        let hidden = Visibility { visible_to_camera: false, casts_shadow: true };
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, MaterialType::Emission { color: red, two_sided: true }).with_visibility(hidden);
+        let options = Options { sky: Sky::None, ..Default::default() };
+
+        let world = World::new(vec![sphere.clone()], vec![]);
+        let color = ray_color(&forward_ray(), &world, &mut Random::new(), &options);
+        assert_eq!((color.r, color.g, color.b, color.a), (0.0, 0.0, 0.0, 0.0));
+
+        let mirror = Plane::new(Vec3::new(0.0, 0.0, -3.0), NVec3::new(0.0, 0.0, 1.0), MaterialType::Metal(Color::new(1.0, 1.0, 1.0), 0.0));
+        let world = World::new(vec![sphere], vec![]).with_planes(vec![mirror]);
+        let color = ray_color(&forward_ray(), &world, &mut Random::new(), &options);
+        assert_eq!((color.r, color.g, color.b), (1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn missed_rays_are_transparent_without_a_sky() {
+        let world = World::new(vec![], vec![]);
+        let mut options = Options { sky: Sky::None, ..Default::default() };
+
+        let color = ray_color(&forward_ray(), &world, &mut Random::new(), &options);
+        assert_eq!((color.r, color.g, color.b, color.a), (0.0, 0.0, 0.0, 0.0));
+
+        options.sky = Sky::Color(Color::new(0.1, 0.2, 0.3));
+        let color = ray_color(&forward_ray(), &world, &mut Random::new(), &options);
+        assert_eq!((color.r, color.g, color.b, color.a), (0.1, 0.2, 0.3, 1.0));
+    }
+
+    #[test]
+    fn shadowed_diffuse_surfaces_get_the_ambient_light() {
+        // Nothing but a wall to bounce towards, which only the ambient lights.
+        let wall = Plane::new(Vec3::new(0.0, 0.0, -2.0), NVec3::new(0.0, 0.0, 1.0), MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let world = World::new(vec![], vec![]).with_planes(vec![wall]);
+        let mut options = Options { sky: Sky::None, ..Default::default() };
+
+        let color = ray_color(&forward_ray(), &world, &mut Random::new(), &options);
+        assert_eq!((color.r, color.g, color.b), (0.0, 0.0, 0.0));
+
+        options.ambient = Color::new(0.2, 0.4, 0.6);
+        let color = ray_color(&forward_ray(), &world, &mut Random::new(), &options);
+        assert_eq!((color.r, color.g, color.b), (0.1, 0.2, 0.3));
+    }
+
+    #[test]
+    fn sky_follows_the_up_axis() {
+        let mut options = Options::new(1, 1, None, true);
+        let up   = NVec3::new(0.0,  1.0, 0.0);
+        let down = NVec3::new(0.0, -1.0, 0.0);
+        let same = |a: Color, b: Color| (a.r - b.r).abs() < 1e-6 && (a.g - b.g).abs() < 1e-6 && (a.b - b.b).abs() < 1e-6;
+
+        assert!(same(sky_color(&up,   &options), options.sky_zenith));
+        assert!(same(sky_color(&down, &options), options.sky_horizon));
+
+        options.positive_is_up = false;
+        assert!(same(sky_color(&up,   &options), options.sky_horizon));
+        assert!(same(sky_color(&down, &options), options.sky_zenith));
+
+        options.sky_intensity = 2.0;
+        assert!(same(sky_color(&down, &options), Color::new(1.0, 1.4, 2.0)));
+    }
+
+    #[test]
+    fn accumulated_samples_are_averaged() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(2, 4, None, true);
+
+        let mut accumulation = vec![Color::new(0.0, 0.0, 0.0); 8 * 8];
+        let mut sample_counts = vec![0; 8 * 8];
+        let first = render_accumulate(&world, &camera, Framebuffer::new(8, 8), &mut accumulation, &mut sample_counts, &mut options);
+        assert!(sample_counts.iter().all(|&count| count == 2));
+        let after_one: Vec<Color> = accumulation.clone();
+
+        let second = render_accumulate(&world, &camera, Framebuffer::new(8, 8), &mut accumulation, &mut sample_counts, &mut options);
+        assert!(sample_counts.iter().all(|&count| count == 4));
+        assert_eq!(options.seed, 0);
+
+        // The second pass took new samples rather than repeating the first.
+        assert!(accumulation.iter().zip(after_one.iter()).any(|(sum, first)| (sum.r - 2.0 * first.r).abs() > 1e-4));
+
+        // The first pass is the same as a regular render.
+        let single = ray_trace(&world, &camera, Framebuffer::new(8, 8), &mut options);
+        for (a, b) in first.pixels.iter().zip(single.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+        }
+        // The sky in the corner is the same for every sample.
+        assert_eq!(second[[0, 0]].b, first[[0, 0]].b);
+    }
+
+    #[test]
+    fn accumulation_weights_pixels_by_their_sample_counts() {
+        // The left half is only sky, which converges after few samples.
+        let world = World::new(
+            vec![Sphere::new(Vec3::new(2.0, 0.0, -1.0), 1.5, MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)))],
+            vec![]
+        );
+        let camera = Camera::new(2.0);
+        let mut options = Options { noise_threshold: Some(0.005), ..Options::new(64, 4, None, true) };
+
+        let mut accumulation = vec![Color::new(0.0, 0.0, 0.0); 8 * 4];
+        let mut sample_counts = vec![0; 8 * 4];
+        render_accumulate(&world, &camera, Framebuffer::new(8, 4), &mut accumulation, &mut sample_counts, &mut options);
+
+        // The first pass is the same as a regular render, pixel by pixel.
+        options.output_linear = true;
+        let single = ray_trace_with_output(&world, &camera, Framebuffer::new(8, 4), &mut options);
+        options.output_linear = false;
+        let counts = single.sample_counts.unwrap();
+        assert_eq!(sample_counts, counts);
+        assert!(counts.iter().any(|&count| count != counts[0]));
+        for ((sum, &count), color) in accumulation.iter().zip(counts.iter()).zip(single.linear.unwrap().iter()) {
+            assert!((sum.r / count as f32 - color.r).abs() < 1e-4);
+        }
+
+        // The sky hardly changes with another pass, however many samples it took.
+        let sky = accumulation[0].r / sample_counts[0] as f32;
+        let second = render_accumulate(&world, &camera, Framebuffer::new(8, 4), &mut accumulation, &mut sample_counts, &mut options);
+        assert!(sample_counts[0] > counts[0]);
+        assert!((accumulation[0].r / sample_counts[0] as f32 - sky).abs() < 1e-2, "{} vs {}", accumulation[0].r / sample_counts[0] as f32, sky);
+        assert_eq!(second[[0, 0]].b, ray_trace(&world, &camera, Framebuffer::new(8, 4), &mut options)[[0, 0]].b);
+    }
+
+    #[test]
+    fn bottom_left_origin_flips_the_output() {
+        let world = single_sphere_world(MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5)));
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(2, 2, None, true);
+        options.output_ids = true;
+
+        let top_left = ray_trace_with_output(&world, &camera, Framebuffer::new(6, 4), &mut options);
+        options.image_origin = ImageOrigin::BottomLeft;
+        let bottom_left = ray_trace_with_output(&world, &camera, Framebuffer::new(6, 4), &mut options);
+
+        let mut flipped = bottom_left.framebuffer.clone();
+        flipped.flip_vertically();
+        for (a, b) in top_left.framebuffer.pixels.iter().zip(flipped.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+        }
+        // The sky is brighter blue towards the top.
+        assert!(top_left.framebuffer[[0, 0]].r < top_left.framebuffer[[3, 0]].r);
+        assert!(bottom_left.framebuffer[[3, 0]].r < bottom_left.framebuffer[[0, 0]].r);
+
+        let mut ids = bottom_left.ids.unwrap();
+        flip_rows(&mut ids, 6, 4);
+        assert_eq!(ids, top_left.ids.unwrap());
+    }
+
+    #[test]
+    fn pixel_filter_weights_integrate_to_one() {
+        let mut random = Random::new();
+        for filter in [PixelFilter::Box, PixelFilter::Tent, PixelFilter::Gaussian { sigma: 0.5 }] {
+            let radius = filter.radius();
+            let count = 100_000;
+            let total: f32 = (0..count).map(|_| {
+                let dx = random.random_bilateral_f32() * radius;
+                let dy = random.random_bilateral_f32() * radius;
+                filter.weight(dx, dy)
+            }).sum();
+
+            // The mean weight times the area sampled.
+            let integral = total / count as f32 * (2.0 * radius) * (2.0 * radius);
+            assert!((integral - 1.0).abs() < 0.02, "{:?} integrates to {}", filter, integral);
+        }
+    }
+
+    #[test]
+    fn filtered_pixels_keep_constant_color() {
+        let world = single_sphere_world(MaterialType::Emission { color: Color::new(0.25, 0.25, 0.25), two_sided: true });
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(8, 2, None, true);
+        options.pixel_filter = PixelFilter::Gaussian { sigma: 0.5 };
+
+        let image = ray_trace(&world, &camera, Framebuffer::new(9, 9), &mut options);
+        assert_eq!(image[[4, 4]].r, 127);
+    }
+
+    #[test]
+    fn pixels_average_identical_samples_to_the_sample() {
+        let world = World::new(vec![], vec![]);
+        let sky = Color::new(0.9, 0.05, 0.3);
+        for filter in [PixelFilter::Box, PixelFilter::Tent, PixelFilter::Gaussian { sigma: 0.5 }] {
+            let options = Options { sky: Sky::Color(sky), pixel_filter: filter, ..Options::new(37, 2, None, true) };
+            let pixel = render_pixel(&world, &Camera::new(1.0), &options, &mut Random::new(), 5, 5, 2, 2);
+            let Color { r, g, b, a } = pixel.linear;
+            assert!((r - sky.r).abs() < 1e-5 && (g - sky.g).abs() < 1e-5 && (b - sky.b).abs() < 1e-5 && (a - 1.0).abs() < 1e-5, "{:?}", (r, g, b, a));
+        }
+    }
+
+    #[test]
+    fn material_override_replaces_scene_materials() {
+        let world = single_sphere_world(MaterialType::Metal(Color::new(0.9, 0.1, 0.1), 0.0));
+        let mut options = Options::new(1, 1, None, true);
+        options.material_override = Some(MaterialType::Diffuse(Color::new(0.2, 0.6, 0.4)));
+
+        // With a single bounce, the path only gets the color of the first hit.
+        let mut random = Random::new();
+        let color = ray_color(&forward_ray(), &world, &mut random, &options);
+        assert!((color.r - 0.2 * options.exhausted_ambient.r).abs() < 1e-6);
+        assert!((color.g - 0.6 * options.exhausted_ambient.g).abs() < 1e-6);
+
+        // Lights keep emitting, unless they're overridden as well.
+        let world = single_sphere_world(MaterialType::Emission { color: Color::new(3.0, 3.0, 3.0), two_sided: true });
+        assert_eq!(ray_color(&forward_ray(), &world, &mut random, &options).r, 3.0);
+        options.override_emission = true;
+        assert!(ray_color(&forward_ray(), &world, &mut random, &options).r < 1.0);
+    }
+
+    #[test]
+    fn backfaces_are_only_hit_without_culling() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        // Counter-clockwise seen from +Z, so the front faces the origin.
+        let triangle = Triangle::new(Vec3::new(-0.5, -0.5, -1.0), Vec3::new(0.5, -0.5, -1.0), Vec3::new(0.0, 0.5, -1.0), material);
+        let from_behind = Ray::new(Vec3::new(0.0, 0.0, -2.0), NVec3::new(0.0, 0.0, 1.0));
+
+        assert!(triangle.intersect_culled(&forward_ray(), 0.001, f32::INFINITY, true).is_some());
+        assert!(triangle.intersect_culled(&from_behind,   0.001, f32::INFINITY, true).is_none());
+        assert!(triangle.intersect_culled(&forward_ray(), 0.001, f32::INFINITY, false).is_some());
+        assert!(triangle.intersect_culled(&from_behind,   0.001, f32::INFINITY, false).is_some());
+
+        let mesh = Mesh::new(vec![triangle]).with_backface_culling(true);
+        assert!(mesh.hit(&forward_ray(), 0.001, f32::INFINITY).is_some());
+        assert!(mesh.hit(&from_behind,   0.001, f32::INFINITY).is_none());
+    }
+
+    #[test]
+    fn sphere_normal_faces_the_ray() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, MaterialType::Dielectric(1.5));
+
+        let outside = sphere.hit(&forward_ray(), 0.001, f32::INFINITY).unwrap();
+        assert!(outside.front_face);
+        assert!((outside.t - 0.5).abs() < 1e-5 && (outside.normal.z() - 1.0).abs() < 1e-5);
+
+        let inside = Ray::new(Vec3::new(0.0, 0.0, -1.0), NVec3::new(0.0, 0.0, -1.0));
+        let hit = sphere.hit(&inside, 0.001, f32::INFINITY).unwrap();
+        assert!(!hit.front_face);
+        assert!((hit.t - 0.5).abs() < 1e-5 && (hit.normal.z() - 1.0).abs() < 1e-5);
+        // The texture coordinates still come from the outward normal.
+        assert_eq!(hit.uv, sphere_uv(&NVec3::new(0.0, 0.0, -1.0)));
+    }
+
+    #[test]
+    fn triangle_back_hits_flip_the_normal() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangle = Triangle::new(Vec3::new(-0.5, -0.5, -1.0), Vec3::new(0.5, -0.5, -1.0), Vec3::new(0.0, 0.5, -1.0), material);
+
+        let front = triangle.intersect(&forward_ray(), 0.001, f32::INFINITY).unwrap();
+        assert!(front.front_face && front.normal.z() > 0.99);
+
+        let from_behind = Ray::new(Vec3::new(0.0, 0.0, -2.0), NVec3::new(0.0, 0.0, 1.0));
+        let back = triangle.intersect(&from_behind, 0.001, f32::INFINITY).unwrap();
+        assert!(!back.front_face && back.normal.z() < -0.99);
+    }
+
+    #[test]
+    fn world_bounding_box_covers_all_spheres() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let world = World::new(vec![
+            Sphere::new(Vec3::new(-1.0, 0.0, -2.0), 0.5, material.clone()),
+            Sphere::new(Vec3::new( 2.0, 1.0, -4.0), 1.0, material),
+        ], vec![]);
+
+        let (min, max) = world.bounding_box().unwrap();
+        assert_eq!((min.x, min.y, min.z), (-1.5, -0.5, -5.0));
+        assert_eq!((max.x, max.y, max.z), ( 3.0,  2.0, -1.5));
+
+        assert!(World::new(vec![], vec![Mesh::new(vec![])]).bounding_box().is_none());
+    }
+
+    #[test]
+    fn rays_missing_the_mesh_bounds_test_no_triangles() {
+        let material = MaterialType::Diffuse(Color::new(0.5, 0.5, 0.5));
+        let triangles = (0..8).map(|i| {
+            let x = i as f32;
+            Triangle::new(Vec3::new(x, 0.0, -2.0), Vec3::new(x + 1.0, 0.0, -2.0), Vec3::new(x, 1.0, -2.0), material.clone())
+        }).collect();
+        let mesh = Mesh::new(triangles);
+        let (min, max) = (mesh.bounding_box().min, mesh.bounding_box().max);
+        assert_eq!((min, max), (Vec3::new(0.0, 0.0, -2.0), Vec3::new(8.0, 1.0, -2.0)));
+
+        let triangle_tests = |ray: &Ray| {
+            COUNTERS.with(|cell| cell.set(Counters::default()));
+            let hit = mesh.hit(ray, RAY_EPSILON, f32::INFINITY).is_some();
+            (hit, COUNTERS.with(Cell::get).triangle_tests)
+        };
+        let above = Ray::new(Vec3::new(0.5, 5.0, 0.0), NVec3::new(0.0, 0.0, -1.0));
+        assert_eq!(triangle_tests(&above), (false, 0));
+
+        let (hit, tests) = triangle_tests(&Ray::new(Vec3::new(0.25, 0.25, 0.0), NVec3::new(0.0, 0.0, -1.0)));
+        assert!(hit);
+        assert_eq!(tests > 0, cfg!(feature = "render-stats"));
+    }
+
+    #[test]
+    fn render_stats_count_the_rays() {
+        let world = World::new(
+            vec![Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, MaterialType::Emission { color: Color::new(1.0, 1.0, 1.0), two_sided: true })],
+            vec![Mesh::new(vec![Triangle::new(Vec3::new(-9.0, -9.0, -5.0), Vec3::new(9.0, -9.0, -5.0), Vec3::new(0.0, 9.0, -5.0), MaterialType::Emission { color: Color::new(1.0, 1.0, 1.0), two_sided: true })])]
+        );
+        let camera = Camera::new(1.0);
+        let mut options = Options::new(3, 1, None, true);
+        options.threads = 2;
+
+        let stats = ray_trace_with_output(&world, &camera, Framebuffer::new(10, 10), &mut options).stats;
+        assert_eq!(stats.primary_rays, 300);
+        if !cfg!(feature = "render-stats") {
+            assert_eq!((stats.total_rays, stats.hits, stats.sphere_tests, stats.triangle_tests), (0, 0, 0, 0));
+            return;
+        }
+
+        // Everything is emissive, so each sample is a single ray.
+        assert_eq!(stats.total_rays, 300);
+        assert_eq!(stats.sphere_tests, 300);
+        assert!(stats.hits > 0 && stats.hits <= 300);
+        assert!(stats.triangle_tests > 0 && stats.triangle_tests <= 300);
+    }
+}
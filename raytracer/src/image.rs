@@ -1,8 +1,72 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{stdout, Write, Result};
+use std::io::{stdout, Write, Result, Error, ErrorKind};
+use std::convert::TryFrom;
 use std::path::Path;
 
-use crate::color::ColorU8;
+use crate::color::{ColorU8, Color};
+
+
+/// Where row 0 of an image is. Framebuffers are `TopLeft` unless stated
+/// otherwise, which is also the order `write_image` writes the rows in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ImageOrigin {
+    #[default]
+    TopLeft,
+    BottomLeft,
+}
+
+impl ImageOrigin {
+    /// The row in an image with this origin for `row` counted from the top.
+    pub fn row_from_top(self, row: usize, height: usize) -> usize {
+        match self {
+            ImageOrigin::TopLeft    => row,
+            ImageOrigin::BottomLeft => height - row - 1,
+        }
+    }
+}
+
+/// Reverses the order of the rows of a `width` x `height` buffer, converting
+/// it between `ImageOrigin::TopLeft` and `ImageOrigin::BottomLeft`.
+pub fn flip_rows<T>(buffer: &mut [T], width: usize, height: usize) {
+    assert_eq!(buffer.len(), width * height, "Buffer doesn't match the dimensions!");
+    for row in 0..height / 2 {
+        let (top, bottom) = buffer.split_at_mut((height - row - 1) * width);
+        top[row * width..(row + 1) * width].swap_with_slice(&mut bottom[..width]);
+    }
+}
+
+
+/// The most pixels `Framebuffer::try_new` allocates, 256 megapixels (1 GB).
+pub const DEFAULT_MAX_PIXELS: usize = 1 << 28;
+
+/// Why a framebuffer with some dimensions couldn't be made.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DimensionError {
+    /// `width * height` doesn't fit in a `usize`.
+    Overflow,
+    /// More pixels than allowed.
+    TooManyPixels { count: usize, max: usize },
+}
+
+impl fmt::Display for DimensionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DimensionError::Overflow => write!(f, "Image dimensions overflow"),
+            DimensionError::TooManyPixels { count, max } => write!(f, "Image has {} pixels, the limit is {}", count, max),
+        }
+    }
+}
+impl std::error::Error for DimensionError {}
+
+/// The number of pixels in a `width` x `height` image, if it's at most `max_pixels`.
+pub fn pixel_count(width: usize, height: usize, max_pixels: usize) -> std::result::Result<usize, DimensionError> {
+    let count = width.checked_mul(height).ok_or(DimensionError::Overflow)?;
+    if count > max_pixels {
+        return Err(DimensionError::TooManyPixels { count, max: max_pixels });
+    }
+    Ok(count)
+}
 
 
 #[derive(Debug, Clone)]
@@ -13,11 +77,138 @@ pub struct Framebuffer {
 }
 
 impl Framebuffer {
+    /// Panics if `width * height` overflows, see `try_new` for a checked version.
     pub fn new(width: usize, height: usize) -> Self {
+        pixel_count(width, height, usize::MAX).expect("Framebuffer is too large!");
         let mut pixels : Vec<ColorU8> = Vec::with_capacity(width * height);
         pixels.resize(width * height, ColorU8 { r: 0, g: 0, b: 0, a: 0 });
         Self { width, height, pixels }
     }
+
+    /// Same as `new`, but errors instead of allocating more than `DEFAULT_MAX_PIXELS`.
+    pub fn try_new(width: usize, height: usize) -> std::result::Result<Self, DimensionError> {
+        Self::try_new_with_limit(width, height, DEFAULT_MAX_PIXELS)
+    }
+    pub fn try_new_with_limit(width: usize, height: usize, max_pixels: usize) -> std::result::Result<Self, DimensionError> {
+        pixel_count(width, height, max_pixels)?;
+        Ok(Self::new(width, height))
+    }
+
+    /// Splits the framebuffer into bands of `rows_per_band` whole rows (the
+    /// last may have fewer), which can be written from different threads at
+    /// once as they don't overlap.
+    pub fn row_bands_mut(&mut self, rows_per_band: usize) -> impl Iterator<Item = RowBand<'_>> {
+        assert!(rows_per_band > 0, "Bands need at least one row!");
+        let width = self.width;
+        self.pixels.chunks_mut((width * rows_per_band).max(1))
+            .enumerate()
+            .map(move |(index, pixels)| RowBand { row_start: index * rows_per_band, width, pixels })
+    }
+
+    /// Converts between `ImageOrigin::TopLeft` and `ImageOrigin::BottomLeft`.
+    pub fn flip_vertically(&mut self) {
+        flip_rows(&mut self.pixels, self.width, self.height);
+    }
+
+    /// Edge-preserving bilateral filter. Each pixel becomes a weighted average
+    /// of its neighbours, where the weight falls off both with the distance in
+    /// pixels (`sigma_spatial`) and with the difference in color (`sigma_color`,
+    /// in 8-bit channel units). The kernel is clamped at the image borders.
+    ///
+    /// NOTE: This operates on the final 8-bit image, i.e. after gamma
+    ///  correction, so `sigma_color` is in display space. Alpha is kept as is.
+    ///  A sigma that isn't positive gives no smoothing, so the image is
+    ///  returned unchanged.
+    pub fn bilateral_denoise(&self, sigma_spatial: f32, sigma_color: f32) -> Framebuffer {
+        let mut result = self.clone();
+        if !(sigma_spatial > 0.0 && sigma_color > 0.0) {
+            return result;
+        }
+        let radius = (2.0 * sigma_spatial).ceil().max(1.0) as isize;
+
+        let spatial_factor = -1.0 / (2.0 * sigma_spatial * sigma_spatial);
+        let color_factor   = -1.0 / (2.0 * sigma_color * sigma_color);
+
+        for row in 0..self.height {
+            for column in 0..self.width {
+                let center = self[[row, column]];
+                let (mut r, mut g, mut b, mut total) = (0.0, 0.0, 0.0, 0.0);
+
+                let row_start    = (row as isize - radius).max(0) as usize;
+                let row_end      = (row as isize + radius).min(self.height as isize - 1) as usize;
+                let column_start = (column as isize - radius).max(0) as usize;
+                let column_end   = (column as isize + radius).min(self.width as isize - 1) as usize;
+
+                for y in row_start..=row_end {
+                    for x in column_start..=column_end {
+                        let other = self[[y, x]];
+
+                        let dy = y as f32 - row as f32;
+                        let dx = x as f32 - column as f32;
+                        let dr = other.r as f32 - center.r as f32;
+                        let dg = other.g as f32 - center.g as f32;
+                        let db = other.b as f32 - center.b as f32;
+
+                        let weight = f32::exp(
+                            (dx*dx + dy*dy) * spatial_factor + (dr*dr + dg*dg + db*db) * color_factor
+                        );
+
+                        r += other.r as f32 * weight;
+                        g += other.g as f32 * weight;
+                        b += other.b as f32 * weight;
+                        total += weight;
+                    }
+                }
+
+                result[[row, column]] = ColorU8 {
+                    r: (r / total).round() as u8,
+                    g: (g / total).round() as u8,
+                    b: (b / total).round() as u8,
+                    a: center.a,
+                };
+            }
+        }
+
+        result
+    }
+
+    /// Box-averages `factor`x`factor` blocks of pixels, e.g. to antialias an
+    /// image rendered at a higher resolution. If the dimensions aren't
+    /// divisible by `factor`, the blocks at the right and bottom edges are
+    /// clamped to the image and only average the pixels they cover.
+    pub fn downscale(&self, factor: usize) -> Framebuffer {
+        assert!(factor > 0, "Can't downscale by a factor of 0!");
+
+        let width  = self.width.div_ceil(factor);
+        let height = self.height.div_ceil(factor);
+        let mut result = Framebuffer::new(width, height);
+
+        for row in 0..height {
+            for column in 0..width {
+                let (mut r, mut g, mut b, mut a, mut count) = (0u32, 0u32, 0u32, 0u32, 0u32);
+
+                for y in row*factor..((row+1)*factor).min(self.height) {
+                    for x in column*factor..((column+1)*factor).min(self.width) {
+                        let pixel = self[[y, x]];
+                        r += pixel.r as u32;
+                        g += pixel.g as u32;
+                        b += pixel.b as u32;
+                        a += pixel.a as u32;
+                        count += 1;
+                    }
+                }
+
+                result[[row, column]] = ColorU8 {
+                    r: ((r + count / 2) / count) as u8,
+                    g: ((g + count / 2) / count) as u8,
+                    b: ((b + count / 2) / count) as u8,
+                    a: ((a + count / 2) / count) as u8,
+                };
+            }
+        }
+
+        result
+    }
 }
 
 impl std::ops::Index<[usize; 2]> for Framebuffer {
@@ -36,6 +227,109 @@ impl std::ops::IndexMut<[usize; 2]> for Framebuffer {
 }
 
 
+/// Some consecutive rows of a framebuffer, see `Framebuffer::row_bands_mut`.
+/// It's indexed by `[row, column]` like the framebuffer, i.e. the rows start
+/// at `row_start` rather than 0.
+pub struct RowBand<'a> {
+    pub row_start: usize,
+    pub width:     usize,
+    pub pixels:    &'a mut [ColorU8],
+}
+
+impl RowBand<'_> {
+    /// The rows of the framebuffer in the band.
+    pub fn rows(&self) -> std::ops::Range<usize> {
+        let height = self.pixels.len().checked_div(self.width).unwrap_or(0);
+        self.row_start..self.row_start + height
+    }
+}
+
+impl std::ops::Index<[usize; 2]> for RowBand<'_> {
+    type Output = ColorU8;
+    fn index(&self, index: [usize; 2]) -> &Self::Output {
+        let [row, column] = index;
+        &self.pixels[(row - self.row_start) * self.width + column]
+    }
+}
+
+impl std::ops::IndexMut<[usize; 2]> for RowBand<'_> {
+    fn index_mut(&mut self, index: [usize; 2]) -> &mut Self::Output {
+        let [row, column] = index;
+        &mut self.pixels[(row - self.row_start) * self.width + column]
+    }
+}
+
+
+impl Framebuffer {
+    /// Converts a depth buffer (as produced by `ray_trace_with_output`) to a
+    /// grayscale image, from white at the nearest hit to black at the
+    /// farthest. Misses (infinite depth) are black.
+    pub fn from_depth(depth: &[f32], width: usize, height: usize) -> Framebuffer {
+        assert_eq!(depth.len(), width * height, "Depth buffer doesn't match the dimensions!");
+
+        let finite = depth.iter().cloned().filter(|t| t.is_finite());
+        let near = finite.clone().fold(f32::INFINITY, f32::min);
+        let far  = finite.fold(f32::NEG_INFINITY, f32::max);
+        let range = if far > near { far - near } else { 1.0 };
+
+        let mut framebuffer = Framebuffer::new(width, height);
+        for (pixel, t) in framebuffer.pixels.iter_mut().zip(depth) {
+            let value = if t.is_finite() { ((1.0 - (t - near) / range) * 255.999) as u8 } else { 0 };
+            *pixel = ColorU8 { r: value, g: value, b: value, a: 255 };
+        }
+
+        framebuffer
+    }
+}
+
+/// Deterministic images for testing the display path without rendering.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TestPattern {
+    /// Black and white squares of `cell_size` pixels, starting with white in the top left.
+    Checkerboard { cell_size: usize },
+    /// Eight vertical bars of white, yellow, cyan, green, magenta, red, blue and black.
+    ColorBars,
+    /// Red goes from 0 at the left to 255 at the right, and green from 0 at
+    /// the bottom to 255 at the top, like the u and v of a texture.
+    UvGradient,
+}
+
+impl Framebuffer {
+    pub fn test_pattern(width: usize, height: usize, kind: TestPattern) -> Framebuffer {
+        // From 0 at the first to 255 at the last of `count`, or 0 if there's only one.
+        let ramp = |index: usize, count: usize| if count > 1 { (index * 255 / (count - 1)) as u8 } else { 0 };
+
+        let mut framebuffer = Framebuffer::new(width, height);
+        for row in 0..height {
+            for column in 0..width {
+                let (r, g, b) = match kind {
+                    TestPattern::Checkerboard { cell_size } => {
+                        let cell_size = cell_size.max(1);
+                        let value = if (row / cell_size + column / cell_size) % 2 == 0 { 255 } else { 0 };
+                        (value, value, value)
+                    },
+                    TestPattern::ColorBars => {
+                        // Each bit of the bar's index removes a channel: 1 blue, 2 red and 4 green.
+                        let bar = column * 8 / width;
+                        let channel = |bit: usize| if bar & bit == 0 { 255 } else { 0 };
+                        (channel(2), channel(4), channel(1))
+                    },
+                    TestPattern::UvGradient => (ramp(column, width), ramp(height - row - 1, height), 0),
+                };
+                framebuffer[[row, column]] = ColorU8 { r, g, b, a: 255 };
+            }
+        }
+
+        framebuffer
+    }
+}
+
+/// Writes a depth buffer as a grayscale image, see `Framebuffer::from_depth`.
+pub fn write_depth_image(depth: &[f32], width: usize, height: usize, output: Option<&str>) -> Result<()> {
+    write_image(&Framebuffer::from_depth(depth, width, height), output)
+}
+
+
 
 /// https://en.wikipedia.org/wiki/Netpbm#PPM_example
 ///
@@ -57,9 +351,21 @@ impl std::ops::IndexMut<[usize; 2]> for Framebuffer {
 ///   0   0   0  # black
 ///
 pub fn write_image(framebuffer: &Framebuffer, output: Option<&str>) -> Result<()> {
+    write_image_with_max_value(framebuffer, output, 255)
+}
+
+/// Same as `write_image`, but with the channels scaled to [0, `max_value`]
+/// instead of [0, 255], e.g. 65535 for tools that expect 16-bit PPMs. Note
+/// that it doesn't add any precision. `max_value` can't be 0.
+pub fn write_image_with_max_value(framebuffer: &Framebuffer, output: Option<&str>, max_value: u16) -> Result<()> {
+    if max_value == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "The max value of a PPM image must be in 1..=65535"));
+    }
+    let scale = |channel: u8| (channel as u32 * max_value as u32 + 127) / 255;
+
     let mut writer = match output {
         Some(x) => {
-            Box::new(File::create(&Path::new(x)).unwrap()) as Box<dyn Write>
+            Box::new(File::create(Path::new(x))?) as Box<dyn Write>
         }
         None => Box::new(stdout()) as Box<dyn Write>,
     };
@@ -67,15 +373,749 @@ pub fn write_image(framebuffer: &Framebuffer, output: Option<&str>) -> Result<()
 
     write!(&mut writer,
         "P3\n{width} {height}\n{max_color_value}\n",
-        width=framebuffer.width, height=framebuffer.height, max_color_value=255
+        width=framebuffer.width, height=framebuffer.height, max_color_value=max_value
     )?;
 
     for row in 0usize..framebuffer.height {
         for column in 0usize..framebuffer.width {
             let color = framebuffer[[row, column]];
-            write!(&mut writer, "{} {} {}\n", color.r, color.g, color.b)?;
+            writeln!(&mut writer, "{} {} {}", scale(color.r), scale(color.g), scale(color.b))?;
         }
     }
 
     Ok(())
 }
+
+
+
+/// Reads a PPM image, in either the ASCII (P3) or the binary (P6) format,
+/// e.g. as written by `write_image`, or a PAM (P7) image with the RGB or
+/// RGB_ALPHA tuple type. Channels with another max value than 255 are
+/// rescaled, binary images with a max value above 255 have two bytes per
+/// channel. Without an alpha channel, the alpha is set to 255. Images
+/// without pixels, or with more than `DEFAULT_MAX_PIXELS`, are invalid.
+pub fn read_image(path: &str) -> Result<Framebuffer> {
+    let data = std::fs::read(Path::new(path))?;
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("'{}' isn't a valid PPM image", path));
+
+    let mut index = 0;
+    let format = next_token(&data, &mut index);
+    let binary = match format {
+        Some(b"P3") => false,
+        Some(b"P6") | Some(b"P7") => true,
+        _ => return Err(invalid()),
+    };
+
+    let next_number = |index: &mut usize| {
+        next_token(&data, index)
+            .and_then(|token| std::str::from_utf8(token).ok())
+            .and_then(|token| token.parse::<usize>().ok())
+            .ok_or_else(invalid)
+    };
+    let (width, height, max_value, depth) = if format == Some(b"P7") {
+        // The header is made of `<name> <value>` lines, up to ENDHDR.
+        let (mut width, mut height, mut max_value, mut depth) = (None, None, None, None);
+        loop {
+            match next_token(&data, &mut index) {
+                Some(b"WIDTH")    => width     = Some(next_number(&mut index)?),
+                Some(b"HEIGHT")   => height    = Some(next_number(&mut index)?),
+                Some(b"MAXVAL")   => max_value = Some(next_number(&mut index)?),
+                Some(b"DEPTH")    => depth     = Some(next_number(&mut index)?),
+                Some(b"TUPLTYPE") => { next_token(&data, &mut index); },
+                Some(b"ENDHDR")   => break,
+                _ => return Err(invalid()),
+            }
+        }
+        match (width, height, max_value, depth) {
+            (Some(width), Some(height), Some(max_value), Some(depth @ 3..=4)) => (width, height, max_value, depth),
+            _ => return Err(invalid()),
+        }
+    } else {
+        (next_number(&mut index)?, next_number(&mut index)?, next_number(&mut index)?, 3)
+    };
+    if max_value == 0 || max_value > 65535 {
+        return Err(invalid());
+    }
+    let count = match pixel_count(width, height, DEFAULT_MAX_PIXELS) {
+        Ok(count) if count > 0 => count,
+        _ => return Err(invalid()),
+    };
+
+    let mut channels = Vec::new();
+    if binary {
+        // A single whitespace separates the header from the data.
+        let bytes = if max_value > 255 { 2 } else { 1 };
+        let start = index + 1;
+        let end = count.checked_mul(depth * bytes).and_then(|size| size.checked_add(start));
+        let end = match end {
+            Some(end) if end <= data.len() => end,
+            _ => return Err(invalid()),
+        };
+        channels.reserve_exact(count * depth);
+        if bytes == 2 {
+            channels.extend(data[start..end].chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]) as usize));
+        } else {
+            channels.extend(data[start..end].iter().map(|&channel| channel as usize));
+        }
+    } else {
+        // Not reserved up front, the header alone doesn't say there's that much data.
+        for _ in 0..count * 3 {
+            channels.push(next_number(&mut index)?);
+        }
+    }
+
+    let scale = |channel: usize| ((channel.min(max_value) * 255 + max_value / 2) / max_value) as u8;
+    let mut framebuffer = Framebuffer::new(width, height);
+    for (pixel, texel) in framebuffer.pixels.iter_mut().zip(channels.chunks_exact(depth)) {
+        let a = if depth == 4 { scale(texel[3]) } else { 255 };
+        *pixel = ColorU8 { r: scale(texel[0]), g: scale(texel[1]), b: scale(texel[2]), a };
+    }
+
+    Ok(framebuffer)
+}
+
+/// The next whitespace separated token in a PPM header, skipping '#' comments.
+fn next_token<'a>(data: &'a [u8], index: &mut usize) -> Option<&'a [u8]> {
+    loop {
+        while *index < data.len() && data[*index].is_ascii_whitespace() { *index += 1; }
+        if *index < data.len() && data[*index] == b'#' {
+            while *index < data.len() && data[*index] != b'\n' { *index += 1; }
+        } else {
+            break;
+        }
+    }
+
+    let start = *index;
+    while *index < data.len() && !data[*index].is_ascii_whitespace() { *index += 1; }
+    if start < *index { Some(&data[start..*index]) } else { None }
+}
+
+
+
+/// Settings for `apply_bloom`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bloom {
+    pub threshold: f32,
+    pub radius:    f32,
+    pub intensity: f32,
+}
+
+/// Makes bright parts of the image glow: the light above the luminance
+/// `threshold` is blurred with a Gaussian (with `radius` as the standard
+/// deviation, in pixels) and added back, scaled by `intensity`.
+///
+/// `colors` is in linear color with the same layout as a framebuffer, and
+/// should be bloomed before it's tone mapped. Edges are clamped.
+pub fn apply_bloom(colors: &mut [Color], width: usize, height: usize, threshold: f32, radius: f32, intensity: f32) {
+    assert_eq!(colors.len(), width * height, "Color buffer doesn't match the dimensions!");
+
+    // The part of each pixel that's above the threshold.
+    let mut bright: Vec<Color> = colors.iter().map(|color| {
+        let luminance = color.luminance();
+        if luminance <= threshold || luminance <= 0.0 {
+            Color::new(0.0, 0.0, 0.0)
+        } else {
+            let scale = (luminance - threshold) / luminance;
+            Color::new(color.r * scale, color.g * scale, color.b * scale)
+        }
+    }).collect();
+    if bright.iter().all(|color| color.r == 0.0 && color.g == 0.0 && color.b == 0.0) {
+        return;
+    }
+
+    // Separable Gaussian, first along the rows and then along the columns.
+    let sigma  = radius.max(1e-3);
+    let extent = (3.0 * sigma).ceil() as isize;
+    let kernel: Vec<f32> = (-extent..=extent).map(|x| f32::exp(-(x * x) as f32 / (2.0 * sigma * sigma))).collect();
+    let total: f32 = kernel.iter().sum();
+
+    let blur = |source: &[Color], step: (isize, isize)| -> Vec<Color> {
+        let mut result = vec![Color::new(0.0, 0.0, 0.0); source.len()];
+        for row in 0..height as isize {
+            for column in 0..width as isize {
+                let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
+                for (offset, weight) in (-extent..=extent).zip(kernel.iter()) {
+                    let y = (row + offset * step.0).clamp(0, height as isize - 1) as usize;
+                    let x = (column + offset * step.1).clamp(0, width as isize - 1) as usize;
+                    let color = &source[y * width + x];
+                    r += color.r * weight;
+                    g += color.g * weight;
+                    b += color.b * weight;
+                }
+                result[row as usize * width + column as usize] = Color::new(r / total, g / total, b / total);
+            }
+        }
+        result
+    };
+    bright = blur(&bright, (0, 1));
+    bright = blur(&bright, (1, 0));
+
+    for (color, glow) in colors.iter_mut().zip(bright.iter()) {
+        color.r += glow.r * intensity;
+        color.g += glow.g * intensity;
+        color.b += glow.b * intensity;
+    }
+}
+
+/// The luminance `auto_exposure` brings the average of an image to, i.e.
+/// middle gray (before gamma correction).
+pub const AUTO_EXPOSURE_KEY: f32 = 0.18;
+
+/// The exposure that scales the geometric mean luminance of the linear
+/// `colors` to `AUTO_EXPOSURE_KEY`, as in Reinhard et al., "Photographic Tone
+/// Reproduction for Digital Images". Unlike the plain mean, the geometric one
+/// isn't thrown off much by a few very bright pixels, e.g. lights. Black (or
+/// empty) images get an exposure of 1.
+pub fn auto_exposure(colors: &[Color]) -> f32 {
+    // Keeps black pixels from taking the logarithm to -infinity.
+    const DELTA: f32 = 1e-4;
+    if !colors.iter().any(|color| color.luminance() > 0.0) {
+        return 1.0;
+    }
+
+    let log_sum: f32 = colors.iter().map(|color| (DELTA + color.luminance().max(0.0)).ln()).sum();
+    let mean = (log_sum / colors.len() as f32).exp();
+    AUTO_EXPOSURE_KEY / mean
+}
+
+/// Writes linear colors as a Radiance HDR (.hdr) image, using the flat (not
+/// run-length encoded) RGBE format: each pixel is stored as three 8-bit
+/// mantissas sharing an 8-bit exponent.
+///
+/// `colors` has the same layout as a framebuffer, `[row * width + column]`
+/// with row 0 at the top. The colors are written as is, i.e. they're not tone
+/// mapped, gamma corrected or clamped. Alpha is dropped.
+pub fn write_hdr(colors: &[Color], width: usize, height: usize, path: &str) -> Result<()> {
+    assert_eq!(colors.len(), width * height, "Color buffer doesn't match the dimensions!");
+
+    let mut data = Vec::with_capacity(64 + colors.len() * 4);
+    write!(&mut data, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+    for color in colors {
+        data.extend_from_slice(&to_rgbe(color));
+    }
+
+    std::fs::write(Path::new(path), data)
+}
+
+/// Writes display colors, e.g. from `common::display_colors`, as a PNG with
+/// 16 bits per channel. That's 65536 levels instead of the 256 in 8-bit
+/// images, so smooth gradients (e.g. around bright lights) don't band, and
+/// there's room to edit large prints. The colors are clamped to [0, 1] and
+/// written as is; they should already be gamma corrected. Alpha is dropped.
+///
+/// `colors` has the same layout as a framebuffer. The image data isn't
+/// compressed.
+pub fn write_png16(colors: &[Color], width: usize, height: usize, path: &str) -> Result<()> {
+    assert_eq!(colors.len(), width * height, "Color buffer doesn't match the dimensions!");
+
+    // Each row starts with its filter type, 0 for none.
+    let mut scanlines = Vec::with_capacity(height * (1 + width * 6));
+    for row in colors.chunks(width.max(1)).take(height) {
+        scanlines.push(0);
+        for color in row {
+            for &channel in [color.r, color.g, color.b].iter() {
+                scanlines.extend_from_slice(&to_u16_channel(channel).to_be_bytes());
+            }
+        }
+    }
+
+    // 16-bit depth, RGB.
+    std::fs::write(Path::new(path), png_data(width, height, 16, 2, &scanlines)?)
+}
+
+/// Writes the framebuffer as an 8-bit RGBA PNG, keeping the alpha, e.g. for
+/// a render against `sky none` with a transparent background. The colors
+/// aren't premultiplied by the alpha, which is what PNG expects and what
+/// `ray_trace` gives. The image data isn't compressed.
+pub fn write_png(framebuffer: &Framebuffer, path: &str) -> Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+
+    let mut scanlines = Vec::with_capacity(height * (1 + width * 4));
+    for row in 0..height {
+        scanlines.push(0);
+        for column in 0..width {
+            let ColorU8 { r, g, b, a } = framebuffer[[row, column]];
+            scanlines.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+
+    // 8-bit depth, RGBA.
+    std::fs::write(Path::new(path), png_data(width, height, 8, 6, &scanlines)?)
+}
+
+/// A PNG file of the unfiltered `scanlines`, with the given bit depth and color type.
+fn png_data(width: usize, height: usize, bit_depth: u8, color_type: u8, scanlines: &[u8]) -> Result<Vec<u8>> {
+    let too_large = || Error::new(ErrorKind::InvalidInput, "Image is too large for a PNG");
+    let (png_width, png_height) = (u32::try_from(width).map_err(|_| too_large())?, u32::try_from(height).map_err(|_| too_large())?);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&png_width.to_be_bytes());
+    header.extend_from_slice(&png_height.to_be_bytes());
+    // Deflate, no filtering, not interlaced.
+    header.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+    let mut data = Vec::with_capacity(scanlines.len() + 128);
+    data.extend_from_slice(b"\x89PNG\r\n\x1a\n");
+    write_png_chunk(&mut data, b"IHDR", &header);
+    write_png_chunk(&mut data, b"IDAT", &zlib_stored(scanlines));
+    write_png_chunk(&mut data, b"IEND", &[]);
+    Ok(data)
+}
+
+/// A display channel in [0, 1] as a 16-bit value.
+pub fn to_u16_channel(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+}
+
+fn write_png_chunk(data: &mut Vec<u8>, kind: &[u8; 4], content: &[u8]) {
+    data.extend_from_slice(&(content.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(content);
+    let crc = crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of uncompressed deflate blocks.
+fn zlib_stored(bytes: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(bytes.len() + bytes.len() / 65535 * 5 + 16);
+    stream.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = bytes.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let length = block.len() as u16;
+        stream.push(last);
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    // Adler-32 of the uncompressed data.
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    stream.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    stream
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn to_rgbe(color: &Color) -> [u8; 4] {
+    let (r, g, b) = (color.r.max(0.0), color.g.max(0.0), color.b.max(0.0));
+    let max = r.max(g).max(b);
+    if max < 1e-32 || !max.is_finite() {
+        return [0, 0, 0, 0];
+    }
+
+    // max = mantissa * 2^exponent, with the mantissa in [0.5, 1).
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max / 2f32.powi(exponent) >= 1.0 { exponent += 1; }
+    let scale = 256.0 / 2f32.powi(exponent);
+
+    let channel = |value: f32| (value * scale).min(255.0) as u8;
+    [channel(r), channel(g), channel(b), (exponent + 128).clamp(0, 255) as u8]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::common::{World, Sphere, Options, Sky, ray_trace};
+    use crate::materials::MaterialType;
+    use crate::maths::{Vec3, IVector};
+
+    fn filled(width: usize, height: usize, color: ColorU8) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(width, height);
+        framebuffer.pixels.iter_mut().for_each(|pixel| *pixel = color);
+        framebuffer
+    }
+
+    #[test]
+    fn bilateral_denoise_keeps_flat_color() {
+        let color = ColorU8 { r: 200, g: 100, b: 50, a: 255 };
+        let framebuffer = filled(7, 5, color);
+        let result = framebuffer.bilateral_denoise(2.0, 30.0);
+
+        for pixel in result.pixels.iter() {
+            assert_eq!((pixel.r, pixel.g, pixel.b, pixel.a), (color.r, color.g, color.b, color.a));
+        }
+    }
+
+    #[test]
+    fn bilateral_denoise_preserves_edges() {
+        let mut framebuffer = filled(8, 4, ColorU8 { r: 0, g: 0, b: 0, a: 255 });
+        for row in 0..4 {
+            for column in 4..8 {
+                framebuffer[[row, column]] = ColorU8 { r: 255, g: 255, b: 255, a: 255 };
+            }
+        }
+        let result = framebuffer.bilateral_denoise(1.5, 10.0);
+
+        assert_eq!(result[[2, 3]].r, 0);
+        assert_eq!(result[[2, 4]].r, 255);
+    }
+
+    #[test]
+    fn bilateral_denoise_with_zero_sigma_is_a_copy() {
+        let mut framebuffer = filled(4, 4, ColorU8 { r: 200, g: 100, b: 50, a: 255 });
+        framebuffer[[1, 2]] = ColorU8 { r: 10, g: 20, b: 30, a: 128 };
+
+        for (sigma_spatial, sigma_color) in [(0.0, 10.0), (1.5, 0.0), (0.0, 0.0)] {
+            let result = framebuffer.bilateral_denoise(sigma_spatial, sigma_color);
+            for (pixel, original) in result.pixels.iter().zip(framebuffer.pixels.iter()) {
+                assert_eq!((pixel.r, pixel.g, pixel.b, pixel.a), (original.r, original.g, original.b, original.a));
+            }
+        }
+    }
+
+    #[test]
+    fn downscale_gradient_by_2() {
+        let mut framebuffer = Framebuffer::new(4, 2);
+        for row in 0..2 {
+            for column in 0..4 {
+                let value = (column * 20 + row * 10) as u8;
+                framebuffer[[row, column]] = ColorU8 { r: value, g: 255 - value, b: 0, a: 255 };
+            }
+        }
+        let result = framebuffer.downscale(2);
+
+        assert_eq!((result.width, result.height), (2, 1));
+        // (0 + 20 + 10 + 30) / 4 and (40 + 60 + 50 + 70) / 4.
+        assert_eq!(result[[0, 0]].r, 15);
+        assert_eq!(result[[0, 1]].r, 55);
+        assert_eq!(result[[0, 0]].g, 240);
+        assert_eq!(result[[0, 1]].a, 255);
+    }
+
+    #[test]
+    fn downscale_clamps_remainder() {
+        let mut framebuffer = filled(5, 3, ColorU8 { r: 10, g: 10, b: 10, a: 255 });
+        framebuffer[[2, 4]] = ColorU8 { r: 250, g: 250, b: 250, a: 255 };
+        let result = framebuffer.downscale(2);
+
+        assert_eq!((result.width, result.height), (3, 2));
+        assert_eq!(result[[0, 0]].r, 10);
+        assert_eq!(result[[1, 2]].r, 250);
+    }
+
+    #[test]
+    fn from_depth_normalizes() {
+        let depth = [1.0, 2.0, 3.0, f32::INFINITY];
+        let framebuffer = Framebuffer::from_depth(&depth, 2, 2);
+
+        assert_eq!(framebuffer[[0, 0]].r, 255);
+        assert_eq!(framebuffer[[0, 1]].r, 127);
+        assert_eq!(framebuffer[[1, 0]].r, 0);
+        assert_eq!(framebuffer[[1, 1]].r, 0);
+    }
+
+    #[test]
+    fn write_image_with_another_max_value() {
+        let mut framebuffer = filled(2, 1, ColorU8 { r: 255, g: 128, b: 0, a: 255 });
+        framebuffer[[0, 1]] = ColorU8 { r: 1, g: 2, b: 254, a: 255 };
+        let path = std::env::temp_dir().join(format!("raytracer_max_value_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        write_image_with_max_value(&framebuffer, Some(path), 65535).unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        let result = read_image(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(text.starts_with("P3\n2 1\n65535\n65535 32896 0\n"), "{}", text);
+        for (a, b) in framebuffer.pixels.iter().zip(result.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+        }
+
+        assert!(write_image_with_max_value(&framebuffer, Some(path), 0).is_err());
+
+        let missing = std::env::temp_dir().join(format!("raytracer_missing_{}/image.ppm", std::process::id()));
+        assert!(write_image(&framebuffer, missing.to_str()).is_err());
+    }
+
+    #[test]
+    fn read_back_written_image() {
+        let mut framebuffer = filled(3, 2, ColorU8 { r: 10, g: 20, b: 30, a: 255 });
+        framebuffer[[1, 2]] = ColorU8 { r: 255, g: 128, b: 0, a: 255 };
+
+        let path = std::env::temp_dir().join(format!("raytracer_read_back_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_image(&framebuffer, Some(path)).unwrap();
+        let result = read_image(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!((result.width, result.height), (3, 2));
+        for (a, b) in framebuffer.pixels.iter().zip(result.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+        }
+    }
+
+    #[test]
+    fn reads_pam_with_alpha() {
+        let mut data = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n".to_vec();
+        data.extend([10, 20, 30, 0, 40, 50, 60, 255]);
+        let path = std::env::temp_dir().join(format!("raytracer_reads_pam_{}.pam", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &data).unwrap();
+        let result = read_image(path);
+        std::fs::write(path, b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nENDHDR\n\0\0\0\0").unwrap();
+        let gray = read_image(path);
+        std::fs::remove_file(path).unwrap();
+
+        let result = result.unwrap();
+        assert_eq!((result.width, result.height), (2, 1));
+        assert_eq!((result[[0, 0]].r, result[[0, 0]].a), (10, 0));
+        assert_eq!((result[[0, 1]].b, result[[0, 1]].a), (60, 255));
+        assert!(gray.is_err());
+    }
+
+    #[test]
+    fn images_with_impossible_sizes_are_invalid() {
+        let path = std::env::temp_dir().join(format!("raytracer_impossible_sizes_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+        let results: Vec<_> = [
+            &b"P6 4294967296 4294967296 255\n\0\0\0"[..],
+            b"P6 18446744073709551615 2 255\n\0\0\0",
+            b"P3 100000 100000 255 1 2 3",
+            b"P6 16384 16384 255\n\0\0\0",
+            b"P6 0 0 255\n",
+            b"P3 0 4 255",
+        ].iter().map(|data| {
+            std::fs::write(path, data).unwrap();
+            read_image(path).map(|_| ()).map_err(|error| error.kind())
+        }).collect();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(results.iter().all(|result| *result == Err(ErrorKind::InvalidData)), "{:?}", results);
+    }
+
+    #[test]
+    fn write_hdr_round_trip() {
+        let colors = [
+            Color::new(0.0, 0.0, 0.0), Color::new(1.0, 0.5, 0.25),
+            Color::new(12.5, 3.0, 0.1), Color::new(0.01, 0.02, 0.03),
+        ];
+        let path = std::env::temp_dir().join(format!("raytracer_write_hdr_{}.hdr", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_hdr(&colors, 2, 2, path).unwrap();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 2\n";
+        assert_eq!(&data[..header.len()], &header[..]);
+
+        let pixels = &data[header.len()..];
+        assert_eq!(pixels.len(), 4 * 4);
+        for (color, rgbe) in colors.iter().zip(pixels.chunks_exact(4)) {
+            let scale = if rgbe[3] == 0 { 0.0 } else { 2f32.powi(rgbe[3] as i32 - 128 - 8) };
+            let decoded = [rgbe[0] as f32 * scale, rgbe[1] as f32 * scale, rgbe[2] as f32 * scale];
+            let max = color.r.max(color.g).max(color.b);
+
+            // The shared exponent leaves 8 bits of precision relative to the largest channel.
+            for (a, b) in decoded.iter().zip([color.r, color.g, color.b]) {
+                assert!((a - b).abs() <= max / 128.0, "{:?} vs {:?}", decoded, color);
+            }
+        }
+    }
+
+    #[test]
+    fn bloom_keeps_sub_threshold_image() {
+        let mut colors: Vec<Color> = (0..12).map(|i| Color::new(0.05 * i as f32, 0.3, 0.1)).collect();
+        let expected = colors.clone();
+        apply_bloom(&mut colors, 4, 3, 1.0, 2.0, 1.0);
+
+        for (a, b) in colors.iter().zip(expected.iter()) {
+            assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+        }
+    }
+
+    #[test]
+    fn bloom_spreads_bright_pixels() {
+        let at = |row: usize, column: usize| row * 5 + column;
+        let mut colors = vec![Color::new(0.0, 0.0, 0.0); 5 * 5];
+        colors[at(2, 2)] = Color::new(10.0, 10.0, 10.0);
+        apply_bloom(&mut colors, 5, 5, 1.0, 1.0, 1.0);
+
+        assert!(colors[at(2, 1)].r > 0.0 && colors[at(1, 2)].r > 0.0);
+        assert!(colors[at(2, 2)].r > 10.0);
+        assert!(colors[at(2, 1)].r > colors[at(2, 0)].r);
+    }
+
+    #[test]
+    fn auto_exposure_brings_images_to_middle_gray() {
+        let uniform = |value: f32| vec![Color::new(value, value, value); 16];
+        let dim    = auto_exposure(&uniform(0.01));
+        let bright = auto_exposure(&uniform(3.0));
+        assert!(dim > 1.0 && bright < 1.0);
+        // Close to, but not quite, the key, as black is offset a little for the logarithm.
+        assert!((dim * 0.01 / AUTO_EXPOSURE_KEY - 1.0).abs() < 0.02);
+        assert!((bright * 3.0 / AUTO_EXPOSURE_KEY - 1.0).abs() < 1e-3);
+
+        assert_eq!(auto_exposure(&uniform(0.0)), 1.0);
+        assert_eq!(auto_exposure(&[]), 1.0);
+    }
+
+    #[test]
+    fn flip_rows_reverses_the_rows() {
+        let mut buffer = vec![1, 2, 3, 4, 5, 6];
+        flip_rows(&mut buffer, 2, 3);
+        assert_eq!(buffer, vec![5, 6, 3, 4, 1, 2]);
+
+        let mut buffer = vec![1, 2, 3, 4];
+        flip_rows(&mut buffer, 2, 2);
+        assert_eq!(buffer, vec![3, 4, 1, 2]);
+
+        assert_eq!(ImageOrigin::BottomLeft.row_from_top(0, 3), 2);
+        assert_eq!(ImageOrigin::TopLeft.row_from_top(0, 3), 0);
+    }
+
+    #[test]
+    fn oversized_framebuffers_are_errors() {
+        assert_eq!(Framebuffer::try_new(usize::MAX, 2).unwrap_err(), DimensionError::Overflow);
+        assert_eq!(Framebuffer::try_new(65535, 65535).unwrap_err(), DimensionError::TooManyPixels { count: 65535 * 65535, max: DEFAULT_MAX_PIXELS });
+        assert_eq!(Framebuffer::try_new_with_limit(10, 10, 99).unwrap_err(), DimensionError::TooManyPixels { count: 100, max: 99 });
+
+        let framebuffer = Framebuffer::try_new_with_limit(10, 10, 100).unwrap();
+        assert_eq!(framebuffer.pixels.len(), 100);
+    }
+
+    #[test]
+    fn png16_keeps_gradients_that_8_bits_would_band() {
+        // A gradient over less than one 8-bit step.
+        let colors: Vec<Color> = (0..64).map(|i| {
+            let value = 0.5 + i as f32 / 64.0 / 300.0;
+            Color::new(value, value, value)
+        }).collect();
+
+        let mut levels_16: Vec<u16> = colors.iter().map(|c| to_u16_channel(c.r)).collect();
+        let mut levels_8:  Vec<u8>  = colors.iter().map(|c| (c.r * 255.999) as u8).collect();
+        levels_16.dedup();
+        levels_8.dedup();
+        assert!(levels_8.len() <= 2);
+        assert!(levels_16.len() > 48, "{} levels", levels_16.len());
+
+        let path = std::env::temp_dir().join(format!("raytracer_png16_{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_png16(&colors, 16, 4, path).unwrap();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(&data[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&data[12..16], b"IHDR");
+        assert_eq!((&data[16..20], &data[20..24], data[24], data[25]), (&16u32.to_be_bytes()[..], &4u32.to_be_bytes()[..], 16, 2));
+        assert_eq!(crc32(&data[12..29]).to_be_bytes(), data[29..33]);
+
+        // A single stored block, with the rows after the zlib and block headers.
+        assert_eq!(&data[37..41], b"IDAT");
+        let scanlines = &data[41 + 2 + 5..];
+        assert_eq!(scanlines[0], 0);
+        let second = u16::from_be_bytes([scanlines[1 + 6], scanlines[1 + 7]]);
+        assert_eq!(second, to_u16_channel(colors[1].r));
+    }
+
+    #[test]
+    fn png_keeps_the_alpha_of_a_transparent_sky() {
+        let world = World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, MaterialType::Diffuse(Color::new(0.8, 0.8, 0.8)))], vec![]);
+        let mut options = Options { sky: Sky::None, ..Options::new(4, 4, None, true) };
+        let framebuffer = ray_trace(&world, &Camera::new(1.0), Framebuffer::new(9, 9), &mut options);
+
+        let path = std::env::temp_dir().join(format!("raytracer_png_{}.png", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_png(&framebuffer, path).unwrap();
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!((data[24], data[25]), (8, 6));
+        let scanlines = &data[41 + 2 + 5..];
+        let alpha = |row: usize, column: usize| scanlines[row * (1 + 9 * 4) + 1 + column * 4 + 3];
+        assert_eq!((alpha(0, 0), alpha(0, 8), alpha(8, 0), alpha(8, 8)), (0, 0, 0, 0));
+        assert_eq!(alpha(4, 4), 255);
+    }
+
+    #[test]
+    fn test_patterns_are_deterministic_and_fill_the_buffer() {
+        let patterns = [TestPattern::Checkerboard { cell_size: 4 }, TestPattern::ColorBars, TestPattern::UvGradient];
+        for &kind in patterns.iter() {
+            let a = Framebuffer::test_pattern(16, 9, kind);
+            let b = Framebuffer::test_pattern(16, 9, kind);
+            assert_eq!(a.pixels.len(), 16 * 9);
+            for (a, b) in a.pixels.iter().zip(b.pixels.iter()) {
+                assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+                assert_eq!(a.a, 255);
+            }
+        }
+
+        let checkerboard = Framebuffer::test_pattern(16, 9, TestPattern::Checkerboard { cell_size: 4 });
+        assert_eq!((checkerboard[[0, 0]].r, checkerboard[[0, 4]].r, checkerboard[[4, 4]].r), (255, 0, 255));
+
+        let bars = Framebuffer::test_pattern(16, 9, TestPattern::ColorBars);
+        let bar = |column: usize| { let pixel = bars[[4, column]]; (pixel.r, pixel.g, pixel.b) };
+        assert_eq!([bar(0), bar(2), bar(10), bar(15)], [(255, 255, 255), (255, 255, 0), (255, 0, 0), (0, 0, 0)]);
+
+        let gradient = Framebuffer::test_pattern(16, 9, TestPattern::UvGradient);
+        assert_eq!((gradient[[8, 0]].r, gradient[[8, 0]].g), (0, 0));
+        assert_eq!((gradient[[0, 15]].r, gradient[[0, 15]].g), (255, 255));
+    }
+
+    #[test]
+    fn test_pattern_round_trips_through_ppm() {
+        let framebuffer = Framebuffer::test_pattern(24, 8, TestPattern::ColorBars);
+
+        let path = std::env::temp_dir().join(format!("raytracer_test_pattern_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_image(&framebuffer, Some(path)).unwrap();
+        let result = read_image(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        for (a, b) in framebuffer.pixels.iter().zip(result.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+        }
+    }
+
+    #[test]
+    fn row_bands_can_be_written_from_threads() {
+        let gradient = |row: usize, column: usize| ColorU8 { r: (column * 8) as u8, g: (row * 8) as u8, b: 0, a: 255 };
+
+        let mut serial = Framebuffer::new(13, 30);
+        for row in 0..30 {
+            for column in 0..13 {
+                serial[[row, column]] = gradient(row, column);
+            }
+        }
+
+        let mut parallel = Framebuffer::new(13, 30);
+        std::thread::scope(|scope| {
+            for mut band in parallel.row_bands_mut(7) {
+                scope.spawn(move || {
+                    for row in band.rows() {
+                        for column in 0..band.width {
+                            band[[row, column]] = gradient(row, column);
+                        }
+                    }
+                });
+            }
+        });
+
+        for (a, b) in serial.pixels.iter().zip(parallel.pixels.iter()) {
+            assert_eq!((a.r, a.g, a.b, a.a), (b.r, b.g, b.b, b.a));
+        }
+        let bands: Vec<_> = parallel.row_bands_mut(7).map(|band| band.rows()).collect();
+        assert_eq!(bands, vec![0..7, 7..14, 14..21, 21..28, 28..30]);
+        assert_eq!(Framebuffer::new(0, 3).row_bands_mut(2).count(), 0);
+    }
+}
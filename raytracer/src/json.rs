@@ -0,0 +1,689 @@
+use std::fmt;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::camera::{Camera, Projection};
+use crate::color::Color;
+use crate::common::{Sphere, Triangle, Mesh, Plane, Sky, Visibility, DEFAULT_TRIANGLE_UVS};
+use crate::materials::MaterialType;
+use crate::maths::{Vec3, IVector};
+use crate::scene::Scene;
+use crate::texture::{Texture, SphereMapping, TextureFilter, WrapMode};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+    /// The text isn't valid JSON, at the byte `offset`.
+    Malformed { offset: usize, reason: &'static str },
+    /// A required field is missing, e.g. a sphere's `radius`.
+    MissingField(&'static str),
+    /// A field has the wrong type, or a value the scene can't have.
+    WrongValue(&'static str),
+    /// The `file` of a texture couldn't be read.
+    CouldntOpenFile,
+    /// The scene has something that can't be written as JSON, e.g. a custom
+    /// material.
+    CantWrite(&'static str),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Malformed { offset, reason } => write!(f, "Byte {}: {}", offset, reason),
+            JsonError::MissingField(field) => write!(f, "Missing '{}'", field),
+            JsonError::WrongValue(field)   => write!(f, "'{}' has the wrong value", field),
+            JsonError::CouldntOpenFile     => write!(f, "Couldn't open file"),
+            JsonError::CantWrite(what)     => write!(f, "Can't write {} as JSON", what),
+        }
+    }
+}
+impl std::error::Error for JsonError {}
+
+
+type Result<T> = std::result::Result<T, JsonError>;
+
+
+/// Writes the scene as JSON, with the field names of the scene file, e.g.
+///
+/// `{"camera": {"origin": [0, 0, 0], "aspect": 1}, "spheres": [{"center": [0, 0, -1], "radius": 0.5, "material": {"type": "Diffuse", "color": [0.8, 0.3, 0.3]}}]}`
+///
+/// Materials are written inline with every primitive, as the scene doesn't
+/// remember their names. The camera is written like the `camera` directive,
+/// so only its origin, aspect ratio and projection are kept. Textures are
+/// written as the `file` they were loaded from. Returns `CantWrite` for
+/// custom materials, textures that weren't loaded from a file, and numbers
+/// that aren't finite, which JSON can't express or couldn't be read back.
+pub fn scene_to_json(scene: &Scene) -> Result<String> {
+    let mut json = String::from("{\n");
+
+    let camera = &scene.camera;
+    write!(json, "  \"camera\": {{\"origin\": {}, \"aspect\": {}", vector(camera.position())?, number(camera.aspect_ratio())?).unwrap();
+    if let Projection::Orthographic { scale } = camera.projection() {
+        write!(json, ", \"orthographic\": {{\"scale\": {}}}", number(scale)?).unwrap();
+    }
+    json.push('}');
+
+    if let Some((width, height)) = scene.resolution {
+        write!(json, ",\n  \"output\": {{\"width\": {}, \"height\": {}}}", width, height).unwrap();
+    }
+    match scene.sky {
+        Some(Sky::Gradient)     => json.push_str(",\n  \"sky\": \"gradient\""),
+        Some(Sky::None)         => json.push_str(",\n  \"sky\": \"none\""),
+        Some(Sky::Color(color)) => write!(json, ",\n  \"sky\": {{\"color\": {}}}", rgb(color)?).unwrap(),
+        None => (),
+    }
+    if let Some(seed) = scene.seed {
+        write!(json, ",\n  \"seed\": {}", seed).unwrap();
+    }
+    if let Some(ambient) = scene.ambient {
+        write!(json, ",\n  \"ambient\": {{\"color\": {}}}", rgb(ambient)?).unwrap();
+    }
+
+    let spheres = scene.spheres.iter().map(|sphere| {
+        let mut fields = format!("\"center\": {}, \"radius\": {}, \"material\": {}", vector(sphere.center)?, number(sphere.radius)?, material(&sphere.material)?);
+        primitive_fields(&mut fields, sphere.name.as_deref(), sphere.visibility);
+        Ok(fields)
+    });
+    array(&mut json, "spheres", spheres)?;
+
+    let triangles = scene.mesh.triangles().iter().map(|triangle| {
+        let [v0, v1, v2] = triangle.vertices();
+        let mut fields = format!("\"v0\": {}, \"v1\": {}, \"v2\": {}, \"material\": {}", vector(v0)?, vector(v1)?, vector(v2)?, material(triangle.material())?);
+        if let Some([c0, c1, c2]) = triangle.colors() {
+            write!(fields, ", \"colors\": [{}, {}, {}]", rgb(c0)?, rgb(c1)?, rgb(c2)?).unwrap();
+        }
+        if triangle.uvs() != DEFAULT_TRIANGLE_UVS {
+            let [uv0, uv1, uv2] = triangle.uvs();
+            write!(fields, ", \"uvs\": [{}, {}, {}]", uv(uv0)?, uv(uv1)?, uv(uv2)?).unwrap();
+        }
+        primitive_fields(&mut fields, triangle.name(), triangle.visibility());
+        Ok(fields)
+    });
+    array(&mut json, "triangles", triangles)?;
+
+    let planes = scene.planes.iter().map(|plane| {
+        let mut fields = format!("\"point\": {}, \"normal\": {}, \"material\": {}", vector(plane.point)?, vector(plane.normal.into())?, material(&plane.material)?);
+        primitive_fields(&mut fields, plane.name.as_deref(), plane.visibility);
+        Ok(fields)
+    });
+    array(&mut json, "planes", planes)?;
+
+    json.push_str("\n}\n");
+    Ok(json)
+}
+
+/// Reads a scene written by `scene_to_json`. Only the `camera` is required,
+/// and fields that aren't part of the scene are ignored, so editors can
+/// keep their own data in the file.
+pub fn scene_from_json(source: &str) -> Result<Scene> {
+    let mut parser = Parser { source, index: 0, depth: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.index != source.len() {
+        return Err(parser.error("Expected the end of the input"));
+    }
+
+    let camera_field = value.field("camera")?;
+    let mut camera = Camera::new_at(camera_field.field("origin")?.vector("origin")?, camera_field.field("aspect")?.float("aspect")?);
+    if let Some(orthographic) = camera_field.optional("orthographic") {
+        camera = camera.with_projection(Projection::Orthographic { scale: orthographic.field("scale")?.float("scale")? });
+    }
+
+    let resolution = match value.optional("output") {
+        Some(output) => Some((output.field("width")?.integer("width")? as usize, output.field("height")?.integer("height")? as usize)),
+        None => None,
+    };
+    let sky = match value.optional("sky") {
+        Some(Json::String(sky)) if sky == "gradient" => Some(Sky::Gradient),
+        Some(Json::String(sky)) if sky == "none"     => Some(Sky::None),
+        Some(sky @ Json::Object(_)) => Some(Sky::Color(sky.field("color")?.color("color")?)),
+        Some(_) => return Err(JsonError::WrongValue("sky")),
+        None => None,
+    };
+    let seed = match value.optional("seed") {
+        Some(seed) => Some(seed.integer("seed")?),
+        None => None,
+    };
+    let ambient = match value.optional("ambient") {
+        Some(ambient) => Some(ambient.field("color")?.color("color")?),
+        None => None,
+    };
+
+    let spheres = value.elements("spheres")?.iter().map(|fields| {
+        let sphere = Sphere::new(fields.field("center")?.vector("center")?, fields.field("radius")?.float("radius")?, fields.material()?)
+            .with_visibility(fields.visibility()?);
+        Ok(match fields.name()? { Some(name) => sphere.with_name(name), None => sphere })
+    }).collect::<Result<Vec<Sphere>>>()?;
+
+    let triangles = value.elements("triangles")?.iter().map(|fields| {
+        let mut triangle = Triangle::new(fields.field("v0")?.vector("v0")?, fields.field("v1")?.vector("v1")?, fields.field("v2")?.vector("v2")?, fields.material()?)
+            .with_visibility(fields.visibility()?);
+        if let Some(colors) = fields.optional("colors") {
+            match colors {
+                Json::Array(colors) if colors.len() == 3 =>
+                    triangle = triangle.with_colors([colors[0].color("colors")?, colors[1].color("colors")?, colors[2].color("colors")?]),
+                _ => return Err(JsonError::WrongValue("colors")),
+            }
+        }
+        if let Some(uvs) = fields.optional("uvs") {
+            match uvs {
+                Json::Array(uvs) if uvs.len() == 3 =>
+                    triangle = triangle.with_uvs([uvs[0].uv("uvs")?, uvs[1].uv("uvs")?, uvs[2].uv("uvs")?]),
+                _ => return Err(JsonError::WrongValue("uvs")),
+            }
+        }
+        Ok(match fields.name()? { Some(name) => triangle.with_name(name), None => triangle })
+    }).collect::<Result<Vec<Triangle>>>()?;
+
+    let planes = value.elements("planes")?.iter().map(|fields| {
+        let normal = fields.field("normal")?.vector("normal")?.try_normalize().ok_or(JsonError::WrongValue("normal"))?;
+        let plane = Plane::new(fields.field("point")?.vector("point")?, normal, fields.material()?)
+            .with_visibility(fields.visibility()?);
+        Ok(match fields.name()? { Some(name) => plane.with_name(name), None => plane })
+    }).collect::<Result<Vec<Plane>>>()?;
+
+    Ok(Scene { camera, spheres, mesh: Mesh::new(triangles), planes, resolution, sky, seed, ambient })
+}
+
+
+// ----------------- WRITING ----------------------
+/// JSON has no infinity or NaN.
+fn number(x: f32) -> Result<String> {
+    if x.is_finite() { Ok(x.to_string()) } else { Err(JsonError::CantWrite("a number that isn't finite")) }
+}
+
+fn vector(v: Vec3) -> Result<String> {
+    Ok(format!("[{}, {}, {}]", number(v.x)?, number(v.y)?, number(v.z)?))
+}
+
+fn rgb(color: Color) -> Result<String> {
+    Ok(format!("[{}, {}, {}]", number(color.r)?, number(color.g)?, number(color.b)?))
+}
+
+fn uv((u, v): (f32, f32)) -> Result<String> {
+    Ok(format!("[{}, {}]", number(u)?, number(v)?))
+}
+
+fn string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"'  => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn material(material: &MaterialType) -> Result<String> {
+    let file = |texture: &Texture| texture.path().map(string).ok_or(JsonError::CantWrite("a texture that wasn't loaded from a file"));
+    Ok(match material {
+        MaterialType::Diffuse(color)     => format!("{{\"type\": \"Diffuse\", \"color\": {}}}", rgb(*color)?),
+        MaterialType::Metal(color, fuzz) => format!("{{\"type\": \"Metal\", \"color\": {}, \"fuzz\": {}}}", rgb(*color)?, number(*fuzz)?),
+        MaterialType::GlossyMetal { albedo, roughness } => format!("{{\"type\": \"GlossyMetal\", \"color\": {}, \"roughness\": {}}}", rgb(*albedo)?, number(*roughness)?),
+        MaterialType::Dielectric(ir)     => format!("{{\"type\": \"Dielectric\", \"ir\": {}}}", number(*ir)?),
+        MaterialType::Emission { color, two_sided } => format!("{{\"type\": \"Emission\", \"color\": {}, \"one_sided\": {}}}", rgb(*color)?, !two_sided),
+        MaterialType::Isotropic(color)   => format!("{{\"type\": \"Isotropic\", \"color\": {}}}", rgb(*color)?),
+        MaterialType::Checker { even, odd, scale } => format!("{{\"type\": \"Checker\", \"even\": {}, \"odd\": {}, \"scale\": {}}}", rgb(*even)?, rgb(*odd)?, number(*scale)?),
+        MaterialType::Textured { texture, mapping, filter, wrap } => {
+            let filter = match filter { TextureFilter::Nearest => "nearest", TextureFilter::Bilinear => "bilinear" };
+            let wrap = match wrap { WrapMode::Repeat => "repeat", WrapMode::Clamp => "clamp", WrapMode::Mirror => "mirror" };
+            format!("{{\"type\": \"Textured\", \"file\": {}, \"u_offset\": {}, \"rotation\": {}, \"flip_v\": {}, \"filter\": \"{}\", \"wrap\": \"{}\"}}",
+                file(texture)?, number(mapping.u_offset)?, number(mapping.rotation)?, mapping.flip_v, filter, wrap)
+        },
+        MaterialType::Cutout { texture, threshold } => format!("{{\"type\": \"Cutout\", \"file\": {}, \"threshold\": {}}}", file(texture)?, number(*threshold)?),
+        MaterialType::Custom(_) => return Err(JsonError::CantWrite("a custom material")),
+    })
+}
+
+/// Adds the optional fields every primitive has to `fields`.
+fn primitive_fields(fields: &mut String, name: Option<&str>, visibility: Visibility) {
+    if let Some(name) = name {
+        write!(fields, ", \"name\": {}", string(name)).unwrap();
+    }
+    if !visibility.visible_to_camera {
+        fields.push_str(", \"hidden\": true");
+    }
+    if !visibility.casts_shadow {
+        fields.push_str(", \"noshadow\": true");
+    }
+}
+
+/// Adds the array `key` with an object per line, unless it's empty.
+fn array<I: Iterator<Item=Result<String>>>(json: &mut String, key: &str, objects: I) -> Result<()> {
+    let objects = objects.map(|fields| Ok(format!("    {{{}}}", fields?))).collect::<Result<Vec<String>>>()?;
+    if !objects.is_empty() {
+        write!(json, ",\n  \"{}\": [\n{}\n  ]", key, objects.join(",\n")).unwrap();
+    }
+    Ok(())
+}
+
+
+// ----------------- READING ----------------------
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// As written, so floats and seeds are read without a detour through f64.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// The fields in the order they're written.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value of `key`, if this is an object with it.
+    fn optional(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+    fn field(&self, key: &'static str) -> Result<&Json> {
+        self.optional(key).ok_or(JsonError::MissingField(key))
+    }
+    /// The elements of the array `key`, where a missing array is empty.
+    fn elements(&self, key: &'static str) -> Result<&[Json]> {
+        match self.optional(key) {
+            Some(Json::Array(elements)) => Ok(elements),
+            Some(_) => Err(JsonError::WrongValue(key)),
+            None => Ok(&[]),
+        }
+    }
+    /// A finite number, e.g. `1e39` overflows an f32.
+    fn float(&self, field: &'static str) -> Result<f32> {
+        match self {
+            Json::Number(number) => number.parse().ok().filter(|x: &f32| x.is_finite()).ok_or(JsonError::WrongValue(field)),
+            _ => Err(JsonError::WrongValue(field)),
+        }
+    }
+    /// A non-negative whole number.
+    fn integer(&self, field: &'static str) -> Result<u64> {
+        match self {
+            Json::Number(number) => number.parse().map_err(|_| JsonError::WrongValue(field)),
+            _ => Err(JsonError::WrongValue(field)),
+        }
+    }
+    fn flag(&self, field: &'static str) -> Result<bool> {
+        match self.optional(field) {
+            Some(Json::Bool(flag)) => Ok(*flag),
+            Some(_) => Err(JsonError::WrongValue(field)),
+            None => Ok(false),
+        }
+    }
+    fn vector(&self, field: &'static str) -> Result<Vec3> {
+        match self {
+            Json::Array(components) if components.len() == 3 =>
+                Ok(Vec3::new(components[0].float(field)?, components[1].float(field)?, components[2].float(field)?)),
+            _ => Err(JsonError::WrongValue(field)),
+        }
+    }
+    fn uv(&self, field: &'static str) -> Result<(f32, f32)> {
+        match self {
+            Json::Array(components) if components.len() == 2 => Ok((components[0].float(field)?, components[1].float(field)?)),
+            _ => Err(JsonError::WrongValue(field)),
+        }
+    }
+    fn color(&self, field: &'static str) -> Result<Color> {
+        let Vec3 { x: r, y: g, z: b } = self.vector(field)?;
+        Ok(Color::new(r, g, b))
+    }
+    fn name(&self) -> Result<Option<&str>> {
+        match self.optional("name") {
+            Some(Json::String(name)) => Ok(Some(name)),
+            Some(_) => Err(JsonError::WrongValue("name")),
+            None => Ok(None),
+        }
+    }
+    fn visibility(&self) -> Result<Visibility> {
+        Ok(Visibility { visible_to_camera: !self.flag("hidden")?, casts_shadow: !self.flag("noshadow")? })
+    }
+    /// The `material` of a primitive.
+    fn material(&self) -> Result<MaterialType> {
+        let material = self.field("material")?;
+        let kind = match material.field("type")? {
+            Json::String(kind) => kind.as_str(),
+            _ => return Err(JsonError::WrongValue("type")),
+        };
+        let color = |field| material.field(field)?.color(field);
+        let float = |field| material.field(field)?.float(field);
+        let optional_float = |field, default| material.optional(field).map_or(Ok(default), |value| value.float(field));
+        let texture = || match material.field("file")? {
+            Json::String(path) => Texture::load(path).map(Arc::new).map_err(|_| JsonError::CouldntOpenFile),
+            _ => Err(JsonError::WrongValue("file")),
+        };
+        let keyword = |field| match material.optional(field) {
+            Some(Json::String(keyword)) => Ok(Some(keyword.as_str())),
+            Some(_) => Err(JsonError::WrongValue(field)),
+            None => Ok(None),
+        };
+        match kind {
+            "Diffuse"     => Ok(MaterialType::Diffuse(color("color")?)),
+            "Metal"       => Ok(MaterialType::Metal(color("color")?, float("fuzz")?)),
+            "GlossyMetal" => Ok(MaterialType::GlossyMetal { albedo: color("color")?, roughness: float("roughness")? }),
+            "Dielectric"  => Ok(MaterialType::Dielectric(float("ir")?)),
+            "Emission"    => Ok(MaterialType::Emission { color: color("color")?, two_sided: !material.flag("one_sided")? }),
+            "Isotropic"   => Ok(MaterialType::Isotropic(color("color")?)),
+            "Checker"     => Ok(MaterialType::Checker { even: color("even")?, odd: color("odd")?, scale: float("scale")? }),
+            "Cutout"      => Ok(MaterialType::Cutout { texture: texture()?, threshold: float("threshold")? }),
+            "Textured"    => {
+                let mapping = SphereMapping { u_offset: optional_float("u_offset", 0.0)?, rotation: optional_float("rotation", 0.0)?, flip_v: material.flag("flip_v")? };
+                let filter = match keyword("filter")? {
+                    None | Some("nearest") => TextureFilter::Nearest,
+                    Some("bilinear") => TextureFilter::Bilinear,
+                    Some(_) => return Err(JsonError::WrongValue("filter")),
+                };
+                let wrap = match keyword("wrap")? {
+                    None | Some("repeat") => WrapMode::Repeat,
+                    Some("clamp")  => WrapMode::Clamp,
+                    Some("mirror") => WrapMode::Mirror,
+                    Some(_) => return Err(JsonError::WrongValue("wrap")),
+                };
+                Ok(MaterialType::Textured { texture: texture()?, mapping, filter, wrap })
+            },
+            _ => Err(JsonError::WrongValue("type")),
+        }
+    }
+}
+
+/// How deep arrays and objects can be nested, so that the recursive parser
+/// can't run out of stack space. Scenes only need a few levels.
+const MAX_NESTING: usize = 64;
+
+struct Parser<'a> {
+    source: &'a str,
+    /// The byte offset of the next character.
+    index:  usize,
+    /// How many arrays and objects the next value is in.
+    depth:  usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, reason: &'static str) -> JsonError {
+        JsonError::Malformed { offset: self.index, reason }
+    }
+    fn rest(&self) -> &'a str {
+        &self.source[self.index..]
+    }
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.index += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+    /// Skips `token` if it's next, after any whitespace.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let found = self.rest().starts_with(token);
+        if found {
+            self.index += token.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+        match self.rest().as_bytes().first() {
+            Some(b'{') | Some(b'[') if self.depth >= MAX_NESTING => Err(self.error("Arrays and objects are nested too deeply")),
+            Some(b'{') => self.nested(Self::object),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ if self.eat("true")  => Ok(Json::Bool(true)),
+            _ if self.eat("false") => Ok(Json::Bool(false)),
+            _ if self.eat("null")  => Ok(Json::Null),
+            Some(_) => Err(self.error("Expected a value")),
+            None    => Err(self.error("Expected a value, found the end of the input")),
+        }
+    }
+
+    /// Parses an object or array one level deeper.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json>) -> Result<Json> {
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.eat("{");
+        let mut fields = Vec::new();
+        if self.eat("}") {
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if !self.rest().starts_with('"') {
+                return Err(self.error("Expected the name of a field"));
+            }
+            let key = self.string()?;
+            if !self.eat(":") {
+                return Err(self.error("Expected a ':' after the name of the field"));
+            }
+            fields.push((key, self.value()?));
+            if self.eat("}") {
+                return Ok(Json::Object(fields));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected a ',' or '}'"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.eat("[");
+        let mut elements = Vec::new();
+        if self.eat("]") {
+            return Ok(Json::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            if self.eat("]") {
+                return Ok(Json::Array(elements));
+            }
+            if !self.eat(",") {
+                return Err(self.error("Expected a ',' or ']'"));
+            }
+        }
+    }
+
+    /// A string, with the `"` next.
+    fn string(&mut self) -> Result<String> {
+        self.index += 1;
+        let mut string = String::new();
+        let mut chars = self.rest().char_indices();
+        loop {
+            let (offset, c) = match chars.next() {
+                Some(next) => next,
+                None => return Err(self.error("The string doesn't end")),
+            };
+            match c {
+                '"' => {
+                    self.index += offset + 1;
+                    return Ok(string);
+                },
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, '"'))  => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/'))  => '/',
+                        Some((_, 'b'))  => '\u{8}',
+                        Some((_, 'f'))  => '\u{c}',
+                        Some((_, 'n'))  => '\n',
+                        Some((_, 'r'))  => '\r',
+                        Some((_, 't'))  => '\t',
+                        Some((_, 'u'))  => {
+                            let digits: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            // Surrogate pairs aren't supported, they only come up outside of the BMP.
+                            u32::from_str_radix(&digits, 16).ok().filter(|_| digits.len() == 4).and_then(char::from_u32)
+                                .ok_or_else(|| JsonError::Malformed { offset: self.index + offset, reason: "Invalid unicode escape" })?
+                        },
+                        _ => return Err(JsonError::Malformed { offset: self.index + offset, reason: "Invalid escape" }),
+                    };
+                    string.push(escaped);
+                },
+                c if (c as u32) < 0x20 => return Err(JsonError::Malformed { offset: self.index + offset, reason: "Control character in a string" }),
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let rest = self.rest();
+        let length = rest.find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c))).unwrap_or(rest.len());
+        let number = &rest[..length];
+        number.parse::<f64>().map_err(|_| self.error("Invalid number"))?;
+        self.index += length;
+        Ok(Json::Number(number.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_input;
+
+    const SCENE: &str = "\
+camera origin 1.0 2.0 3.0 aspect 1.5 orthographic scale 4.0;
+output width 64 height 32;
+sky color 0.1 0.2 0.3;
+seed 42;
+ambient color 0.05 0.05 0.05;
+material RED : Diffuse color 0.8 0.1 0.1;
+material GLASS : Dielectric ir 1.5;
+sphere center 0.0 0.0 -1.0 radius 0.5 material RED name BALL;
+sphere center 1.0 0.0 -1.0 radius 0.25 material GLASS hidden;
+sphere center 0.0 3.0 -1.0 radius 0.5 material Emission color 4.0 4.0 4.0 one_sided noshadow;
+triangle v0 -1.0 0.0 -2.0 v1 1.0 0.0 -2.0 v2 0.0 1.0 -2.0 material Metal color 0.9 0.9 0.9 fuzz 0.1 colors 1.0 0.0 0.0 0.0 1.0 0.0 0.0 0.0 1.0;
+quad v0 -2.0 -1.0 -3.0 v1 2.0 -1.0 -3.0 v2 2.0 1.0 -3.0 v3 -2.0 1.0 -3.0 material GlossyMetal color 0.5 0.5 0.5 roughness 0.3;
+floor checker color 0.9 0.9 0.9 color 0.1 0.1 0.1 scale 0.5 at -1.0;
+";
+
+    #[test]
+    fn scenes_round_trip_through_json() {
+        let scene = parse_input(SCENE).unwrap();
+        let json  = scene_to_json(&scene).unwrap();
+        let back  = scene_from_json(&json).unwrap();
+
+        assert_eq!(back.spheres.len(), 3);
+        assert_eq!(back.mesh.triangles().len(), 3);
+        assert_eq!(back.planes.len(), 1);
+        assert_eq!(back.camera.position(), scene.camera.position());
+        assert_eq!(back.camera.aspect_ratio(), scene.camera.aspect_ratio());
+        assert_eq!(back.camera.projection(), Projection::Orthographic { scale: 4.0 });
+        assert_eq!((back.resolution, back.seed), (Some((64, 32)), Some(42)));
+        assert_eq!(back.spheres[0].name.as_deref(), Some("BALL"));
+        assert!(!back.spheres[1].visibility.visible_to_camera && !back.spheres[2].visibility.casts_shadow);
+        assert!(matches!(back.spheres[2].material, MaterialType::Emission { two_sided: false, .. }));
+        assert!(back.mesh.triangles()[0].colors().is_some());
+        let uvs = |scene: &Scene| scene.mesh.triangles().iter().map(|triangle| triangle.uvs()).collect::<Vec<_>>();
+        assert_eq!(uvs(&back), uvs(&scene));
+        assert!(uvs(&scene).iter().any(|&uvs| uvs != DEFAULT_TRIANGLE_UVS));
+
+        // Everything else is compared through the JSON.
+        assert_eq!(scene_to_json(&back).unwrap(), json);
+    }
+
+    #[test]
+    fn only_the_camera_is_required() {
+        let scene = scene_from_json("{\"camera\": {\"origin\": [0, 0, 0], \"aspect\": 2}, \"editor\": {\"zoom\": null}}").unwrap();
+        assert_eq!(scene.camera.aspect_ratio(), 2.0);
+        assert!(scene.spheres.is_empty() && scene.sky.is_none() && scene.resolution.is_none());
+
+        let json = "{\"camera\": {\"origin\": [0, 0, 0], \"aspect\": 1}, \"spheres\": [{\"center\": [0, 0, -1], \"radius\": 1, \"material\": {\"type\": \"Diffuse\", \"color\": [1, 1, 1]}, \"name\": \"a \\\"b\\\"\\n\\u00e9\"}]}";
+        let scene = scene_from_json(json).unwrap();
+        assert_eq!(scene.spheres[0].name.as_deref(), Some("a \"b\"\né"));
+        assert!(scene_to_json(&scene).unwrap().contains("\"name\": \"a \\\"b\\\"\\né\""));
+
+        assert_eq!(scene_from_json("{}").err(), Some(JsonError::MissingField("camera")));
+        assert_eq!(scene_from_json("{\"camera\": {\"origin\": [0, 0], \"aspect\": 2}}").err(), Some(JsonError::WrongValue("origin")));
+    }
+
+    #[test]
+    fn malformed_json_is_an_error() {
+        for source in ["", "{", "{\"camera\" {}}", "[1, 2,]", "{\"a\": \"unterminated}", "{\"a\": 1} 2", "{\"a\": \"\\q\"}", "{\"a\": tru}"] {
+            assert!(matches!(scene_from_json(source), Err(JsonError::Malformed { .. })), "{:?} should be malformed", source);
+        }
+
+        // Nesting is limited instead of running out of stack.
+        assert!(matches!(scene_from_json(&"[".repeat(100_000)), Err(JsonError::Malformed { reason: "Arrays and objects are nested too deeply", .. })));
+        let nested = format!("{{\"camera\": {{\"origin\": [0, 0, 0], \"aspect\": 1}}, \"editor\": {}1{}}}", "[".repeat(MAX_NESTING - 1), "]".repeat(MAX_NESTING - 1));
+        assert!(scene_from_json(&nested).is_ok());
+    }
+
+    #[test]
+    fn numbers_have_to_be_finite() {
+        let mut scene = parse_input("camera origin 0.0 0.0 0.0 aspect 1.0; sphere center 0.0 0.0 -1.0 radius 0.5 material Diffuse color 1.0 1.0 1.0;").unwrap();
+        scene.spheres[0].radius = f32::INFINITY;
+        assert_eq!(scene_to_json(&scene).err(), Some(JsonError::CantWrite("a number that isn't finite")));
+        scene.spheres[0].radius = 0.5;
+        scene.ambient = Some(Color::new(f32::NAN, 0.0, 0.0));
+        assert_eq!(scene_to_json(&scene).err(), Some(JsonError::CantWrite("a number that isn't finite")));
+
+        assert_eq!(scene_from_json("{\"camera\": {\"origin\": [0, 0, 0], \"aspect\": 1e39}}").err(), Some(JsonError::WrongValue("aspect")));
+    }
+
+    #[test]
+    fn textures_round_trip_through_their_files() {
+        let path = std::env::temp_dir().join(format!("raytracer_json_textures_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+        crate::image::write_image(&crate::image::Framebuffer::new(2, 1), Some(path)).unwrap();
+
+        let source = format!("camera origin 0.0 0.0 0.0 aspect 1.0;
+sphere center 0.0 0.0 -1.0 radius 0.5 material Textured file \"{0}\" u_offset 0.25 rotation 90.0 flip_v filter bilinear wrap mirror;
+quad v0 -1.0 -1.0 -2.0 v1 1.0 -1.0 -2.0 v2 1.0 1.0 -2.0 v3 -1.0 1.0 -2.0 material Cutout file \"{0}\" threshold 0.5;
+", path);
+        let scene = parse_input(&source).unwrap();
+        let json = scene_to_json(&scene).unwrap();
+        let back = scene_from_json(&json);
+        std::fs::remove_file(path).unwrap();
+
+        let back = back.unwrap();
+        match &back.spheres[0].material {
+            MaterialType::Textured { texture, mapping, filter, wrap } => {
+                assert_eq!(texture.path(), Some(path));
+                assert_eq!(*mapping, SphereMapping { u_offset: 0.25, rotation: 90.0, flip_v: true });
+                assert_eq!((*filter, *wrap), (TextureFilter::Bilinear, WrapMode::Mirror));
+            },
+            _ => panic!("Expected a textured material."),
+        }
+        assert!(matches!(back.mesh.triangles()[0].material(), MaterialType::Cutout { threshold, .. } if *threshold == 0.5));
+        assert_eq!(scene_to_json(&back).unwrap(), json);
+
+        // The file has to exist when it's read back.
+        assert_eq!(scene_from_json(&json).err(), Some(JsonError::CouldntOpenFile));
+    }
+
+    #[test]
+    fn materials_json_cant_express_are_errors() {
+        use crate::common::{HitRecord, Ray};
+        use crate::materials::{Material, ScatterData};
+        use crate::random::Random;
+
+        #[derive(Debug)]
+        struct Black;
+        impl Material for Black {
+            fn scatter(&self, _ray: &Ray, _hit: &HitRecord, _random: &mut Random) -> ScatterData {
+                ScatterData { color: Color::new(0.0, 0.0, 0.0), next_ray: None }
+            }
+        }
+
+        let mut scene = parse_input("camera origin 0.0 0.0 0.0 aspect 1.0;").unwrap();
+        scene.spheres.push(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, MaterialType::Custom(Arc::new(Black))));
+        assert_eq!(scene_to_json(&scene).err(), Some(JsonError::CantWrite("a custom material")));
+
+        // A texture made in memory has no file to write.
+        let texture = Arc::new(Texture::new(crate::image::Framebuffer::new(1, 1)));
+        scene.spheres[0].material = MaterialType::Cutout { texture, threshold: 0.5 };
+        assert!(matches!(scene_to_json(&scene), Err(JsonError::CantWrite(_))));
+
+        let camera = "{\"camera\": {\"origin\": [0, 0, 0], \"aspect\": 1}, \"spheres\": [{\"center\": [0, 0, -1], \"radius\": 1, \"material\": {\"type\": \"Textured\"}}]}";
+        assert_eq!(scene_from_json(camera).err(), Some(JsonError::MissingField("file")));
+    }
+}
@@ -7,18 +7,32 @@ pub mod mat3;
 pub mod materials;
 pub mod common;
 pub mod color;
+pub mod texture;
+pub mod bvh;
+pub mod scene;
+pub mod obj;
+pub mod animation;
+pub mod json;
 
-use color::ColorU8;
+pub use color::{ColorU8, Color};
 use maths::Vec3;
-use image::Framebuffer;
+use image::{Framebuffer, DimensionError, DEFAULT_MAX_PIXELS, pixel_count, TestPattern};
 use camera::Camera;
-use common::{World, Options, ray_trace};
+use scene::Scene;
+use common::{World, Options, Sky, ray_trace, ray_trace_f32, primary_hit};
 
+use std::convert::TryFrom;
 use std::ffi::CStr;
 use std::os::raw::c_char;
 use std::ptr::NonNull;
 
 
+/// A framebuffer owned by the host. `pixels` must point to `width * height`
+/// initialized `ColorU8` (RGBA, 4 bytes each), row by row from the top, that
+/// stay valid and aren't otherwise accessed during a call. Anything else is
+/// undefined behaviour. The render functions write the image to `pixels` and
+/// return the framebuffer, or a copy of it with a width and height of 0 if
+/// they refused to render.
 #[repr(C)]
 pub struct CFramebuffer {
     pub width:  usize,
@@ -26,62 +40,500 @@ pub struct CFramebuffer {
     pub pixels: NonNull<ColorU8>,
 }
 
+impl CFramebuffer {
+    /// What the render functions return for a framebuffer they refuse to
+    /// render, see `check_dimensions`. The pixels are left untouched.
+    fn error(self) -> Self {
+        Self { width: 0, height: 0, pixels: self.pixels }
+    }
+
+    /// Copies the pixels of `framebuffer`, which must be the same size, to the host.
+    unsafe fn write(self, framebuffer: &Framebuffer) -> Self {
+        debug_assert_eq!((self.width, self.height), (framebuffer.width, framebuffer.height));
+        std::ptr::copy_nonoverlapping(framebuffer.pixels.as_ptr(), self.pixels.as_ptr(), framebuffer.pixels.len());
+        self
+    }
+}
+
+/// Error codes from `check_dimensions`.
+pub const DIMENSIONS_OK:        i32 = 0;
+pub const DIMENSIONS_OVERFLOW:  i32 = 1;
+pub const DIMENSIONS_TOO_LARGE: i32 = 2;
+
+/// Whether the library accepts a `width` x `height` framebuffer. The render
+/// functions return a framebuffer with a width and height of 0 otherwise.
+#[no_mangle]
+pub extern "C" fn check_dimensions(width: usize, height: usize) -> i32 {
+    match pixel_count(width, height, DEFAULT_MAX_PIXELS) {
+        Ok(_) => DIMENSIONS_OK,
+        Err(DimensionError::Overflow) => DIMENSIONS_OVERFLOW,
+        Err(DimensionError::TooManyPixels { .. }) => DIMENSIONS_TOO_LARGE,
+    }
+}
+
+/// Patterns for `render_test_pattern`, see `image::TestPattern`.
+pub const TEST_PATTERN_CHECKERBOARD: u32 = 0;
+pub const TEST_PATTERN_COLOR_BARS:   u32 = 1;
+pub const TEST_PATTERN_UV_GRADIENT:  u32 = 2;
+
+/// Writes a test pattern to the framebuffer without needing a scene, e.g.
+/// to check how the host displays it. The checkerboard has 8 pixel squares.
+/// Returns a framebuffer with a width and height of 0 if `kind` is unknown
+/// or the dimensions aren't accepted, see `check_dimensions`.
+/// # Safety
+/// `framebuffer` must be valid as described for `CFramebuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn render_test_pattern(framebuffer: CFramebuffer, kind: u32) -> CFramebuffer {
+    let kind = match kind {
+        TEST_PATTERN_CHECKERBOARD => TestPattern::Checkerboard { cell_size: 8 },
+        TEST_PATTERN_COLOR_BARS   => TestPattern::ColorBars,
+        TEST_PATTERN_UV_GRADIENT  => TestPattern::UvGradient,
+        _ => return framebuffer.error(),
+    };
+    if pixel_count(framebuffer.width, framebuffer.height, DEFAULT_MAX_PIXELS).is_err() {
+        return framebuffer.error();
+    }
+    let pattern = Framebuffer::test_pattern(framebuffer.width, framebuffer.height, kind);
+    framebuffer.write(&pattern)
+}
+
 #[repr(C)]
 pub struct WorldHandle {
     world:  Box<World>,
     camera: Box<Camera>,
+    resolution: Option<(usize, usize)>,
+    sky:    Sky,
+    seed:   u64,
+    ambient: Color,
+}
+
+impl WorldHandle {
+    fn new(scene: Scene) -> Self {
+        let (resolution, sky, seed) = (scene.resolution, scene.sky.unwrap_or_default(), scene.seed.unwrap_or_default());
+        let ambient = scene.ambient.unwrap_or(Color::new(0.0, 0.0, 0.0));
+        let (camera, world) = scene.into_world();
+        Self {
+            camera: Box::new(camera),
+            world: Box::new(world),
+            resolution,
+            sky,
+            seed,
+            ambient,
+        }
+    }
+
+    /// The options every render from the host uses, with the sky, seed and
+    /// ambient light of the scene.
+    fn options(&self) -> Options {
+        let mut options = Options::new(16, 8, None, true);
+        options.sky = self.sky;
+        options.seed = self.seed;
+        options.ambient = self.ambient;
+        options
+    }
 }
 
-// TODO: Make it so it takes in a source AND a count,
-//  so we don't rely on null-termination.
+/// Parses the scene in `source` and panics if it doesn't parse. See
+/// `load_world_bytes` for sources that aren't null-terminated.
+/// # Safety
+/// `source` must be a valid pointer to a null-terminated string.
 #[no_mangle]
-pub extern "C" fn load_world<'a>(source: *const c_char) -> Box<WorldHandle> {
-    let c_str = unsafe { CStr::from_ptr(source) };
-    let (camera, spheres, mesh) = parser::parse_input(c_str.to_str().unwrap()).unwrap();
-    let world = World::new(spheres, vec![mesh]);
-    Box::new(WorldHandle {
-        camera: Box::new(camera),
-        world: Box::new(world),
-    })
+pub unsafe extern "C" fn load_world(source: *const c_char) -> Box<WorldHandle> {
+    let c_str = CStr::from_ptr(source);
+    let scene = parser::parse_input(c_str.to_str().unwrap()).unwrap();
+    Box::new(WorldHandle::new(scene))
+}
+
+/// Same as `load_world`, but for a source of `len` bytes of UTF-8 that
+/// doesn't have to be null-terminated, e.g. the contents of a file. Returns
+/// null if `source` is null, isn't valid UTF-8, contains a null character or
+/// doesn't parse.
+/// # Safety
+/// `source` must be null or point to `len` bytes that stay valid during the call.
+#[no_mangle]
+pub unsafe extern "C" fn load_world_bytes(source: *const u8, len: usize) -> *mut WorldHandle {
+    if source.is_null() {
+        return std::ptr::null_mut();
+    }
+    let bytes = std::slice::from_raw_parts(source, len);
+    match std::str::from_utf8(bytes) {
+        Ok(source) if !source.contains('\0') => match parser::parse_input(source) {
+            Ok(scene) => Box::into_raw(Box::new(WorldHandle::new(scene))),
+            Err(_) => std::ptr::null_mut(),
+        },
+        _ => std::ptr::null_mut(),
+    }
 }
 
 
+/// Renders the world into the framebuffer. Returns a framebuffer with a
+/// width and height of 0 if `handle` is null or the dimensions aren't
+/// accepted, see `check_dimensions`.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`, and
+/// `framebuffer` must be valid as described for `CFramebuffer`.
 #[no_mangle]
-pub extern "C" fn render(framebuffer: CFramebuffer, handle: *const WorldHandle) -> CFramebuffer {
-    let mut options = Options::new(16, 8, None, true);
+pub unsafe extern "C" fn render(framebuffer: CFramebuffer, handle: *const WorldHandle) -> CFramebuffer {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return framebuffer.error(),
+    };
+    let WorldHandle { world, camera, .. } = handle;
+    let mut options = handle.options();
+    if framebuffer.width == 0 || framebuffer.height == 0 {
+        return framebuffer;
+    }
+    let (width, height, pixels) = (framebuffer.width, framebuffer.height, framebuffer.pixels);
+    let rendered = match Framebuffer::try_from(framebuffer) {
+        Ok(framebuffer) => ray_trace(world, camera, framebuffer, &mut options),
+        Err((framebuffer, _)) => return framebuffer.error(),
+    };
 
-    let WorldHandle { world, camera } = unsafe { &(*handle) };
-    let framebuffer = ray_trace(world, camera, framebuffer.into(), &mut options);
+    CFramebuffer { width, height, pixels }.write(&rendered)
+}
+
+
+/// Renders the world as linear RGBA floats, see `common::ray_trace_f32`.
+/// Returns false without touching `pixels` if a pointer is null or the
+/// dimensions aren't accepted, see `check_dimensions`.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`, and
+/// `pixels` must be null or point to `width * height * 4` floats.
+#[no_mangle]
+pub unsafe extern "C" fn render_f32(width: usize, height: usize, pixels: *mut f32, handle: *const WorldHandle) -> bool {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return false,
+    };
+    let WorldHandle { world, camera, .. } = handle;
+    let mut options = handle.options();
+    let count = match pixel_count(width, height, DEFAULT_MAX_PIXELS) {
+        Ok(count) if !pixels.is_null() => count,
+        _ => return false,
+    };
 
-    framebuffer.into()
+    let rendered = ray_trace_f32(world, camera, width, height, &mut options);
+    std::ptr::copy_nonoverlapping(rendered.as_ptr() as *const f32, pixels, count * 4);
+    true
 }
 
 
+/// Adds another 16 samples per pixel to the ones in `accumulation` and
+/// returns the average of all of them, see `common::render_accumulate`.
+///
+/// The host owns `accumulation`, which must point to `width * height * 4`
+/// floats (RGBA per pixel, in the same order as the framebuffer), and
+/// `sample_counts`, which must point to `width * height` counts of the
+/// samples in each pixel. They have to be kept alive and unchanged between
+/// calls, and are only read and written during the call. To restart, e.g.
+/// when the camera or scene changes, fill both of them with zeroes.
+/// Returns a framebuffer with a width and height of 0 if a pointer is null
+/// or the dimensions aren't accepted, see `check_dimensions`.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`,
+/// `framebuffer` must be valid as described for `CFramebuffer`, and
+/// `accumulation` and `sample_counts` must be valid as described above.
 #[no_mangle]
-pub extern "C" fn move_camera_position(camera: Box<Camera>, x: f32, y: f32, z: f32) -> Box<Camera> {
-    Camera::new_at(camera.position() + Vec3{ x, y, z }, camera.aspect_ratio()).into()
+pub unsafe extern "C" fn render_accumulate(framebuffer: CFramebuffer, accumulation: *mut f32, sample_counts: *mut u32, handle: *const WorldHandle) -> CFramebuffer {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return framebuffer.error(),
+    };
+    let WorldHandle { world, camera, .. } = handle;
+    let mut options = handle.options();
+    if accumulation.is_null() || sample_counts.is_null() {
+        return framebuffer.error();
+    }
+    if framebuffer.width == 0 || framebuffer.height == 0 {
+        return framebuffer;
+    }
+    let (width, height, pixels) = (framebuffer.width, framebuffer.height, framebuffer.pixels);
+    let framebuffer = match Framebuffer::try_from(framebuffer) {
+        Ok(framebuffer) => framebuffer,
+        Err((framebuffer, _)) => return framebuffer.error(),
+    };
+    let count = framebuffer.width * framebuffer.height;
+    let floats = std::slice::from_raw_parts_mut(accumulation, count * 4);
+    let sample_counts = std::slice::from_raw_parts_mut(sample_counts, count);
+
+    let mut sums: Vec<Color> = floats.chunks_exact(4).map(|c| Color::new_with_alpha(c[0], c[1], c[2], c[3])).collect();
+    let framebuffer = common::render_accumulate(world, camera, framebuffer, &mut sums, sample_counts, &mut options);
+    for (c, sum) in floats.chunks_exact_mut(4).zip(sums.iter()) {
+        c.copy_from_slice(&[sum.r, sum.g, sum.b, sum.a]);
+    }
+
+    CFramebuffer { width, height, pixels }.write(&framebuffer)
 }
 
 
+/// Returns the id of the object under the pixel at (`row`, `column`) of a
+/// `width` x `height` framebuffer, or 0 if there's only background there
+/// or `handle` is null.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`.
+#[no_mangle]
+pub unsafe extern "C" fn object_id_at(handle: *const WorldHandle, width: usize, height: usize, row: usize, column: usize) -> u32 {
+    let WorldHandle { world, camera, .. } = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return 0,
+    };
+    primary_hit(world, camera, width, height, row, column).map_or(0, |hit| hit.id)
+}
+
+/// Writes the resolution suggested by the scene's `output` directive to
+/// `width` and `height`. Returns false, and leaves them untouched, if the
+/// scene doesn't suggest one or a pointer is null.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`, and
+/// `width` and `height` must be null or valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn world_resolution(handle: *const WorldHandle, width: *mut usize, height: *mut usize) -> bool {
+    if width.is_null() || height.is_null() {
+        return false;
+    }
+    match handle.as_ref().and_then(|handle| handle.resolution) {
+        Some((w, h)) => {
+            *width  = w;
+            *height = h;
+            true
+        },
+        None => false,
+    }
+}
 
+/// The number of spheres in the world, or 0 if `handle` is null.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`.
+#[no_mangle]
+pub unsafe extern "C" fn world_sphere_count(handle: *const WorldHandle) -> usize {
+    handle.as_ref().map_or(0, |handle| handle.world.sphere_count())
+}
 
+/// The number of triangles in the world, or 0 if `handle` is null.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`.
+#[no_mangle]
+pub unsafe extern "C" fn world_triangle_count(handle: *const WorldHandle) -> usize {
+    handle.as_ref().map_or(0, |handle| handle.world.triangle_count())
+}
 
-impl Into<Framebuffer> for CFramebuffer {
-    fn into(self) -> Framebuffer {
-        let count = self.width * self.height;
-        Framebuffer {
-            width:  self.width,
-            height: self.height,
-            pixels: unsafe { std::slice::from_raw_parts(self.pixels.as_ptr(), count).to_vec() },
-        }
+/// Moves the camera of the world to (`x`, `y`, `z`) without turning it, so
+/// an interactive viewer doesn't have to reload the world. Returns false if
+/// `handle` is null.
+/// # Safety
+/// `handle` must be null or a valid pointer returned by `load_world`. The
+/// world must not be rendering at the same time, e.g. on another thread.
+#[no_mangle]
+pub unsafe extern "C" fn world_set_camera_origin(handle: *mut WorldHandle, x: f32, y: f32, z: f32) -> bool {
+    match handle.as_mut() {
+        Some(handle) => {
+            handle.camera.set_origin(Vec3 { x, y, z });
+            true
+        },
+        None => false,
     }
 }
-impl From<Framebuffer> for CFramebuffer {
-    fn from(mut framebuffer: Framebuffer) -> Self {
-        Self {
+
+/// Turns the camera of the world towards (`tx`, `ty`, `tz`), see
+/// `Camera::look_at`. Returns false if `handle` is null or the camera can't
+/// look at the target, in which case it's left as it is.
+/// # Safety
+/// Same as for `world_set_camera_origin`.
+#[no_mangle]
+pub unsafe extern "C" fn world_set_camera_look_at(handle: *mut WorldHandle, tx: f32, ty: f32, tz: f32) -> bool {
+    handle.as_mut().is_some_and(|handle| handle.camera.look_at(Vec3 { x: tx, y: ty, z: tz }))
+}
+
+
+#[no_mangle]
+pub extern "C" fn move_camera_position(camera: Box<Camera>, x: f32, y: f32, z: f32) -> Box<Camera> {
+    Camera::new_at(camera.position() + Vec3{ x, y, z }, camera.aspect_ratio()).with_projection(camera.projection()).into()
+}
+
+
+
+
+
+/// Copies the host's pixels, trusting that the framebuffer upholds the
+/// `CFramebuffer` contract.
+impl TryFrom<CFramebuffer> for Framebuffer {
+    /// Gives the framebuffer back, so it can be returned to the host.
+    type Error = (CFramebuffer, DimensionError);
+
+    fn try_from(framebuffer: CFramebuffer) -> Result<Self, Self::Error> {
+        let count = match pixel_count(framebuffer.width, framebuffer.height, DEFAULT_MAX_PIXELS) {
+            Ok(count) => count,
+            Err(error) => return Err((framebuffer, error)),
+        };
+        Ok(Self {
             width:  framebuffer.width,
             height: framebuffer.height,
-            pixels: unsafe { std::ptr::NonNull::new_unchecked(framebuffer.pixels.as_mut_ptr()) }
+            pixels: unsafe { std::slice::from_raw_parts(framebuffer.pixels.as_ptr(), count).to_vec() },
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maths::IVector;
+    use std::ffi::CString;
+
+    const SOURCE: &str = "camera origin 0.0 0.0 0.0 aspect 1.0;\nmaterial RED : Diffuse color 1.0 0.0 0.0;\nsphere center 0.0 0.0 -1.0 radius 0.5 material RED;\n";
+
+    #[test]
+    fn colors_are_exported_at_the_root() {
+        // The same types as in `color`, which `image` and the FFI use.
+        let color: color::Color = crate::Color::new(0.5, 0.25, 1.0);
+        let pixel: crate::ColorU8 = image::Framebuffer::new(1, 1).pixels[0];
+        let _: color::ColorU8 = pixel;
+        assert_eq!(color.a, 1.0);
+    }
+
+    #[test]
+    fn renders_into_the_host_buffer() {
+        let source = CString::new(SOURCE).unwrap();
+        let (width, height) = (8, 6);
+        let mut pixels = vec![ColorU8 { r: 1, g: 2, b: 3, a: 4 }; width * height];
+        let host = NonNull::new(pixels.as_mut_ptr()).unwrap();
+        let framebuffer = || CFramebuffer { width, height, pixels: host };
+
+        unsafe {
+            // Refused without touching the pixels.
+            let result = render(framebuffer(), std::ptr::null());
+            assert_eq!((result.width, result.height), (0, 0));
+            assert!(std::slice::from_raw_parts(host.as_ptr(), width * height).iter().all(|p| (p.r, p.g, p.b, p.a) == (1, 2, 3, 4)));
+
+            let empty = render(CFramebuffer { width: 0, height: 0, pixels: NonNull::dangling() }, std::ptr::null());
+            assert_eq!((empty.width, empty.height), (0, 0));
+
+            let handle = load_world(source.as_ptr());
+            let empty = render(CFramebuffer { width: 0, height, pixels: NonNull::dangling() }, &*handle);
+            assert_eq!((empty.width, empty.height), (0, height));
+
+            let result = render(framebuffer(), &*handle);
+            assert_eq!((result.width, result.height, result.pixels), (width, height, host));
+        }
+
+        // The red sphere is in the middle.
+        let center = pixels[3 * width + 4];
+        assert!(center.r > 0 && center.g == 0 && center.a == 255);
+    }
+
+    #[test]
+    fn renders_test_patterns_into_the_host_buffer() {
+        let (width, height) = (16, 2);
+        let mut pixels = vec![ColorU8 { r: 1, g: 2, b: 3, a: 4 }; width * height];
+        let host = NonNull::new(pixels.as_mut_ptr()).unwrap();
+
+        unsafe {
+            let result = render_test_pattern(CFramebuffer { width, height, pixels: host }, 42);
+            assert_eq!((result.width, result.height), (0, 0));
+            assert_eq!(pixels[0].r, 1);
+
+            let result = render_test_pattern(CFramebuffer { width, height, pixels: host }, TEST_PATTERN_CHECKERBOARD);
+            assert_eq!((result.width, result.height), (width, height));
+        }
+        assert_eq!((pixels[0].r, pixels[8].r, pixels[0].a), (255, 0, 255));
+    }
+
+    #[test]
+    fn renders_linear_floats() {
+        let source = CString::new(SOURCE).unwrap();
+        let (width, height) = (8, 6);
+        let mut pixels = vec![-1.0f32; width * height * 4];
+
+        unsafe {
+            assert!(!render_f32(width, height, pixels.as_mut_ptr(), std::ptr::null()));
+            assert_eq!(pixels[0], -1.0);
+
+            let handle = load_world(source.as_ptr());
+            assert!(!render_f32(width, height, std::ptr::null_mut(), &*handle));
+            assert!(render_f32(width, height, pixels.as_mut_ptr(), &*handle));
+        }
+
+        // The red sphere is in the middle.
+        let center = &pixels[(3 * width + 4) * 4..][..4];
+        assert!(center[0] > 0.0 && center[1] == 0.0 && center[3] == 1.0, "{:?}", center);
+        assert!(pixels.iter().all(|value| *value >= 0.0));
+    }
+
+    #[test]
+    fn counts_the_loaded_primitives() {
+        let source = CString::new("\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+sphere center 0.0 0.0 -1.0 radius 0.5 material RED;
+sphere center 0.0 1.0 -1.0 radius 0.5 material RED;
+triangle v0 0.0 0.0 -2.0 v1 1.0 0.0 -2.0 v2 0.0 1.0 -2.0 material RED;
+quad v0 0.0 0.0 -3.0 v1 1.0 0.0 -3.0 v2 1.0 1.0 -3.0 v3 0.0 1.0 -3.0 material RED;
+").unwrap();
+
+        unsafe {
+            let handle = load_world(source.as_ptr());
+            assert_eq!(world_sphere_count(&*handle), 2);
+            assert_eq!(world_triangle_count(&*handle), 3);
+
+            assert_eq!(world_sphere_count(std::ptr::null()), 0);
+            assert_eq!(world_triangle_count(std::ptr::null()), 0);
+        }
+    }
+
+    #[test]
+    fn queries_with_a_null_handle_are_refused() {
+        let (mut width, mut height) = (7, 5);
+        unsafe {
+            assert_eq!(object_id_at(std::ptr::null(), 8, 8, 4, 4), 0);
+            assert!(!world_resolution(std::ptr::null(), &mut width, &mut height));
+        }
+        assert_eq!((width, height), (7, 5));
+    }
+
+    #[test]
+    fn loads_worlds_from_bytes() {
+        let c_source = CString::new(SOURCE).unwrap();
+
+        unsafe {
+            let from_c_string = load_world(c_source.as_ptr());
+            let handle = load_world_bytes(SOURCE.as_ptr(), SOURCE.len());
+            assert!(!handle.is_null());
+            assert_eq!(world_sphere_count(handle), world_sphere_count(&*from_c_string));
+            assert_eq!(world_sphere_count(handle), 1);
+            drop(Box::from_raw(handle));
+
+            // Without the null terminator the length is all there is to go by.
+            let truncated = load_world_bytes(SOURCE.as_ptr(), SOURCE.find("sphere").unwrap());
+            assert_eq!(world_sphere_count(truncated), 0);
+            drop(Box::from_raw(truncated));
+
+            let with_null = format!("{}\0", SOURCE);
+            assert!(load_world_bytes(with_null.as_ptr(), with_null.len()).is_null());
+            assert!(load_world_bytes([0xFF, 0xFE].as_ptr(), 2).is_null());
+            assert!(load_world_bytes(b"sphere".as_ptr(), 6).is_null());
+            assert!(load_world_bytes(std::ptr::null(), 0).is_null());
+        }
+    }
+
+    #[test]
+    fn moves_the_camera_in_place() {
+        let source = CString::new(SOURCE).unwrap();
+
+        unsafe {
+            let mut handle = load_world(source.as_ptr());
+            let before = handle.camera.cast_ray(0.5, 0.5);
+
+            assert!(world_set_camera_origin(&mut *handle, 0.0, 0.0, 2.0));
+            let moved = handle.camera.cast_ray(0.5, 0.5);
+            assert_eq!(handle.camera.position(), Vec3 { x: 0.0, y: 0.0, z: 2.0 });
+            assert_eq!((moved.origin.z, moved.direction.z()), (2.0, before.direction.z()));
+
+            assert!(world_set_camera_look_at(&mut *handle, 2.0, 0.0, 2.0));
+            let turned = handle.camera.cast_ray(0.5, 0.5);
+            assert!((turned.direction.x() - 1.0).abs() < 1e-6);
+
+            assert!(!world_set_camera_origin(std::ptr::null_mut(), 0.0, 0.0, 0.0));
+            assert!(!world_set_camera_look_at(std::ptr::null_mut(), 0.0, 0.0, 0.0));
         }
     }
 }
@@ -9,95 +9,191 @@ pub mod mat3;
 pub mod materials;
 pub mod common;
 pub mod color;
+pub mod texture;
+pub mod bvh;
+pub mod scene;
+pub mod obj;
+pub mod animation;
+pub mod json;
 
 
-use materials::MaterialType;
-use image::{Framebuffer, write_image};
-use camera::Radians;
-use maths::{Vec3, IVector, Y_AXIS};
-use common::{World, Options, Mesh, Triangle, ray_trace};
+use image::{Framebuffer, write_image, write_hdr, write_png16};
+use common::{Options, ray_trace_with_output, display_colors};
 use std::io::stderr;
-use crate::color::Color;
-
-
-fn get_arguments() -> Result<(i32, i32), Box<dyn Error>> {
-    let mut samples_per_pixel = 50;
-    let mut max_ray_bounces   = 8;
-
-    let mut arguments = std::env::args();
-    let _path = arguments.next();
-
-    for argument in arguments {
-        if let Ok(next) = parser::starts_with(&argument, "samples") {
-            let next = parser::starts_with(next, "=")?;
-            let (_, samples) = parser::parse_int(next)?;
-            samples_per_pixel = samples;
-        } else if let Ok(next) = parser::starts_with(&argument, "ray_depth") {
-            let next = parser::starts_with(next, "=")?;
-            let (_, ray_depth) = parser::parse_int(next)?;
-            max_ray_bounces = ray_depth;
-        } else {
-            panic!("Unknown argument '{}'", argument);
+
+
+const USAGE: &str = "\
+Usage: raytracer [options]
+    --scene <file>     Scene to render (default: the built in world.txt)
+    --width <n>        Width of the image in pixels (default: from the scene, or 400)
+    --height <n>       Height of the image in pixels (default: from the scene, or the camera's aspect ratio)
+    --samples <n>      Samples per pixel (default: 50)
+    --bounces <n>      Max ray bounces (default: 8)
+    --output <file>    Output image, .ppm, .png (16-bit) or .hdr (default: image.ppm)
+    --seed <n>         Seed for the random numbers (default: from the scene, or 0)
+    --help             Print this and exit";
+
+
+struct Arguments {
+    scene:             Option<String>,
+    width:             Option<usize>,
+    height:            Option<usize>,
+    samples_per_pixel: i32,
+    max_ray_bounces:   i32,
+    output:            String,
+    seed:              Option<u64>,
+    /// Whether to print the usage instead of rendering.
+    help:              bool,
+}
+
+fn parse_arguments<I: Iterator<Item=String>>(mut arguments: I) -> Result<Arguments, Box<dyn Error>> {
+    let mut result = Arguments {
+        scene:             None,
+        width:             None,
+        height:            None,
+        samples_per_pixel: 50,
+        max_ray_bounces:   8,
+        output:            String::from("image.ppm"),
+        seed:              None,
+        help:              false,
+    };
+
+    while let Some(argument) = arguments.next() {
+        let mut value = || arguments.next().ok_or_else(|| format!("Missing value for '{}'\n{}", argument, USAGE));
+        let positive = |value: String| -> Result<i32, Box<dyn Error>> {
+            match parser::parse_int(&value) {
+                Ok(("", number)) if number > 0 => Ok(number),
+                _ => Err(format!("Expected a positive number, got '{}'", value).into()),
+            }
+        };
+
+        match argument.as_str() {
+            "--scene"   => result.scene  = Some(value()?),
+            "--width"   => result.width  = Some(positive(value()?)? as usize),
+            "--height"  => result.height = Some(positive(value()?)? as usize),
+            "--samples" => result.samples_per_pixel = positive(value()?)?,
+            "--bounces" => result.max_ray_bounces   = positive(value()?)?,
+            "--output"  => result.output = value()?,
+            "--seed"    => {
+                let value = value()?;
+                match value.parse::<u64>() {
+                    Ok(seed) => result.seed = Some(seed),
+                    Err(_) => return Err(format!("Expected a number, got '{}'", value).into()),
+                }
+            },
+            "--help"    => result.help = true,
+            _ => match argument.split_once('=') {
+                // The syntax from before the flags, still accepted for now.
+                Some((name @ ("samples" | "ray_depth"), number)) => {
+                    let flag = if name == "samples" { "--samples" } else { "--bounces" };
+                    eprintln!("Warning: '{}' is deprecated, use '{} {}' instead.", argument, flag, number);
+                    let number = positive(String::from(number))?;
+                    if name == "samples" { result.samples_per_pixel = number } else { result.max_ray_bounces = number }
+                },
+                _ => return Err(format!("Unknown argument '{}'\n{}", argument, USAGE).into()),
+            },
         }
     }
 
-    Ok((samples_per_pixel, max_ray_bounces))
+    Ok(result)
 }
 
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let (samples_per_pixel, max_ray_bounces) = get_arguments()?;
-    eprintln!("Using:\n* Samples per pixel: {}\n* Max ray depth: {}", samples_per_pixel, max_ray_bounces);
-    let mut options = Options::new(samples_per_pixel, max_ray_bounces, Some(Box::new(stderr())), true);
-
-    let color1 = MaterialType::Diffuse(Color::new(1.0, 0.0, 1.0));
-    let color2 = MaterialType::Emission(Color::new(0.0, 1.0, 1.0));
-    let color3 = MaterialType::Dielectric(1.5);
-
-    let (_camera, spheres, mesh) = parser::parse_world()?;
-    let world = World::new(
-        spheres, vec![mesh]
-        // vec![
-        //     Mesh::new(
-        //         vec![
-        //             // Front face
-        //             Triangle::new(
-        //                 Vec3::new(-0.1, -0.1, -0.5),
-        //                 Vec3::new( 0.1, -0.1, -0.5),
-        //                 Vec3::new(-0.1,  0.1, -0.5),
-        //                 &color1
-        //             ),
-        //             Triangle::new(
-        //                 Vec3::new(-0.1,  0.1, -0.5),
-        //                 Vec3::new( 0.1, -0.1, -0.5),
-        //                 Vec3::new( 0.1,  0.1, -0.5),
-        //                 &color2
-        //             )
-        //         ]
-        //     )
-        // ],
-    );
-
-    // let camera = camera::Camera::new_at(Vec3::new(0.0, 0.0, 0.0), 1.77778);
-    // let camera = camera::Camera::new_with_vertical_fov(Vec3::new_zero(), Radians(std::f32::consts::PI / 2.0), 1.77778);
-    // let camera = camera::Camera::new_look_at(  // TODO(ted): BUG when z values are the same!
-    //     Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.0, 0.0, 1.0), Y_AXIS.into(), Radians(std::f32::consts::PI / 2.0), 1.77778
-    // );
-    let camera = camera::Camera::new_look_at(
-        Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Y_AXIS.into(), Radians(std::f32::consts::PI / 2.0), 1.77778
-    );
-
-    let aspect_ratio = camera.aspect_ratio();
-    let image_width  = 400;
-    let image_height = (image_width as f32 / aspect_ratio) as usize;
-
-    let framebuffer = Framebuffer::new(image_width, image_height);
-    let framebuffer = ray_trace(&world, &camera, framebuffer, &mut options);
+    let arguments = match parse_arguments(std::env::args().skip(1)) {
+        Ok(arguments) => arguments,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(2);
+        },
+    };
+    if arguments.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    eprintln!("Using:\n* Samples per pixel: {}\n* Max ray depth: {}", arguments.samples_per_pixel, arguments.max_ray_bounces);
+    let mut options = Options::new(arguments.samples_per_pixel, arguments.max_ray_bounces, Some(Box::new(stderr())), true);
+
+    let scene = match &arguments.scene {
+        Some(scene) => parser::parse_file(scene)?,
+        None        => parser::parse_world()?,
+    };
+    let resolution = scene.resolution;
+    if let Some(sky) = scene.sky {
+        options.sky = sky;
+    }
+    if let Some(seed) = arguments.seed.or(scene.seed) {
+        options.seed = seed;
+    }
+    if let Some(ambient) = scene.ambient {
+        options.ambient = ambient;
+    }
+    let (camera, world) = scene.into_world();
+
+    let (image_width, image_height) = match (arguments.width, arguments.height, resolution) {
+        (None, None, Some(resolution)) => resolution,
+        (width, height, _) => {
+            let width = width.unwrap_or(400);
+            (width, height.unwrap_or_else(|| ((width as f32 / camera.aspect_ratio()) as usize).max(1)))
+        },
+    };
+
+    let hdr   = arguments.output.ends_with(".hdr");
+    let png16 = arguments.output.ends_with(".png");
+    options.output_linear = hdr || png16;
+
+    let framebuffer = Framebuffer::try_new(image_width, image_height)?;
+    let output = ray_trace_with_output(&world, &camera, framebuffer, &mut options);
 
 
     eprint!(" Done!\nWriting image...");
-    write_image(&framebuffer, Some("image.ppm"))?;
-    eprint!("          Done!\n");
+    match &output.linear {
+        Some(linear) if hdr => write_hdr(linear, image_width, image_height, &arguments.output)?,
+        Some(linear) if png16 => write_png16(&display_colors(linear, image_width, image_height, &options), image_width, image_height, &arguments.output)?,
+        _ => write_image(&output.framebuffer, Some(&arguments.output))?,
+    }
+    eprintln!("          Done!");
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    return Ok(());
+    fn arguments(line: &str) -> Result<Arguments, Box<dyn Error>> {
+        parse_arguments(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_command_line_arguments() {
+        let parsed = arguments("--scene scene.txt --width 64 --height 32 --samples 4 --bounces 2 --output out.hdr").unwrap();
+        assert_eq!(parsed.scene.as_deref(), Some("scene.txt"));
+        assert_eq!((parsed.width, parsed.height), (Some(64), Some(32)));
+        assert_eq!((parsed.samples_per_pixel, parsed.max_ray_bounces), (4, 2));
+        assert_eq!(parsed.output, "out.hdr");
+
+        let defaults = arguments("").unwrap();
+        assert_eq!((defaults.width, defaults.height, defaults.samples_per_pixel), (None, None, 50));
+
+        assert!(arguments("--width").is_err());
+        assert!(arguments("--width -3").is_err());
+        assert!(arguments("--width 6x").is_err());
+        assert_eq!(arguments("--samples 1_000").unwrap().samples_per_pixel, 1000);
+        assert!(arguments("--frobnicate 1").is_err());
+        assert_eq!(arguments("--seed 0").unwrap().seed, Some(0));
+        assert!(arguments("--seed -1").is_err());
+        assert_eq!(arguments("--seed 18446744073709551615").unwrap().seed, Some(u64::MAX));
+        assert!(arguments("--help").unwrap().help);
+    }
+
+    #[test]
+    fn accepts_the_old_arguments() {
+        let parsed = arguments("samples=4 ray_depth=2").unwrap();
+        assert_eq!((parsed.samples_per_pixel, parsed.max_ray_bounces), (4, 2));
+        assert!(arguments("samples=").is_err());
+        assert!(arguments("ray_depth=-1").is_err());
+        assert!(arguments("bounces=2").is_err());
+    }
 }
@@ -3,6 +3,10 @@ use std::fmt;
 use crate::maths::{Vec3, IVector, X_AXIS, Y_AXIS, Z_AXIS};
 use std::fmt::Formatter;
 
+/// Below this determinant, relative to the product of the lengths of the
+/// rows, a matrix counts as singular.
+const SINGULAR_TOLERANCE: f32 = 1e-6;
+
 #[derive(Copy, Clone, Debug)]
 pub struct Mat3 {
     r1: Vec3,
@@ -50,7 +54,7 @@ impl Mat3 {
         Self { r1, r2, r3 }
     }
     pub fn mul_vec3(&self, rhs: &Vec3) -> Vec3 {
-        rhs.clone()
+        Vec3::new(self.r1.dot(rhs), self.r2.dot(rhs), self.r3.dot(rhs))
     }
     #[allow(unused_parens)]
     pub fn cofactor(&self) -> Self {
@@ -76,11 +80,16 @@ impl Mat3 {
     pub fn adjugate(&self) -> Self {
         self.cofactor().transpose()
     }
+    /// None if the matrix is singular, or so close to it that the inverse
+    /// would be mostly rounding errors. The determinant is compared relative
+    /// to the product of the lengths of the rows, which is the largest it can
+    /// be for those lengths (Hadamard's inequality). So it measures how close
+    /// to parallel the rows are, and scaling the rows doesn't matter.
     #[allow(unused_parens)]
     pub fn inverse(&self) -> Option<Self> {
         // Then the inverse of A is the transpose of the cofactor matrix (adjugate) times
         // the reciprocal of the determinant of A, i.e. A^(-1) = (1 / det(A)) * C^T.
-        //     let determinant = self.det();
+        //     let determinant = self.determinant();
         //     if determinant == 0.0 {
         //         None
         //     } else {
@@ -108,14 +117,15 @@ impl Mat3 {
         );
 
         let determinant = self.r1.x*cf_r1x + self.r1.y*cf_r1y + self.r1.z*cf_r1z;
-        if determinant == 0.0 {
+        let scale = self.r1.length() * self.r2.length() * self.r3.length();
+        if determinant.is_nan() || determinant.abs() <= SINGULAR_TOLERANCE * scale {
             None
         } else {
             Some(adjugate.mul_scalar(1.0 / determinant))
         }
     }
 
-    pub fn det(&self) -> f32 {
+    pub fn determinant(&self) -> f32 {
         self.r1.x * (self.r2.y*self.r3.z - self.r3.y*self.r2.z)
        -self.r1.y * (self.r2.x*self.r3.z - self.r3.x*self.r2.z)
        +self.r1.z * (self.r2.x*self.r3.y - self.r3.x*self.r2.y)
@@ -207,7 +217,8 @@ mod tests {
             Vec3::new( 6.0,  -3.0,  3.0),
             Vec3::new( 34.0, -4.0, -2.0),
         );
-        assert!((a.det() - 138.0) < 1e-8, "{} != {}", a.det(), 138);
+        assert!((a.determinant() - 138.0).abs() < 1e-4, "{} != {}", a.determinant(), 138);
+        assert_eq!(Mat3::identity().determinant(), 1.0);
     }
 
     #[test]
@@ -241,4 +252,82 @@ mod tests {
 
         assert!(a.inverse().unwrap().equals(&b), "\n{}\n{}\n", a.inverse().unwrap(), b);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mul_vec3() {
+        let a = Mat3::new(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(-1.0, 0.0, 2.0),
+        );
+        let v = a.mul_vec3(&Vec3::new(1.0, -1.0, 2.0));
+        assert_eq!((v.x, v.y, v.z), (5.0, -1.0, 3.0));
+    }
+
+    #[test]
+    fn inverse_of_identity_is_identity() {
+        assert!(Mat3::identity().inverse().unwrap().equals(&Mat3::identity()));
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let a = Mat3::new(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(2.0, 4.0, 6.0),
+            Vec3::new(0.0, 1.0, 1.0),
+        );
+        assert!(a.inverse().is_none());
+        assert!(Mat3::new(Vec3::new_zero(), Vec3::new_zero(), Vec3::new_zero()).inverse().is_none());
+
+        // Nearly parallel rows.
+        let b = Mat3::new(
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(1.0, 1e-8, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        assert!(b.inverse().is_none());
+
+        // Small but well conditioned.
+        assert!(Mat3::identity().mul_scalar(1e-3).inverse().is_some());
+    }
+
+    #[test]
+    fn inverse_of_non_uniformly_scaled_matrix() {
+        let a = Mat3::new(
+            Vec3::new(1000.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        let b = Mat3::new(
+            Vec3::new(0.001, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        assert!(a.inverse().unwrap().equals(&b), "\n{:?}\n", a.inverse());
+
+        // A scaled rotation about z.
+        let (sin, cos) = 0.5f32.sin_cos();
+        let c = Mat3::new(
+            Vec3::new(1000.0 * cos, -1000.0 * sin, 0.0),
+            Vec3::new(0.01 * sin, 0.01 * cos, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        let product = c.mul(&c.inverse().unwrap());
+        assert!(product.equals(&Mat3::identity()), "\n{}\n", product);
+    }
+
+    #[test]
+    fn inverse_of_random_matrix() {
+        use crate::random::Random;
+
+        let mut random = Random::new();
+        let mut row = || Vec3::new(random.random_bilateral_f32(), random.random_bilateral_f32(), random.random_bilateral_f32()) * 4.0;
+        let a = Mat3::new(row(), row(), row());
+        let product = a.mul(&a.inverse().unwrap());
+
+        let identity = Mat3::identity();
+        for (p, i) in [(product.r1, identity.r1), (product.r2, identity.r2), (product.r3, identity.r3)].iter() {
+            assert!((*p - *i).length() < 1e-4, "\n{}\n{}\n", a, product);
+        }
+    }
+}
@@ -1,14 +1,104 @@
-use crate::common::{HitRecord, Ray, random_unit_sphere};
+use crate::common::{HitRecord, Ray, Sphere, Plane, World, Options, Sky, random_unit_sphere, ray_trace};
+use crate::camera::Camera;
+use crate::image::Framebuffer;
 use crate::random::{Random};
-use crate::maths::{Vec3, NVec3, reflect, refract, IVector};
+use crate::maths::{Vec3, NVec3, reflect, refract, orthonormal_basis, IVector};
 use crate::color::Color;
+use crate::texture::{Texture, SphereMapping, TextureFilter, WrapMode};
 
-#[derive(Debug, Copy, Clone)]
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
 pub enum MaterialType {
     Diffuse(Color),
+    /// Color and fuzz. The fuzz is clamped to [0, 1] when scattering, as
+    /// larger values would scatter rays into the surface.
     Metal(Color, f32),  // TODO: Encode the fuzz in the length of the vector.
+    /// GGX/Trowbridge-Reitz microfacet reflection. `roughness` is in [0, 1],
+    /// where 0 is a perfect mirror.
+    GlossyMetal { albedo: Color, roughness: f32 },
     Dielectric(f32),
-    Emission(Color),
+    /// Emits `color`. A one sided emitter only emits from its front face,
+    /// and absorbs light from behind, see `HitRecord::front_face`.
+    Emission { color: Color, two_sided: bool },
+    /// A diffuse material with the color from a texture, sampled at the
+    /// (u, v) of the hit after adjusting it with `mapping`.
+    Textured { texture: Arc<Texture>, mapping: SphereMapping, filter: TextureFilter, wrap: WrapMode },
+    /// A diffuse material with the color from a texture, like `Textured`,
+    /// that isn't there wherever the alpha of the texture is below
+    /// `threshold`, e.g. for leaves and fences. Rays go through those parts
+    /// as if nothing was hit. The texture covers the whole of a quad, see
+    /// `parse_quad`. PPM images have no alpha, so the texture has to be e.g.
+    /// a PAM image, see `read_image`.
+    Cutout { texture: Arc<Texture>, threshold: f32 },
+    /// A diffuse checkerboard of squares of `scale` by `scale` in texture
+    /// coordinates, e.g. world units on a `Plane`, in the colors `even` and `odd`.
+    Checker { even: Color, odd: Color, scale: f32 },
+    /// Scatters in any direction with the same probability, whatever the
+    /// direction of the ray and the normal, and attenuates by the color.
+    /// It's the phase function of smoke and fog inside a volume.
+    Isotropic(Color),
+    /// A material implemented outside the library, see `Material`.
+    Custom(Arc<dyn Material>),
+}
+
+impl MaterialType {
+    /// Whether the material scatters like a perfectly diffuse (Lambertian)
+    /// surface, i.e. with a density of cos(θ)/π.
+    pub fn is_lambertian(&self) -> bool {
+        matches!(self, MaterialType::Diffuse(_) | MaterialType::Textured { .. } | MaterialType::Cutout { .. } | MaterialType::Checker { .. })
+    }
+
+    /// Whether a hit at the texture coordinates `uv` is cut out, so that
+    /// the ray should go on as if it didn't hit anything.
+    pub fn cuts_out(&self, uv: (f32, f32)) -> bool {
+        match self {
+            MaterialType::Cutout { texture, threshold } => texture.sample(uv.0, uv.1, TextureFilter::Nearest, WrapMode::Repeat).a < *threshold,
+            _ => false,
+        }
+    }
+
+    /// The base color of the material at the texture coordinates `uv`,
+    /// ignoring how it scatters light.
+    pub fn albedo(&self, uv: (f32, f32)) -> Color {
+        match self {
+            MaterialType::Diffuse(color)  => *color,
+            MaterialType::Isotropic(color) => *color,
+            MaterialType::Metal(color, _) => *color,
+            MaterialType::GlossyMetal { albedo, .. } => *albedo,
+            MaterialType::Dielectric(_)   => Color::new(1.0, 1.0, 1.0),
+            MaterialType::Custom(_)       => Color::new(1.0, 1.0, 1.0),
+            MaterialType::Emission { color, .. } => *color,
+            MaterialType::Textured { texture, mapping, filter, wrap } => {
+                let (u, v) = mapping.apply(uv);
+                texture.sample(u, v, *filter, *wrap)
+            },
+            MaterialType::Cutout { texture, .. } => texture.sample(uv.0, uv.1, TextureFilter::Nearest, WrapMode::Repeat),
+            MaterialType::Checker { even, odd, scale } => {
+                let (u, v) = uv;
+                let square = (u / scale).floor() + (v / scale).floor();
+                if square.rem_euclid(2.0) < 1.0 { *even } else { *odd }
+            },
+        }
+    }
+
+    /// A `size` x `size` thumbnail of the material on a sphere, lit by a
+    /// light to the upper left, standing on a gray checker floor in front of
+    /// a gray background. It takes few samples, so it's quick but a bit noisy.
+    pub fn render_preview(&self, size: usize) -> Framebuffer {
+        let light = MaterialType::Emission { color: Color::new(6.0, 6.0, 6.0), two_sided: true };
+        let world = World::new(vec![
+            Sphere::new(Vec3::new(0.0, 0.0, -1.6), 0.6, self.clone()),
+            Sphere::new(Vec3::new(-1.2, 1.2, -0.6), 0.4, light),
+        ], vec![]);
+        let floor = MaterialType::Checker { even: Color::new(0.6, 0.6, 0.6), odd: Color::new(0.3, 0.3, 0.3), scale: 0.3 };
+        let world = world.with_planes(vec![Plane::new(Vec3::new(0.0, -0.6, 0.0), NVec3::new(0.0, 1.0, 0.0), floor)]);
+        let camera = Camera::new(1.0);
+
+        let mut options = Options::new(16, 6, None, true);
+        options.sky = Sky::Color(Color::new(0.18, 0.18, 0.18));
+        ray_trace(&world, &camera, Framebuffer::new(size, size), &mut options)
+    }
 }
 
 pub struct ScatterData {
@@ -16,7 +106,15 @@ pub struct ScatterData {
     pub next_ray: Option<Ray>,
 }
 
-pub trait Material {
+/// How a material scatters light. Implement it to use your own material with
+/// `MaterialType::Custom`, e.g.
+/// `Sphere::new(center, radius, MaterialType::Custom(Arc::new(MyMaterial)))`.
+/// The world is shared between the render threads, so it has to be `Send`
+/// and `Sync`. Custom materials can't be used from scene files.
+pub trait Material: std::fmt::Debug + Send + Sync {
+    /// Returns the color to multiply the path with (the BRDF times the cosine
+    /// over the pdf), and the ray to continue along, or None to end the path.
+    /// `hit.normal` points against `ray`.
     fn scatter(&self, ray: &Ray, hit: &HitRecord, random: &mut Random) -> ScatterData;
 }
 
@@ -33,8 +131,12 @@ impl Material for MaterialType {
         match self {
             MaterialType::Diffuse(color)     => diffuse_scatter(*color, ray, hit, random),
             MaterialType::Metal(color, fuzz) => metal_scatter(*color, *fuzz, ray, hit, random),
+            MaterialType::GlossyMetal { albedo, roughness } => glossy_metal_scatter(*albedo, *roughness, ray, hit, random),
             MaterialType::Dielectric(ir)     => dielectric_scatter(*ir, ray, hit, random),
-            MaterialType::Emission(color)    => emission_scatter(*color, ray, hit, random),
+            MaterialType::Isotropic(color)   => isotropic_scatter(*color, ray, hit, random),
+            MaterialType::Emission { color, two_sided } => emission_scatter(*color, *two_sided, ray, hit, random),
+            MaterialType::Textured { .. } | MaterialType::Cutout { .. } | MaterialType::Checker { .. } => diffuse_scatter(self.albedo(hit.uv), ray, hit, random),
+            MaterialType::Custom(material)   => material.scatter(ray, hit, random),
         }
     }
 }
@@ -53,51 +155,323 @@ fn diffuse_scatter(color: Color, _ray: &Ray, hit: &HitRecord, random: &mut Rando
 
 fn metal_scatter(color: Color, fuzz: f32, ray: &Ray, hit: &HitRecord, random: &mut Random) -> ScatterData {
     let reflected = reflect(ray.direction.into(), hit.normal);
-    let direction = reflected + fuzz*random_unit_sphere(random);
+    let direction = reflected + fuzz.clamp(0.0, 1.0)*random_unit_sphere(random);
+
+    // The fuzz can cancel out the reflection, leaving no direction to scatter in.
+    match direction.try_normalize() {
+        Some(direction) if hit_front_face(&direction.into(), &hit.normal) =>
+            ScatterData { color, next_ray: Some(Ray::new(hit.position, direction)) },
+        _ => ScatterData { color, next_ray: None },
+    }
+}
 
-    if hit_front_face(&direction, &hit.normal) {
-        ScatterData { color, next_ray: Some(Ray::new(hit.position, direction.normalize())) }
+/// Samples a microfacet normal from the GGX distribution and reflects about it.
+/// https://www.cs.cornell.edu/~srm/publications/EGSR07-btdf.pdf
+fn glossy_metal_scatter(albedo: Color, roughness: f32, ray: &Ray, hit: &HitRecord, random: &mut Random) -> ScatterData {
+    let view   = -ray.direction;
+    let normal = if view.dot(&hit.normal) < 0.0 { -hit.normal } else { hit.normal };
+    let alpha  = roughness.clamp(0.0, 1.0).powi(2);
+
+    // Sample the microfacet normal proportional to D(h) * (n • h).
+    let microfacet = if alpha == 0.0 {
+        normal
     } else {
-        ScatterData { color, next_ray: None }
+        let xi  = random.random_f32();
+        let phi = 2.0 * std::f32::consts::PI * random.random_f32();
+        let cos_theta = f32::sqrt((1.0 - xi) / (1.0 + (alpha*alpha - 1.0) * xi));
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta*cos_theta));
+
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        (sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * normal).normalize()
+    };
+
+    let direction = reflect(ray.direction.into(), microfacet);
+    let n_dot_l = direction.dot(&normal);
+    let n_dot_v = view.dot(&normal).max(1e-6);
+    let n_dot_h = microfacet.dot(&normal).max(1e-6);
+    let v_dot_h = view.dot(&microfacet).max(0.0);
+
+    // Reflected into the surface, so the light is absorbed.
+    if n_dot_l <= 0.0 {
+        return ScatterData { color: Color::new_with_alpha(0.0, 0.0, 0.0, albedo.a), next_ray: None };
+    }
+
+    // Smith masking-shadowing, divided by the pdf of the sampled direction.
+    let g1 = |n_dot_x: f32| 2.0 * n_dot_x / (n_dot_x + f32::sqrt(alpha*alpha + (1.0 - alpha*alpha) * n_dot_x*n_dot_x));
+    let weight = g1(n_dot_v) * g1(n_dot_l) * v_dot_h / (n_dot_v * n_dot_h);
+
+    ScatterData {
+        color: Color::new(albedo.r * weight, albedo.g * weight, albedo.b * weight),
+        next_ray: Some(Ray::new(hit.position, direction.normalize())),
     }
 }
 
-fn dielectric_scatter(ir: f32, ray: &Ray, hit: &HitRecord, _random: &mut Random) -> ScatterData {
-    let (normal, refraction_ratio) =
-        if hit_front_face(&ray.direction.into(), &hit.normal) {
-            (-hit.normal, 1.0/ir)  // Ray is inside the object.
+fn dielectric_scatter(ir: f32, ray: &Ray, hit: &HitRecord, random: &mut Random) -> ScatterData {
+    // The normal always points against the ray, so only the ratio depends on the side.
+    let normal = hit.normal;
+    let refraction_ratio =
+        if hit.front_face {
+            1.0/ir  // Ray enters the object.
         } else {
-            (hit.normal, ir)       // Ray is outside the object.
+            ir      // Ray leaves the object.
         };
 
+    // Schlick's approximation of the reflectance.
+    fn reflectance(cos_theta: f32, refraction_ratio: f32) -> f32 {
+        let r0 = (1.0-refraction_ratio) / (1.0+refraction_ratio);
+        let r0 = r0*r0;
+        r0 + (1.0-r0) * f32::powi(1.0 - cos_theta, 5)
+    }
+    let cos_theta = NVec3::dot(&-ray.direction, &normal).min(1.0);
+    let sin_theta = f32::sqrt(1.0 - cos_theta*cos_theta);
+
+    // Past the critical angle all of the light is reflected (total internal
+    // reflection). The path keeps going either way, clear glass absorbs nothing.
+    let cannot_refract = refraction_ratio * sin_theta > 1.0;
+    let direction =
+        if cannot_refract || reflectance(cos_theta, refraction_ratio) > random.random_f32() {
+            reflect(ray.direction.into(), normal)
+        } else {
+            refract(ray.direction, normal, refraction_ratio)
+        };
 
-    // fn reflectance(cos_theta: f32, refraction_ratio: f32) -> f32 {
-    //     // Use Schlick's approximation for reflectance.
-    //     let r0 = (1.0-refraction_ratio) / (1.0+refraction_ratio);
-    //     let r0 = r0*r0;
-    //     r0 + (1.0-r0) * f32::powi(1.0 - cos_theta, 5)
-    // }
-    // let cos_theta = NVec3::dot(&-ray.direction, &hit.normal);
-    // let sin_theta = f32::sqrt(1.0 - cos_theta*cos_theta);
-    //
-    // let cannot_refract = refraction_ratio * sin_theta < 1.0;
-    // let direction =
-    //     if cannot_refract || reflectance(cos_theta, refraction_ratio) > random.random_f32() {
-    //         reflect(ray.direction.into(), hit.normal).normalize()
-    //     } else {
-    //         refract(ray.direction, hit.normal, refraction_ratio).normalize()
-    //     };
-    //
-    // let scattered = Ray::new(hit.position, direction);
-    // ScatterData { color: Vec3::new(1.0, 1.0, 1.0), next_ray: Some(scattered) }
-
-    let refracted = refract(ray.direction, normal, refraction_ratio);
-    let scattered = Ray::new(hit.position, refracted.normalize());
+    let scattered = Ray::new(hit.position, direction.normalize());
     ScatterData { color: Color::new(1.0, 1.0, 1.0), next_ray: Some(scattered) }
 }
 
 
-fn emission_scatter(color: Color, _ray: &Ray, _hit: &HitRecord, _random: &mut Random) -> ScatterData {
-    ScatterData { color, next_ray: None }
+fn isotropic_scatter(color: Color, _ray: &Ray, hit: &HitRecord, random: &mut Random) -> ScatterData {
+    ScatterData { color, next_ray: Some(Ray::new(hit.position, random_unit_sphere(random))) }
+}
+
+fn emission_scatter(color: Color, two_sided: bool, _ray: &Ray, hit: &HitRecord, _random: &mut Random) -> ScatterData {
+    if two_sided || hit.front_face {
+        ScatterData { color, next_ray: None }
+    } else {
+        ScatterData { color: Color::new_with_alpha(0.0, 0.0, 0.0, color.a), next_ray: None }
+    }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit_record(material: &MaterialType) -> HitRecord<'_> {
+        HitRecord { position: Vec3::new_zero(), normal: NVec3::new(0.0, 1.0, 0.0), front_face: true, t: 1.0, material, id: 0, uv: (0.0, 0.0), barycentric: None, vertex_color: None }
+    }
+
+    #[test]
+    fn glossy_metal_without_roughness_is_a_mirror() {
+        let material = MaterialType::GlossyMetal { albedo: Color::new(0.9, 0.8, 0.7), roughness: 0.0 };
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 0.0), NVec3::new(1.0, -1.0, 0.0));
+        let mut random = Random::new();
+
+        let ScatterData { color, next_ray } = material.scatter(&ray, &hit_record(&material), &mut random);
+        let direction = next_ray.unwrap().direction;
+
+        assert!((Vec3::from(direction) - Vec3::from(NVec3::new(1.0, 1.0, 0.0))).length() < 1e-5);
+        assert!((color.r - 0.9).abs() < 1e-5 && (color.g - 0.8).abs() < 1e-5 && (color.b - 0.7).abs() < 1e-5);
+    }
+
+    #[test]
+    fn glossy_metal_reflects_above_the_surface() {
+        let material = MaterialType::GlossyMetal { albedo: Color::new(1.0, 1.0, 1.0), roughness: 0.6 };
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 0.0), NVec3::new(1.0, -1.0, 0.0));
+        let mut random = Random::new();
+
+        let mut directions = Vec::new();
+        for _ in 0..100 {
+            let ScatterData { color, next_ray } = material.scatter(&ray, &hit_record(&material), &mut random);
+            assert!(color.r.is_finite() && color.r >= 0.0);
+            if let Some(next_ray) = next_ray {
+                assert!(next_ray.direction.y() > 0.0);
+                directions.push(next_ray.direction);
+            }
+        }
+        assert!(directions.iter().any(|d| (Vec3::from(*d) - Vec3::from(directions[0])).length() > 0.1));
+    }
+
+    #[test]
+    fn rough_grazing_reflections_dont_add_energy() {
+        let material = MaterialType::GlossyMetal { albedo: Color::new(1.0, 1.0, 1.0), roughness: 1.0 };
+        let ray = Ray::new(Vec3::new(-1.0, 0.05, 0.0), NVec3::new(1.0, -0.05, 0.0));
+        let mut random = Random::new();
+
+        let samples = 4096;
+        let mut total = 0.0;
+        let mut absorbed = 0;
+        for _ in 0..samples {
+            let ScatterData { color, next_ray } = material.scatter(&ray, &hit_record(&material), &mut random);
+            if next_ray.is_none() {
+                // Would be added as emission by `ray_color`.
+                assert_eq!((color.r, color.g, color.b), (0.0, 0.0, 0.0));
+                absorbed += 1;
+            }
+            total += color.r;
+        }
+        assert!(absorbed > 0);
+        assert!(total / samples as f32 <= 1.0, "{} is more than the albedo", total / samples as f32);
+    }
+
+    #[test]
+    fn textured_spheres_use_the_wrap_mode() {
+        let mut image = Framebuffer::new(4, 1);
+        for column in 0..4 {
+            image[[0, column]] = crate::color::ColorU8 { r: (column * 85) as u8, g: 0, b: 0, a: 255 };
+        }
+        let texture = Arc::new(Texture::new(image));
+        // The front of the sphere is at a u of 0.25, moved past the seam to 1.25.
+        let mapping = SphereMapping { u_offset: 1.0, rotation: 0.0, flip_v: false };
+        let column_at = |wrap: WrapMode| {
+            let material = MaterialType::Textured { texture: texture.clone(), mapping, filter: TextureFilter::Nearest, wrap };
+            let world = World::new(vec![Sphere::new(Vec3::new_zero(), 1.0, material)], Vec::new());
+            let hit = world.hit(&Ray::new(Vec3::new(0.0, 0.0, 5.0), NVec3::new(0.0, 0.0, -1.0))).unwrap();
+            let r = hit.material.albedo(hit.uv).r;
+            (0..4).find(|&column| ((column as f32 * 85.0 / 255.0).powi(2) - r).abs() < 1e-6).unwrap()
+        };
+
+        assert_eq!(column_at(WrapMode::Repeat), 1);
+        assert_eq!(column_at(WrapMode::Clamp),  3);
+        assert_eq!(column_at(WrapMode::Mirror), 2);
+    }
+
+    #[test]
+    fn metal_fuzz_above_one_is_clamped() {
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), NVec3::new(0.0, -1.0, 0.0));
+        let seed = std::num::NonZeroU32::new(7).unwrap();
+        let (mut clamped, mut fuzzy) = (Random::new_with_seed(seed), Random::new_with_seed(seed));
+        let (one, large) = (MaterialType::Metal(Color::new(1.0, 1.0, 1.0), 1.0), MaterialType::Metal(Color::new(1.0, 1.0, 1.0), 25.0));
+
+        for _ in 0..256 {
+            let expected = one.scatter(&ray, &hit_record(&one), &mut clamped).next_ray;
+            let scattered = large.scatter(&ray, &hit_record(&large), &mut fuzzy).next_ray;
+            match (expected, scattered) {
+                (Some(expected), Some(scattered)) => {
+                    // Straight down, so a fuzz of at most 1 never scatters below the surface.
+                    assert!(scattered.direction.y() >= 0.0);
+                    assert!((Vec3::from(scattered.direction) - Vec3::from(expected.direction)).length() < 1e-6);
+                },
+                (None, None) => {},
+                _ => panic!("A fuzz of 25 scattered differently from a fuzz of 1"),
+            }
+        }
+    }
+
+    #[test]
+    fn one_sided_emission_is_black_from_behind() {
+        let material = MaterialType::Emission { color: Color::new(2.0, 2.0, 2.0), two_sided: false };
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), NVec3::new(0.0, -1.0, 0.0));
+        let mut random = Random::new();
+
+        let front = material.scatter(&ray, &hit_record(&material), &mut random);
+        assert_eq!((front.color.r, front.color.g, front.color.b), (2.0, 2.0, 2.0));
+
+        let back = HitRecord { front_face: false, ..hit_record(&material) };
+        let ScatterData { color, next_ray } = material.scatter(&ray, &back, &mut random);
+        assert_eq!((color.r, color.g, color.b), (0.0, 0.0, 0.0));
+        assert!(next_ray.is_none());
+
+        let two_sided = MaterialType::Emission { color: Color::new(2.0, 2.0, 2.0), two_sided: true };
+        assert_eq!(two_sided.scatter(&ray, &HitRecord { front_face: false, ..hit_record(&two_sided) }, &mut random).color.r, 2.0);
+    }
+
+    /// Shows what it's hit with a flat color.
+    #[derive(Debug)]
+    struct ConstantColor(Color);
+    impl Material for ConstantColor {
+        fn scatter(&self, _ray: &Ray, _hit: &HitRecord, _random: &mut Random) -> ScatterData {
+            ScatterData { color: self.0, next_ray: None }
+        }
+    }
+
+    #[test]
+    fn custom_materials_scatter_with_their_own_implementation() {
+        let material = MaterialType::Custom(Arc::new(ConstantColor(Color::new(0.1, 0.2, 0.3))));
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), NVec3::new(0.0, -1.0, 0.0));
+        let mut random = Random::new();
+
+        let ScatterData { color, next_ray } = material.scatter(&ray, &hit_record(&material), &mut random);
+        assert_eq!((color.r, color.g, color.b), (0.1, 0.2, 0.3));
+        assert!(next_ray.is_none());
+        assert!(!material.is_lambertian());
+    }
+
+    #[test]
+    fn isotropic_scatters_uniformly_over_the_sphere() {
+        let material = MaterialType::Isotropic(Color::new(0.5, 0.5, 0.5));
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), NVec3::new(0.0, -1.0, 0.0));
+        let hit = hit_record(&material);
+        let mut random = Random::new();
+
+        // Each octant, and both sides of the normal, get about the same share.
+        let samples = 80_000;
+        let mut octants = [0; 8];
+        let mut sum = Vec3::new_zero();
+        for _ in 0..samples {
+            let ScatterData { color, next_ray } = material.scatter(&ray, &hit, &mut random);
+            assert_eq!(color.r, 0.5);
+            let direction = Vec3::from(next_ray.unwrap().direction);
+            let octant = (direction.x > 0.0) as usize | ((direction.y > 0.0) as usize) << 1 | ((direction.z > 0.0) as usize) << 2;
+            octants[octant] += 1;
+            sum += direction;
+        }
+
+        for count in octants.iter() {
+            let share = *count as f32 / samples as f32;
+            assert!((share - 0.125).abs() < 0.01, "Share of an octant is {}", share);
+        }
+        assert!((sum / samples as f32).length() < 0.02);
+    }
+
+    #[test]
+    fn checker_alternates_between_squares() {
+        let material = MaterialType::Checker { even: Color::new(1.0, 1.0, 1.0), odd: Color::new(0.0, 0.0, 0.0), scale: 2.0 };
+        let red = |uv| material.albedo(uv).r;
+        assert_eq!([red((0.5, 0.5)), red((2.5, 0.5)), red((2.5, 2.5)), red((-0.5, 0.5)), red((-2.5, 0.5))], [1.0, 0.0, 1.0, 0.0, 1.0]);
+        assert!(material.is_lambertian());
+    }
+
+    #[test]
+    fn dielectric_reflects_past_the_critical_angle() {
+        // Leaving glass at a grazing angle, far past the critical angle of about 42°.
+        let material = MaterialType::Dielectric(1.5);
+        let hit = HitRecord { front_face: false, ..hit_record(&material) };
+        let ray = Ray::new(Vec3::new(-1.0, 0.2, 0.0), NVec3::new(1.0, -0.2, 0.0));
+
+        let mut random = Random::new();
+        for _ in 0..100 {
+            let ScatterData { color, next_ray } = material.scatter(&ray, &hit, &mut random);
+            let direction = next_ray.unwrap().direction;
+            assert_eq!((color.r, color.g, color.b), (1.0, 1.0, 1.0));
+            assert!((direction.x() - ray.direction.x()).abs() < 1e-5 && direction.y() > 0.0);
+        }
+    }
+
+    #[test]
+    fn glass_sphere_has_no_black_core() {
+        let world = World::new(vec![Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, MaterialType::Dielectric(1.5))], vec![]);
+        let mut options = Options::new(16, 8, None, true);
+        let image = ray_trace(&world, &Camera::new(1.0), Framebuffer::new(9, 9), &mut options);
+
+        let center = image[[4, 4]];
+        assert!(center.r > 64 && center.g > 64 && center.b > 64, "{:?}", (center.r, center.g, center.b));
+    }
+
+    #[test]
+    fn previews_differ_between_materials() {
+        let red   = MaterialType::Diffuse(Color::new(0.8, 0.1, 0.1)).render_preview(16);
+        let metal = MaterialType::Metal(Color::new(0.9, 0.9, 0.9), 0.0).render_preview(16);
+        assert_eq!((red.width, red.height), (16, 16));
+
+        let difference: u32 = red.pixels.iter().zip(metal.pixels.iter())
+            .map(|(a, b)| (a.r as i32 - b.r as i32).unsigned_abs() + (a.g as i32 - b.g as i32).unsigned_abs())
+            .sum();
+        assert!(difference > 16 * 16 * 8, "{}", difference);
+
+        // The red sphere is in the middle.
+        let center = red[[8, 8]];
+        assert!(center.r > center.g && center.r > center.b);
+    }
+}
@@ -5,7 +5,7 @@ use std::convert::From;
 // https://www.youtube.com/watch?v=ReTetN51r7A
 
 
-/// A • B = |A| * |B| * cos x
+// A • B = |A| * |B| * cos x
 // pub fn dot() {
 //
 // }
@@ -36,6 +36,19 @@ pub fn refract(uv: NVec3, n: NVec3, etai_over_etat: f32) -> Vec3 {
 }
 
 
+/// Two vectors that together with `n` form an orthonormal basis.
+/// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+pub fn orthonormal_basis(n: NVec3) -> (NVec3, NVec3) {
+    let sign = 1.0f32.copysign(n.z());
+    let a = -1.0 / (sign + n.z());
+    let b = n.x() * n.y() * a;
+    (
+        NVec3::new_unchecked(1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()),
+        NVec3::new_unchecked(b, sign + n.y() * n.y() * a, -n.y()),
+    )
+}
+
+
 pub trait IVector : Sized + Copy {
     fn x(&self) -> f32;   fn r(&self) -> f32 { self.x() }
     fn y(&self) -> f32;   fn g(&self) -> f32 { self.y() }
@@ -79,6 +92,9 @@ impl Vec3 {
 
     pub fn normalize(&self) -> NVec3 { NVec3::new(self.x(), self.y(), self.z()) }
 
+    /// Normalizes the vector, or returns `None` if it's too short to have a direction.
+    pub fn try_normalize(&self) -> Option<NVec3> { NVec3::try_new(self.x(), self.y(), self.z()) }
+
     pub fn dot(&self, rhs: &impl IVector) -> f32 { self.x()*rhs.x() + self.y()*rhs.y() + self.z()*rhs.z() }
 
     pub fn length_squared(&self) -> f32 { self.dot(self) }
@@ -120,6 +136,16 @@ impl IVector for NVec3 {
 }
 
 impl NVec3 {
+    /// Like `new`, but returns `None` instead of NaN components when
+    /// the input is (near) zero and thus has no direction.
+    pub fn try_new(x: f32, y: f32, z: f32) -> Option<Self> {
+        if Vec3::new(x, y, z).near_zero() {
+            None
+        } else {
+            Some(Self::new(x, y, z))
+        }
+    }
+
     pub fn normalize(&self) -> NVec3 { NVec3::new(self.x(), self.y(), self.z()) }
 
     pub fn dot(&self, rhs: &impl IVector) -> f32 { self.x()*rhs.x() + self.y()*rhs.y() + self.z()*rhs.z() }
@@ -271,11 +297,51 @@ mod tests {
     #[test]
     fn test_cross() {
         vec3_equal(
-            cross(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)),
+            Vec3::new(1.0, 0.0, 0.0).cross(&Vec3::new(0.0, 1.0, 0.0)),
             Vec3::new(0.0, 0.0, 1.0)
         );
     }
 
+    #[test]
+    fn test_near_zero() {
+        assert!(Vec3::new_zero().near_zero());
+        assert!(Vec3::new(1e-9, -1e-9, 0.0).near_zero());
+        assert!(!Vec3::new(1e-9, 1e-7, 0.0).near_zero());
+        assert!(!Vec3::new(0.0, 0.0, -1.0).near_zero());
+    }
+
+    #[test]
+    fn test_try_new_zero() {
+        assert!(NVec3::try_new(0.0, 0.0, 0.0).is_none());
+        assert!(Vec3::new_zero().try_normalize().is_none());
+    }
+
+    #[test]
+    fn test_try_new_tiny() {
+        assert!(NVec3::try_new(1e-9, -1e-9, 1e-10).is_none());
+        assert!(NVec3::try_new(1e-20, 0.0, 0.0).is_none());
+
+        let n = NVec3::try_new(1e-6, 0.0, 0.0).unwrap();
+        vec3_equal(n.into(), Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_try_new() {
+        let n = NVec3::try_new(0.0, 3.0, 4.0).unwrap();
+        vec3_equal(n.into(), Vec3::new(0.0, 0.6, 0.8));
+        assert!(n.x().is_finite() && n.y().is_finite() && n.z().is_finite());
+    }
+
+    #[test]
+    fn test_orthonormal_basis() {
+        for n in &[NVec3::new(0.0, 0.0, 1.0), NVec3::new(0.0, 0.0, -1.0), NVec3::new(1.0, 2.0, -3.0)] {
+            let (t, b) = orthonormal_basis(*n);
+            assert!(t.dot(n).abs() < 1e-6 && b.dot(n).abs() < 1e-6 && t.dot(&b).abs() < 1e-6);
+            assert!((Vec3::from(t).length() - 1.0).abs() < 1e-6);
+            assert!((Vec3::from(b).length() - 1.0).abs() < 1e-6);
+        }
+    }
+
     #[test]
     fn test_refract() {
         let a = NVec3::new(1.0, 0.0, -1.0);
@@ -0,0 +1,181 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::common::{Triangle, Mesh};
+use crate::materials::MaterialType;
+use crate::maths::{Vec3, IVector};
+
+
+#[derive(Debug, Clone)]
+pub enum ObjError {
+    CouldntOpenFile,
+    /// A line that couldn't be parsed, counting from 1.
+    Malformed { line: usize, reason: &'static str },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjError::CouldntOpenFile => write!(f, "Couldn't open file"),
+            ObjError::Malformed { line, reason } => write!(f, "Line {}: {}", line, reason),
+        }
+    }
+}
+impl std::error::Error for ObjError {}
+
+
+type Result<T> = std::result::Result<T, ObjError>;
+
+
+/// Parses the geometry of a Wavefront OBJ file, giving every triangle
+/// `material`. Each `o` or `g` group becomes its own mesh, named after the
+/// group. Faces with more than three vertices are split into a fan of
+/// triangles, so they should be convex. Only the vertex index of a `v/vt/vn`
+/// triple is used, and everything but vertices, faces and groups is ignored.
+pub fn parse_obj(source: &str, material: &MaterialType) -> Result<Vec<Mesh>> {
+    let mut vertices: Vec<Vec3> = Vec::new();
+    let mut meshes = Vec::new();
+    let mut triangles: Vec<Triangle> = Vec::new();
+    let mut group: Option<String> = None;
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |reason| ObjError::Malformed { line: line_number, reason };
+
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("v") => {
+                let mut coordinate = || -> Result<f32> {
+                    tokens.next()
+                        .and_then(|token| token.parse::<f32>().ok())
+                        .ok_or_else(|| error("Expected three coordinates for the vertex"))
+                };
+                vertices.push(Vec3::new(coordinate()?, coordinate()?, coordinate()?));
+            },
+            Some("f") => {
+                let corners = tokens
+                    .map(|token| vertex_index(token, vertices.len()).map(|i| vertices[i]))
+                    .collect::<Option<Vec<Vec3>>>()
+                    .ok_or_else(|| error("Face refers to a vertex that doesn't exist"))?;
+                if corners.len() < 3 {
+                    return Err(error("Face has fewer than three vertices"));
+                }
+
+                for i in 1..corners.len() - 1 {
+                    let triangle = Triangle::new(corners[0], corners[i], corners[i + 1], material.clone());
+                    triangles.push(match &group {
+                        Some(name) => triangle.with_name(name),
+                        None => triangle,
+                    });
+                }
+            },
+            Some("o") | Some("g") => {
+                if !triangles.is_empty() {
+                    meshes.push(Mesh::new(std::mem::take(&mut triangles)));
+                }
+                group = tokens.next().map(String::from);
+            },
+            _ => (),
+        }
+    }
+
+    if !triangles.is_empty() {
+        meshes.push(Mesh::new(triangles));
+    }
+    Ok(meshes)
+}
+
+/// Reads and parses the OBJ file at `path`, see `parse_obj`.
+pub fn parse_obj_file<P: AsRef<Path>>(path: P, material: &MaterialType) -> Result<Vec<Mesh>> {
+    let source = std::fs::read_to_string(path).map_err(|_| ObjError::CouldntOpenFile)?;
+    parse_obj(&source, material)
+}
+
+/// The 0-based index of the vertex in a `v`, `v/vt`, `v//vn` or `v/vt/vn`
+/// face element, given the number of vertices so far. OBJ indices start at
+/// 1, and negative ones count back from the last vertex.
+fn vertex_index(element: &str, vertex_count: usize) -> Option<usize> {
+    let index = element.split('/').next()?.parse::<i64>().ok()?;
+    let index =
+        if index > 0 {
+            index - 1
+        } else {
+            vertex_count as i64 + index
+        };
+
+    if 0 <= index && index < vertex_count as i64 { Some(index as usize) } else { None }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+
+    const MATERIAL: MaterialType = MaterialType::Diffuse(Color { r: 0.5, g: 0.5, b: 0.5, a: 1.0 });
+
+    #[test]
+    fn polygons_are_split_into_triangles() {
+        let source = "\
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v -0.5 0.5 0
+f 1 2 3 4
+f 1/1/1 2/2/1 3/3/1
+f 1//1 -2//1 -1//1
+f 1 2 3 4 5
+";
+        let meshes = parse_obj(source, &MATERIAL).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].triangles().len(), 2 + 1 + 1 + 3);
+
+        let [v0, v1, v2] = meshes[0].triangles()[1].vertices();
+        assert_eq!((v0.x, v0.y, v1.x, v1.y, v2.x, v2.y), (0.0, 0.0, 1.0, 1.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn groups_become_meshes() {
+        let source = "\
+# Two groups and some things that are ignored.
+mtllib scene.mtl
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vn 0 0 1
+o first
+usemtl red
+f 1 2 3
+g second
+s off
+f 3 2 1
+f 1 2 3
+g empty
+";
+        let meshes = parse_obj(source, &MATERIAL).unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].triangles().len(), 1);
+        assert_eq!(meshes[1].triangles().len(), 2);
+        assert_eq!(meshes[1].triangles()[0].name(), Some("second"));
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        let line = |source: &str| match parse_obj(source, &MATERIAL) {
+            Err(ObjError::Malformed { line, .. }) => line,
+            _ => panic!("Expected an error for {:?}", source),
+        };
+
+        assert_eq!(line("v 0 0 0\nv 1 0 0\nf 1 2\n"), 3);
+        assert_eq!(line("v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n"), 5);
+        assert_eq!(line("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n"), 4);
+        assert_eq!(line("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 x\n"), 4);
+        assert_eq!(line("v 0 zero 0\n"), 1);
+
+        let error = parse_obj("v 0 0\n", &MATERIAL).err().unwrap();
+        assert_eq!(error.to_string(), "Line 1: Expected three coordinates for the vertex");
+    }
+}
@@ -1,10 +1,18 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use crate::materials::MaterialType;
-use crate::common::{Sphere, Triangle, Mesh};
-use crate::camera::Camera;
-use crate::maths::Vec3;
+use crate::color::Color;
+use crate::common::{Sphere, Triangle, Mesh, Plane, Sky, Visibility};
+use crate::camera::{Camera, Projection};
+use crate::scene::Scene;
+use crate::maths::{Vec3, NVec3, IVector};
+use crate::mat3::Mat3;
+use crate::texture::{Texture, SphereMapping, TextureFilter, WrapMode};
+
+use std::sync::Arc;
 
 
 #[derive(Debug, Clone)]
@@ -14,7 +22,20 @@ pub enum ParseError {
     WrongSyntax,
     DidntStartWith,
     NotAI32,
-    NotAF32
+    NotAF32,
+    /// A file includes itself, directly or through other files.
+    IncludeCycle,
+    IncludeTooDeep,
+    /// A `repeat` block has more copies than `MAX_REPEAT_COUNT`, or the
+    /// repeats of a scene copy more than `MAX_REPEATED_PRIMITIVES` in total.
+    RepeatTooLarge,
+    /// A required field of a primitive, e.g. a sphere's `radius`, is missing.
+    MissingField(&'static str),
+    /// A field of a primitive is given more than once.
+    DuplicateField(&'static str),
+    /// An identifier was expected, but the source continued with this
+    /// character instead, or ended if None.
+    ExpectedIdentifier(Option<char>),
 }
 
 impl fmt::Display for ParseError {
@@ -23,6 +44,13 @@ impl fmt::Display for ParseError {
             ParseError::CouldntOpenFile => write!(f, "Couldn't open file"),
             ParseError::MissingCamera => write!(f, "Missing camera"),
             ParseError::WrongSyntax   => write!(f, "Wrong syntax"),
+            ParseError::IncludeCycle  => write!(f, "File includes itself"),
+            ParseError::IncludeTooDeep => write!(f, "Includes nested deeper than {}", MAX_INCLUDE_DEPTH),
+            ParseError::RepeatTooLarge => write!(f, "Repeats more than {} times, or more than {} primitives in total", MAX_REPEAT_COUNT, MAX_REPEATED_PRIMITIVES),
+            ParseError::MissingField(field)   => write!(f, "Missing '{}'", field),
+            ParseError::DuplicateField(field) => write!(f, "'{}' is given more than once", field),
+            ParseError::ExpectedIdentifier(Some(c)) => write!(f, "Expected an identifier, found '{}'", c),
+            ParseError::ExpectedIdentifier(None)    => write!(f, "Expected an identifier, found the end of the input"),
             _ => write!(f, "Error."),
         }
     }
@@ -44,11 +72,50 @@ impl std::error::Error for ParseError {}
 
 type Result<T> = std::result::Result<T, ParseError>;
 
-pub fn parse_world() -> Result<(Camera, Vec<Sphere>, Mesh)>  {
-    parse_input(
-        &std::fs::read_to_string("/Users/tedkleinbergman/Programming/rust_raytracer/src/world.txt")
-            .map_err(|_| ParseError::CouldntOpenFile)?
-    )
+
+/// How many files deep includes can be nested.
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// How many copies a `repeat` block can make.
+pub const MAX_REPEAT_COUNT: usize = 10_000;
+
+/// How many primitives the `repeat` blocks of a scene can copy together,
+/// including the included files, so nested repeats can't multiply past it.
+pub const MAX_REPEATED_PRIMITIVES: usize = 1_000_000;
+
+/// Used in place of unknown materials when not parsing strictly.
+pub const DEFAULT_MATERIAL: MaterialType = MaterialType::Diffuse(Color { r: 1.0, g: 0.0, b: 1.0, a: 1.0 });
+
+pub struct ParseOptions {
+    /// If true, referencing an undefined material is an error. Otherwise
+    /// a warning is logged and `DEFAULT_MATERIAL` is used instead.
+    pub strict_materials: bool,
+    pub logger: Option<Box<dyn Write>>,
+}
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            strict_materials: true,
+            logger: None,
+        }
+    }
+}
+
+fn lookup_material(name: &str, materials: &HashMap<String, MaterialType>, options: &mut ParseOptions) -> Result<MaterialType> {
+    if let Some(material) = materials.get(name) {
+        Ok(material.clone())
+    } else if options.strict_materials {
+        Err(ParseError::WrongSyntax)
+    } else {
+        if let Some(logger) = &mut options.logger {
+            writeln!(logger, "Warning: Unknown material '{}', using the default material.", name).unwrap();
+        }
+        Ok(DEFAULT_MATERIAL)
+    }
+}
+
+pub fn parse_world() -> Result<Scene>  {
+    parse_file("/Users/tedkleinbergman/Programming/rust_raytracer/src/world.txt")
 }
 
 pub fn skip_whitespace(source: &str) -> &str {
@@ -56,9 +123,15 @@ pub fn skip_whitespace(source: &str) -> &str {
     &source[index..]
 }
 
-pub fn get_identifier(source: &str) -> (&str, &str) {
+/// Splits off the identifier at the start of `source`, a letter or `_`
+/// followed by any letters, digits and `_`.
+pub fn get_identifier(source: &str) -> Result<(&str, &str)> {
+    match source.chars().next() {
+        Some(c) if c.is_alphabetic() || c == '_' => (),
+        c => return Err(ParseError::ExpectedIdentifier(c)),
+    }
     let index = source.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(source.len());
-    (&source[index..], &source[..index])  // TODO: Index error on first slice.
+    Ok((&source[index..], &source[..index]))
 }
 
 // Returns the source at the start of the substring target.
@@ -87,61 +160,126 @@ pub fn starts_with<'a>(source: &'a str, target: &str) -> Result<&'a str> {
     }
 }
 
+/// Parses a non-negative integer. Like in Rust, digits can be grouped with
+/// single underscores, e.g. `1_000_000`.
 pub fn parse_int(source: &str) -> Result<(&str, i32)> {
-    let data = source.as_bytes();
-    let mut index = 0;
+    let index = source.find(|c: char| !(c.is_ascii_digit() || c == '_')).unwrap_or(source.len());
+    let digits = &source[0..index];
 
-    for c in data[index..data.len()].into_iter() {
-        if b'0' <= *c && *c <= b'9' {
-            index += 1;
-        } else {
-            break;
-        }
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err(ParseError::NotAI32);
     }
 
-    let result = source[0..index].parse::<i32>()?;
-    Ok((&source[index..], result))  // Index error
+    let result = digits.replace('_', "").parse::<i32>()?;
+    Ok((&source[index..], result))
 }
 
 
+/// Parses a float like `-1.5`, `.5`, `2` or `3e-2`. There's at most one
+/// leading `-`, and a sign after it is only allowed in the exponent. A
+/// number that runs into a sign or a dot, like `1-2` or `1.2.3`, is an error
+/// instead of stopping there.
 pub fn parse_float(source: &str) -> Result<(&str, f32)> {
-    let data = source.as_bytes();
-    let mut found_dot = false;
-    let mut index     = 0;
-
-    if data.len() < 3 {
+    let data   = source.as_bytes();
+    let digits = |from: usize| data[from..].iter().take_while(|c| c.is_ascii_digit()).count();
+
+    let mut index = if data.first() == Some(&b'-') { 1 } else { 0 };
+    let mut mantissa_digits = digits(index);
+    index += mantissa_digits;
+    if data.get(index) == Some(&b'.') {
+        index += 1;
+        let fraction_digits = digits(index);
+        mantissa_digits += fraction_digits;
+        index += fraction_digits;
+    }
+    if mantissa_digits == 0 {
         return Err(ParseError::NotAF32);
     }
 
-    if data[0] == b'-' {
-        index = 1;
+    if let Some(b'e') | Some(b'E') = data.get(index) {
+        let sign = matches!(data.get(index + 1), Some(b'+') | Some(b'-')) as usize;
+        let exponent_digits = digits(index + 1 + sign);
+        if exponent_digits > 0 {
+            index += 1 + sign + exponent_digits;
+        }
     }
 
-    for c in data[index..data.len()].into_iter() {
-        if b'0' <= *c && *c <= b'9' {
-            index += 1;
-        } else if *c == b'.' {
-            if !found_dot { found_dot = true } else { return Err(ParseError::NotAF32); }
-            index += 1;
-        } else {
-            break;
-        }
+    if let Some(b'+') | Some(b'-') | Some(b'.') = data.get(index) {
+        return Err(ParseError::NotAF32);
     }
 
     let result = source[0..index].parse::<f32>()?;
-    Ok((&source[index..], result))  // Index error
+    Ok((&source[index..], result))
+}
+
+/// string : " <characters except "> "
+pub fn parse_string(source: &str) -> Result<(&str, &str)> {
+    let source = starts_with(source, "\"")?;
+    let end = source.find('"').ok_or(ParseError::WrongSyntax)?;
+    Ok((&source[end+1..], &source[..end]))
 }
 
+/// vec3 : <f32> [,] <f32> [,] <f32>
+///
+/// The components can be separated by whitespace and comments as well.
+/// Whole numbers can be written without a dot, e.g. `0 0 -1`.
 pub fn parse_vec3(source: &str) -> Result<(&str, Vec3)> {
     let (source, x) = parse_float(source)?;
-    let source      = skip_whitespace(source);
+    let source      = skip_separator(source)?;
     let (source, y) = parse_float(source)?;
-    let source      = skip_whitespace(source);
+    let source      = skip_separator(source)?;
     let (source, z) = parse_float(source)?;
     Ok((source, Vec3{x, y, z}))
 }
 
-/// camera : camera origin <f32> <f32> <f32> aspect <f32> ;
+/// Skips the whitespace, comments and an optional comma between the components of a vector.
+fn skip_separator(source: &str) -> Result<&str> {
+    let source = skip_trivia(source)?;
+    match starts_with(source, ",") {
+        Ok(source) => skip_trivia(source),
+        Err(_)     => Ok(source),
+    }
+}
+
+/// A color that reflects light, clamped to [0, 1] as it would reflect more
+/// light than it receives otherwise. Logs a warning if it had to be clamped.
+/// Emission isn't clamped, as lights need to be brighter than 1.
+pub fn parse_reflectance<'a>(source: &'a str, options: &mut ParseOptions) -> Result<(&'a str, Color)> {
+    let (source, color) = parse_color(source)?;
+    let clamped = Color::new(color.r.clamp(0.0, 1.0), color.g.clamp(0.0, 1.0), color.b.clamp(0.0, 1.0));
+
+    if (clamped.r, clamped.g, clamped.b) != (color.r, color.g, color.b) {
+        if let Some(logger) = &mut options.logger {
+            writeln!(logger, "Warning: Color {} {} {} reflects more light than it receives, clamping it to [0, 1].", color.r, color.g, color.b).unwrap();
+        }
+    }
+    Ok((source, clamped))
+}
+
+/// color : <f32> <f32> <f32> | #<RRGGBB>
+///
+/// The three floats are a linear color. The hex form is how colors are
+/// usually picked, i.e. in display space, so it's gamma decoded (by squaring,
+/// the inverse of the sqrt in the renderer) to get the linear color.
+pub fn parse_color(source: &str) -> Result<(&str, Color)> {
+    if let Ok(source) = starts_with(source, "#") {
+        let digits = source.get(..6).ok_or(ParseError::WrongSyntax)?;
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::WrongSyntax);
+        }
+
+        let channel = |i: usize| -> Result<f32> {
+            let value = u8::from_str_radix(&digits[i..i+2], 16).map_err(|_| ParseError::WrongSyntax)?;
+            Ok((value as f32 / 255.0).powi(2))
+        };
+        return Ok((&source[6..], Color::new(channel(0)?, channel(2)?, channel(4)?)));
+    }
+
+    let (source, c) = parse_vec3(source)?;
+    Ok((source, c.into()))
+}
+
+/// camera : camera origin <f32> <f32> <f32> aspect <f32> [orthographic scale <f32>] ;
 pub fn parse_camera(source: &str) -> Option<Result<(&str, Camera)>> {
     if let Ok(source) = starts_with(source, "camera") {
         let result = || {
@@ -155,11 +293,19 @@ pub fn parse_camera(source: &str) -> Option<Result<(&str, Camera)>> {
             let source = starts_with(source, "aspect")?;
             let source = skip_whitespace(source);
             let (source, a) = parse_float(source)?;
-            let source = skip_whitespace(source);
+            let mut source = skip_whitespace(source);
+
+            let mut projection = Projection::Perspective;
+            if let Ok(next) = starts_with(source, "orthographic") {
+                let next = starts_with(skip_whitespace(next), "scale")?;
+                let (next, scale) = parse_float(skip_whitespace(next))?;
+                projection = Projection::Orthographic { scale };
+                source = skip_whitespace(next);
+            }
 
             let source = starts_with(source, ";")?;
 
-            Ok((source, Camera::new_at(o, a)))
+            Ok((source, Camera::new_at(o, a).with_projection(projection)))
         };
         return Some(result());
     }
@@ -168,31 +314,68 @@ pub fn parse_camera(source: &str) -> Option<Result<(&str, Camera)>> {
 
 
 /// material :  material <name> : <type> ;
-/// type     :  <diffuse> | <metal> | <dielectric>
-/// diffuse  :  Diffuse color <f32> <f32> <f32>
-/// metal    :  Metal color <f32> <f32> <f32> fuzz <f32>
+/// type     :  <diffuse> | <metal> | <glossy_metal> | <dielectric> | <textured> | <emission> | <isotropic> | <cutout>
+/// diffuse  :  Diffuse color <color>
+/// metal    :  Metal color <color> fuzz <f32>
+/// glossy_metal : GlossyMetal color <color> roughness <f32>
 /// dielectric : Dielectric ir <f32>
-pub fn parse_material(source: &str) -> Option<Result<(&str, &str, MaterialType)>> {
+/// textured :  Textured file <string> [u_offset <f32>] [rotation <f32>] [flip_v] [filter <nearest | bilinear>] [wrap <repeat | clamp | mirror>]
+/// emission :  Emission (color <color> | temperature <f32>) [strength <f32>] [one_sided]
+/// isotropic : Isotropic color <color>
+/// cutout   :  Cutout file <string> threshold <f32>
+///
+/// The `file` of a texture is resolved relative to `directory`, like an
+/// include in the same scene.
+pub fn parse_material<'a>(source: &'a str, directory: &Path, options: &mut ParseOptions) -> Option<Result<(&'a str, &'a str, MaterialType)>> {
     if let Ok(source) = starts_with(source, "material") {
-        let result = || {
+        let mut result = || {
             let source = skip_whitespace(source);
 
-            let (source, name) = get_identifier(source);
+            let (source, name) = get_identifier(source)?;
             let source = skip_whitespace(source);
             let source = starts_with(source, ":")?;
             let source = skip_whitespace(source);
 
+            let (source, material) = parse_material_type(source, directory, options).unwrap_or(Err(ParseError::WrongSyntax))?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, ";")?;
+
+            Ok((source, name, material))
+        };
+        return Some(result());
+    }
+    None
+}
+
+/// The names of the material types, see `parse_material_type`.
+const MATERIAL_TYPES: [&str; 8] = ["Diffuse", "Metal", "GlossyMetal", "Dielectric", "Emission", "Textured", "Isotropic", "Cutout"];
+
+/// Parses a <type> of material without the `;` at the end, if `source`
+/// starts with the name of one.
+pub fn parse_material_type<'a>(source: &'a str, directory: &Path, options: &mut ParseOptions) -> Option<Result<(&'a str, MaterialType)>> {
+    let keyword = get_identifier(source).map_or("", |(_, keyword)| keyword);
+    if MATERIAL_TYPES.contains(&keyword) {
+        let mut result = || {
             if let Ok(source) = starts_with(source, "Diffuse") {
                 let source = skip_whitespace(source);
 
                 let source = starts_with(source, "color")?;
                 let source = skip_whitespace(source);
-                let (source, c) = parse_vec3(source)?;
+                let (source, c) = parse_reflectance(source, options)?;
                 let source = skip_whitespace(source);
 
-                let source = starts_with(source, ";")?;
+                return Ok((source, MaterialType::Diffuse(c)));
+            }
+
+            if let Ok(source) = starts_with(source, "Isotropic") {
+                let source = skip_whitespace(source);
+
+                let source = starts_with(source, "color")?;
+                let source = skip_whitespace(source);
+                let (source, c) = parse_reflectance(source, options)?;
+                let source = skip_whitespace(source);
 
-                return Ok((source, name, MaterialType::Diffuse(c.into())));
+                return Ok((source, MaterialType::Isotropic(c)));
             }
 
             if let Ok(source) = starts_with(source, "Metal") {
@@ -200,7 +383,7 @@ pub fn parse_material(source: &str) -> Option<Result<(&str, &str, MaterialType)>
 
                 let source = starts_with(source, "color")?;
                 let source = skip_whitespace(source);
-                let (source, c) = parse_vec3(source)?;
+                let (source, c) = parse_reflectance(source, options)?;
                 let source = skip_whitespace(source);
 
                 let source = starts_with(source, "fuzz")?;
@@ -208,9 +391,23 @@ pub fn parse_material(source: &str) -> Option<Result<(&str, &str, MaterialType)>
                 let (source, f) = parse_float(source)?;
                 let source = skip_whitespace(source);
 
-                let source = starts_with(source, ";")?;
+                return Ok((source, MaterialType::Metal(c, f)));
+            }
+
+            if let Ok(source) = starts_with(source, "GlossyMetal") {
+                let source = skip_whitespace(source);
 
-                return Ok((source, name, MaterialType::Metal(c.into(), f)));
+                let source = starts_with(source, "color")?;
+                let source = skip_whitespace(source);
+                let (source, c) = parse_reflectance(source, options)?;
+                let source = skip_whitespace(source);
+
+                let source = starts_with(source, "roughness")?;
+                let source = skip_whitespace(source);
+                let (source, r) = parse_float(source)?;
+                let source = skip_whitespace(source);
+
+                return Ok((source, MaterialType::GlossyMetal { albedo: c, roughness: r }));
             }
 
             if let Ok(source) = starts_with(source, "Dielectric") {
@@ -221,9 +418,107 @@ pub fn parse_material(source: &str) -> Option<Result<(&str, &str, MaterialType)>
                 let (source, i) = parse_float(source)?;
                 let source = skip_whitespace(source);
 
-                let source = starts_with(source, ";")?;
+                return Ok((source, MaterialType::Dielectric(i)));
+            }
+
+            if let Ok(source) = starts_with(source, "Emission") {
+                let source = skip_whitespace(source);
+
+                // A temperature is in kelvin, see `Color::from_blackbody`.
+                let (source, color) =
+                    if let Ok(source) = starts_with(source, "temperature") {
+                        let (source, kelvin) = parse_float(skip_whitespace(source))?;
+                        (source, Color::from_blackbody(kelvin))
+                    } else {
+                        let source = starts_with(source, "color")?;
+                        parse_color(skip_whitespace(source))?
+                    };
+                let mut source = skip_whitespace(source);
+
+                let mut strength = 1.0;
+                if let Ok(next) = starts_with(source, "strength") {
+                    let (next, s) = parse_float(skip_whitespace(next))?;
+                    strength = s;
+                    source = skip_whitespace(next);
+                }
+
+                // Only emits from the front face, e.g. for a ceiling light.
+                let mut two_sided = true;
+                if let Ok(next) = starts_with(source, "one_sided") {
+                    two_sided = false;
+                    source = skip_whitespace(next);
+                }
+
+                let color = Color::new(color.r * strength, color.g * strength, color.b * strength);
+                return Ok((source, MaterialType::Emission { color, two_sided }));
+            }
+
+            if let Ok(source) = starts_with(source, "Textured") {
+                let source = skip_whitespace(source);
+
+                let source = starts_with(source, "file")?;
+                let source = skip_whitespace(source);
+                let (source, path) = parse_string(source)?;
+                let mut source = skip_whitespace(source);
+
+                let mut mapping = SphereMapping::default();
+                if let Ok(next) = starts_with(source, "u_offset") {
+                    let (next, u_offset) = parse_float(skip_whitespace(next))?;
+                    mapping.u_offset = u_offset;
+                    source = skip_whitespace(next);
+                }
+                if let Ok(next) = starts_with(source, "rotation") {
+                    let (next, rotation) = parse_float(skip_whitespace(next))?;
+                    mapping.rotation = rotation;
+                    source = skip_whitespace(next);
+                }
+                if let Ok(next) = starts_with(source, "flip_v") {
+                    mapping.flip_v = true;
+                    source = skip_whitespace(next);
+                }
+
+                let mut filter = TextureFilter::default();
+                if let Ok(next) = starts_with(source, "filter") {
+                    let (next, name) = get_identifier(skip_whitespace(next))?;
+                    filter = match name {
+                        "nearest"  => TextureFilter::Nearest,
+                        "bilinear" => TextureFilter::Bilinear,
+                        _ => return Err(ParseError::WrongSyntax),
+                    };
+                    source = skip_whitespace(next);
+                }
+
+                let mut wrap = WrapMode::default();
+                if let Ok(next) = starts_with(source, "wrap") {
+                    let (next, name) = get_identifier(skip_whitespace(next))?;
+                    wrap = match name {
+                        "repeat" => WrapMode::Repeat,
+                        "clamp"  => WrapMode::Clamp,
+                        "mirror" => WrapMode::Mirror,
+                        _ => return Err(ParseError::WrongSyntax),
+                    };
+                    source = skip_whitespace(next);
+                }
+
+                let texture = Texture::load(&directory.join(path).to_string_lossy()).map_err(|_| ParseError::CouldntOpenFile)?;
+                return Ok((source, MaterialType::Textured { texture: Arc::new(texture), mapping, filter, wrap }));
+            }
+
+            if let Ok(source) = starts_with(source, "Cutout") {
+                let source = skip_whitespace(source);
+
+                let source = starts_with(source, "file")?;
+                let source = skip_whitespace(source);
+                let (source, path) = parse_string(source)?;
+                let source = skip_whitespace(source);
+
+                let source = starts_with(source, "threshold")?;
+                let source = skip_whitespace(source);
+                let (source, threshold) = parse_float(source)?;
+                let source = skip_whitespace(source);
 
-                return Ok((source, name, MaterialType::Dielectric(i)));
+                let texture = Texture::load(&directory.join(path).to_string_lossy()).map_err(|_| ParseError::CouldntOpenFile)?;
+                return Ok((source, MaterialType::Cutout { texture: Arc::new(texture), threshold }));
             }
 
             Err(ParseError::WrongSyntax)
@@ -233,150 +528,1538 @@ pub fn parse_material(source: &str) -> Option<Result<(&str, &str, MaterialType)>
     None
 }
 
-/// sphere : sphere center <f32> <f32> <f32> radius <f32> material <name> ;
-pub fn parse_sphere<'a>(source: &'a str, materials: &HashMap<&'a str, MaterialType>) -> Option<Result<(&'a str, Sphere)>> {
+/// sphere : sphere center <f32> <f32> <f32> radius <f32> material (<name> | <type>) [name <name>] [hidden] [noshadow] ;
+///
+/// The fields of the primitives can be given in any order. The material is
+/// either the name of a defined one, or an inline <type> like in `parse_material`.
+/// A `hidden` primitive isn't seen by the camera, only in reflections and
+/// such, and a `noshadow` one doesn't block the rays to the lights.
+pub fn parse_sphere<'a>(source: &'a str, materials: &HashMap<String, MaterialType>, directory: &Path, options: &mut ParseOptions) -> Option<Result<(&'a str, Sphere)>> {
     if let Ok(source) = starts_with(source, "sphere") {
-        let result = || {
-            let source = skip_whitespace(source);
+        let mut result = || {
+            let (source, fields) = parse_fields(source, &["center"], &["radius"], &[], materials, directory, options)?;
+            let material = fields.material(materials, options)?;
 
-            let source = starts_with(source, "center")?;
-            let source = skip_whitespace(source);
-            let (source, c) = parse_vec3(source)?;
-            let source = skip_whitespace(source);
+            let mut sphere = Sphere::new(fields.vector("center")?, fields.float("radius")?, material).with_visibility(fields.visibility());
+            if let Some(name) = fields.name() {
+                sphere = sphere.with_name(name);
+            }
+            Ok((source, sphere))
+        };
+        return Some(result());
+    }
 
-            let source = starts_with(source, "radius")?;
-            let source = skip_whitespace(source);
-            let (source, r) = parse_float(source)?;
-            let source = skip_whitespace(source);
+    None
+}
 
-            let source = starts_with(source, "material")?;
-            let source = skip_whitespace(source);
-            let (source, m) = get_identifier(source);
-            let source = skip_whitespace(source);
+/// triangle : triangle v0 <f32> <f32> <f32> v1 <f32> <f32> <f32> v2 <f32> <f32> <f32> material (<name> | <type>) [colors <color> <color> <color>] [name <name>] [hidden] [noshadow] ;
+///
+/// The optional `colors` of v0, v1 and v2 are interpolated over the triangle
+/// and multiply the color of the material.
+pub fn parse_triangle<'a>(source: &'a str, materials: &HashMap<String, MaterialType>, directory: &Path, options: &mut ParseOptions) -> Option<Result<(&'a str, Triangle)>> {
+    if let Ok(source) = starts_with(source, "triangle") {
+        let mut result = || {
+            let (source, fields) = parse_fields(source, &["v0", "v1", "v2"], &[], &["colors"], materials, directory, options)?;
+            let material = fields.material(materials, options)?;
 
-            let source = starts_with(source, ";")?;
+            let mut triangle = Triangle::new(fields.vector("v0")?, fields.vector("v1")?, fields.vector("v2")?, material).with_visibility(fields.visibility());
+            if let Ok(colors) = fields.colors("colors") {
+                triangle = triangle.with_colors(colors);
+            }
+            if let Some(name) = fields.name() {
+                triangle = triangle.with_name(name);
+            }
+            Ok((source, triangle))
+        };
+
+        return Some(result());
+    }
+
+    None
+}
 
-            let material = materials.get(m).ok_or(ParseError::WrongSyntax)?.to_owned();
 
-            return Ok((
-                source, Sphere{ center: c, radius: r, material }
-            ));
+/// quad : quad v0 <f32> <f32> <f32> v1 <f32> <f32> <f32> v2 <f32> <f32> <f32> v3 <f32> <f32> <f32> material (<name> | <type>) [name <name>] [hidden] [noshadow] ;
+///
+/// Split into the triangles (v0, v1, v2) and (v0, v2, v3), so the winding of both
+/// is the same as the quad's. Warns if the corners aren't (nearly) in a plane.
+/// The texture coordinates of v0, v1, v2 and v3 are the corners (0, 0), (1, 0),
+/// (1, 1) and (0, 1) of a texture, so a texture covers the whole quad.
+pub fn parse_quad<'a>(source: &'a str, materials: &HashMap<String, MaterialType>, directory: &Path, options: &mut ParseOptions) -> Option<Result<(&'a str, [Triangle; 2])>> {
+    if let Ok(source) = starts_with(source, "quad") {
+        let mut result = || {
+            let (source, fields) = parse_fields(source, &["v0", "v1", "v2", "v3"], &[], &[], materials, directory, options)?;
+            let (v0, v1, v2, v3) = (fields.vector("v0")?, fields.vector("v1")?, fields.vector("v2")?, fields.vector("v3")?);
+            let material = fields.material(materials, options)?;
+
+            // Distance of the last corner from the plane of the others, relative to the size of the quad.
+            let normal = (v1 - v0).cross(&(v2 - v0));
+            let size = (v2 - v0).length().max((v3 - v1).length());
+            let distance = normal.dot(&(v3 - v0)).abs() / normal.length().max(1e-12);
+            if distance > 1e-3 * size {
+                if let Some(logger) = &mut options.logger {
+                    writeln!(logger, "Warning: The corners of a quad aren't in a plane, it's rendered as two triangles that bend.").unwrap();
+                }
+            }
+
+            let mut first  = Triangle::new(v0, v1, v2, material.clone()).with_visibility(fields.visibility())
+                .with_uvs([(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)]);
+            let mut second = Triangle::new(v0, v2, v3, material).with_visibility(fields.visibility())
+                .with_uvs([(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)]);
+            if let Some(name) = fields.name() {
+                first  = first.with_name(name);
+                second = second.with_name(name);
+            }
+            Ok((source, [first, second]))
         };
+
         return Some(result());
     }
 
     None
 }
 
-/// triangle : triangle v0 <f32> <f32> <f32> v1 <f32> <f32> <f32> v2 <f32> <f32> <f32> material <name> ;
-pub fn parse_triangle<'a>(source: &'a str, materials: &HashMap<&'a str, MaterialType>) -> Option<Result<(&'a str, Triangle)>> {
-    if let Ok(source) = starts_with(source, "triangle") {
+/// The fields of a primitive, parsed by `parse_fields`.
+struct Fields<'a> {
+    vectors:     Vec<(&'static str, Vec3)>,
+    floats:      Vec<(&'static str, f32)>,
+    colors:      Vec<(&'static str, [Color; 3])>,
+    identifiers: Vec<(&'static str, &'a str)>,
+    /// A material given inline instead of by name.
+    material:    Option<MaterialType>,
+    /// The fields without a value, `hidden` and `noshadow`.
+    flags:       Vec<&'static str>,
+}
+
+impl<'a> Fields<'a> {
+    fn vector(&self, field: &'static str) -> Result<Vec3> {
+        self.vectors.iter().find(|(f, _)| *f == field).map(|(_, v)| *v).ok_or(ParseError::MissingField(field))
+    }
+    fn float(&self, field: &'static str) -> Result<f32> {
+        self.floats.iter().find(|(f, _)| *f == field).map(|(_, v)| *v).ok_or(ParseError::MissingField(field))
+    }
+    fn colors(&self, field: &'static str) -> Result<[Color; 3]> {
+        self.colors.iter().find(|(f, _)| *f == field).map(|(_, v)| *v).ok_or(ParseError::MissingField(field))
+    }
+    fn identifier(&self, field: &'static str) -> Result<&'a str> {
+        self.identifiers.iter().find(|(f, _)| *f == field).map(|(_, v)| *v).ok_or(ParseError::MissingField(field))
+    }
+    /// The inline material, or else the one named by `material`.
+    fn material(&self, materials: &HashMap<String, MaterialType>, options: &mut ParseOptions) -> Result<MaterialType> {
+        match &self.material {
+            Some(material) => Ok(material.clone()),
+            None => lookup_material(self.identifier("material")?, materials, options),
+        }
+    }
+    /// The optional `name` of the primitive.
+    fn name(&self) -> Option<&'a str> {
+        self.identifier("name").ok()
+    }
+    /// Which rays see the primitive, from the `hidden` and `noshadow` flags.
+    fn visibility(&self) -> Visibility {
+        Visibility {
+            visible_to_camera: !self.flags.contains(&"hidden"),
+            casts_shadow:      !self.flags.contains(&"noshadow"),
+        }
+    }
+}
+
+/// Parses `<field> <value>` pairs in any order up to and including the `;`.
+/// The fields are the given `vectors`, `floats` and `colors` (three colors
+/// each), plus the `material` and `name` identifiers and the `hidden` and
+/// `noshadow` flags every primitive has. Unless it's the name of one of the
+/// `materials`, a material type after `material` is parsed as an inline material.
+fn parse_fields<'a>(source: &'a str, vectors: &[&'static str], floats: &[&'static str], colors: &[&'static str], materials: &HashMap<String, MaterialType>, directory: &Path, options: &mut ParseOptions) -> Result<(&'a str, Fields<'a>)> {
+    let mut fields = Fields { vectors: Vec::new(), floats: Vec::new(), colors: Vec::new(), identifiers: Vec::new(), material: None, flags: Vec::new() };
+    let mut source = skip_whitespace(source);
+
+    while !source.starts_with(';') {
+        let (next, keyword) = get_identifier(source)?;
+        let next = skip_whitespace(next);
+
+        let duplicate = |field: &'static str, fields: &Fields| -> Result<&'static str> {
+            let given = fields.vectors.iter().map(|(f, _)| f)
+                .chain(fields.floats.iter().map(|(f, _)| f))
+                .chain(fields.colors.iter().map(|(f, _)| f))
+                .chain(fields.identifiers.iter().map(|(f, _)| f))
+                .chain(fields.material.iter().map(|_| &"material"))
+                .chain(fields.flags.iter())
+                .any(|f| *f == field);
+            if given { Err(ParseError::DuplicateField(field)) } else { Ok(field) }
+        };
+
+        source =
+            if let Some(field) = vectors.iter().find(|f| **f == keyword) {
+                let field = duplicate(field, &fields)?;
+                let (next, v) = parse_vec3(next)?;
+                fields.vectors.push((field, v));
+                next
+            } else if let Some(field) = floats.iter().find(|f| **f == keyword) {
+                let field = duplicate(field, &fields)?;
+                let (next, v) = parse_float(next)?;
+                fields.floats.push((field, v));
+                next
+            } else if let Some(field) = colors.iter().find(|f| **f == keyword) {
+                let field = duplicate(field, &fields)?;
+                let (next, c0) = parse_color(next)?;
+                let (next, c1) = parse_color(skip_whitespace(next))?;
+                let (next, c2) = parse_color(skip_whitespace(next))?;
+                fields.colors.push((field, [c0, c1, c2]));
+                next
+            } else if let Some(field) = ["hidden", "noshadow"].iter().find(|f| **f == keyword) {
+                fields.flags.push(duplicate(field, &fields)?);
+                next
+            } else if let Some(field) = ["material", "name"].iter().find(|f| **f == keyword) {
+                let field = duplicate(field, &fields)?;
+                let (after, identifier) = get_identifier(next)?;
+                let inline = if field == "material" && !materials.contains_key(identifier) { parse_material_type(next, directory, options) } else { None };
+                if let Some(result) = inline {
+                    let (next, material) = result?;
+                    fields.material = Some(material);
+                    source = skip_whitespace(next);
+                    continue;
+                }
+                let next = after;
+                fields.identifiers.push((field, identifier));
+                next
+            } else {
+                return Err(ParseError::WrongSyntax);
+            };
+        source = skip_whitespace(source);
+    }
+
+    Ok((&source[1..], fields))
+}
+
+
+pub fn skip_comment(mut source: &str) -> Result<&str>  {
+    while let Ok(comment_line) = starts_with(source, "//") {
+        if let Ok(end_line) = find(comment_line, "\n") {
+            source = &end_line[1..];  // Go past new line.
+        } else {
+            // The last line of the file is a comment.
+            return Ok(&comment_line[comment_line.len()..]);
+        }
+    }
+
+    Ok(source)
+}
+
+
+/// Skips any mix of whitespace and comments.
+pub fn skip_trivia(mut source: &str) -> Result<&str> {
+    loop {
+        let next = skip_comment(skip_whitespace(source))?;
+        if next.len() == source.len() {
+            return Ok(source);
+        }
+        source = next;
+    }
+}
+
+
+/// transform : transform (translate <f32> <f32> <f32> | rotate_y <f32> | scale <f32>)* {
+///
+/// Starts a block of statements ending with `}`, which are moved by the
+/// transforms in the order they're written. Angles are in degrees. The
+/// scale is uniform and positive, so spheres stay spheres and triangles
+/// keep their front.
+pub fn parse_transform(source: &str) -> Option<Result<(&str, Transform)>> {
+    if let Ok(source) = starts_with(source, "transform") {
         let result = || {
-            let source = skip_whitespace(source);
+            let mut transform = Transform::identity();
+            let mut source = skip_whitespace(source);
+
+            while starts_with(source, "{").is_err() {
+                let (next, operation) = get_identifier(source)?;
+                let next = skip_whitespace(next);
+                let (next, step) = match operation {
+                    "translate" => {
+                        let (next, offset) = parse_vec3(next)?;
+                        (next, Transform { linear: Mat3::identity(), translation: offset, scale: 1.0 })
+                    },
+                    "rotate_y" => {
+                        let (next, degrees) = parse_float(next)?;
+                        let (sin, cos) = degrees.to_radians().sin_cos();
+                        let rotation = Mat3::new(Vec3::new(cos, 0.0, sin), Vec3::new(0.0, 1.0, 0.0), Vec3::new(-sin, 0.0, cos));
+                        (next, Transform { linear: rotation, translation: Vec3::new_zero(), scale: 1.0 })
+                    },
+                    "scale" => {
+                        let (next, scale) = parse_float(next)?;
+                        if scale <= 0.0 {
+                            return Err(ParseError::WrongSyntax);
+                        }
+                        (next, Transform { linear: Mat3::identity().mul_scalar(scale), translation: Vec3::new_zero(), scale })
+                    },
+                    _ => return Err(ParseError::WrongSyntax),
+                };
+                transform = step.after(&transform);
+                source = skip_whitespace(next);
+            }
+
+            Ok((&source[1..], transform))
+        };
+        return Some(result());
+    }
+    None
+}
 
-            let source = starts_with(source, "v0")?;
+/// repeat : repeat <i32> along <f32> <f32> <f32> step <f32> {
+///
+/// Starts a block of statements ending with `}`, which is instantiated
+/// `count` times, from 1 up to `MAX_REPEAT_COUNT`. Copy `i` (from 0) is
+/// translated by `i * step * along`, in the space of the enclosing transforms.
+pub fn parse_repeat(source: &str) -> Option<Result<(&str, Repeat)>> {
+    if let Ok(source) = starts_with(source, "repeat") {
+        let result = || {
             let source = skip_whitespace(source);
-            let (source, v0) = parse_vec3(source)?;
+            let (source, count) = parse_int(source)?;
+            if count <= 0 {
+                return Err(ParseError::WrongSyntax);
+            }
+            if count as usize > MAX_REPEAT_COUNT {
+                return Err(ParseError::RepeatTooLarge);
+            }
             let source = skip_whitespace(source);
-
-            let source = starts_with(source, "v1")?;
+            let source = starts_with(source, "along")?;
+            let source = skip_whitespace(source);
+            let (source, along) = parse_vec3(source)?;
             let source = skip_whitespace(source);
-            let (source, v1) = parse_vec3(source)?;
+            let source = starts_with(source, "step")?;
             let source = skip_whitespace(source);
+            let (source, step) = parse_float(source)?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, "{")?;
+            Ok((source, Repeat { count: count as usize, offset: step * along }))
+        };
+        return Some(result());
+    }
+    None
+}
+
+/// The copies of a `repeat` block.
+#[derive(Debug, Copy, Clone)]
+pub struct Repeat {
+    pub count:  usize,
+    /// Between one copy and the next.
+    pub offset: Vec3,
+}
+
+impl Repeat {
+    /// The translation of copy `index`.
+    fn copy(&self, index: usize) -> Transform {
+        Transform { linear: Mat3::identity(), translation: index as f32 * self.offset, scale: 1.0 }
+    }
+}
+
+/// A rotation and uniform scale followed by a translation.
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    linear:      Mat3,
+    translation: Vec3,
+    scale:       f32,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self { linear: Mat3::identity(), translation: Vec3::new_zero(), scale: 1.0 }
+    }
+    /// This transform applied after `first`.
+    pub fn after(&self, first: &Transform) -> Self {
+        Self {
+            linear:      self.linear.mul(&first.linear),
+            translation: self.linear.mul_vec3(&first.translation) + self.translation,
+            scale:       self.scale * first.scale,
+        }
+    }
+    pub fn apply(&self, point: Vec3) -> Vec3 {
+        self.linear.mul_vec3(&point) + self.translation
+    }
+    fn sphere(&self, mut sphere: Sphere) -> Sphere {
+        sphere.center = self.apply(sphere.center);
+        sphere.radius *= self.scale;
+        sphere
+    }
+    fn triangle(&self, triangle: Triangle) -> Triangle {
+        triangle.map_vertices(|v| self.apply(v))
+    }
+    fn plane(&self, mut plane: Plane) -> Plane {
+        plane.point  = self.apply(plane.point);
+        plane.normal = self.linear.mul_vec3(&plane.normal.into()).normalize();
+        plane
+    }
+}
 
-            let source = starts_with(source, "v2")?;
+/// output : output width <i32> height <i32> ;
+pub fn parse_output(source: &str) -> Option<Result<(&str, (usize, usize))>> {
+    if let Ok(source) = starts_with(source, "output") {
+        let result = || {
+            let source = skip_whitespace(source);
+            let source = starts_with(source, "width")?;
             let source = skip_whitespace(source);
-            let (source, v2) = parse_vec3(source)?;
+            let (source, width) = parse_int(source)?;
             let source = skip_whitespace(source);
+            let source = starts_with(source, "height")?;
+            let source = skip_whitespace(source);
+            let (source, height) = parse_int(source)?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, ";")?;
+            if width <= 0 || height <= 0 {
+                return Err(ParseError::WrongSyntax);
+            }
+            Ok((source, (width as usize, height as usize)))
+        };
+        return Some(result());
+    }
+    None
+}
 
-            let source = starts_with(source, "material")?;
+/// floor : floor checker color <color> color <color> scale <f32> [at <f32>] ;
+///
+/// A horizontal plane facing up at a height of `at`, 0 by default, with
+/// checkers of `scale` world units.
+pub fn parse_floor(source: &str) -> Option<Result<(&str, Plane)>> {
+    if let Ok(source) = starts_with(source, "floor") {
+        let result = || {
             let source = skip_whitespace(source);
-            let (source, m) = get_identifier(source);
+            let source = starts_with(source, "checker")?;
             let source = skip_whitespace(source);
+            let source = starts_with(source, "color")?;
+            let (source, even) = parse_color(skip_whitespace(source))?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, "color")?;
+            let (source, odd) = parse_color(skip_whitespace(source))?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, "scale")?;
+            let (source, scale) = parse_float(skip_whitespace(source))?;
+            let mut source = skip_whitespace(source);
+            if scale <= 0.0 {
+                return Err(ParseError::WrongSyntax);
+            }
 
+            let mut height = 0.0;
+            if let Ok(next) = starts_with(source, "at") {
+                let (next, at) = parse_float(skip_whitespace(next))?;
+                height = at;
+                source = skip_whitespace(next);
+            }
             let source = starts_with(source, ";")?;
 
-            let material = materials.get(m).ok_or(ParseError::WrongSyntax)?.to_owned();
-
-            return Ok((
-                source, Triangle::new(v0, v1, v2, material)
-            ));
+            let material = MaterialType::Checker { even, odd, scale };
+            Ok((source, Plane::new(Vec3::new(0.0, height, 0.0), NVec3::new(0.0, 1.0, 0.0), material)))
         };
-
         return Some(result());
     }
+    None
+}
 
+/// sky : sky (none | color <color>) ;
+pub fn parse_sky(source: &str) -> Option<Result<(&str, Sky)>> {
+    if let Ok(source) = starts_with(source, "sky") {
+        let result = || {
+            let source = skip_whitespace(source);
+            let (source, sky) =
+                if let Ok(source) = starts_with(source, "none") {
+                    (source, Sky::None)
+                } else {
+                    let source = starts_with(source, "color")?;
+                    let (source, color) = parse_color(skip_whitespace(source))?;
+                    (source, Sky::Color(color))
+                };
+            let source = skip_whitespace(source);
+            let source = starts_with(source, ";")?;
+            Ok((source, sky))
+        };
+        return Some(result());
+    }
     None
 }
 
+/// seed : seed <i32> ;
+pub fn parse_seed(source: &str) -> Option<Result<(&str, u64)>> {
+    if let Ok(source) = starts_with(source, "seed") {
+        let result = || {
+            let source = skip_whitespace(source);
+            let (source, seed) = parse_int(source)?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, ";")?;
+            Ok((source, seed as u64))
+        };
+        return Some(result());
+    }
+    None
+}
 
-pub fn skip_comment(mut source: &str) -> Result<&str>  {
-    while let Ok(comment_line) = starts_with(source, "//") {
-        if let Ok(end_line) = find(comment_line, "\n") {
-            source = &end_line[1..];  // Go past new line. TODO: Index error.
-        } else {
-            return Err(ParseError::WrongSyntax);  // TODO: Better error.
-        }
+/// ambient : ambient color <color> ;
+pub fn parse_ambient(source: &str) -> Option<Result<(&str, Color)>> {
+    if let Ok(source) = starts_with(source, "ambient") {
+        let result = || {
+            let source = skip_whitespace(source);
+            let source = starts_with(source, "color")?;
+            let (source, color) = parse_color(skip_whitespace(source))?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, ";")?;
+            Ok((source, color))
+        };
+        return Some(result());
     }
+    None
+}
 
-    Ok(source)
+/// include : include <string> ;
+pub fn parse_include(source: &str) -> Option<Result<(&str, &str)>> {
+    if let Ok(source) = starts_with(source, "include") {
+        let result = || {
+            let source = skip_whitespace(source);
+            let (source, path) = parse_string(source)?;
+            let source = skip_whitespace(source);
+            let source = starts_with(source, ";")?;
+            Ok((source, path))
+        };
+        return Some(result());
+    }
+    None
 }
 
 
 /// --- Syntax ----
-/// program  :  <camera> (<material>)* (<sphere>)* (<triangle>)*
-/// camera   :  camera origin <f32> <f32> <f32> aspect <f32> ;
+/// program  :  <camera> (<statement>)*
+/// statement : <material> | <sphere> | <triangle> | <quad> | <floor> | <output> | <sky> | <seed> | <ambient> | <include> | (<transform> | <repeat>) (<statement>)* }
+/// camera   :  camera origin <f32> <f32> <f32> aspect <f32> [orthographic scale <f32>] ;
 /// material :  material <name> : <type> ;
-/// type     :  <diffuse> | <metal> | <dielectric>
-/// diffuse  :  Diffuse color <f32> <f32> <f32>
-/// metal    :  Metal color <f32> <f32> <f32> fuzz <f32>
+/// type     :  <diffuse> | <metal> | <glossy_metal> | <dielectric> | <textured> | <emission> | <isotropic> | <cutout>
+/// diffuse  :  Diffuse color <color>
+/// metal    :  Metal color <color> fuzz <f32>
+/// glossy_metal : GlossyMetal color <color> roughness <f32>
 /// dielectric : Dielectric ir <f32>
-/// sphere   :  sphere center <f32> <f32> <f32> radius <f32> material <name> ;
-/// triangle :  v0 <f32> <f32> <f32> v1 <f32> <f32> <f32> v2 <f32> <f32> <f32> material <name> ;
-pub fn parse_input(mut source: &str) -> Result<(Camera, Vec<Sphere>, Mesh)> {
-    let mut materials = HashMap::new();
-    let mut spheres : Vec<Sphere> = Vec::new();
-    let mut triangles: Vec<Triangle> = Vec::new();
+/// textured :  Textured file <string> [u_offset <f32>] [rotation <f32>] [flip_v] [filter <nearest | bilinear>] [wrap <repeat | clamp | mirror>]
+/// emission :  Emission (color <color> | temperature <f32>) [strength <f32>] [one_sided]
+/// isotropic : Isotropic color <color>
+/// cutout   :  Cutout file <string> threshold <f32>
+/// sphere   :  sphere center <f32> <f32> <f32> radius <f32> material (<name> | <type>) [name <name>] [hidden] [noshadow] ;
+/// triangle :  triangle v0 <f32> <f32> <f32> v1 <f32> <f32> <f32> v2 <f32> <f32> <f32> material (<name> | <type>) [colors <color> <color> <color>] [name <name>] [hidden] [noshadow] ;
+/// quad     :  quad v0 <f32> <f32> <f32> v1 <f32> <f32> <f32> v2 <f32> <f32> <f32> v3 <f32> <f32> <f32> material (<name> | <type>) [name <name>] [hidden] [noshadow] ;
+/// floor    :  floor checker color <color> color <color> scale <f32> [at <f32>] ;
+/// output   :  output width <i32> height <i32> ;
+/// sky      :  sky (none | color <color>) ;
+/// seed     :  seed <i32> ;
+/// ambient  :  ambient color <color> ;
+/// include  :  include <string> ;
+/// transform : transform (translate <f32> <f32> <f32> | rotate_y <f32> | scale <f32>)* {
+/// repeat   :  repeat <i32> along <f32> <f32> <f32> step <f32> {
+///
+/// The fields of a sphere, triangle or quad can be given in any order.
+/// Materials must be defined before they're used by name, primitives can
+/// also give their own material inline. An included file contains
+/// statements (but no camera) and shares the materials with the includer.
+/// Includes and the `file` of textures are resolved relative to the working
+/// directory, use `parse_file` to resolve them relative to the scene file. The `output` directive
+/// suggests a resolution to render at, `sky` the background and `seed` the
+/// random numbers to render with, the last one wins. `sky none` makes the
+/// background transparent.
+pub fn parse_input(source: &str) -> Result<Scene> {
+    parse_input_with_options(source, &mut ParseOptions::default())
+}
+
+/// Same as `parse_input`, but with control over how lenient the parser is.
+pub fn parse_input_with_options(source: &str, options: &mut ParseOptions) -> Result<Scene> {
+    parse_scene(source, Path::new(""), Vec::new(), options, false).map(|(scene, _)| scene)
+}
+
+/// Same as `parse_input`, but doesn't stop at the first error. A statement
+/// that fails is skipped up to the next `;` and its error collected, so the
+/// scene contains everything that parsed. Without a valid camera, the scene
+/// gets one at the origin with an aspect ratio of 1.
+pub fn parse_all(source: &str) -> (Scene, Vec<ParseError>) {
+    parse_all_with_options(source, &mut ParseOptions::default())
+}
+
+pub fn parse_all_with_options(source: &str, options: &mut ParseOptions) -> (Scene, Vec<ParseError>) {
+    parse_scene(source, Path::new(""), Vec::new(), options, true).expect("Errors should be collected.")
+}
+
+/// Reads and parses the scene file at `path`, see `parse_input`. Includes
+/// are resolved relative to the directory of the including file.
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Scene> {
+    parse_file_with_options(path, &mut ParseOptions::default())
+}
+
+pub fn parse_file_with_options<P: AsRef<Path>>(path: P, options: &mut ParseOptions) -> Result<Scene> {
+    let path = path.as_ref().canonicalize().map_err(|_| ParseError::CouldntOpenFile)?;
+    let source = std::fs::read_to_string(&path).map_err(|_| ParseError::CouldntOpenFile)?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    parse_scene(&source, &directory, vec![path], options, false).map(|(scene, _)| scene)
+}
+
+/// If `collect_errors`, the errors of failed statements are returned with the
+/// scene instead of stopping the parse.
+fn parse_scene(mut source: &str, directory: &Path, includes: Vec<PathBuf>, options: &mut ParseOptions, collect_errors: bool) -> Result<(Scene, Vec<ParseError>)> {
+    let mut statements = Statements {
+        materials: HashMap::new(), spheres: Vec::new(), triangles: Vec::new(), planes: Vec::new(), resolution: None, sky: None, seed: None, ambient: None,
+        transform: Transform::identity(), includes, repeated: 0,
+        errors: if collect_errors { Some(Vec::new()) } else { None },
+    };
 
     // Parse camera
-    source = skip_comment(source)?;
+    source = skip_comment(skip_whitespace(source))?;
     let camera =
-        if let Some(result) = parse_camera(source) {
-            let (next, camera) = result?;
-            source = skip_whitespace(next);
-            camera
-        } else {
-            return Err(ParseError::MissingCamera);
+        match parse_camera(source) {
+            Some(Ok((next, camera))) => {
+                source = skip_whitespace(next);
+                camera
+            },
+            Some(Err(error)) => {
+                statements.recover(error)?;
+                source = skip_statement(source);
+                Camera::new(1.0)
+            },
+            None => {
+                statements.recover(ParseError::MissingCamera)?;
+                Camera::new(1.0)
+            },
         };
 
-    // Parse all materials
-    source = skip_comment(source)?;
-    while let Some(result) = parse_material(source) {
-        let (next, name, material) = result?;
-        materials.insert(name, material);
-        source = skip_whitespace(next);
-        source = skip_comment(source)?;
+    statements.parse(source, directory, options)?;
+
+    let scene = Scene {
+        camera,
+        spheres:    statements.spheres,
+        mesh:       Mesh::new(statements.triangles),
+        planes:     statements.planes,
+        resolution: statements.resolution,
+        sky:        statements.sky,
+        seed:       statements.seed,
+        ambient:    statements.ambient,
+    };
+    Ok((scene, statements.errors.unwrap_or_default()))
+}
+
+/// Skips past the next `;`, to where the next statement should start.
+fn skip_statement(source: &str) -> &str {
+    find(source, ";").map(|end| &end[1..]).unwrap_or("")
+}
+
+/// What's been parsed so far, shared between the files of a scene.
+struct Statements {
+    materials: HashMap<String, MaterialType>,
+    spheres:   Vec<Sphere>,
+    triangles: Vec<Triangle>,
+    planes:    Vec<Plane>,
+    resolution: Option<(usize, usize)>,
+    sky:       Option<Sky>,
+    seed:      Option<u64>,
+    ambient:   Option<Color>,
+    /// The combined transform of the blocks the current statement is in.
+    transform: Transform,
+    /// The files currently being parsed, from the outermost.
+    includes:  Vec<PathBuf>,
+    /// How many primitives the `repeat` blocks have copied so far.
+    repeated:  usize,
+    /// The errors of the statements that failed, if the parse shouldn't stop
+    /// at the first one.
+    errors:    Option<Vec<ParseError>>,
+}
+
+impl Statements {
+    fn parse(&mut self, source: &str, directory: &Path, options: &mut ParseOptions) -> Result<()> {
+        self.parse_block(source, directory, options, false).map(|_| ())
     }
 
-    // Parse all spheres.
-    while let Some(result) = parse_sphere(source, &materials) {
-        let (next, sphere) = result?;
-        spheres.push(sphere);
-        source = skip_whitespace(next);
-        source = skip_comment(source)?;
+    /// Parses the statements up to the end of `source`, or if `in_block`, up
+    /// to and including the `}` that ends the transform block.
+    fn parse_block<'a>(&mut self, mut source: &'a str, directory: &Path, options: &mut ParseOptions, in_block: bool) -> Result<&'a str> {
+        source = skip_comment(skip_whitespace(source))?;
+
+        loop {
+            if source.is_empty() {
+                if in_block {
+                    self.recover(ParseError::WrongSyntax)?;
+                }
+                return Ok(source);
+            }
+
+            let next =
+                if let Ok(next) = starts_with(source, "}") {
+                    if in_block {
+                        return Ok(next);
+                    }
+                    self.recover(ParseError::WrongSyntax)?;
+                    next
+                } else {
+                    match self.parse_statement(source, directory, options) {
+                        Ok(next) => next,
+                        Err(error) => {
+                            self.recover(error)?;
+                            skip_statement(source)
+                        },
+                    }
+                };
+
+            source = skip_comment(skip_whitespace(next))?;
+        }
     }
 
-    // Parse all triangles.
-    while let Some(result) = parse_triangle(source, &materials) {
-        let (next, triangle) = result?;
-        triangles.push(triangle);
-        source = skip_whitespace(next);
-        source = skip_comment(source)?;
+    /// Parses the statement at the start of `source`.
+    fn parse_statement<'a>(&mut self, source: &'a str, directory: &Path, options: &mut ParseOptions) -> Result<&'a str> {
+        let next =
+            if let Some(result) = parse_material(source, directory, options) {
+                let (next, name, material) = result?;
+                self.materials.insert(name.to_string(), material);
+                next
+            } else if let Some(result) = parse_sphere(source, &self.materials, directory, options) {
+                let (next, sphere) = result?;
+                self.spheres.push(self.transform.sphere(sphere));
+                next
+            } else if let Some(result) = parse_triangle(source, &self.materials, directory, options) {
+                let (next, triangle) = result?;
+                self.triangles.push(self.transform.triangle(triangle));
+                next
+            } else if let Some(result) = parse_quad(source, &self.materials, directory, options) {
+                let (next, [first, second]) = result?;
+                self.triangles.push(self.transform.triangle(first));
+                self.triangles.push(self.transform.triangle(second));
+                next
+            } else if let Some(result) = parse_floor(source) {
+                let (next, plane) = result?;
+                self.planes.push(self.transform.plane(plane));
+                next
+            } else if let Some(result) = parse_output(source) {
+                let (next, resolution) = result?;
+                self.resolution = Some(resolution);
+                next
+            } else if let Some(result) = parse_sky(source) {
+                let (next, sky) = result?;
+                self.sky = Some(sky);
+                next
+            } else if let Some(result) = parse_seed(source) {
+                let (next, seed) = result?;
+                self.seed = Some(seed);
+                next
+            } else if let Some(result) = parse_ambient(source) {
+                let (next, ambient) = result?;
+                self.ambient = Some(ambient);
+                next
+            } else if let Some(result) = parse_include(source) {
+                let (next, path) = result?;
+                self.include(&directory.join(path), options)?;
+                next
+            } else if let Some(result) = parse_transform(source) {
+                let (next, transform) = result?;
+                let outer = self.transform;
+                self.transform = outer.after(&transform);
+                let next = self.parse_block(next, directory, options, true);
+                self.transform = outer;
+                next?
+            } else if let Some(result) = parse_repeat(source) {
+                let (next, repeat) = result?;
+                self.repeat(next, repeat, directory, options)?
+            } else {
+                return Err(ParseError::WrongSyntax);
+            };
+        Ok(next)
     }
 
-    if !source.is_empty() {
-        Err(ParseError::WrongSyntax)
-    } else {
-        Ok((camera, spheres, Mesh::new(triangles)))
+    /// Parses the block, which starts at `source`, once as the first copy of
+    /// `repeat`, and then copies its primitives for the rest. So the files it
+    /// includes are only read once, and its errors are only reported once.
+    fn repeat<'a>(&mut self, source: &'a str, repeat: Repeat, directory: &Path, options: &mut ParseOptions) -> Result<&'a str> {
+        let (spheres, triangles, planes) = (self.spheres.len(), self.triangles.len(), self.planes.len());
+        let next = self.parse_block(source, directory, options, true)?;
+        let (spheres, triangles, planes) = (spheres..self.spheres.len(), triangles..self.triangles.len(), planes..self.planes.len());
+
+        let copies = (spheres.len() + triangles.len() + planes.len()).checked_mul(repeat.count - 1);
+        self.repeated = match copies.and_then(|copies| copies.checked_add(self.repeated)) {
+            Some(repeated) if repeated <= MAX_REPEATED_PRIMITIVES => repeated,
+            _ => return Err(ParseError::RepeatTooLarge),
+        };
+
+        for index in 1..repeat.count {
+            // The offset of the copy in the space of the enclosing transforms.
+            let copy = repeat.copy(index);
+            let offset = Transform { translation: self.transform.linear.mul_vec3(&copy.translation), ..copy };
+
+            for i in spheres.clone() { self.spheres.push(offset.sphere(self.spheres[i].clone())); }
+            for i in triangles.clone() { self.triangles.push(offset.triangle(self.triangles[i].clone())); }
+            for i in planes.clone() { self.planes.push(offset.plane(self.planes[i].clone())); }
+        }
+        Ok(next)
+    }
+
+    /// Collects `error` if the parse shouldn't stop at it, otherwise returns it.
+    fn recover(&mut self, error: ParseError) -> Result<()> {
+        match &mut self.errors {
+            Some(errors) => {
+                errors.push(error);
+                Ok(())
+            },
+            None => Err(error),
+        }
+    }
+
+    fn include(&mut self, path: &Path, options: &mut ParseOptions) -> Result<()> {
+        let path = path.canonicalize().map_err(|_| ParseError::CouldntOpenFile)?;
+        if self.includes.contains(&path) {
+            return Err(ParseError::IncludeCycle);
+        }
+        if self.includes.len() >= MAX_INCLUDE_DEPTH {
+            return Err(ParseError::IncludeTooDeep);
+        }
+
+        let source = std::fs::read_to_string(&path).map_err(|_| ParseError::CouldntOpenFile)?;
+        let directory = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+
+        self.includes.push(path);
+        let result = self.parse(&source, &directory, options);
+        self.includes.pop();
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Options, Ray, ray_trace};
+    use crate::image::Framebuffer;
+
+    const SOURCE: &str = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+sphere center 0.0 0.0 -1.0 radius 0.5 material RED;
+sphere center 0.0 1.0 -1.0 radius 0.5 material MISSING;
+";
+
+    #[test]
+    fn unknown_material_is_an_error_when_strict() {
+        assert!(parse_input(SOURCE).is_err());
+
+        let mut options = ParseOptions { strict_materials: true, logger: None };
+        assert!(parse_input_with_options(SOURCE, &mut options).is_err());
+    }
+
+    #[test]
+    fn unknown_material_uses_default_when_not_strict() {
+        let mut options = ParseOptions { strict_materials: false, logger: None };
+        let spheres = parse_input_with_options(SOURCE, &mut options).unwrap().spheres;
+
+        assert_eq!(spheres.len(), 2);
+        match spheres[0].material {
+            MaterialType::Diffuse(c) => assert_eq!((c.r, c.g, c.b), (1.0, 0.0, 0.0)),
+            _ => panic!("Expected the defined material."),
+        }
+        match spheres[1].material {
+            MaterialType::Diffuse(c) => assert_eq!((c.r, c.g, c.b), (1.0, 0.0, 1.0)),
+            _ => panic!("Expected the default material."),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parses_isotropic() {
+        let (source, name, material) = parse_material("material FOG : Isotropic color 0.9 0.9 0.9;", Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        assert!(source.is_empty());
+        assert_eq!(name, "FOG");
+        assert!(matches!(material, MaterialType::Isotropic(color) if (color.r, color.g, color.b) == (0.9, 0.9, 0.9)));
+    }
+
+    #[test]
+    fn parses_glossy_metal() {
+        let (source, name, material) = parse_material("material BRUSHED : GlossyMetal color 0.8 0.8 0.9 roughness 0.35;", Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        assert!(source.is_empty());
+        assert_eq!(name, "BRUSHED");
+        match material {
+            MaterialType::GlossyMetal { albedo, roughness } => {
+                assert_eq!((albedo.r, albedo.g, albedo.b), (0.8, 0.8, 0.9));
+                assert_eq!(roughness, 0.35);
+            },
+            _ => panic!("Expected a glossy metal."),
+        }
+    }
+
+    #[test]
+    fn parses_textured_with_mapping() {
+        let path = std::env::temp_dir().join(format!("raytracer_parses_textured_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+        crate::image::write_image(&crate::image::Framebuffer::new(2, 1), Some(path)).unwrap();
+
+        let source = format!("material EARTH : Textured file \"{}\" u_offset 0.25 rotation 90.0 flip_v filter bilinear wrap mirror;", path);
+        let result = parse_material(&source, Path::new(""), &mut ParseOptions::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let (source, name, material) = result.unwrap();
+        assert!(source.is_empty());
+        assert_eq!(name, "EARTH");
+        match material {
+            MaterialType::Textured { mapping, filter, wrap, .. } => {
+                assert_eq!(mapping, SphereMapping { u_offset: 0.25, rotation: 90.0, flip_v: true });
+                assert_eq!(filter, TextureFilter::Bilinear);
+                assert_eq!(wrap, WrapMode::Mirror);
+            },
+            _ => panic!("Expected a textured material."),
+        }
+    }
+
+    #[test]
+    fn cutout_quads_are_see_through() {
+        // Transparent on the left half, opaque on the right.
+        let path = std::env::temp_dir().join(format!("raytracer_cutout_quads_{}.pam", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut data = b"P7\nWIDTH 2\nHEIGHT 2\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n".to_vec();
+        data.extend([255, 255, 255, 0, 255, 255, 255, 255, 255, 255, 255, 0, 255, 255, 255, 255]);
+        std::fs::write(path, &data).unwrap();
+
+        let source = format!("camera origin 0.0 0.0 0.0 aspect 1.0;
+material LEAF : Cutout file \"{}\" threshold 0.5;
+quad v0 -1.0 -1.0 -2.0 v1 1.0 -1.0 -2.0 v2 1.0 1.0 -2.0 v3 -1.0 1.0 -2.0 material LEAF;
+quad v0 -9.0 -9.0 -4.0 v1 9.0 -9.0 -4.0 v2 9.0 9.0 -4.0 v3 -9.0 9.0 -4.0 material Diffuse color 0.5 0.5 0.5;
+", path);
+        let result = parse_input(&source);
+        std::fs::remove_file(path).unwrap();
+
+        let (_, world) = result.unwrap().into_world();
+        let depth_at = |x: f32, y: f32| world.hit(&Ray::new(Vec3::new_zero(), Vec3::new(x, y, -2.0).normalize())).unwrap().position.z;
+        // Through both triangles of the quad, which meet along y = x, on each side.
+        for (x, y) in [(-0.5, 0.5), (-0.25, -0.75)] {
+            assert!((depth_at(x, y) + 4.0).abs() < 1e-4, "({}, {}) isn't see-through", x, y);
+        }
+        for (x, y) in [(0.5, -0.5), (0.25, 0.75)] {
+            assert!((depth_at(x, y) + 2.0).abs() < 1e-4, "({}, {}) is see-through", x, y);
+        }
+    }
+
+    #[test]
+    fn textured_with_missing_file_is_an_error() {
+        let result = parse_material("material EARTH : Textured file \"does/not/exist.ppm\";", Path::new(""), &mut ParseOptions::default()).unwrap();
+        assert!(matches!(result, Err(ParseError::CouldntOpenFile)));
+    }
+
+    #[test]
+    fn textures_without_pixels_are_an_error() {
+        let path = std::env::temp_dir().join(format!("raytracer_textures_without_pixels_{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, b"P6 0 0 255\n").unwrap();
+
+        let textured = format!("material EARTH : Textured file \"{}\";", path);
+        let cutout = format!("material LEAF : Cutout file \"{}\" threshold 0.5;", path);
+        let textured = parse_material(&textured, Path::new(""), &mut ParseOptions::default()).unwrap();
+        let cutout = parse_material(&cutout, Path::new(""), &mut ParseOptions::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(textured, Err(ParseError::CouldntOpenFile)));
+        assert!(matches!(cutout, Err(ParseError::CouldntOpenFile)));
+    }
+
+    #[test]
+    fn parses_cutout() {
+        let path = std::env::temp_dir().join(format!("raytracer_parses_cutout_{}.pam", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\xff\xff\xff\0").unwrap();
+
+        let source = format!("material LEAF : Cutout file \"{}\" threshold 0.5;", path);
+        let result = parse_material(&source, Path::new(""), &mut ParseOptions::default()).unwrap();
+        let without_threshold = format!("material LEAF : Cutout file \"{}\";", path);
+        let missing = parse_material(&without_threshold, Path::new(""), &mut ParseOptions::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let (source, name, material) = result.unwrap();
+        assert!(source.is_empty());
+        assert_eq!(name, "LEAF");
+        match material {
+            MaterialType::Cutout { threshold, .. } => assert_eq!(threshold, 0.5),
+            _ => panic!("Expected a cutout material."),
+        }
+        assert!(material.cuts_out((0.5, 0.5)));
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn parses_floats() {
+        assert_eq!(parse_float("-1.5;").unwrap(), (";", -1.5));
+        assert_eq!(parse_float(".5 1").unwrap(), (" 1", 0.5));
+        assert_eq!(parse_float("2.").unwrap(), ("", 2.0));
+        assert_eq!(parse_float("3e-2,").unwrap(), (",", 0.03));
+        assert_eq!(parse_float("1E+3").unwrap(), ("", 1000.0));
+        // Not an exponent, so it's left for whatever comes next.
+        assert_eq!(parse_float("1end").unwrap(), ("end", 1.0));
+    }
+
+    #[test]
+    fn malformed_floats_are_errors() {
+        for source in ["--1.0", "1-2", "+.", "+1.0", "1.2.3", "-", ".", "-.e5", "1e5-"] {
+            assert!(matches!(parse_float(source), Err(ParseError::NotAF32)), "{:?} should be an error", source);
+        }
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        let (source, white) = parse_color("#ffffff;").unwrap();
+        assert_eq!(source, ";");
+        assert_eq!((white.r, white.g, white.b), (1.0, 1.0, 1.0));
+
+        let (_, black) = parse_color("#000000").unwrap();
+        assert_eq!((black.r, black.g, black.b), (0.0, 0.0, 0.0));
+
+        let (_, red) = parse_color("#FF0000").unwrap();
+        assert_eq!((red.r, red.g, red.b), (1.0, 0.0, 0.0));
+
+        assert!(parse_color("#fff").is_err());
+        assert!(parse_color("#gggggg").is_err());
+    }
+
+    #[test]
+    fn hex_colors_are_gamma_decoded() {
+        let (_, _, material) = parse_material("material GRAY : Diffuse color #808080;", Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        match material {
+            MaterialType::Diffuse(c) => assert!((c.r - (128.0f32 / 255.0).powi(2)).abs() < 1e-6),
+            _ => panic!("Expected a diffuse material."),
+        }
+    }
+
+    /// A fresh directory for the scene files of a test.
+    fn scene_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("raytracer_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn includes_share_materials() {
+        let directory = scene_directory("includes_share_materials");
+        std::fs::create_dir_all(directory.join("library")).unwrap();
+        std::fs::write(directory.join("library/materials.scene"), "material RED : Diffuse color 1.0 0.0 0.0;\ninclude \"more.scene\";\n").unwrap();
+        std::fs::write(directory.join("library/more.scene"), "material BLUE : Diffuse color 0.0 0.0 1.0;\n").unwrap();
+        std::fs::write(directory.join("main.scene"), "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+include \"library/materials.scene\";
+sphere center 0.0 0.0 -1.0 radius 0.5 material RED;
+sphere center 0.0 1.0 -1.0 radius 0.5 material BLUE;
+").unwrap();
+
+        let result = parse_file(directory.join("main.scene"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let spheres = result.unwrap().spheres;
+        assert_eq!(spheres.len(), 2);
+        match spheres[1].material {
+            MaterialType::Diffuse(c) => assert_eq!((c.r, c.g, c.b), (0.0, 0.0, 1.0)),
+            _ => panic!("Expected the included material."),
+        }
+    }
+
+    #[test]
+    fn textures_are_found_next_to_the_scene() {
+        let directory = scene_directory("textures_are_found_next_to_the_scene");
+        std::fs::create_dir_all(directory.join("library")).unwrap();
+        std::fs::write(directory.join("library/earth.ppm"), b"P6 1 1 255\n\xff\0\0").unwrap();
+        std::fs::write(directory.join("library/leaf.pam"), b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\xff\xff\xff\xff").unwrap();
+        std::fs::write(directory.join("library/materials.scene"), "\
+material EARTH : Textured file \"earth.ppm\";
+material LEAF : Cutout file \"leaf.pam\" threshold 0.5;
+").unwrap();
+        std::fs::write(directory.join("main.scene"), "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+include \"library/materials.scene\";
+sphere center 0.0 0.0 -1.0 radius 0.5 material EARTH;
+sphere center 0.0 1.0 -1.0 radius 0.5 material Textured file \"library/earth.ppm\";
+").unwrap();
+
+        let result = parse_file(directory.join("main.scene"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let spheres = result.unwrap().spheres;
+        assert_eq!(spheres.len(), 2);
+        assert!(spheres.iter().all(|sphere| sphere.material.albedo((0.5, 0.5)).r == 1.0));
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let directory = scene_directory("include_cycle_is_an_error");
+        std::fs::write(directory.join("a.scene"), "include \"b.scene\";\n").unwrap();
+        std::fs::write(directory.join("b.scene"), "include \"a.scene\";\n").unwrap();
+        std::fs::write(directory.join("main.scene"), "camera origin 0.0 0.0 0.0 aspect 1.0;\ninclude \"a.scene\";\n").unwrap();
+
+        let result = parse_file(directory.join("main.scene"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(matches!(result, Err(ParseError::IncludeCycle)));
+    }
+
+    #[test]
+    fn parses_emission() {
+        let (_, _, material) = parse_material("material LAMP : Emission temperature 6500 strength 4.0;", Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        match material {
+            MaterialType::Emission { color: c, two_sided } => {
+                assert!(two_sided);
+                let expected = Color::from_blackbody(6500.0);
+                assert!((c.r - 4.0 * expected.r).abs() < 1e-5 && (c.b - 4.0 * expected.b).abs() < 1e-5);
+            },
+            _ => panic!("Expected an emission material."),
+        }
+
+        let (_, _, material) = parse_material("material LAMP : Emission color 2.0 1.0 0.5;", Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        match material {
+            MaterialType::Emission { color: c, .. } => assert_eq!((c.r, c.g, c.b), (2.0, 1.0, 0.5)),
+            _ => panic!("Expected an emission material."),
+        }
+
+        let (_, _, material) = parse_material("material CEILING : Emission color 4.0 4.0 4.0 strength 2.0 one_sided;", Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        assert!(matches!(material, MaterialType::Emission { two_sided: false, .. }));
+    }
+
+    #[test]
+    fn parses_orthographic_camera() {
+        let (_, camera) = parse_camera("camera origin 0.0 0.0 0.0 aspect 1.5 orthographic scale 3.0;").unwrap().unwrap();
+        assert_eq!(camera.projection(), Projection::Orthographic { scale: 3.0 });
+
+        let (_, camera) = parse_camera("camera origin 0.0 0.0 0.0 aspect 1.5;").unwrap().unwrap();
+        assert_eq!(camera.projection(), Projection::Perspective);
+    }
+
+    #[test]
+    fn named_objects_keep_their_names() {
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+sphere center 0.0 0.0 -1.0 radius 0.5 material RED name left_ball;
+sphere center 0.0 1.0 -1.0 radius 0.5 material RED;
+triangle v0 0.0 0.0 -2.0 v1 1.0 0.0 -2.0 v2 0.0 1.0 -2.0 material RED name wedge;
+";
+        let Scene { spheres, mesh, .. } = parse_input(source).unwrap();
+        assert_eq!(spheres[0].name.as_deref(), Some("left_ball"));
+        assert_eq!(spheres[1].name, None);
+
+        let world = crate::common::World::new(spheres, vec![mesh]);
+        assert_eq!(world.name_of(1), Some("left_ball"));
+        assert_eq!(world.name_of(2), None);
+        assert_eq!(world.name_of(3), Some("wedge"));
+        assert_eq!(world.name_of(0), None);
+        assert_eq!(world.name_of(4), None);
+
+        assert!(parse_input("camera origin 0.0 0.0 0.0 aspect 1.0; material RED : Diffuse color 1.0 0.0 0.0; sphere center 0.0 0.0 -1.0 radius 0.5 material RED name ;").is_err());
+    }
+
+    #[test]
+    fn quads_are_hit_anywhere() {
+        use crate::common::{World, Ray};
+        use crate::maths::{NVec3, IVector};
+
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+quad v0 0.0 0.0 -1.0 v1 1.0 0.0 -1.0 v2 1.0 1.0 -1.0 v3 0.0 1.0 -1.0 material RED;
+";
+        let Scene { spheres, mesh, .. } = parse_input(source).unwrap();
+        let world = World::new(spheres, vec![mesh]);
+
+        let forward = NVec3::new(0.0, 0.0, -1.0);
+        for (x, y) in [(0.1, 0.1), (0.9, 0.1), (0.9, 0.9), (0.1, 0.9), (0.5, 0.5), (0.3, 0.7)] {
+            let ray = Ray::new(Vec3::new(x, y, 0.0), forward);
+            let hit = world.hit(&ray).expect("Should hit the quad!");
+            assert!((hit.t - 1.0).abs() < 1e-5 && hit.normal.z() > 0.99);
+        }
+        let ray = Ray::new(Vec3::new(1.5, 0.5, 0.0), forward);
+        assert!(world.hit(&ray).is_none());
+    }
+
+    #[test]
+    fn vectors_can_have_commas_and_comments() {
+        let (rest, v) = parse_vec3("1.0, 2.0, 3.0;").unwrap();
+        assert_eq!((v.x, v.y, v.z, rest), (1.0, 2.0, 3.0, ";"));
+
+        let (rest, v) = parse_vec3("1.0 2.0 3.0 material").unwrap();
+        assert_eq!((v.x, v.y, v.z, rest), (1.0, 2.0, 3.0, " material"));
+
+        let (_, v) = parse_vec3("1.0  // x\n  -2.0, // y\n  // and z\n  3.5").unwrap();
+        assert_eq!((v.x, v.y, v.z), (1.0, -2.0, 3.5));
+
+        assert!(parse_vec3("1.0,, 2.0, 3.0").is_err());
+    }
+
+    #[test]
+    fn vectors_can_be_whole_numbers() {
+        let (rest, v) = parse_vec3("0 0 -1;").unwrap();
+        assert_eq!((v.x, v.y, v.z, rest), (0.0, 0.0, -1.0, ";"));
+
+        let (rest, v) = parse_vec3("5 -3 2").unwrap();
+        assert_eq!((v.x, v.y, v.z, rest), (5.0, -3.0, 2.0, ""));
+
+        let scene = parse_input("camera origin 0 0 0 aspect 1; sphere center 0 0 -1 radius 1 material Diffuse color 1 0 0;").unwrap();
+        assert_eq!((scene.spheres[0].center, scene.spheres[0].radius), (Vec3::new(0.0, 0.0, -1.0), 1.0));
+    }
+
+    #[test]
+    fn file_can_end_with_a_comment() {
+        assert_eq!(skip_comment("// The end").unwrap(), "");
+        assert_eq!(skip_comment("// One\n// Two").unwrap(), "");
+
+        let source = "camera origin 0.0 0.0 0.0 aspect 1.0;\nmaterial RED : Diffuse color 1.0 0.0 0.0;\nsphere center 0.0 0.0 -1.0 radius 0.5 material RED;\n// No newline after this";
+        let spheres = parse_input(source).unwrap().spheres;
+        assert_eq!(spheres.len(), 1);
+    }
+
+    #[test]
+    fn output_directive_suggests_a_resolution() {
+        let camera = "camera origin 0.0 0.0 0.0 aspect 2.0;\n";
+        assert_eq!(parse_input(camera).unwrap().resolution, None);
+
+        let scene = parse_input(&format!("{}output width 640 height 320;\n", camera)).unwrap();
+        assert_eq!(scene.resolution, Some((640, 320)));
+
+        assert!(parse_input(&format!("{}output width 0 height 320;", camera)).is_err());
+        assert!(parse_input(&format!("{}output width -640 height 320;", camera)).is_err());
+        assert!(parse_input(&format!("{}output width 640;", camera)).is_err());
+    }
+
+    #[test]
+    fn integers_can_have_underscores() {
+        assert_eq!(parse_int("1_000;").unwrap(), (";", 1000));
+        assert_eq!(parse_int("1_000_000").unwrap(), ("", 1_000_000));
+        assert_eq!(parse_int("42 rest").unwrap(), (" rest", 42));
+
+        assert!(parse_int("_1").is_err());
+        assert!(parse_int("1_").is_err());
+        assert!(parse_int("1__0").is_err());
+        assert!(parse_int("").is_err());
+    }
+
+    /// A logger that can be read after it's given to the parser.
+    #[derive(Clone, Default)]
+    struct SharedLog(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+    impl Write for SharedLog {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> { self.0.borrow_mut().write(buffer) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+    impl SharedLog {
+        fn contents(&self) -> String { String::from_utf8(self.0.borrow().clone()).unwrap() }
+    }
+
+    #[test]
+    fn reflectance_is_clamped_but_emission_isnt() {
+        let log = SharedLog::default();
+        let mut options = ParseOptions { strict_materials: true, logger: Some(Box::new(log.clone())) };
+
+        let (_, _, material) = parse_material("material BRIGHT : Diffuse color 2.0 0.5 -1.0;", Path::new(""), &mut options).unwrap().unwrap();
+        match material {
+            MaterialType::Diffuse(c) => assert_eq!((c.r, c.g, c.b), (1.0, 0.5, 0.0)),
+            _ => panic!("Expected a diffuse material."),
+        }
+        assert!(log.contents().contains("Warning"), "{}", log.contents());
+
+        let log = SharedLog::default();
+        let mut options = ParseOptions { strict_materials: true, logger: Some(Box::new(log.clone())) };
+        let (_, _, material) = parse_material("material LAMP : Emission color 10.0 10.0 10.0;", Path::new(""), &mut options).unwrap().unwrap();
+        match material {
+            MaterialType::Emission { color: c, .. } => assert_eq!((c.r, c.g, c.b), (10.0, 10.0, 10.0)),
+            _ => panic!("Expected an emission material."),
+        }
+        parse_material("material GRAY : Metal color 0.5 0.5 0.5 fuzz 0.1;", Path::new(""), &mut options).unwrap().unwrap();
+        assert_eq!(log.contents(), "");
+    }
+
+    #[test]
+    fn fields_can_be_in_any_order() {
+        let source = "camera origin 0.0 0.0 0.0 aspect 1.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+sphere material RED radius 1.0 center 0.0 0.0 -1.0;
+sphere name ball center 0.0 2.0 -1.0 material RED radius 0.5;
+triangle v2 0.0 1.0 -2.0 material RED v0 0.0 0.0 -2.0 v1 1.0 0.0 -2.0;
+";
+        let Scene { spheres, mesh, .. } = parse_input(source).unwrap();
+        assert_eq!((spheres[0].radius, spheres[0].center.z), (1.0, -1.0));
+        assert_eq!((spheres[1].radius, spheres[1].center.y), (0.5, 2.0));
+        assert_eq!(spheres[1].name.as_deref(), Some("ball"));
+
+        let [v0, v1, v2] = mesh.triangles()[0].vertices();
+        assert_eq!((v0.x, v1.x, v2.y), (0.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn triangles_can_have_vertex_colors() {
+        let source = "camera origin 0.0 0.0 0.0 aspect 1.0;
+material WHITE : Diffuse color 1.0 1.0 1.0;
+triangle v0 0.0 0.0 -2.0 v1 1.0 0.0 -2.0 v2 0.0 1.0 -2.0 material WHITE colors 1.0 0.0 0.0 #00ff00 0.0 0.0 1.0;
+";
+        let Scene { mesh, .. } = parse_input(source).unwrap();
+        let [red, green, blue] = mesh.triangles()[0].colors().unwrap();
+        assert_eq!((red.r, green.g, blue.b), (1.0, 1.0, 1.0));
+        assert_eq!((red.g, green.r, blue.g), (0.0, 0.0, 0.0));
+
+        let source = "triangle v0 0.0 0.0 -2.0 v1 1.0 0.0 -2.0 v2 0.0 1.0 -2.0 material RED colors 1.0 0.0 0.0;";
+        let materials: HashMap<String, MaterialType> = vec![(String::from("RED"), DEFAULT_MATERIAL)].into_iter().collect();
+        assert!(parse_triangle(source, &materials, Path::new(""), &mut ParseOptions::default()).unwrap().is_err());
+    }
+
+    #[test]
+    fn fields_must_be_given_once() {
+        let materials: HashMap<String, MaterialType> = vec![(String::from("RED"), DEFAULT_MATERIAL)].into_iter().collect();
+        let sphere = |source| parse_sphere(source, &materials, Path::new(""), &mut ParseOptions::default()).unwrap().map(|_| ());
+
+        assert!(matches!(sphere("sphere center 0.0 0.0 -1.0 material RED;"), Err(ParseError::MissingField("radius"))));
+        assert!(matches!(sphere("sphere radius 1.0 center 0.0 0.0 -1.0;"), Err(ParseError::MissingField("material"))));
+        assert!(matches!(sphere("sphere radius 1.0 radius 2.0 center 0.0 0.0 -1.0 material RED;"), Err(ParseError::DuplicateField("radius"))));
+        assert!(matches!(sphere("sphere radius 1.0 center 0.0 0.0 -1.0 material RED color 1.0;"), Err(ParseError::WrongSyntax)));
+        assert!(sphere("sphere radius 1.0 center 0.0 0.0 -1.0 material RED").is_err());
+        assert!(sphere("sphere radius 1.0 center 0.0 0.0 -1.0 material RED;").is_ok());
+    }
+
+    #[test]
+    fn parses_visibility_flags() {
+        let materials: HashMap<String, MaterialType> = vec![(String::from("RED"), DEFAULT_MATERIAL)].into_iter().collect();
+        let sphere = |source| parse_sphere(source, &materials, Path::new(""), &mut ParseOptions::default()).unwrap().map(|(_, sphere)| sphere.visibility);
+
+        assert_eq!(sphere("sphere center 0.0 0.0 -1.0 radius 1.0 material RED;").unwrap(), Visibility::default());
+        assert_eq!(sphere("sphere hidden center 0.0 0.0 -1.0 radius 1.0 material RED;").unwrap(), Visibility { visible_to_camera: false, casts_shadow: true });
+        assert_eq!(sphere("sphere center 0.0 0.0 -1.0 radius 1.0 material RED noshadow hidden;").unwrap(), Visibility { visible_to_camera: false, casts_shadow: false });
+        assert!(matches!(sphere("sphere center 0.0 0.0 -1.0 radius 1.0 material RED hidden hidden;"), Err(ParseError::DuplicateField("hidden"))));
+
+        let (_, [first, second]) = parse_quad("quad v0 0 0 0 v1 1 0 0 v2 1 1 0 v3 0 1 0 material RED noshadow;", &materials, Path::new(""), &mut ParseOptions::default()).unwrap().unwrap();
+        assert!(!first.visibility().casts_shadow && !second.visibility().casts_shadow);
+    }
+
+    #[test]
+    fn transform_blocks_move_their_primitives() {
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+transform translate 1.0 2.0 3.0 {
+    triangle v0 0.0 0.0 0.0 v1 1.0 0.0 0.0 v2 0.0 1.0 0.0 material RED;
+    transform scale 2.0 rotate_y 90.0 {
+        sphere center 1.0 0.0 0.0 radius 0.5 material RED;
+    }
+}
+sphere center 1.0 0.0 0.0 radius 0.5 material RED;
+";
+        let Scene { spheres, mesh, .. } = parse_input(source).unwrap();
+
+        let [v0, v1, v2] = mesh.triangles()[0].vertices();
+        assert_eq!(v0, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(v1, Vec3::new(2.0, 2.0, 3.0));
+        assert_eq!(v2, Vec3::new(1.0, 3.0, 3.0));
+
+        // Scaled to (2, 0, 0), rotated to (0, 0, -2) and then translated.
+        assert!((spheres[0].center - Vec3::new(1.0, 2.0, 1.0)).length() < 1e-5);
+        assert!((spheres[0].radius - 1.0).abs() < 1e-6);
+        assert_eq!(spheres[1].center, Vec3::new(1.0, 0.0, 0.0));
+
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        assert!(parse_input(&format!("{} transform translate 1.0 0.0 0.0 {{", camera)).is_err());
+        assert!(parse_input(&format!("{} }}", camera)).is_err());
+        assert!(parse_input(&format!("{} transform scale -1.0 {{ }}", camera)).is_err());
+        assert!(parse_input(&format!("{} transform shear 1.0 {{ }}", camera)).is_err());
+    }
+
+    #[test]
+    fn repeat_blocks_copy_their_primitives() {
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material m : Diffuse color 1.0 0.0 0.0;
+repeat 3 along 1 0 0 step 2 { sphere center 0 0 0 radius 0.5 material m; }
+transform translate 0.0 1.0 0.0 {
+    repeat 2 along 0.0 0.0 1.0 step -1.5 {
+        triangle v0 0.0 0.0 0.0 v1 1.0 0.0 0.0 v2 0.0 1.0 0.0 material m;
+    }
+}
+";
+        let Scene { spheres, mesh, .. } = parse_input(source).unwrap();
+        let xs: Vec<f32> = spheres.iter().map(|sphere| sphere.center.x).collect();
+        assert_eq!(xs, vec![0.0, 2.0, 4.0]);
+
+        let v0s: Vec<Vec3> = mesh.triangles().iter().map(|triangle| triangle.vertices()[0]).collect();
+        assert_eq!(v0s, vec![Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, -1.5)]);
+
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        assert!(parse_input(&format!("{} repeat 0 along 1.0 0.0 0.0 step 1.0 {{ }}", camera)).is_err());
+        assert!(parse_input(&format!("{} repeat 2 along 1.0 0.0 0.0 {{ }}", camera)).is_err());
+        assert!(parse_input(&format!("{} repeat 2 along 1.0 0.0 0.0 step 1.0 {{", camera)).is_err());
+
+        // An error in the block is only reported once.
+        let (scene, errors) = parse_all(&format!("{} repeat 4 along 1.0 0.0 0.0 step 1.0 {{ sphere radius 1.0; sphere center 0 0 0 radius 0.5 material Diffuse color 1.0 1.0 1.0; }}", camera));
+        assert_eq!((scene.spheres.len(), errors.len()), (4, 1));
+
+        let too_many = format!("{} repeat {} along 1.0 0.0 0.0 step 1.0 {{ }}", camera, MAX_REPEAT_COUNT + 1);
+        assert!(matches!(parse_input(&too_many), Err(ParseError::RepeatTooLarge)));
+        assert!(matches!(parse_input(&format!("{} repeat 1000000000 along 1.0 0.0 0.0 step 1.0 {{ }}", camera)), Err(ParseError::RepeatTooLarge)));
+    }
+
+    #[test]
+    fn nested_repeats_are_limited_in_total() {
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        let sphere = "sphere center 0 0 0 radius 0.5 material Diffuse color 1.0 1.0 1.0;";
+        let nested = |outer: usize, inner: usize| format!(
+            "{} repeat {} along 1.0 0.0 0.0 step 1.0 {{ repeat {} along 0.0 1.0 0.0 step 1.0 {{ {} }} }}", camera, outer, inner, sphere
+        );
+
+        assert_eq!(parse_input(&nested(20, 30)).unwrap().spheres.len(), 600);
+        assert!(matches!(parse_input(&nested(MAX_REPEAT_COUNT, MAX_REPEAT_COUNT)), Err(ParseError::RepeatTooLarge)));
+
+        // Side by side, each within the limit, but not together.
+        let half = format!("repeat {} along 1.0 0.0 0.0 step 1.0 {{ repeat 100 along 0.0 1.0 0.0 step 1.0 {{ {} }} }}", MAX_REPEATED_PRIMITIVES / 150, sphere);
+        assert!(parse_input(&format!("{} {}", camera, half)).is_ok());
+        assert!(matches!(parse_input(&format!("{} {} {}", camera, half, half)), Err(ParseError::RepeatTooLarge)));
+    }
+
+    #[test]
+    fn repeat_copies_follow_the_enclosing_transforms() {
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+transform translate 0.0 0.0 -5.0 {
+    transform scale 2.0 rotate_y 90.0 {
+        repeat 3 along 1.0 0.0 0.0 step 1.0 {
+            sphere center 0 0 0 radius 0.5 material Diffuse color 1.0 1.0 1.0;
+            repeat 2 along 0.0 1.0 0.0 step 1.0 { floor checker color 1.0 1.0 1.0 color 0.0 0.0 0.0 scale 1.0; }
+        }
+    }
+}
+";
+        let Scene { spheres, planes, .. } = parse_input(source).unwrap();
+        assert_eq!((spheres.len(), planes.len()), (3, 6));
+        for (index, sphere) in spheres.iter().enumerate() {
+            // Along x, rotated to -z and scaled by 2.
+            let expected = Vec3::new(0.0, 0.0, -5.0 - 2.0 * index as f32);
+            assert!((sphere.center - expected).length() < 1e-4, "{:?}", sphere.center);
+            assert_eq!(sphere.radius, 1.0);
+        }
+        let heights: Vec<f32> = planes.iter().map(|plane| plane.point.y).collect();
+        assert_eq!(heights, vec![0.0, 2.0, 0.0, 2.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn parse_all_collects_errors_and_keeps_going() {
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 2.0;
+material RED : Diffuse color 1.0 0.0 0.0;
+sphere center 0.0 0.0 -1.0 material RED;
+cylinder radius 1.0;
+sphere center 0.0 0.0 -1.0 radius 0.5 material MISSING;
+transform translate 0.0 1.0 0.0 {
+    sphere center 0.0 0.0 -1.0 radius 0.5 material RED;
+    sphere radius 0.5 material RED;
+}
+sphere center 0.0 0.0 -2.0 radius 0.5 material RED;
+";
+        assert!(parse_input(source).is_err());
+
+        let (scene, errors) = parse_all(source);
+        assert_eq!(errors.len(), 4);
+        assert!(matches!(errors[0], ParseError::MissingField("radius")));
+        assert!(matches!(errors[3], ParseError::MissingField("center")));
+        assert_eq!(scene.spheres.len(), 2);
+        assert_eq!(scene.spheres[0].center, Vec3::new(0.0, 1.0, -1.0));
+        assert_eq!(scene.spheres[1].center, Vec3::new(0.0, 0.0, -2.0));
+
+        let (scene, errors) = parse_all("sphere center 0.0 0.0 -1.0 radius 0.5 material RED; }");
+        assert!(matches!(errors[..], [ParseError::MissingCamera, ParseError::WrongSyntax, ParseError::WrongSyntax]));
+        assert!(scene.spheres.is_empty());
+
+        let (_, errors) = parse_all(SOURCE.replace("MISSING", "RED").as_str());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn primitives_can_have_inline_materials() {
+        let source = "\
+camera origin 0.0 0.0 0.0 aspect 1.0;
+material Metallic : Diffuse color 0.1 0.2 0.3;
+sphere center 0.0 0.0 -1.0 radius 0.5 material Diffuse color 0.8 0.2 0.2 name RED;
+sphere material Metal color 0.9 0.9 0.9 fuzz 0.1 center 0.0 1.0 -1.0 radius 0.5;
+sphere center 0.0 2.0 -1.0 radius 0.5 material Metallic;
+triangle v0 0.0 0.0 -2.0 v1 1.0 0.0 -2.0 v2 0.0 1.0 -2.0 material Emission color 1.0 1.0 1.0 one_sided;
+";
+        let Scene { spheres, mesh, .. } = parse_input(source).unwrap();
+
+        assert!(matches!(spheres[0].material, MaterialType::Diffuse(c) if (c.r, c.g, c.b) == (0.8, 0.2, 0.2)));
+        assert_eq!(spheres[0].name.as_deref(), Some("RED"));
+        assert!(matches!(spheres[1].material, MaterialType::Metal(c, fuzz) if c.r == 0.9 && fuzz == 0.1));
+        // A defined name wins over a material type it starts with.
+        assert!(matches!(spheres[2].material, MaterialType::Diffuse(c) if c.b == 0.3));
+        assert!(matches!(mesh.triangles()[0].material(), MaterialType::Emission { two_sided: false, .. }));
+
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        assert!(parse_input(&format!("{} sphere center 0.0 0.0 -1.0 radius 0.5 material Metal color 0.9 0.9 0.9;", camera)).is_err());
+        assert!(matches!(
+            parse_input(&format!("{} sphere center 0.0 0.0 -1.0 radius 0.5 material Dielectric ir 1.5 material Dielectric ir 1.3;", camera)),
+            Err(ParseError::DuplicateField("material"))
+        ));
+    }
+
+    #[test]
+    fn identifiers_must_start_with_a_letter() {
+        assert_eq!(get_identifier("RED_2;").unwrap(), (";", "RED_2"));
+        assert_eq!(get_identifier("_private").unwrap(), ("", "_private"));
+        assert!(matches!(get_identifier(" RED"), Err(ParseError::ExpectedIdentifier(Some(' ')))));
+        assert!(matches!(get_identifier("2RED"), Err(ParseError::ExpectedIdentifier(Some('2')))));
+        assert!(matches!(get_identifier(""), Err(ParseError::ExpectedIdentifier(None))));
+
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        assert!(matches!(
+            parse_input(&format!("{} material : Diffuse color 1.0 0.0 0.0;", camera)),
+            Err(ParseError::ExpectedIdentifier(Some(':')))
+        ));
+        assert!(matches!(
+            parse_input(&format!("{} material 1RED : Diffuse color 1.0 0.0 0.0;", camera)),
+            Err(ParseError::ExpectedIdentifier(Some('1')))
+        ));
+        assert!(matches!(
+            parse_input(&format!("{} sphere center 0.0 0.0 -1.0 radius 0.5 material ;", camera)),
+            Err(ParseError::ExpectedIdentifier(Some(';')))
+        ));
+    }
+
+    #[test]
+    fn sky_directive_sets_the_background() {
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        assert!(parse_input(camera).unwrap().sky.is_none());
+        assert!(matches!(parse_input(&format!("{} sky none;", camera)).unwrap().sky, Some(Sky::None)));
+
+        let sky = parse_input(&format!("{} sky color 0.1 0.2 0.3;", camera)).unwrap().sky;
+        assert!(matches!(sky, Some(Sky::Color(c)) if (c.r, c.g, c.b) == (0.1, 0.2, 0.3)));
+        assert!(parse_input(&format!("{} sky gradient;", camera)).is_err());
+    }
+
+    #[test]
+    fn ambient_directive_sets_the_ambient_light() {
+        let camera = "camera origin 0.0 0.0 0.0 aspect 1.0;";
+        assert!(parse_input(camera).unwrap().ambient.is_none());
+
+        let ambient = parse_input(&format!("{} ambient color 0.1 0.2 0.3;", camera)).unwrap().ambient;
+        assert!(matches!(ambient, Some(c) if (c.r, c.g, c.b) == (0.1, 0.2, 0.3)));
+        assert!(parse_input(&format!("{} ambient 0.1 0.2 0.3;", camera)).is_err());
+    }
+
+    #[test]
+    fn seeded_scenes_render_the_same_noise() {
+        let scene = |seed: &str| format!("camera origin 0.0 0.0 0.0 aspect 1.0;
+{}
+material GRAY : Diffuse color 0.5 0.5 0.5;
+sphere center 0.0 0.0 -1.0 radius 0.5 material GRAY;
+", seed);
+        let render = |source: &str| -> Vec<(u8, u8, u8, u8)> {
+            let scene = parse_input(source).unwrap();
+            let mut options = Options { seed: scene.seed.unwrap_or_default(), ..Options::new(2, 4, None, true) };
+            let (camera, world) = scene.into_world();
+            ray_trace(&world, &camera, Framebuffer::new(8, 8), &mut options).pixels.iter().map(|p| (p.r, p.g, p.b, p.a)).collect()
+        };
+
+        assert_eq!(parse_input(&scene("seed 1_234;")).unwrap().seed, Some(1234));
+        assert!(parse_input(&scene("")).unwrap().seed.is_none());
+        assert!(parse_input(&scene("seed -1;")).is_err());
+
+        let seeded = scene("seed 42;");
+        assert_eq!(render(&seeded), render(&seeded));
+        assert_ne!(render(&seeded), render(&scene("seed 43;")));
+    }
+
+    #[test]
+    fn floor_appears_below_the_camera() {
+        let source = "camera origin 0.0 0.0 0.0 aspect 1.0;\nfloor checker color 1.0 1.0 1.0 color #202020 scale 0.5 at -1.0;";
+        let scene = parse_input(source).unwrap();
+        assert!(matches!(scene.planes[0].material, MaterialType::Checker { scale, .. } if scale == 0.5));
+
+        let (camera, world) = scene.into_world();
+        let (position, normal, _) = world.trace_primary(&camera, 0.5, 0.1).expect("Should hit the floor!");
+        assert!((position.y() + 1.0).abs() < 1e-4 && normal.y() > 0.99);
+        assert!(world.trace_primary(&camera, 0.5, 0.9).is_none());
+
+        assert!(parse_input("camera origin 0.0 0.0 0.0 aspect 1.0; floor checker color 1.0 1.0 1.0 scale 1.0;").is_err());
+        assert!(parse_input("camera origin 0.0 0.0 0.0 aspect 1.0; floor checker color 1.0 1.0 1.0 color 0.0 0.0 0.0 scale 0.0;").is_err());
+    }
+}
@@ -1,9 +1,30 @@
 use std::num::{Wrapping, NonZeroU32};
+use std::convert::TryFrom;
 
 pub struct Random {
     state: Wrapping<u32>,
 }
 
+/// The dimensions of a pixel sample that get their own generator, see
+/// `Random::for_pixel_stream`. If two dimensions drew from the same
+/// sequence, e.g. the offset of a sample in the pixel and its point on the
+/// lens, they'd be correlated and the noise would get a structure.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SampleStream {
+    /// Everything along the path: scattering, light sampling and roulette.
+    Paths,
+    /// The offset of the sample within the pixel.
+    Jitter,
+    /// The point on the lens, for cameras with an aperture.
+    Lens,
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Random {
     pub fn new() -> Random {
         Self::new_with_seed(NonZeroU32::new(2547549).unwrap())
@@ -11,6 +32,20 @@ impl Random {
     pub fn new_with_seed(seed: NonZeroU32) -> Random {
         Self { state: Wrapping(seed.get()) }
     }
+    /// A generator for the pixel at (`row`, `column`), whose sequence only
+    /// depends on the coordinates and the global `seed`. This keeps renders
+    /// the same no matter in which order, or on which thread, pixels are traced.
+    pub fn for_pixel(seed: u64, row: usize, column: usize) -> Random {
+        Self::for_pixel_stream(seed, row, column, SampleStream::Paths)
+    }
+    /// Same as `for_pixel`, but with an independent sequence for each
+    /// `stream` of the pixel. The `Paths` stream is the one of `for_pixel`.
+    pub fn for_pixel_stream(seed: u64, row: usize, column: usize, stream: SampleStream) -> Random {
+        let key = seed ^ split_mix_64((row as u64) << 32 | column as u64 & 0xFFFF_FFFF);
+        let hash = split_mix_64(key.wrapping_add((stream as u64).wrapping_mul(0xD1B5_4A32_D192_ED03)));
+        let state = (hash ^ (hash >> 32)) as u32;
+        Self::new_with_seed(NonZeroU32::new(state).unwrap_or(NonZeroU32::MIN))
+    }
     /// Random number between [0, 1].
     pub fn random_f32(&mut self) -> f32 {
         self.xor_shift_32() as f32 / u32::MAX as f32
@@ -19,6 +54,32 @@ impl Random {
     pub fn random_bilateral_f32(&mut self) -> f32 {
         self.random_f32() * 2.0 - 1.0
     }
+    /// Random number between [`min`, `max`].
+    pub fn random_range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.random_f32()
+    }
+    /// Random bits. The generator never gives 0, so this is in [1, u32::MAX].
+    pub fn random_u32(&mut self) -> u32 {
+        self.xor_shift_32()
+    }
+    /// Random index in [0, `n`), where each is equally likely. Panics if `n`
+    /// is 0 or doesn't fit in a `u32`.
+    pub fn random_index(&mut self, n: usize) -> usize {
+        assert!(n > 0, "Can't pick an index out of nothing!");
+        let n = u32::try_from(n).expect("Too many indices to pick from!") as u64;
+
+        // The u32s are in [1, u32::MAX], so there are u32::MAX of them. Taking
+        // them modulo `n` would make the smaller indices more likely, unless
+        // the ones above the last whole multiple of `n` are thrown away.
+        let count = u32::MAX as u64;
+        let limit = count - count % n;
+        loop {
+            let x = (self.xor_shift_32() - 1) as u64;
+            if x < limit {
+                return (x % n) as usize;
+            }
+        }
+    }
     fn xor_shift_32(&mut self) -> u32
     {
         let mut x = self.state;
@@ -30,22 +91,105 @@ impl Random {
     }
 }
 
+/// The finalizer of SplitMix64, which spreads nearby inputs over the whole range.
+fn split_mix_64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_is_between_0_and_1() {
         let x = u32::MAX as f32 / u32::MAX as f32;
-        assert!(0.0 <= x && x <= 1.0);
+        assert!((0.0..=1.0).contains(&x));
 
         let y = 0.0 / u32::MAX as f32;
-        assert!(0.0 <= y && y <= 1.0);
+        assert!((0.0..=1.0).contains(&y));
     }
     #[test]
     fn test_is_between_minus_1_and_1() {
         let x = (u32::MAX as f32 / u32::MAX as f32) * 2.0 - 1.0;
-        assert!(-1.0 <= x && x <= 1.0);
+        assert!((-1.0..=1.0).contains(&x));
 
         let y = (0.0 / u32::MAX as f32) * 2.0 - 1.0;
-        assert!(-1.0 <= y && y <= 1.0);
+        assert!((-1.0..=1.0).contains(&y));
     }
-}
\ No newline at end of file
+    #[test]
+    fn pixel_generators_are_reproducible_and_distinct() {
+        let first = |seed, row, column| Random::for_pixel(seed, row, column).random_f32();
+
+        assert_eq!(first(7, 3, 4), first(7, 3, 4));
+        assert_ne!(first(7, 3, 4), first(7, 4, 3));
+        assert_ne!(first(7, 3, 4), first(8, 3, 4));
+        assert_ne!(first(0, 0, 0), first(0, 0, 1));
+    }
+    #[test]
+    fn pixel_streams_are_uncorrelated() {
+        // The Pearson correlation of the values drawn for the same samples
+        // from two streams, over pixels and over the samples of a pixel.
+        let correlation = |pairs: &[(f32, f32)]| {
+            let n = pairs.len() as f32;
+            let (mean_a, mean_b) = pairs.iter().fold((0.0, 0.0), |(a, b), (x, y)| (a + x / n, b + y / n));
+            let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+            for (x, y) in pairs {
+                covariance += (x - mean_a) * (y - mean_b);
+                variance_a += (x - mean_a).powi(2);
+                variance_b += (y - mean_b).powi(2);
+            }
+            covariance / (variance_a * variance_b).sqrt()
+        };
+
+        let streams = [SampleStream::Paths, SampleStream::Jitter, SampleStream::Lens];
+        for (i, &a) in streams.iter().enumerate() {
+            for &b in &streams[i + 1..] {
+                let pixels: Vec<(f32, f32)> = (0..10_000)
+                    .map(|pixel| (Random::for_pixel_stream(3, pixel / 100, pixel % 100, a).random_f32(), Random::for_pixel_stream(3, pixel / 100, pixel % 100, b).random_f32()))
+                    .collect();
+                assert!(correlation(&pixels).abs() < 0.03, "{:?} and {:?} over pixels", a, b);
+
+                let (mut first, mut second) = (Random::for_pixel_stream(3, 5, 7, a), Random::for_pixel_stream(3, 5, 7, b));
+                let samples: Vec<(f32, f32)> = (0..10_000).map(|_| (first.random_f32(), second.random_f32())).collect();
+                assert!(correlation(&samples).abs() < 0.03, "{:?} and {:?} over samples", a, b);
+            }
+        }
+        assert_eq!(Random::for_pixel(3, 5, 7).random_u32(), Random::for_pixel_stream(3, 5, 7, SampleStream::Paths).random_u32());
+    }
+    #[test]
+    fn ranges_stay_in_bounds() {
+        let mut random = Random::new();
+        for _ in 0..10_000 {
+            let x = random.random_range(-3.0, 5.0);
+            assert!((-3.0..=5.0).contains(&x));
+            assert!(random.random_index(7) < 7);
+        }
+        assert_eq!(random.random_index(1), 0);
+        assert_eq!(random.random_range(2.0, 2.0), 2.0);
+    }
+    #[test]
+    fn indices_and_bits_are_uniform() {
+        let mut random = Random::new();
+        let mut counts = [0u32; 7];
+        for _ in 0..70_000 {
+            counts[random.random_index(counts.len())] += 1;
+        }
+        // Each is expected 10 000 times, with a standard deviation below 100.
+        assert!(counts.iter().all(|&count| (9_500..10_500).contains(&count)), "{:?}", counts);
+
+        let mut bits = [0u32; 32];
+        for _ in 0..10_000 {
+            let x = random.random_u32();
+            for (bit, count) in bits.iter_mut().enumerate() {
+                *count += (x >> bit) & 1;
+            }
+        }
+        assert!(bits.iter().all(|&count| (4_700..5_300).contains(&count)), "{:?}", bits);
+
+        let below_half = (0..10_000).filter(|_| random.random_range(10.0, 20.0) < 15.0).count();
+        assert!((4_700..5_300).contains(&below_half));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::common::{Sphere, Triangle, Mesh, Plane, World, Sky};
+use crate::materials::MaterialType;
+use crate::maths::{Vec3, IVector};
+
+
+/// Everything a scene file describes.
+pub struct Scene {
+    pub camera:  Camera,
+    pub spheres: Vec<Sphere>,
+    pub mesh:    Mesh,
+    pub planes:  Vec<Plane>,
+    /// The resolution the scene is meant to be rendered at, from the
+    /// `output` directive. `None` leaves it up to the caller.
+    pub resolution: Option<(usize, usize)>,
+    /// The background from the `sky` directive. `None` leaves it up to the
+    /// caller, see `Options::sky`.
+    pub sky: Option<Sky>,
+    /// The seed for the random numbers from the `seed` directive, so every
+    /// render of the scene has the same noise. `None` leaves it up to the
+    /// caller, see `Options::seed`. A seed the caller picks itself wins.
+    pub seed: Option<u64>,
+    /// The light from the `ambient` directive. `None` leaves it up to the
+    /// caller, see `Options::ambient`.
+    pub ambient: Option<Color>,
+}
+
+impl Scene {
+    pub fn into_world(self) -> (Camera, World) {
+        (self.camera, World::new(self.spheres, vec![self.mesh]).with_planes(self.planes))
+    }
+
+    /// The Cornell box: a white room with a red wall to the left, a green wall
+    /// to the right and a square light in the ceiling, with a tall and a short
+    /// box on the floor. The room spans [-1, 1] in x and y and [-3, -1] in z,
+    /// and the camera looks into it through the open side at z = -1.
+    pub fn cornell_box() -> Scene {
+        let white = MaterialType::Diffuse(Color::new(0.73, 0.73, 0.73));
+        let red   = MaterialType::Diffuse(Color::new(0.65, 0.05, 0.05));
+        let green = MaterialType::Diffuse(Color::new(0.12, 0.45, 0.15));
+        let light = MaterialType::Emission { color: Color::new(15.0, 15.0, 15.0), two_sided: false };
+
+        let corner = |x: f32, y: f32, z: f32| Vec3::new(x, y, z);
+        let mut triangles = Vec::new();
+
+        // The walls face into the room.
+        triangles.extend(quad([corner(-1.0, -1.0, -1.0), corner( 1.0, -1.0, -1.0), corner( 1.0, -1.0, -3.0), corner(-1.0, -1.0, -3.0)], Vec3::new( 0.0,  1.0, 0.0), &white));
+        triangles.extend(quad([corner(-1.0,  1.0, -1.0), corner( 1.0,  1.0, -1.0), corner( 1.0,  1.0, -3.0), corner(-1.0,  1.0, -3.0)], Vec3::new( 0.0, -1.0, 0.0), &white));
+        triangles.extend(quad([corner(-1.0, -1.0, -3.0), corner( 1.0, -1.0, -3.0), corner( 1.0,  1.0, -3.0), corner(-1.0,  1.0, -3.0)], Vec3::new( 0.0,  0.0, 1.0), &white));
+        triangles.extend(quad([corner(-1.0, -1.0, -1.0), corner(-1.0, -1.0, -3.0), corner(-1.0,  1.0, -3.0), corner(-1.0,  1.0, -1.0)], Vec3::new( 1.0,  0.0, 0.0), &red));
+        triangles.extend(quad([corner( 1.0, -1.0, -1.0), corner( 1.0, -1.0, -3.0), corner( 1.0,  1.0, -3.0), corner( 1.0,  1.0, -1.0)], Vec3::new(-1.0,  0.0, 0.0), &green));
+
+        // Just below the ceiling, so it's not hidden by it.
+        triangles.extend(quad([corner(-0.25, 0.999, -1.75), corner(0.25, 0.999, -1.75), corner(0.25, 0.999, -2.25), corner(-0.25, 0.999, -2.25)], Vec3::new(0.0, -1.0, 0.0), &light));
+
+        triangles.extend(cuboid(Vec3::new(-0.35, -0.4, -2.3), Vec3::new(0.3, 0.6, 0.3),  0.3, &white));
+        triangles.extend(cuboid(Vec3::new( 0.35, -0.7, -1.7), Vec3::new(0.3, 0.3, 0.3), -0.3, &white));
+
+        Scene {
+            camera:     Camera::new_at(Vec3::new(0.0, 0.0, 0.0), 1.0),
+            spheres:    Vec::new(),
+            mesh:       Mesh::new(triangles),
+            planes:     Vec::new(),
+            resolution: Some((512, 512)),
+            sky:        None,
+            seed:       None,
+            ambient:    None,
+        }
+    }
+}
+
+/// Two triangles for the quad with the corners in order around its edge,
+/// with the front facing `facing`.
+fn quad(corners: [Vec3; 4], facing: Vec3, material: &MaterialType) -> [Triangle; 2] {
+    let [v0, v1, v2, v3] = corners;
+    let (v1, v3) = if (v1 - v0).cross(&(v2 - v0)).dot(&facing) < 0.0 { (v3, v1) } else { (v1, v3) };
+    [
+        Triangle::new(v0, v1, v2, material.clone()),
+        Triangle::new(v0, v2, v3, material.clone()),
+    ]
+}
+
+/// A box with the half extents `size`, turned `angle` radians around the
+/// y axis through its `center`, with the faces pointing out.
+fn cuboid(center: Vec3, size: Vec3, angle: f32, material: &MaterialType) -> Vec<Triangle> {
+    let (sin, cos) = angle.sin_cos();
+    let turn = |v: Vec3| Vec3::new(v.x * cos - v.z * sin, v.y, v.x * sin + v.z * cos);
+    let corner = |x: f32, y: f32, z: f32| center + turn(Vec3::new(x * size.x, y * size.y, z * size.z));
+
+    let mut triangles = Vec::new();
+    for &(axis, sign) in [(0, 1.0), (0, -1.0), (1, 1.0), (1, -1.0), (2, 1.0), (2, -1.0)].iter() {
+        // The corners of the face, in order around it, in the box's own space.
+        let square = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+        let point = |(a, b): (f32, f32)| match axis {
+            0 => corner(sign, a, b),
+            1 => corner(a, sign, b),
+            _ => corner(a, b, sign),
+        };
+        let normal = point((0.0, 0.0)) - center;
+        triangles.extend(quad([point(square[0]), point(square[1]), point(square[2]), point(square[3])], normal, material));
+    }
+    triangles
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Options, ray_trace};
+    use crate::image::Framebuffer;
+
+    #[test]
+    fn cornell_box_renders() {
+        let (camera, world) = Scene::cornell_box().into_world();
+        let mut options = Options::new(4, 4, None, true);
+        let framebuffer = ray_trace(&world, &camera, Framebuffer::new(16, 16), &mut options);
+
+        let brightness: Vec<u32> = framebuffer.pixels.iter().map(|p| p.r as u32 + p.g as u32 + p.b as u32).collect();
+        assert!(brightness.iter().any(|&b| b > 0));
+        assert!(brightness.iter().any(|&b| b != brightness[0]));
+
+        // The red wall is to the left and the green one to the right.
+        let left  = framebuffer[[8, 0]];
+        let right = framebuffer[[8, 15]];
+        assert!(left.r > left.g && right.g > right.r);
+    }
+}
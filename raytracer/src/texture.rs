@@ -0,0 +1,223 @@
+use std::f32::consts::PI;
+use std::io::Result;
+
+use crate::color::Color;
+use crate::image::{Framebuffer, read_image};
+use crate::maths::{NVec3, IVector};
+
+
+/// How a texture is sampled between the centers of its texels.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum TextureFilter {
+    /// The closest texel, which looks blocky when magnified.
+    #[default]
+    Nearest,
+    /// Interpolates between the four closest texels.
+    Bilinear,
+}
+
+/// How texture coordinates outside of [0, 1] map into the image.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum WrapMode {
+    /// Tiles the image, using the fractional part.
+    #[default]
+    Repeat,
+    /// Stretches the edges of the image.
+    Clamp,
+    /// Tiles the image, flipping every other tile.
+    Mirror,
+}
+
+impl WrapMode {
+    /// Maps the texel `index` into 0..`count`.
+    fn apply_index(&self, index: isize, count: usize) -> usize {
+        let count = count as isize;
+        let index = match self {
+            WrapMode::Repeat => index.rem_euclid(count),
+            WrapMode::Clamp  => index.clamp(0, count - 1),
+            WrapMode::Mirror => {
+                let index = index.rem_euclid(2 * count);
+                if index >= count { 2 * count - 1 - index } else { index }
+            },
+        };
+        index as usize
+    }
+}
+
+/// An image that can be sampled with (u, v) coordinates in [0, 1], where
+/// (0, 0) is the bottom left corner of the image.
+#[derive(Debug, Clone)]
+pub struct Texture {
+    image: Framebuffer,
+    /// The file the image was loaded from, see `load`.
+    path:  Option<String>,
+}
+
+impl Texture {
+    pub fn new(image: Framebuffer) -> Self {
+        assert!(image.width > 0 && image.height > 0, "Can't create a texture from an empty image!");
+        Self { image, path: None }
+    }
+
+    /// Loads a PPM image, see `read_image`.
+    pub fn load(path: &str) -> Result<Self> {
+        Ok(Self { path: Some(path.to_string()), ..Self::new(read_image(path)?) })
+    }
+
+    /// The file the texture was loaded from, or None if it was made from an
+    /// image in memory.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Samples the texture at (`u`, `v`), where `wrap` handles both the
+    /// coordinates outside of [0, 1] and the texels past the edges that the
+    /// bilinear filter reaches.
+    pub fn sample(&self, u: f32, v: f32, filter: TextureFilter, wrap: WrapMode) -> Color {
+        let Framebuffer { width, height, .. } = self.image;
+
+        // Position in texels, where the centers are at half integers.
+        let x = u * width as f32;
+        let y = (1.0 - v) * height as f32;
+
+        match filter {
+            TextureFilter::Nearest => self.texel(x.floor() as isize, y.floor() as isize, wrap),
+            TextureFilter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (column, row) = (x.floor() as isize, y.floor() as isize);
+                let (tx, ty) = (x - x.floor(), y - y.floor());
+
+                let top    = self.texel(column, row, wrap).lerp(&self.texel(column + 1, row, wrap), tx);
+                let bottom = self.texel(column, row + 1, wrap).lerp(&self.texel(column + 1, row + 1, wrap), tx);
+                top.lerp(&bottom, ty)
+            },
+        }
+    }
+
+    /// The linear color of a texel, with out of range coordinates wrapped.
+    ///
+    /// NOTE: The image is assumed to be gamma corrected the same way as the
+    ///  rendered output (approximately sqrt), so the texels are squared to get
+    ///  back to linear colors.
+    fn texel(&self, column: isize, row: isize, wrap: WrapMode) -> Color {
+        let Framebuffer { width, height, .. } = self.image;
+        let column = wrap.apply_index(column, width);
+        let row    = wrap.apply_index(row, height);
+
+        let texel = self.image[[row, column]];
+        let linear = |channel: u8| (channel as f32 / 255.0).powi(2);
+        Color::new_with_alpha(linear(texel.r), linear(texel.g), linear(texel.b), texel.a as f32 / 255.0)
+    }
+}
+
+
+/// The standard spherical mapping of a point on the unit sphere. `u` goes
+/// around the y axis, starting (and ending) at -x. `v` goes from the bottom
+/// (-y) to the top (+y).
+pub fn sphere_uv(point: &NVec3) -> (f32, f32) {
+    let theta = f32::acos((-point.y()).clamp(-1.0, 1.0));
+    let phi   = f32::atan2(-point.z(), point.x()) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+/// Adjusts where a texture ends up on a sphere. The default is the standard
+/// mapping from `sphere_uv`.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct SphereMapping {
+    /// Added to `u`, i.e. moves the seam. In texture widths.
+    pub u_offset: f32,
+    /// Rotation around the sphere's (y) axis in degrees, counter-clockwise
+    /// when looking from above.
+    pub rotation: f32,
+    /// Puts the top of the texture at the bottom of the sphere.
+    pub flip_v:   bool,
+}
+
+impl SphereMapping {
+    /// The adjusted (u, v). `u` isn't wrapped back into [0, 1], that's up to
+    /// the `WrapMode` the texture is sampled with.
+    pub fn apply(&self, (u, v): (f32, f32)) -> (f32, f32) {
+        let u = u + self.u_offset - self.rotation / 360.0;
+        let v = if self.flip_v { 1.0 - v } else { v };
+        (u, v)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::ColorU8;
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-5 && (a.1 - b.1).abs() < 1e-5
+    }
+
+    #[test]
+    fn sphere_uv_poles_and_seam() {
+        assert!(close(sphere_uv(&NVec3::new(-1.0, 0.0, 0.0)), (0.0, 0.5)));
+        assert!(close(sphere_uv(&NVec3::new( 1.0, 0.0, 0.0)), (0.5, 0.5)));
+        assert!(close(sphere_uv(&NVec3::new(0.0, 0.0,  1.0)), (0.25, 0.5)));
+        assert!((sphere_uv(&NVec3::new(0.0,  1.0, 0.0)).1 - 1.0).abs() < 1e-5);
+        assert!((sphere_uv(&NVec3::new(0.0, -1.0, 0.0)).1 - 0.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn default_mapping_is_identity() {
+        assert!(close(SphereMapping::default().apply((0.3, 0.8)), (0.3, 0.8)));
+    }
+
+    #[test]
+    fn mapping_offset_rotation_and_flip() {
+        let mapping = SphereMapping { u_offset: 0.25, rotation: 0.0, flip_v: true };
+        assert!(close(mapping.apply((0.9, 0.8)), (1.15, 0.2)));
+
+        let mapping = SphereMapping { u_offset: 0.0, rotation: 90.0, flip_v: false };
+        assert!(close(mapping.apply((0.5, 0.5)), (0.25, 0.5)));
+    }
+
+    #[test]
+    fn sample_picks_nearest_texel() {
+        let mut image = Framebuffer::new(2, 2);
+        image[[0, 0]] = ColorU8 { r: 255, g: 0, b: 0, a: 255 };  // Top left.
+        image[[1, 1]] = ColorU8 { r: 0, g: 255, b: 0, a: 255 };  // Bottom right.
+        let texture = Texture::new(image);
+
+        assert_eq!(texture.sample(0.25, 0.75, TextureFilter::Nearest, WrapMode::Repeat).r, 1.0);
+        assert_eq!(texture.sample(0.75, 0.25, TextureFilter::Nearest, WrapMode::Repeat).g, 1.0);
+        assert_eq!(texture.sample(1.25, 0.75, TextureFilter::Nearest, WrapMode::Repeat).r, 1.0);
+    }
+
+    #[test]
+    fn bilinear_interpolates_between_texels() {
+        let mut image = Framebuffer::new(2, 1);
+        image[[0, 0]] = ColorU8 { r: 0,   g: 0, b: 0, a: 255 };
+        image[[0, 1]] = ColorU8 { r: 255, g: 0, b: 0, a: 255 };
+        let texture = Texture::new(image);
+
+        // Exactly at the texel centers.
+        assert!((texture.sample(0.25, 0.5, TextureFilter::Bilinear, WrapMode::Repeat).r - 0.0).abs() < 1e-6);
+        assert!((texture.sample(0.75, 0.5, TextureFilter::Bilinear, WrapMode::Repeat).r - 1.0).abs() < 1e-6);
+        // Halfway between them, and across the wrapped edge.
+        assert!((texture.sample(0.5, 0.5, TextureFilter::Bilinear, WrapMode::Repeat).r - 0.5).abs() < 1e-6);
+        assert!((texture.sample(0.0, 0.5, TextureFilter::Bilinear, WrapMode::Repeat).r - 0.5).abs() < 1e-6);
+        assert!((texture.sample(0.5, 0.5, TextureFilter::Nearest, WrapMode::Repeat).r - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sample_with_wrap_modes() {
+        let mut image = Framebuffer::new(4, 1);
+        for column in 0..4 {
+            image[[0, column]] = ColorU8 { r: (column * 85) as u8, g: 0, b: 0, a: 255 };
+        }
+        let texture = Texture::new(image);
+        let column_at = |u: f32, wrap: WrapMode| {
+            let r = texture.sample(u, 0.5, TextureFilter::Nearest, wrap).r;
+            (0..4).find(|&column| ((column as f32 * 85.0 / 255.0).powi(2) - r).abs() < 1e-6).unwrap()
+        };
+
+        assert_eq!([-0.25, 1.5, 2.0].map(|u| column_at(u, WrapMode::Repeat)), [3, 2, 0]);
+        assert_eq!([-0.25, 1.5, 2.0].map(|u| column_at(u, WrapMode::Clamp)),  [0, 3, 3]);
+        assert_eq!([-0.25, 1.5, 2.0].map(|u| column_at(u, WrapMode::Mirror)), [0, 1, 0]);
+    }
+}